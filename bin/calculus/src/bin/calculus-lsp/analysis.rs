@@ -0,0 +1,275 @@
+//! What the server tells about a source, the offsets are in bytes.
+//!
+//! Nothing is evaluated: the types are inferred by `typeck`, and the values
+//! are the constants folded by `optimizer`.
+
+use calculus_parser::builtin::{Arity, BuiltinFunc};
+use calculus_parser::calculator_ast::{Expr, ExprKind, ExprList, Span};
+use calculus_parser::cst::{Highlight, SyntaxTree, KEYWORDS};
+use calculus_parser::diagnostic::Diagnostic;
+use calculus_parser::environment::Environment;
+use calculus_parser::optimizer::optimize;
+use calculus_parser::pretty::pretty;
+use calculus_parser::typeck::{self, Type};
+use lsp_types::{CompletionItem, CompletionItemKind};
+
+/// The syntax error, or all the errors found by the type checker.
+pub fn diagnostics(source: &str) -> Vec<Diagnostic> {
+    let tree = match SyntaxTree::parse(source) {
+        Ok(tree) => tree,
+        Err(diagnostic) => return vec![diagnostic],
+    };
+    match typeck::check(tree.ast(), &Environment::new()) {
+        Ok(_) => vec![],
+        Err(errors) => errors
+            .iter()
+            .map(|e| Diagnostic::from_eval_error(e, source))
+            .collect(),
+    }
+}
+
+/// The text shown for the expression at `offset` with its span, like
+/// `x: int = 6`, its type and its value if it's a constant.
+pub fn hover(source: &str, offset: usize) -> Option<(Span, String)> {
+    let tree = SyntaxTree::parse(source).ok()?;
+    let list = tree.ast();
+    let expr = innermost(list, offset)?;
+    let types = typeck::check(list, &Environment::new()).ok();
+    let ty = types
+        .map(|types| types.of(expr))
+        .filter(|ty| *ty != Type::Any);
+
+    let (name, value) = match expr.kind {
+        ExprKind::VarRef(ref name) | ExprKind::Assign(ref name, _) => {
+            (Some(name.clone()), variable_value(&tree, expr.span.start))
+        }
+        ExprKind::Builtin(func, _) if on_name(source, expr, func.name(), offset) => {
+            let text = format!("{}: built-in, {}", func.name(), arity(func.arity()));
+            return Some((expr.span, text));
+        }
+        ExprKind::Call(ref name, _) => {
+            let params = find(
+                list,
+                &|e| matches!(e.kind, ExprKind::FuncDef(ref f) if f.name == *name),
+            )
+            .and_then(|e| match e.kind {
+                ExprKind::FuncDef(ref func) => Some(func.params.join(", ")),
+                _ => None,
+            });
+            let signature = params.map(|params| format!("fn {}({})", name, params));
+            (signature, None)
+        }
+        ExprKind::Literal(_) => (None, None),
+        _ => (None, constant(expr)),
+    };
+
+    let mut text = name.unwrap_or_default();
+    if let Some(ty) = ty {
+        if !text.is_empty() {
+            text.push_str(": ");
+        }
+        text.push_str(&ty.to_string());
+    }
+    if let Some(value) = value {
+        if !text.is_empty() {
+            text.push(' ');
+        }
+        text.push_str("= ");
+        text.push_str(&value);
+    }
+    if text.is_empty() {
+        return None;
+    }
+    Some((expr.span, text))
+}
+
+/// Where the variable at `offset` is defined first.
+pub fn definition(source: &str, offset: usize) -> Option<Span> {
+    let tree = SyntaxTree::parse(source).ok()?;
+    let definitions = tree.definitions(offset)?;
+    definitions.first().copied()
+}
+
+/// The built-in functions, the keywords, and the variables and the
+/// functions of the source if it parses.
+pub fn completions(source: &str) -> Vec<CompletionItem> {
+    let item = |label: &str, kind, detail: Option<String>| CompletionItem {
+        label: label.into(),
+        kind: Some(kind),
+        detail,
+        ..CompletionItem::default()
+    };
+
+    let mut items: Vec<_> = BuiltinFunc::all()
+        .map(|func| {
+            let detail = format!("built-in, {}", arity(func.arity()));
+            item(func.name(), CompletionItemKind::FUNCTION, Some(detail))
+        })
+        .collect();
+    items.extend(
+        KEYWORDS
+            .iter()
+            .map(|keyword| item(keyword, CompletionItemKind::KEYWORD, None)),
+    );
+
+    if let Ok(tree) = SyntaxTree::parse(source) {
+        for (span, class) in tree.highlights() {
+            let kind = match class {
+                Highlight::Variable | Highlight::Parameter => CompletionItemKind::VARIABLE,
+                Highlight::Function => CompletionItemKind::FUNCTION,
+                _ => continue,
+            };
+            let name = &source[span.start..span.end];
+            if !items.iter().any(|item| item.label == name) {
+                items.push(item(name, kind, None));
+            }
+        }
+    }
+    items
+}
+
+fn arity(arity: Arity) -> String {
+    match arity {
+        Arity::Exact(1) => "takes 1 argument".into(),
+        Arity::Exact(n) => format!("takes {} arguments", n),
+        Arity::AtLeast(n) => format!("takes {} or more arguments", n),
+    }
+}
+
+// Whether `offset` is on the name the expression starts with.
+fn on_name(source: &str, expr: &Expr, name: &str, offset: usize) -> bool {
+    source[expr.span.start..].starts_with(name) && offset <= expr.span.start + name.len()
+}
+
+// The value of a variable assigned only once with a constant.
+fn variable_value(tree: &SyntaxTree, offset: usize) -> Option<String> {
+    let definitions = tree.definitions(offset)?;
+    let def = match definitions[..] {
+        [def] => def,
+        _ => return None,
+    };
+    let assign = find(tree.ast(), &|e| {
+        matches!(e.kind, ExprKind::Assign(..)) && e.span.start == def.start
+    })?;
+    match assign.kind {
+        ExprKind::Assign(_, ref value) => constant(value),
+        _ => None,
+    }
+}
+
+// The value of the expression as source, if it's folded to a constant.
+fn constant(expr: &Expr) -> Option<String> {
+    let folded = optimize(expr);
+    match folded.kind {
+        ExprKind::Literal(_) => Some(pretty(&folded)),
+        _ => None,
+    }
+}
+
+// The innermost expression covering `offset`, the end of a span is in it
+// for the cursor after the last character.
+fn innermost(list: &ExprList, offset: usize) -> Option<&Expr> {
+    let covers = |expr: &&Expr| expr.span.start <= offset && offset <= expr.span.end;
+    let mut expr = list.0.iter().flatten().map(|expr| &**expr).find(covers)?;
+    while let Some(child) = expr.children().into_iter().find(covers) {
+        expr = child;
+    }
+    Some(expr)
+}
+
+fn find<'a>(list: &'a ExprList, pred: &dyn Fn(&Expr) -> bool) -> Option<&'a Expr> {
+    fn walk<'a>(expr: &'a Expr, pred: &dyn Fn(&Expr) -> bool) -> Option<&'a Expr> {
+        if pred(expr) {
+            return Some(expr);
+        }
+        expr.children()
+            .into_iter()
+            .find_map(|child| walk(child, pred))
+    }
+    list.0.iter().flatten().find_map(|expr| walk(expr, pred))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hover_at(source: &str, needle: &str) -> Option<String> {
+        hover(source, source.find(needle).unwrap()).map(|(_, text)| text)
+    }
+
+    #[test]
+    fn test_hover() {
+        let src = "x = 2 * 3; y = x + 0.5; z = 1; z = 2; fn f(a) { a * 2 }; w = sqrt(y)";
+        assert_eq!(Some("x: int = 6".into()), hover_at(src, "x ="));
+        assert_eq!(Some("x: int = 6".into()), hover_at(src, "x +"));
+        assert_eq!(Some("y: float".into()), hover_at(src, "y ="));
+        // Assigned twice, the value is not known.
+        assert_eq!(Some("z: int".into()), hover_at(src, "z = 2"));
+        assert_eq!(Some("int = 6".into()), hover_at(src, "* 3"));
+        assert_eq!(
+            Some("sqrt: built-in, takes 1 argument".into()),
+            hover_at(src, "sqrt")
+        );
+        assert_eq!(Some("int".into()), hover_at(src, "3;"));
+
+        let src = "fn f(a, b) { a + b }; f(1, 2) + 1";
+        assert_eq!(Some("fn f(a, b)".into()), hover_at(src, "f(1"));
+        // The values are shown even if the types are not known.
+        assert_eq!(
+            Some("s = \"ab\"".into()),
+            hover_at("s = \"a\" + \"b\"; s - 1", "s =")
+        );
+        assert_eq!(None, hover_at("a = (", "a"));
+    }
+
+    #[test]
+    fn test_diagnostics() {
+        assert!(diagnostics("a = 1; a * 2").is_empty());
+        let errors = diagnostics("a = 1 +");
+        assert_eq!(1, errors.len());
+        assert!(errors[0].message.starts_with("unexpected end of input"));
+        let errors = diagnostics("a = (true) - 1; b + 1");
+        let messages: Vec<_> = errors.iter().map(|e| e.message.as_str()).collect();
+        assert_eq!(
+            vec!["type mismatch in `(true) - 1`", "undefined variable `b`"],
+            messages
+        );
+        assert_eq!(Span::new(16, 17), errors[1].span);
+    }
+
+    #[test]
+    fn test_definition() {
+        let src = "x = 1; fn f(x) { x + 1 }; x += 2; f(x)";
+        assert_eq!(
+            Some(Span::new(0, 1)),
+            definition(src, src.rfind('x').unwrap())
+        );
+        assert_eq!(Some(Span::new(12, 13)), definition(src, 17));
+        assert_eq!(None, definition(src, 3));
+    }
+
+    #[test]
+    fn test_completions() {
+        let items = completions("rate = 0.5; fn g(n) { n * rate }");
+        let find = |label: &str| items.iter().find(|item| item.label == label);
+        assert_eq!(
+            Some("built-in, takes 4 arguments"),
+            find("integrate").and_then(|item| item.detail.as_deref())
+        );
+        assert_eq!(
+            Some(CompletionItemKind::KEYWORD),
+            find("while").unwrap().kind
+        );
+        assert_eq!(
+            Some(CompletionItemKind::VARIABLE),
+            find("rate").unwrap().kind
+        );
+        assert_eq!(Some(CompletionItemKind::FUNCTION), find("g").unwrap().kind);
+        assert_eq!(1, items.iter().filter(|item| item.label == "rate").count());
+        // Only the built-ins and the keywords if the source doesn't parse.
+        assert_eq!(
+            BuiltinFunc::all().count() + KEYWORDS.len(),
+            completions("a = (").len()
+        );
+    }
+}
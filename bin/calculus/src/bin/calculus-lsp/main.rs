@@ -0,0 +1,32 @@
+//! The language server of the calculator, it speaks LSP over the standard
+//! input and output, so it can be tried without an editor:
+//!
+//! ```text
+//! calculus-lsp < messages
+//! ```
+//!
+//! The documents are the `.calc` sources, the server reports their syntax
+//! and type errors, shows the types and the constant values on hover, goes
+//! to the definitions of the variables and completes the built-ins.
+
+extern crate calculus_parser;
+
+mod analysis;
+mod rpc;
+mod server;
+
+fn main() {
+    use std::io;
+    use std::process;
+
+    let stdin = io::stdin();
+    let stdout = io::stdout();
+    let code = match server::Server::new().serve(&mut stdin.lock(), &mut stdout.lock()) {
+        Ok(code) => code,
+        Err(e) => {
+            eprintln!("error: {}", e);
+            1
+        }
+    };
+    process::exit(code);
+}
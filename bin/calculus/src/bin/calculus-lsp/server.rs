@@ -0,0 +1,440 @@
+//! The language server, it keeps the open documents and answers the
+//! messages of the editor.
+
+use std::collections::HashMap;
+use std::io::{self, BufRead, Write};
+
+use calculus_parser::calculator_ast::Span;
+use lsp_types::{
+    CompletionOptions, CompletionParams, CompletionResponse, Diagnostic, DiagnosticSeverity,
+    DidChangeTextDocumentParams, DidCloseTextDocumentParams, DidOpenTextDocumentParams,
+    GotoDefinitionParams, GotoDefinitionResponse, Hover, HoverContents, HoverParams,
+    HoverProviderCapability, InitializeResult, Location, MarkupContent, MarkupKind, OneOf,
+    Position, PublishDiagnosticsParams, Range, ServerCapabilities, ServerInfo,
+    TextDocumentSyncCapability, TextDocumentSyncKind, Uri,
+};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use serde_json::Value;
+
+use crate::analysis;
+use crate::rpc::{self, ResponseError};
+
+#[derive(Default)]
+pub struct Server {
+    // The texts by the URIs.
+    documents: HashMap<String, String>,
+    initialized: bool,
+    shutdown: bool,
+    exited: bool,
+}
+
+impl Server {
+    pub fn new() -> Self {
+        Server::default()
+    }
+
+    /// Serve until the `exit` notification or the end of the input, returns
+    /// the exit code, which is 0 only if the server was shut down first.
+    pub fn serve(&mut self, input: &mut impl BufRead, output: &mut impl Write) -> io::Result<i32> {
+        while let Some(body) = rpc::read_message(input)? {
+            for message in self.handle(&body) {
+                rpc::write_message(output, &message)?;
+            }
+            if self.exited {
+                break;
+            }
+        }
+        Ok(if self.shutdown { 0 } else { 1 })
+    }
+
+    /// Handle the body of a message, returns the messages to send back.
+    pub fn handle(&mut self, body: &str) -> Vec<Value> {
+        let message: Value = match serde_json::from_str(body) {
+            Ok(message) => message,
+            Err(e) => {
+                let error = ResponseError::new(rpc::PARSE_ERROR, e.to_string());
+                return vec![rpc::response(Value::Null, Err(error))];
+            }
+        };
+        let method = message.get("method").and_then(Value::as_str);
+        let params = message.get("params").cloned().unwrap_or(Value::Null);
+        match (message.get("id"), method) {
+            (Some(id), Some(method)) => {
+                let result = self.request(method, params);
+                vec![rpc::response(id.clone(), result)]
+            }
+            (None, Some(method)) => self.notification(method, params),
+            // The server sends no requests, so there are no responses to it.
+            _ => vec![],
+        }
+    }
+
+    fn request(&mut self, method: &str, params: Value) -> Result<Value, ResponseError> {
+        if !self.initialized && method != "initialize" {
+            let message = "the server is not initialized";
+            return Err(ResponseError::new(rpc::SERVER_NOT_INITIALIZED, message));
+        }
+        if self.shutdown {
+            let message = "the server is shut down";
+            return Err(ResponseError::new(rpc::INVALID_REQUEST, message));
+        }
+
+        match method {
+            "initialize" => {
+                self.initialized = true;
+                Ok(to_value(initialize_result()))
+            }
+            "shutdown" => {
+                self.shutdown = true;
+                Ok(Value::Null)
+            }
+            "textDocument/hover" => Ok(to_value(self.hover(from_value(params)?))),
+            "textDocument/definition" => Ok(to_value(self.definition(from_value(params)?))),
+            "textDocument/completion" => Ok(to_value(self.completion(from_value(params)?))),
+            _ => Err(ResponseError::new(
+                rpc::METHOD_NOT_FOUND,
+                format!("unknown method `{}`", method),
+            )),
+        }
+    }
+
+    // The notifications with bad parameters are dropped, there is no
+    // response to them.
+    fn notification(&mut self, method: &str, params: Value) -> Vec<Value> {
+        if method == "exit" {
+            self.exited = true;
+            return vec![];
+        }
+        if !self.initialized {
+            return vec![];
+        }
+
+        // The documents are synced in full, the last change is the text.
+        let documents = &mut self.documents;
+        let changed = match method {
+            "textDocument/didOpen" => {
+                from_value(params)
+                    .ok()
+                    .map(|params: DidOpenTextDocumentParams| {
+                        let document = params.text_document;
+                        documents.insert(document.uri.to_string(), document.text);
+                        document.uri
+                    })
+            }
+            "textDocument/didChange" => {
+                from_value(params)
+                    .ok()
+                    .and_then(|mut params: DidChangeTextDocumentParams| {
+                        let change = params.content_changes.pop()?;
+                        let uri = params.text_document.uri;
+                        documents.insert(uri.to_string(), change.text);
+                        Some(uri)
+                    })
+            }
+            // The diagnostics of a closed document are cleared.
+            "textDocument/didClose" => {
+                from_value(params)
+                    .ok()
+                    .map(|params: DidCloseTextDocumentParams| {
+                        documents.remove(params.text_document.uri.as_str());
+                        params.text_document.uri
+                    })
+            }
+            _ => None,
+        };
+        changed
+            .map(|uri| self.publish_diagnostics(uri))
+            .into_iter()
+            .collect()
+    }
+
+    fn publish_diagnostics(&self, uri: Uri) -> Value {
+        let diagnostics = match self.documents.get(uri.as_str()) {
+            Some(text) => analysis::diagnostics(text)
+                .into_iter()
+                .map(|diagnostic| Diagnostic {
+                    range: range(text, diagnostic.span),
+                    severity: Some(DiagnosticSeverity::ERROR),
+                    source: Some("calculus".into()),
+                    message: diagnostic.message,
+                    ..Diagnostic::default()
+                })
+                .collect(),
+            None => vec![],
+        };
+        let params = PublishDiagnosticsParams {
+            uri,
+            diagnostics,
+            version: None,
+        };
+        rpc::notification("textDocument/publishDiagnostics", to_value(params))
+    }
+
+    fn hover(&self, params: HoverParams) -> Option<Hover> {
+        let position = params.text_document_position_params;
+        let text = self.documents.get(position.text_document.uri.as_str())?;
+        let (span, value) = analysis::hover(text, offset(text, position.position))?;
+        Some(Hover {
+            contents: HoverContents::Markup(MarkupContent {
+                kind: MarkupKind::Markdown,
+                value: format!("```calculus\n{}\n```", value),
+            }),
+            range: Some(range(text, span)),
+        })
+    }
+
+    fn definition(&self, params: GotoDefinitionParams) -> Option<GotoDefinitionResponse> {
+        let position = params.text_document_position_params;
+        let uri = position.text_document.uri;
+        let text = self.documents.get(uri.as_str())?;
+        let span = analysis::definition(text, offset(text, position.position))?;
+        Some(GotoDefinitionResponse::Scalar(Location {
+            uri,
+            range: range(text, span),
+        }))
+    }
+
+    fn completion(&self, params: CompletionParams) -> Option<CompletionResponse> {
+        let uri = params.text_document_position.text_document.uri;
+        let text = self.documents.get(uri.as_str())?;
+        Some(CompletionResponse::Array(analysis::completions(text)))
+    }
+}
+
+fn initialize_result() -> InitializeResult {
+    InitializeResult {
+        capabilities: ServerCapabilities {
+            text_document_sync: Some(TextDocumentSyncCapability::Kind(TextDocumentSyncKind::FULL)),
+            hover_provider: Some(HoverProviderCapability::Simple(true)),
+            definition_provider: Some(OneOf::Left(true)),
+            completion_provider: Some(CompletionOptions::default()),
+            ..ServerCapabilities::default()
+        },
+        server_info: Some(ServerInfo {
+            name: "calculus-lsp".into(),
+            version: Some(env!("CARGO_PKG_VERSION").into()),
+        }),
+    }
+}
+
+fn from_value<T: DeserializeOwned>(params: Value) -> Result<T, ResponseError> {
+    serde_json::from_value(params)
+        .map_err(|e| ResponseError::new(rpc::INVALID_PARAMS, e.to_string()))
+}
+
+fn to_value(value: impl Serialize) -> Value {
+    serde_json::to_value(value).unwrap()
+}
+
+// The positions of LSP count the characters in UTF-16 code units.
+
+fn position(text: &str, offset: usize) -> Position {
+    let before = &text[..offset.min(text.len())];
+    let line_start = before.rfind('\n').map_or(0, |pos| pos + 1);
+    Position {
+        line: before.matches('\n').count() as u32,
+        character: before[line_start..].encode_utf16().count() as u32,
+    }
+}
+
+fn range(text: &str, span: Span) -> Range {
+    Range {
+        start: position(text, span.start),
+        end: position(text, span.end),
+    }
+}
+
+// The offset of the position, the ones after a line or the text are moved
+// to its end.
+fn offset(text: &str, position: Position) -> usize {
+    let mut line_start = 0;
+    for _ in 0..position.line {
+        match text[line_start..].find('\n') {
+            Some(pos) => line_start += pos + 1,
+            None => return text.len(),
+        }
+    }
+    let line_end = text[line_start..]
+        .find('\n')
+        .map_or(text.len(), |pos| line_start + pos);
+
+    let mut units = 0;
+    for (i, c) in text[line_start..line_end].char_indices() {
+        if units >= position.character as usize {
+            return line_start + i;
+        }
+        units += c.len_utf16();
+    }
+    line_end
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn framed(messages: &[Value]) -> Vec<u8> {
+        let mut input = Vec::new();
+        for message in messages {
+            rpc::write_message(&mut input, message).unwrap();
+        }
+        input
+    }
+
+    fn request(id: i64, method: &str, params: Value) -> Value {
+        json!({ "jsonrpc": "2.0", "id": id, "method": method, "params": params })
+    }
+
+    fn at(line: u32, character: u32) -> Value {
+        json!({
+            "textDocument": { "uri": "file:///a.calc" },
+            "position": { "line": line, "character": character },
+        })
+    }
+
+    #[test]
+    fn test_session() {
+        let input = framed(&[
+            request(0, "textDocument/hover", at(0, 0)),
+            request(1, "initialize", json!({ "capabilities": {} })),
+            rpc::notification("initialized", json!({})),
+            rpc::notification(
+                "textDocument/didOpen",
+                json!({ "textDocument": {
+                    "uri": "file:///a.calc",
+                    "languageId": "calculus",
+                    "version": 1,
+                    "text": "rate = 0.5;\nfn f(x) { x * rate };\nf(2) + y",
+                }}),
+            ),
+            request(2, "textDocument/hover", at(0, 1)),
+            request(3, "textDocument/definition", at(1, 15)),
+            request(4, "textDocument/completion", at(2, 0)),
+            request(5, "textDocument/rename", at(0, 0)),
+            request(6, "shutdown", Value::Null),
+            rpc::notification("exit", Value::Null),
+            request(7, "textDocument/hover", at(0, 0)),
+        ]);
+
+        let mut output = Vec::new();
+        let code = Server::new()
+            .serve(&mut input.as_slice(), &mut output)
+            .unwrap();
+        assert_eq!(0, code);
+
+        let mut output = output.as_slice();
+        let mut messages = Vec::new();
+        while let Some(body) = rpc::read_message(&mut output).unwrap() {
+            messages.push(serde_json::from_str::<Value>(&body).unwrap());
+        }
+        // Nothing is read after `exit`.
+        assert_eq!(8, messages.len());
+
+        assert_eq!(rpc::SERVER_NOT_INITIALIZED, messages[0]["error"]["code"]);
+        let capabilities = &messages[1]["result"]["capabilities"];
+        assert_eq!(json!(1), capabilities["textDocumentSync"]);
+        assert_eq!(json!(true), capabilities["hoverProvider"]);
+
+        let diagnostics = &messages[2]["params"]["diagnostics"];
+        assert_eq!("textDocument/publishDiagnostics", messages[2]["method"]);
+        assert_eq!("undefined variable `y`", diagnostics[0]["message"]);
+        assert_eq!(
+            json!({ "start": { "line": 2, "character": 7 }, "end": { "line": 2, "character": 8 } }),
+            diagnostics[0]["range"]
+        );
+
+        // The types are not known as `y` is undefined.
+        assert_eq!(
+            "```calculus\nrate = 0.5\n```",
+            messages[3]["result"]["contents"]["value"]
+        );
+        assert_eq!(
+            json!({ "line": 0, "character": 0 }),
+            messages[4]["result"]["range"]["start"]
+        );
+        assert!(messages[5]["result"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .any(|item| item["label"] == "sqrt"));
+        assert_eq!(rpc::METHOD_NOT_FOUND, messages[6]["error"]["code"]);
+        assert_eq!(Value::Null, messages[7]["result"]);
+    }
+
+    #[test]
+    fn test_framing() {
+        let read = |input: &str| rpc::read_message(&mut input.as_bytes());
+        assert_eq!(
+            Some("{}".to_string()),
+            read("\r\nContent-Length: 2\r\n\r\n{}").unwrap()
+        );
+        assert_eq!(None, read("").unwrap());
+        // A huge length is refused before the body is allocated.
+        for input in [
+            "Content-Length: 18446744073709551615\r\n\r\n{}",
+            "Content-Length: 67108865\r\n\r\n{}",
+            "Content-Length: x\r\n\r\n{}",
+        ] {
+            assert_eq!(io::ErrorKind::InvalidData, read(input).unwrap_err().kind());
+        }
+        let mut output = Vec::new();
+        let huge = b"Content-Length: 4294967296\r\n\r\n";
+        assert!(Server::new().serve(&mut &huge[..], &mut output).is_err());
+    }
+
+    #[test]
+    fn test_handle() {
+        let mut server = Server::new();
+        assert_eq!(
+            rpc::PARSE_ERROR,
+            server.handle("{ not json")[0]["error"]["code"]
+        );
+        server.handle(&request(1, "initialize", json!({ "capabilities": {} })).to_string());
+        let reply = server.handle(&request(2, "textDocument/hover", json!({})).to_string());
+        assert_eq!(rpc::INVALID_PARAMS, reply[0]["error"]["code"]);
+
+        let open = |text: &str| {
+            rpc::notification(
+                "textDocument/didOpen",
+                json!({ "textDocument": {
+                    "uri": "file:///b.calc", "languageId": "calculus", "version": 1, "text": text,
+                }}),
+            )
+            .to_string()
+        };
+        let reply = server.handle(&open("a = (1"));
+        let message = &reply[0]["params"]["diagnostics"][0]["message"];
+        assert!(message
+            .as_str()
+            .unwrap()
+            .starts_with("unexpected end of input"));
+
+        let change = rpc::notification(
+            "textDocument/didChange",
+            json!({
+                "textDocument": { "uri": "file:///b.calc", "version": 2 },
+                "contentChanges": [{ "text": "a = (1)" }],
+            }),
+        );
+        let reply = server.handle(&change.to_string());
+        assert_eq!(json!([]), reply[0]["params"]["diagnostics"]);
+        // Not shut down.
+        assert!(server
+            .handle(&rpc::notification("exit", Value::Null).to_string())
+            .is_empty());
+        assert!(server.exited && !server.shutdown);
+    }
+
+    #[test]
+    fn test_positions() {
+        let text = "a = \"é😀\" + b\nc";
+        let b = text.find('b').unwrap();
+        assert_eq!(Position::new(0, 12), position(text, b));
+        assert_eq!(b, offset(text, Position::new(0, 12)));
+        assert_eq!(text.len() - 1, offset(text, Position::new(1, 0)));
+        assert_eq!(text.find('\n').unwrap(), offset(text, Position::new(0, 99)));
+        assert_eq!(text.len(), offset(text, Position::new(5, 0)));
+    }
+}
@@ -0,0 +1,276 @@
+use std::fs;
+use std::io::{self, Read};
+
+use calculus_parser::calculator::ListParser;
+use calculus_parser::calculator_ast::Value;
+use calculus_parser::cst::SyntaxTree;
+use calculus_parser::diagnostic::Diagnostic;
+use calculus_parser::environment::Environment;
+
+/// The source has a syntax or an evaluation error.
+pub const EXIT_ERROR: i32 = 1;
+/// The command line is wrong, or the script can't be read.
+pub const EXIT_USAGE: i32 = 2;
+
+pub const USAGE: &str = "\
+usage: calculus [options] [script]
+       calculus [options] -e <source>
+       calculus fmt [script | -e <source>]
+
+Runs the interactive session if there is no script, `-` reads the
+script from the standard input. `fmt` prints the source in the canonical
+layout with its comments, without evaluating it.
+
+options:
+  -e, --eval <source>  evaluate the source and print its value
+  --ast                print the syntax tree instead of evaluating
+  --vars               print the global variables as JSON after evaluating
+  -h, --help           print this help";
+
+/// Where the source comes from.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Input {
+    File(String),
+    Stdin,
+    Expr(String),
+}
+
+impl Input {
+    pub fn read(&self) -> io::Result<String> {
+        match self {
+            Input::File(path) => fs::read_to_string(path),
+            Input::Stdin => {
+                let mut source = String::new();
+                io::stdin().read_to_string(&mut source)?;
+                Ok(source)
+            }
+            Input::Expr(source) => Ok(source.clone()),
+        }
+    }
+}
+
+/// The command line, there is no input for the interactive session.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Options {
+    pub input: Option<Input>,
+    pub ast: bool,
+    pub vars: bool,
+    pub fmt: bool,
+    pub help: bool,
+}
+
+impl Options {
+    /// Parse the arguments without the name of the program.
+    pub fn parse<I>(args: I) -> Result<Self, String>
+    where
+        I: IntoIterator<Item = String>,
+    {
+        let mut options = Options::default();
+        let mut args = args.into_iter().peekable();
+        if args.peek().map(String::as_str) == Some("fmt") {
+            options.fmt = true;
+            args.next();
+        }
+        let set_input = |options: &mut Options, input: Input| match options.input {
+            Some(_) => Err("more than one source is given".to_string()),
+            None => {
+                options.input = Some(input);
+                Ok(())
+            }
+        };
+
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "-e" | "--eval" => match args.next() {
+                    Some(source) => set_input(&mut options, Input::Expr(source))?,
+                    None => return Err(format!("`{}` takes the source to evaluate", arg)),
+                },
+                "--ast" => options.ast = true,
+                "--vars" => options.vars = true,
+                "-h" | "--help" => options.help = true,
+                "-" => set_input(&mut options, Input::Stdin)?,
+                _ if arg.starts_with('-') => return Err(format!("unknown option `{}`", arg)),
+                _ => set_input(&mut options, Input::File(arg))?,
+            }
+        }
+
+        if options.input.is_none() && (options.ast || options.vars || options.fmt) {
+            return Err("`fmt`, `--ast` and `--vars` need a script or `-e`".into());
+        }
+        if [options.ast, options.vars, options.fmt]
+            .iter()
+            .filter(|on| **on)
+            .count()
+            > 1
+        {
+            return Err("only one of `fmt`, `--ast` and `--vars` can be used".into());
+        }
+        Ok(options)
+    }
+}
+
+/// A failed run, `message` is printed to the standard error.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Failure {
+    pub message: String,
+    pub code: i32,
+}
+
+/// Run the source with the options, returns the text to print. The value
+/// of the source is printed for `-e` only, scripts print by `print`.
+pub fn run(options: &Options, source: &str) -> Result<Option<String>, Failure> {
+    let fail = |message| Failure {
+        message,
+        code: EXIT_ERROR,
+    };
+    if options.fmt {
+        let tree = SyntaxTree::parse(source).map_err(|e| fail(e.render(source)))?;
+        return Ok(Some(tree.format()));
+    }
+    let list = ListParser::new()
+        .parse(source)
+        .map_err(|e| fail(Diagnostic::from_parse_error(&e).render(source)))?;
+    if options.ast {
+        return Ok(Some(format!("{:?}", list)));
+    }
+
+    let mut env = Environment::new();
+    let v = list
+        .eval(&mut env)
+        .map_err(|e| fail(Diagnostic::from_eval_error(&e, source).render(source)))?;
+    if options.vars {
+        let vars: Vec<_> = env
+            .globals()
+            .into_iter()
+            .map(|(name, v)| format!("{}:{}", json_string(name), json(&v)))
+            .collect();
+        return Ok(Some(format!("{{{}}}", vars.join(","))));
+    }
+    match options.input {
+        Some(Input::Expr(_)) => Ok(Some(v.to_string())),
+        _ => Ok(None),
+    }
+}
+
+// Numbers and lists are JSON values, the values JSON doesn't have are
+// strings of how they are printed.
+fn json(v: &Value) -> String {
+    match v {
+        Value::I64(i) => i.to_string(),
+        Value::F64(f) if f.is_finite() => f.to_string(),
+        Value::F64(_) | Value::Null => "null".into(),
+        Value::Bool(b) => b.to_string(),
+        Value::Str(s) => json_string(s),
+        Value::List(list) => {
+            let items: Vec<_> = list.iter().map(json).collect();
+            format!("[{}]", items.join(","))
+        }
+        Value::Func(_) | Value::Rational(_) | Value::Quantity(_) => json_string(&v.to_string()),
+    }
+}
+
+fn json_string(s: &str) -> String {
+    let mut quoted = String::with_capacity(s.len() + 2);
+    quoted.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            '\r' => quoted.push_str("\\r"),
+            '\t' => quoted.push_str("\\t"),
+            c if c.is_control() => quoted.push_str(&format!("\\u{:04x}", c as u32)),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn options(args: &[&str]) -> Result<Options, String> {
+        Options::parse(args.iter().map(|arg| arg.to_string()))
+    }
+
+    #[test]
+    fn test_options() {
+        assert_eq!(Ok(Options::default()), options(&[]));
+        let parsed = options(&["--vars", "script.calc"]).unwrap();
+        assert_eq!(Some(Input::File("script.calc".into())), parsed.input);
+        assert!(parsed.vars && !parsed.ast);
+        let parsed = options(&["-e", "1 + 2", "--ast"]).unwrap();
+        assert_eq!(Some(Input::Expr("1 + 2".into())), parsed.input);
+        assert!(parsed.ast);
+        assert_eq!(Some(Input::Stdin), options(&["-"]).unwrap().input);
+        assert!(options(&["--help"]).unwrap().help);
+        let parsed = options(&["fmt", "-"]).unwrap();
+        assert!(parsed.fmt);
+        assert_eq!(Some(Input::Stdin), parsed.input);
+        assert!(options(&["fmt"]).is_err());
+        assert!(options(&["fmt", "a.calc", "--ast"]).is_err());
+        // Only the first argument is the command.
+        assert_eq!(
+            Some(Input::File("fmt".into())),
+            options(&["--vars", "fmt"]).unwrap().input
+        );
+
+        assert!(options(&["-e"]).unwrap_err().contains("takes the source"));
+        assert!(options(&["--fast"]).unwrap_err().contains("unknown option"));
+        assert!(options(&["a.calc", "-e", "1"])
+            .unwrap_err()
+            .contains("more than one source"));
+        assert!(options(&["--vars"]).is_err());
+        assert!(options(&["-e", "1", "--ast", "--vars"]).is_err());
+    }
+
+    #[test]
+    fn test_run() {
+        let eval = options(&["-e", ""]).unwrap();
+        assert_eq!(Ok(Some("7".to_string())), run(&eval, "a = 3; a * 2 + 1"));
+        let script = options(&["a.calc"]).unwrap();
+        assert_eq!(Ok(None), run(&script, "a = 3"));
+        // A trailing `;` and an empty script are accepted.
+        assert_eq!(Ok(None), run(&script, "a = 1;\n"));
+        assert_eq!(Ok(None), run(&script, ""));
+        assert_eq!(Ok(Some("0".to_string())), run(&eval, "// nothing\n"));
+
+        let ast = options(&["--ast", "-e", ""]).unwrap();
+        assert!(run(&ast, "a + 1").unwrap().unwrap().contains("Add"));
+
+        let fmt = options(&["fmt", "a.calc"]).unwrap();
+        assert_eq!(
+            Ok(Some(
+                "fn f(x) {\n    y = x * 2;\n    y\n};\nf((1 + 2) * 3)".to_string()
+            )),
+            run(&fmt, "fn f(x){y=x*2;y};f(((1+2))*3)")
+        );
+        assert_eq!(
+            Ok(Some("// sum\na = 1 + 2; // three\na * 2".to_string())),
+            run(&fmt, "// sum\na=1+2;// three\na*2")
+        );
+        assert_eq!(Ok(Some("a = 1".to_string())), run(&fmt, "a = 1;\n"));
+        assert_eq!(Ok(Some("".to_string())), run(&fmt, ""));
+
+        let vars = options(&["--vars", "a.calc"]).unwrap();
+        assert_eq!(
+            Ok(Some(
+                r#"{"a":[1,2.5,null],"b":"x \"y\"\n","c":"fn(x)","d":"3 km","e":true}"#.to_string()
+            )),
+            run(
+                &vars,
+                "a = [1, 2.5, null]; b = \"x \\\"y\\\"\\n\"; c = fn(x) { x }; d = 3 km; e = 1 < 2"
+            )
+        );
+
+        let failure = run(&eval, "b = a").unwrap_err();
+        assert_eq!(EXIT_ERROR, failure.code);
+        assert_eq!(
+            "error: undefined variable `a`\n --> 1:5\n  |\n1 | b = a\n  |     ^",
+            failure.message
+        );
+        assert_eq!(EXIT_ERROR, run(&eval, "1 +").unwrap_err().code);
+    }
+}
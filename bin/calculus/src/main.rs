@@ -1,40 +1,67 @@
 extern crate calculus_parser;
 
-#[cfg(not(test))]
+mod cli;
+mod repl;
+
 fn main() {
-    println!(
-        "{:?}",
-        calculus_parser::calculator::ExprParser::new()
-            .parse("22")
-            .unwrap()
-    );
-    println!("nmsl");
-
-    println!(
-        "{:?}",
-        calculus_parser::calculator::ExprParser::new()
-            .parse("22.22312")
-            .unwrap()
-    );
-
-    println!(
-        "{:?}",
-        calculus_parser::calculator::ExprParser::new()
-            .parse("7.321E-3")
-            .unwrap()
-    );
-
-    println!(
-        "{:?}",
-        calculus_parser::calculator::ExprParser::new()
-            .parse("4e-11")
-            .unwrap()
-    );
-
-    println!(
-        "{:?}",
-        calculus_parser::calculator::ExprParser::new()
-            .parse("4e-11 * (3 - 2)")
-            .unwrap()
-    );
+    use std::process;
+
+    let options = match cli::Options::parse(std::env::args().skip(1)) {
+        Ok(options) => options,
+        Err(e) => {
+            eprintln!("error: {}\n\n{}", e, cli::USAGE);
+            process::exit(cli::EXIT_USAGE);
+        }
+    };
+    if options.help {
+        println!("{}", cli::USAGE);
+        return;
+    }
+    let input = match options.input.as_ref() {
+        Some(input) => input,
+        None => return interactive(),
+    };
+
+    let source = match input.read() {
+        Ok(source) => source,
+        Err(e) => {
+            match input {
+                cli::Input::File(path) => eprintln!("error: cannot read `{}`: {}", path, e),
+                _ => eprintln!("error: cannot read the standard input: {}", e),
+            }
+            process::exit(cli::EXIT_USAGE);
+        }
+    };
+    match cli::run(&options, &source) {
+        Ok(Some(output)) => println!("{}", output),
+        Ok(None) => {}
+        Err(failure) => {
+            eprintln!("{}", failure.message);
+            process::exit(failure.code);
+        }
+    }
+}
+
+fn interactive() {
+    use std::io::{self, BufRead, Write};
+
+    let mut repl = repl::Repl::new();
+    let stdin = io::stdin();
+    let mut lines = stdin.lock().lines();
+
+    loop {
+        print!("{}", repl.prompt());
+        io::stdout().flush().unwrap();
+
+        let line = match lines.next() {
+            Some(Ok(line)) => line,
+            _ => break,
+        };
+        if let Some(output) = repl.feed(&line) {
+            println!("{}", output);
+        }
+        if repl.finished() {
+            break;
+        }
+    }
 }
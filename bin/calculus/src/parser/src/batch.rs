@@ -0,0 +1,745 @@
+//! Evaluating a formula over columns of inputs, for the many rows of
+//! bindings of the same variables, like backtesting.
+//!
+//! The formula is evaluated an operator at a time over whole columns
+//! instead of a row at a time: `a * b + c` multiplies the columns of `a`
+//! and `b`, then adds the column of `c`. The columns of integers, floats
+//! and booleans are plain vectors with tight loops for the arithmetic and
+//! the comparisons, the other values are taken element by element by the
+//! rules of `Value`. So the result of every row is the one `Expr::eval`
+//! gives with the variables of the row.
+//!
+//! The branches of `if` and the right sides of `&&` and `||` are evaluated
+//! on the rows taking them only, so `x != 0 && 1 / x > 2` doesn't divide by
+//! zero. If a row fails, the error of the first operator failing is
+//! reported with the first row it fails on.
+//!
+//! The loops, the functions, and the built-in functions with side effects
+//! or taking functions are not supported, the formulas using them are
+//! refused before evaluating.
+//!
+//! ```
+//! use calculus_parser::batch::{Batch, Column};
+//! use calculus_parser::interpreter::Interpreter;
+//!
+//! let src = "spread = ask - bid; if spread > 0.5 then { spread * qty } else { 0.0 }";
+//! let formula = Interpreter::new().parse(src).unwrap();
+//! let mut batch = Batch::new(3);
+//! batch
+//!     .set_column("bid", vec![1.0, 2.0, 3.0])
+//!     .set_column("ask", vec![1.25, 3.0, 3.5])
+//!     .set_var("qty", 10);
+//! assert_eq!(
+//!     Column::F64(vec![0.0, 10.0, 0.0]),
+//!     batch.eval(&formula).unwrap()
+//! );
+//! ```
+
+use std::borrow::Cow;
+use std::collections::{HashMap, HashSet};
+use std::rc::Rc;
+
+use crate::builtin::BuiltinFunc;
+use crate::calculator_ast::{ControlFlow, Expr, ExprKind, ExprList, NumericMode, Opcode, Value};
+use crate::error::{ArithError, BatchError, EvalError};
+
+/// A column of values, one for every row.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Column {
+    I64(Vec<i64>),
+    F64(Vec<f64>),
+    Bool(Vec<bool>),
+    // The other values, or the rows of different types.
+    Values(Vec<Value>),
+}
+
+impl Column {
+    /// A typed column if all the values are of the same type.
+    pub fn from_values(values: Vec<Value>) -> Self {
+        if values.iter().all(|v| matches!(v, Value::I64(_))) {
+            Column::I64(values.iter().map(Value::as_i64).collect())
+        } else if values.iter().all(|v| matches!(v, Value::F64(_))) {
+            Column::F64(values.iter().map(Value::as_f64).collect())
+        } else if values.iter().all(|v| matches!(v, Value::Bool(_))) {
+            Column::Bool(values.iter().map(Value::as_bool).collect())
+        } else {
+            Column::Values(values)
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        match self {
+            Column::I64(c) => c.len(),
+            Column::F64(c) => c.len(),
+            Column::Bool(c) => c.len(),
+            Column::Values(c) => c.len(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// The value of the row.
+    pub fn get(&self, row: usize) -> Value {
+        match self {
+            Column::I64(c) => Value::I64(c[row]),
+            Column::F64(c) => Value::F64(c[row]),
+            Column::Bool(c) => Value::Bool(c[row]),
+            Column::Values(c) => c[row].clone(),
+        }
+    }
+
+    /// The values of all the rows.
+    pub fn values(&self) -> Vec<Value> {
+        (0..self.len()).map(|row| self.get(row)).collect()
+    }
+
+    // The rows at the indexes.
+    fn gather(&self, rows: &[usize]) -> Column {
+        fn pick<T: Clone>(c: &[T], rows: &[usize]) -> Vec<T> {
+            rows.iter().map(|&row| c[row].clone()).collect()
+        }
+        match self {
+            Column::I64(c) => Column::I64(pick(c, rows)),
+            Column::F64(c) => Column::F64(pick(c, rows)),
+            Column::Bool(c) => Column::Bool(pick(c, rows)),
+            Column::Values(c) => Column::Values(pick(c, rows)),
+        }
+    }
+}
+
+impl From<Vec<i64>> for Column {
+    fn from(c: Vec<i64>) -> Self {
+        Column::I64(c)
+    }
+}
+
+impl From<Vec<f64>> for Column {
+    fn from(c: Vec<f64>) -> Self {
+        Column::F64(c)
+    }
+}
+
+impl From<Vec<bool>> for Column {
+    fn from(c: Vec<bool>) -> Self {
+        Column::Bool(c)
+    }
+}
+
+impl From<Vec<Value>> for Column {
+    fn from(values: Vec<Value>) -> Self {
+        Column::from_values(values)
+    }
+}
+
+/// The rows to evaluate the formulas on, a variable is a column with a
+/// value for every row, or a constant.
+#[derive(Clone)]
+pub struct Batch {
+    rows: usize,
+    mode: NumericMode,
+    vars: Vec<(String, Col)>,
+}
+
+impl Batch {
+    pub fn new(rows: usize) -> Self {
+        Batch {
+            rows,
+            mode: NumericMode::default(),
+            vars: Vec::new(),
+        }
+    }
+
+    /// Bind the variable to a column, it must have a value for every row.
+    pub fn set_column(&mut self, name: &str, column: impl Into<Column>) -> &mut Self {
+        self.set(name, Col::rows(column.into()))
+    }
+
+    /// Bind the variable to the same value for every row.
+    pub fn set_var(&mut self, name: &str, value: impl Into<Value>) -> &mut Self {
+        self.set(name, Col::Const(value.into()))
+    }
+
+    pub fn set_mode(&mut self, mode: NumericMode) -> &mut Self {
+        self.mode = mode;
+        self
+    }
+
+    fn set(&mut self, name: &str, col: Col) -> &mut Self {
+        match self.vars.iter_mut().find(|(var, _)| var == name) {
+            Some(var) => var.1 = col,
+            None => self.vars.push((name.into(), col)),
+        }
+        self
+    }
+
+    /// Evaluate the formula on every row, the result is the value of the
+    /// last statement.
+    pub fn eval(&self, list: &ExprList) -> Result<Column, BatchError> {
+        list.0.iter().flatten().try_for_each(|expr| check(expr))?;
+        for (name, col) in self.vars.iter() {
+            match col {
+                Col::Rows(column) if column.len() != self.rows => {
+                    return Err(BatchError::Length {
+                        name: name.clone(),
+                        found: column.len(),
+                        expected: self.rows,
+                    });
+                }
+                _ => {}
+            }
+        }
+        if self.rows == 0 {
+            return Ok(Column::Values(Vec::new()));
+        }
+
+        let mut frame = Frame {
+            rows: (0..self.rows).collect(),
+            vars: self
+                .vars
+                .iter()
+                .map(|(name, col)| (name.clone(), Var::new(col.clone())))
+                .collect(),
+            assigned: HashSet::new(),
+        };
+        Ok(self.list(list, &mut frame)?.into_column(self.rows))
+    }
+
+    fn list(&self, list: &ExprList, frame: &mut Frame) -> Result<Col, BatchError> {
+        let mut col = Col::Const(Value::default());
+        for expr in list.0.iter().flatten() {
+            col = self.expr(expr, frame)?;
+        }
+        Ok(col)
+    }
+
+    fn expr(&self, expr: &Expr, frame: &mut Frame) -> Result<Col, BatchError> {
+        match expr.kind {
+            ExprKind::Literal(ref v) => Ok(Col::Const(v.clone())),
+            ExprKind::VarRef(ref name) => {
+                let undefined = |row| {
+                    let error = EvalError::UndefinedVariable {
+                        name: name.clone(),
+                        span: expr.span,
+                    };
+                    frame.error(row, error)
+                };
+                let var = frame.vars.get(name).ok_or_else(|| undefined(0))?;
+                // Assigned in a branch not taken by all the rows.
+                if let Some(row) = var.defined.as_ref().and_then(|d| d.iter().position(|d| !d)) {
+                    return Err(undefined(row));
+                }
+                Ok(var.col.clone())
+            }
+            ExprKind::Assign(ref name, ref rnode) => {
+                let col = self.expr(rnode, frame)?;
+                frame.vars.insert(name.clone(), Var::new(col.clone()));
+                frame.assigned.insert(name.clone());
+                Ok(col)
+            }
+            ExprKind::OneOp(op, ref node) => {
+                let col = self.expr(node, frame)?;
+                self.unary(op, col, expr, frame)
+            }
+            ExprKind::TwoOp(op @ (Opcode::And | Opcode::Or), ref lnode, ref rnode) => {
+                // The right side is evaluated on the rows the left one
+                // doesn't decide.
+                let decided = op == Opcode::Or;
+                let l = self.expr(lnode, frame)?.truth(frame.len());
+                let needed: Vec<_> = l.iter().map(|l| *l != decided).collect();
+                self.split(
+                    frame,
+                    &needed,
+                    |frame| {
+                        let r = self.expr(rnode, frame)?;
+                        Ok(Col::rows(Column::Bool(r.truth(frame.len()))))
+                    },
+                    |_| Ok(Col::Const(Value::Bool(decided))),
+                )
+            }
+            ExprKind::TwoOp(op, ref lnode, ref rnode) => {
+                let l = self.expr(lnode, frame)?;
+                let r = self.expr(rnode, frame)?;
+                self.binary(op, l, r, expr, frame)
+            }
+            ExprKind::Builtin(func, ref args) => {
+                let arity = func.arity();
+                if !arity.accepts(args.len()) {
+                    let error = EvalError::ArityMismatch {
+                        name: func.name().into(),
+                        expected: arity.min(),
+                        found: args.len(),
+                        span: expr.span,
+                    };
+                    return Err(frame.error(0, error));
+                }
+                let args = args
+                    .iter()
+                    .map(|arg| self.expr(arg, frame))
+                    .collect::<Result<Vec<_>, _>>()?;
+                self.builtin(func, args, expr, frame)
+            }
+            ExprKind::List(ref items) => {
+                let items = items
+                    .iter()
+                    .map(|item| self.expr(item, frame))
+                    .collect::<Result<Vec<_>, _>>()?;
+                map_rows(frame, &items, expr, |items| Ok(Value::from(items.to_vec())))
+            }
+            ExprKind::Index(ref lnode, ref index) => {
+                let l = self.expr(lnode, frame)?;
+                let index = self.expr(index, frame)?;
+                map_rows(frame, &[l, index], expr, |args| args[0].index(&args[1]))
+            }
+            ExprKind::Flow(ControlFlow::Condition(ref flow)) => {
+                let cond = self.expr(&flow.cond, frame)?.truth(frame.len());
+                self.split(
+                    frame,
+                    &cond,
+                    |frame| self.list(&flow.if_branch, frame),
+                    |frame| match flow.else_branch {
+                        Some(ref branch) => self.list(branch, frame),
+                        None => Ok(Col::Const(Value::default())),
+                    },
+                )
+            }
+            // Refused by `check`.
+            ExprKind::Flow(_) | ExprKind::FuncDef(_) | ExprKind::Call(..) | ExprKind::Lambda(_) => {
+                unreachable!()
+            }
+        }
+    }
+
+    // Evaluate `then` on the rows where `mask` is true and `otherwise` on
+    // the rest, the results and the variables assigned are put together.
+    fn split(
+        &self,
+        frame: &mut Frame,
+        mask: &[bool],
+        then: impl FnOnce(&mut Frame) -> Result<Col, BatchError>,
+        otherwise: impl FnOnce(&mut Frame) -> Result<Col, BatchError>,
+    ) -> Result<Col, BatchError> {
+        let (a, b): (Vec<_>, Vec<_>) = (0..frame.len()).partition(|&i| mask[i]);
+        if b.is_empty() {
+            return then(frame);
+        }
+        if a.is_empty() {
+            return otherwise(frame);
+        }
+
+        let mut x = frame.select(&a);
+        let mut y = frame.select(&b);
+        let xcol = then(&mut x)?;
+        let ycol = otherwise(&mut y)?;
+        let n = frame.len();
+        let mut names: Vec<_> = x.assigned.union(&y.assigned).collect();
+        names.sort();
+        for name in names {
+            let var = Var::merge(n, &a, x.vars.get(name), &b, y.vars.get(name));
+            frame.vars.insert(name.clone(), var);
+            frame.assigned.insert(name.clone());
+        }
+        Ok(scatter(n, &a, &xcol, &b, &ycol))
+    }
+
+    fn unary(&self, op: Opcode, col: Col, expr: &Expr, frame: &Frame) -> Result<Col, BatchError> {
+        if let Col::Rows(ref column) = col {
+            let fast = match (op, &**column) {
+                (Opcode::Add, Column::I64(_) | Column::F64(_)) => Some((**column).clone()),
+                (Opcode::Sub, Column::F64(c)) => Some(Column::F64(c.iter().map(|x| -x).collect())),
+                // An overflow is taken by the rules of `Value`.
+                (Opcode::Sub, Column::I64(c)) => c
+                    .iter()
+                    .map(|x| x.checked_neg())
+                    .collect::<Option<_>>()
+                    .map(Column::I64),
+                (Opcode::Not, Column::Bool(c)) => {
+                    Some(Column::Bool(c.iter().map(|x| !x).collect()))
+                }
+                _ => None,
+            };
+            if let Some(column) = fast {
+                return Ok(Col::rows(column));
+            }
+        }
+        let mode = self.mode;
+        map_rows(frame, &[col], expr, |args| op.apply_unary(&args[0], mode))
+    }
+
+    fn binary(
+        &self,
+        op: Opcode,
+        l: Col,
+        r: Col,
+        expr: &Expr,
+        frame: &Frame,
+    ) -> Result<Col, BatchError> {
+        let n = frame.len();
+        if l.is_rows() || r.is_rows() {
+            if let (Some(a), Some(b)) = (l.numbers(n), r.numbers(n)) {
+                let fast = match (&*a, &*b) {
+                    (Column::I64(a), Column::I64(b)) => int_op(op, a, b, self.mode),
+                    (a, b) => Some(float_op(op, &floats(a), &floats(b))),
+                };
+                if let Some(column) = fast {
+                    return Ok(Col::rows(column));
+                }
+            }
+        }
+        let mode = self.mode;
+        map_rows(frame, &[l, r], expr, |args| {
+            op.apply(&args[0], &args[1], mode)
+        })
+    }
+
+    fn builtin(
+        &self,
+        func: BuiltinFunc,
+        args: Vec<Col>,
+        expr: &Expr,
+        frame: &Frame,
+    ) -> Result<Col, BatchError> {
+        let n = frame.len();
+        let fast = match (func, &args[..]) {
+            (BuiltinFunc::Pow, [base, exp]) if base.is_rows() || exp.is_rows() => {
+                // Only the float bases, the integers may be exact.
+                match (base.numbers(n), exp.numbers(n)) {
+                    (Some(base), Some(exp)) => match &*base {
+                        Column::F64(base) => Some(Column::F64(
+                            base.iter()
+                                .zip(floats(&exp).iter())
+                                .map(|(b, e)| b.powf(*e))
+                                .collect(),
+                        )),
+                        _ => None,
+                    },
+                    _ => None,
+                }
+            }
+            (_, [Col::Rows(column)]) => unary_fn(func, column),
+            _ => None,
+        };
+        if let Some(column) = fast {
+            return Ok(Col::rows(column));
+        }
+        let mode = self.mode;
+        map_rows(frame, &args, expr, |args| func.call(args, mode))
+    }
+}
+
+// Refuse the expressions which can't be evaluated a column at a time.
+fn check(expr: &Expr) -> Result<(), BatchError> {
+    let unsupported = |what: String| {
+        Err(BatchError::Unsupported {
+            what,
+            span: expr.span,
+        })
+    };
+    match expr.kind {
+        ExprKind::Flow(ControlFlow::While(_) | ControlFlow::For(_)) => unsupported("a loop".into()),
+        ExprKind::Flow(ControlFlow::Break) => unsupported("`break`".into()),
+        ExprKind::Flow(ControlFlow::Continue) => unsupported("`continue`".into()),
+        ExprKind::FuncDef(_) | ExprKind::Lambda(_) => unsupported("a function".into()),
+        ExprKind::Call(ref name, _) => unsupported(format!("a call of `{}`", name)),
+        ExprKind::Builtin(func, _)
+            if func.is_numeric() || func.is_higher_order() || func == BuiltinFunc::Print =>
+        {
+            unsupported(format!("`{}`", func.name()))
+        }
+        _ => expr.children().into_iter().try_for_each(check),
+    }
+}
+
+// The value of an expression over the rows of a frame, a constant isn't
+// repeated for every row.
+#[derive(Clone)]
+enum Col {
+    Const(Value),
+    Rows(Rc<Column>),
+}
+
+impl Col {
+    fn rows(column: Column) -> Self {
+        Col::Rows(Rc::new(column))
+    }
+
+    fn is_rows(&self) -> bool {
+        matches!(self, Col::Rows(_))
+    }
+
+    fn get(&self, row: usize) -> Value {
+        match self {
+            Col::Const(v) => v.clone(),
+            Col::Rows(column) => column.get(row),
+        }
+    }
+
+    fn gather(&self, rows: &[usize]) -> Col {
+        match self {
+            Col::Const(_) => self.clone(),
+            Col::Rows(column) => Col::rows(column.gather(rows)),
+        }
+    }
+
+    fn into_column(self, n: usize) -> Column {
+        match self {
+            Col::Const(Value::I64(i)) => Column::I64(vec![i; n]),
+            Col::Const(Value::F64(f)) => Column::F64(vec![f; n]),
+            Col::Const(Value::Bool(b)) => Column::Bool(vec![b; n]),
+            Col::Const(v) => Column::Values(vec![v; n]),
+            Col::Rows(column) => Rc::try_unwrap(column).unwrap_or_else(|c| (*c).clone()),
+        }
+    }
+
+    // The column of `I64` or `F64`, a constant is repeated.
+    fn numbers(&self, n: usize) -> Option<Cow<'_, Column>> {
+        match self {
+            Col::Const(Value::I64(i)) => Some(Cow::Owned(Column::I64(vec![*i; n]))),
+            Col::Const(Value::F64(f)) => Some(Cow::Owned(Column::F64(vec![*f; n]))),
+            Col::Rows(column) if matches!(**column, Column::I64(_) | Column::F64(_)) => {
+                Some(Cow::Borrowed(column))
+            }
+            _ => None,
+        }
+    }
+
+    // The truth of every row, see `Value::as_bool`.
+    fn truth(&self, n: usize) -> Vec<bool> {
+        match self {
+            Col::Const(v) => vec![v.as_bool(); n],
+            Col::Rows(column) => match &**column {
+                Column::Bool(c) => c.clone(),
+                Column::I64(c) => c.iter().map(|i| *i != 0).collect(),
+                Column::F64(c) => c.iter().map(|f| *f != 0.0).collect(),
+                Column::Values(c) => c.iter().map(Value::as_bool).collect(),
+            },
+        }
+    }
+}
+
+#[derive(Clone)]
+struct Var {
+    col: Col,
+    // The rows the variable is defined for, `None` if it's all of them.
+    defined: Option<Rc<Vec<bool>>>,
+}
+
+impl Var {
+    fn new(col: Col) -> Self {
+        Var { col, defined: None }
+    }
+
+    // The variable of `n` rows taking the rows `a` from `x` and the rows
+    // `b` from `y`, the rows of a missing side are not defined.
+    fn merge(n: usize, a: &[usize], x: Option<&Var>, b: &[usize], y: Option<&Var>) -> Var {
+        let part = |var: Option<&Var>, len: usize| match var {
+            Some(var) => (var.col.clone(), var.defined.clone()),
+            None => (Col::Const(Value::Null), Some(Rc::new(vec![false; len]))),
+        };
+        let (xcol, xdefined) = part(x, a.len());
+        let (ycol, ydefined) = part(y, b.len());
+        let defined = match (xdefined, ydefined) {
+            (None, None) => None,
+            (xdefined, ydefined) => {
+                let all = |defined: Option<Rc<Vec<bool>>>, len| match defined {
+                    Some(defined) => defined.to_vec(),
+                    None => vec![true; len],
+                };
+                let x = all(xdefined, a.len());
+                let y = all(ydefined, b.len());
+                Some(Rc::new(fill(n, a, &x, b, &y)))
+            }
+        };
+        Var {
+            col: scatter(n, a, &xcol, b, &ycol),
+            defined,
+        }
+    }
+}
+
+// The rows evaluated and the variables over them.
+struct Frame {
+    // The rows of the batch, the errors are reported with them.
+    rows: Vec<usize>,
+    vars: HashMap<String, Var>,
+    // The variables assigned in the frame, they are merged into the frame
+    // the rows are selected from.
+    assigned: HashSet<String>,
+}
+
+impl Frame {
+    fn len(&self) -> usize {
+        self.rows.len()
+    }
+
+    fn error(&self, i: usize, error: EvalError) -> BatchError {
+        BatchError::Row {
+            row: self.rows[i],
+            error,
+        }
+    }
+
+    // The frame of the rows at the indexes.
+    fn select(&self, rows: &[usize]) -> Frame {
+        let vars = self.vars.iter().map(|(name, var)| {
+            let defined = var
+                .defined
+                .as_ref()
+                .map(|defined| Rc::new(rows.iter().map(|&i| defined[i]).collect()));
+            let var = Var {
+                col: var.col.gather(rows),
+                defined,
+            };
+            (name.clone(), var)
+        });
+        Frame {
+            rows: rows.iter().map(|&i| self.rows[i]).collect(),
+            vars: vars.collect(),
+            assigned: HashSet::new(),
+        }
+    }
+}
+
+// Apply `f` on the values of every row by the rules of `Value`, the
+// constants are applied once.
+fn map_rows(
+    frame: &Frame,
+    cols: &[Col],
+    expr: &Expr,
+    f: impl Fn(&[Value]) -> Result<Value, ArithError>,
+) -> Result<Col, BatchError> {
+    if cols.iter().all(|col| !col.is_rows()) {
+        let args: Vec<_> = cols.iter().map(|col| col.get(0)).collect();
+        return f(&args)
+            .map(Col::Const)
+            .map_err(|e| frame.error(0, e.at(expr)));
+    }
+    let mut args = Vec::with_capacity(cols.len());
+    let values = (0..frame.len())
+        .map(|i| {
+            args.clear();
+            args.extend(cols.iter().map(|col| col.get(i)));
+            f(&args).map_err(|e| frame.error(i, e.at(expr)))
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    Ok(Col::rows(Column::from_values(values)))
+}
+
+// The column of `n` rows taking the rows `a` from `x` and the rows `b`
+// from `y`.
+fn scatter(n: usize, a: &[usize], x: &Col, b: &[usize], y: &Col) -> Col {
+    let x = x.clone().into_column(a.len());
+    let y = y.clone().into_column(b.len());
+    Col::rows(match (x, y) {
+        (Column::I64(x), Column::I64(y)) => Column::I64(fill(n, a, &x, b, &y)),
+        (Column::F64(x), Column::F64(y)) => Column::F64(fill(n, a, &x, b, &y)),
+        (Column::Bool(x), Column::Bool(y)) => Column::Bool(fill(n, a, &x, b, &y)),
+        (x, y) => Column::Values(fill(n, a, &x.values(), b, &y.values())),
+    })
+}
+
+fn fill<T: Clone + Default>(n: usize, a: &[usize], x: &[T], b: &[usize], y: &[T]) -> Vec<T> {
+    let mut out = vec![T::default(); n];
+    for (&i, v) in a.iter().zip(x).chain(b.iter().zip(y)) {
+        out[i] = v.clone();
+    }
+    out
+}
+
+fn floats(column: &Column) -> Cow<'_, [f64]> {
+    match column {
+        Column::F64(c) => Cow::Borrowed(c),
+        Column::I64(c) => Cow::Owned(c.iter().map(|i| *i as f64).collect()),
+        _ => unreachable!(),
+    }
+}
+
+// The arithmetic and the comparisons of integers, `None` if it fails on a
+// row, the rules of `Value` tell the error or the exact result then.
+fn int_op(op: Opcode, a: &[i64], b: &[i64], mode: NumericMode) -> Option<Column> {
+    let zip = |f: fn(i64, i64) -> Option<i64>| {
+        a.iter()
+            .zip(b)
+            .map(|(x, y)| f(*x, *y))
+            .collect::<Option<_>>()
+            .map(Column::I64)
+    };
+    let cmp = |f: fn(&i64, &i64) -> bool| {
+        Some(Column::Bool(
+            a.iter().zip(b).map(|(x, y)| f(x, y)).collect(),
+        ))
+    };
+    match op {
+        Opcode::Add => zip(i64::checked_add),
+        Opcode::Sub => zip(i64::checked_sub),
+        Opcode::Mul => zip(i64::checked_mul),
+        Opcode::Rem => zip(i64::checked_rem),
+        Opcode::Div if mode == NumericMode::Checked => zip(i64::checked_div),
+        // Only exact quotients are `I64` in the exact mode.
+        Opcode::Div => zip(|x, y| match x.checked_rem(y)? {
+            0 => x.checked_div(y),
+            _ => None,
+        }),
+        Opcode::Equal => cmp(i64::eq),
+        Opcode::NotEqual => cmp(i64::ne),
+        Opcode::LargerOrEqual => cmp(i64::ge),
+        Opcode::LargerThan => cmp(i64::gt),
+        Opcode::LessOrEqual => cmp(i64::le),
+        Opcode::LessThan => cmp(i64::lt),
+        _ => None,
+    }
+}
+
+// The arithmetic and the comparisons where any of the sides is a float.
+fn float_op(op: Opcode, a: &[f64], b: &[f64]) -> Column {
+    let zip =
+        |f: fn(f64, f64) -> f64| Column::F64(a.iter().zip(b).map(|(x, y)| f(*x, *y)).collect());
+    let cmp =
+        |f: fn(&f64, &f64) -> bool| Column::Bool(a.iter().zip(b).map(|(x, y)| f(x, y)).collect());
+    match op {
+        Opcode::Add => zip(|x, y| x + y),
+        Opcode::Sub => zip(|x, y| x - y),
+        Opcode::Mul => zip(|x, y| x * y),
+        Opcode::Div => zip(|x, y| x / y),
+        Opcode::Rem => zip(|x, y| x % y),
+        Opcode::Equal => cmp(f64::eq),
+        Opcode::NotEqual => cmp(f64::ne),
+        Opcode::LargerOrEqual => cmp(f64::ge),
+        Opcode::LargerThan => cmp(f64::gt),
+        Opcode::LessOrEqual => cmp(f64::le),
+        Opcode::LessThan => cmp(f64::lt),
+        _ => unreachable!(),
+    }
+}
+
+// The built-in functions of one number, see `BuiltinFunc::call`.
+fn unary_fn(func: BuiltinFunc, column: &Column) -> Option<Column> {
+    let float = |f: fn(f64) -> f64| match column {
+        Column::F64(c) => Some(Column::F64(c.iter().map(|x| f(*x)).collect())),
+        Column::I64(c) => Some(Column::F64(c.iter().map(|x| f(*x as f64)).collect())),
+        _ => None,
+    };
+    // Integers are already rounded.
+    let round = |f: fn(f64) -> f64| match column {
+        Column::I64(_) => Some(column.clone()),
+        _ => float(f),
+    };
+    match func {
+        BuiltinFunc::Sqrt => float(f64::sqrt),
+        BuiltinFunc::Exp => float(f64::exp),
+        BuiltinFunc::Ln => float(f64::ln),
+        BuiltinFunc::Log => float(f64::log10),
+        BuiltinFunc::Sin => float(f64::sin),
+        BuiltinFunc::Cos => float(f64::cos),
+        BuiltinFunc::Tan => float(f64::tan),
+        BuiltinFunc::Asin => float(f64::asin),
+        BuiltinFunc::Acos => float(f64::acos),
+        BuiltinFunc::Atan => float(f64::atan),
+        BuiltinFunc::Floor => round(f64::floor),
+        BuiltinFunc::Ceil => round(f64::ceil),
+        BuiltinFunc::Round => round(f64::round),
+        BuiltinFunc::Abs if matches!(column, Column::F64(_)) => float(f64::abs),
+        _ => None,
+    }
+}
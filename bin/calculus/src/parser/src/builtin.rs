@@ -0,0 +1,310 @@
+use std::cmp::Ordering;
+use std::convert::TryFrom;
+
+use num_bigint::BigInt;
+use num_rational::BigRational;
+use num_traits::{Signed, Zero};
+
+use crate::calculator_ast::{NumericMode, Opcode, Value};
+use crate::error::ArithError;
+use crate::units;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum BuiltinFunc {
+    Sqrt,
+    Exp,
+    // natural logarithm
+    Ln,
+    // base 10 logarithm
+    Log,
+    Pow,
+    Abs,
+    Min,
+    Max,
+    Floor,
+    Ceil,
+    Round,
+    Sin,
+    Cos,
+    Tan,
+    Asin,
+    Acos,
+    Atan,
+    Print,
+    // `integrate(f, x, a, b)` and `solve(f, x, guess)` evaluate `f`
+    // repeatedly with the variable `x` bound, see `numeric`.
+    Integrate,
+    Solve,
+    Len,
+    Sum,
+    // `map(list, f)`, `filter(list, f)` and `reduce(list, f, init)` call
+    // the function value `f`, see `list`.
+    Map,
+    Filter,
+    Reduce,
+    // `convert(x, 1 km)` is `x in km`, see `units`.
+    Convert,
+}
+
+/// How many arguments a built-in function takes.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Arity {
+    Exact(usize),
+    AtLeast(usize),
+}
+
+impl Arity {
+    pub fn accepts(self, n: usize) -> bool {
+        match self {
+            Arity::Exact(expected) => n == expected,
+            Arity::AtLeast(expected) => n >= expected,
+        }
+    }
+
+    pub fn min(self) -> usize {
+        match self {
+            Arity::Exact(n) | Arity::AtLeast(n) => n,
+        }
+    }
+}
+
+// The registry of all the built-in functions.
+const BUILTINS: &[(&str, BuiltinFunc)] = &[
+    ("sqrt", BuiltinFunc::Sqrt),
+    ("exp", BuiltinFunc::Exp),
+    ("ln", BuiltinFunc::Ln),
+    ("log", BuiltinFunc::Log),
+    ("pow", BuiltinFunc::Pow),
+    ("abs", BuiltinFunc::Abs),
+    ("min", BuiltinFunc::Min),
+    ("max", BuiltinFunc::Max),
+    ("floor", BuiltinFunc::Floor),
+    ("ceil", BuiltinFunc::Ceil),
+    ("round", BuiltinFunc::Round),
+    ("sin", BuiltinFunc::Sin),
+    ("cos", BuiltinFunc::Cos),
+    ("tan", BuiltinFunc::Tan),
+    ("asin", BuiltinFunc::Asin),
+    ("acos", BuiltinFunc::Acos),
+    ("atan", BuiltinFunc::Atan),
+    ("print", BuiltinFunc::Print),
+    ("integrate", BuiltinFunc::Integrate),
+    ("solve", BuiltinFunc::Solve),
+    ("len", BuiltinFunc::Len),
+    ("sum", BuiltinFunc::Sum),
+    ("map", BuiltinFunc::Map),
+    ("filter", BuiltinFunc::Filter),
+    ("reduce", BuiltinFunc::Reduce),
+    ("convert", BuiltinFunc::Convert),
+];
+
+impl BuiltinFunc {
+    pub fn from_name(name: &str) -> Option<Self> {
+        BUILTINS
+            .iter()
+            .find(|(builtin, _)| *builtin == name)
+            .map(|(_, func)| *func)
+    }
+
+    pub fn name(self) -> &'static str {
+        BUILTINS
+            .iter()
+            .find(|(_, func)| *func == self)
+            .map(|(name, _)| *name)
+            .unwrap()
+    }
+
+    /// All the built-in functions.
+    pub fn all() -> impl Iterator<Item = BuiltinFunc> {
+        BUILTINS.iter().map(|(_, func)| *func)
+    }
+
+    pub fn arity(self) -> Arity {
+        match self {
+            BuiltinFunc::Pow => Arity::Exact(2),
+            BuiltinFunc::Integrate => Arity::Exact(4),
+            BuiltinFunc::Solve | BuiltinFunc::Reduce => Arity::Exact(3),
+            BuiltinFunc::Map | BuiltinFunc::Filter | BuiltinFunc::Convert => Arity::Exact(2),
+            BuiltinFunc::Min | BuiltinFunc::Max | BuiltinFunc::Sum | BuiltinFunc::Print => {
+                Arity::AtLeast(1)
+            }
+            _ => Arity::Exact(1),
+        }
+    }
+
+    /// Whether the function takes an expression and a variable instead
+    /// of values, they can't be called by `call`.
+    pub fn is_numeric(self) -> bool {
+        matches!(self, BuiltinFunc::Integrate | BuiltinFunc::Solve)
+    }
+
+    /// Whether the function calls a function value, they can't be called
+    /// by `call`.
+    pub fn is_higher_order(self) -> bool {
+        matches!(
+            self,
+            BuiltinFunc::Map | BuiltinFunc::Filter | BuiltinFunc::Reduce
+        )
+    }
+
+    /// Call the function, the arity should be checked by the caller.
+    ///
+    /// The results follow the promotion rules of `Value`: functions which
+    /// are exact on integers and fractions (`abs`, `min`, `max`, `floor`,
+    /// `ceil`, `round` and `pow` with an integer exponent) keep them, the
+    /// others return `F64`. `Bool` is not accepted except by `print`.
+    ///
+    /// `min`, `max` and `sum` take the elements if the only argument is a
+    /// list, `min` and `max` of an empty list are `Null`.
+    ///
+    /// Quantities keep their unit in `abs`, `min`, `max`, `sum`, `floor`,
+    /// `ceil` and `round`, `sqrt` and `pow` with an integer exponent change
+    /// the dimension. The other functions take plain numbers only.
+    pub fn call(self, args: &[Value], mode: NumericMode) -> Result<Value, ArithError> {
+        match self {
+            BuiltinFunc::Sqrt => match &args[0] {
+                Value::Quantity(q) => match q.dimension().sqrt() {
+                    Some(dim) => Ok(units::from_si(q.si_value().sqrt(), dim)),
+                    None => Err(ArithError::DimensionMismatch),
+                },
+                v => float_fn(v, f64::sqrt),
+            },
+            BuiltinFunc::Exp => float_fn(&args[0], f64::exp),
+            BuiltinFunc::Ln => float_fn(&args[0], f64::ln),
+            BuiltinFunc::Log => float_fn(&args[0], f64::log10),
+            BuiltinFunc::Sin => float_fn(&args[0], f64::sin),
+            BuiltinFunc::Cos => float_fn(&args[0], f64::cos),
+            BuiltinFunc::Tan => float_fn(&args[0], f64::tan),
+            BuiltinFunc::Asin => float_fn(&args[0], f64::asin),
+            BuiltinFunc::Acos => float_fn(&args[0], f64::acos),
+            BuiltinFunc::Atan => float_fn(&args[0], f64::atan),
+            BuiltinFunc::Floor => round_fn(&args[0], f64::floor, BigRational::floor),
+            BuiltinFunc::Ceil => round_fn(&args[0], f64::ceil, BigRational::ceil),
+            BuiltinFunc::Round => round_fn(&args[0], f64::round, BigRational::round),
+            BuiltinFunc::Abs => match &args[0] {
+                Value::I64(i) if *i < 0 => Value::I64(*i).neg_in(mode),
+                Value::I64(_) => Ok(args[0].clone()),
+                Value::Rational(r) => Ok(Value::from_rational(r.abs())),
+                Value::Quantity(q) => Ok(Value::from(q.with_value(q.value.abs()))),
+                v => Ok(Value::F64(v.numeric()?.abs())),
+            },
+            BuiltinFunc::Pow => pow(&args[0], &args[1], mode),
+            BuiltinFunc::Min => extremum(elements(args), Ordering::Less),
+            BuiltinFunc::Max => extremum(elements(args), Ordering::Greater),
+            BuiltinFunc::Sum => {
+                let args = elements(args);
+                // The sum of quantities starts from zero in their unit.
+                let zero = match args.first() {
+                    Some(Value::Quantity(q)) => Value::from(q.with_value(0.0)),
+                    _ => Value::I64(0),
+                };
+                args.iter()
+                    .try_fold(zero, |acc, v| Opcode::Add.apply(&acc, v, mode))
+            }
+            BuiltinFunc::Len => Ok(Value::I64(args[0].length()? as i64)),
+            BuiltinFunc::Convert => units::convert(&args[0], &args[1]),
+            BuiltinFunc::Print => {
+                let line: Vec<_> = args.iter().map(|v| v.to_string()).collect();
+                println!("{}", line.join(" "));
+                Ok(args.last().unwrap().clone())
+            }
+            BuiltinFunc::Integrate
+            | BuiltinFunc::Solve
+            | BuiltinFunc::Map
+            | BuiltinFunc::Filter
+            | BuiltinFunc::Reduce => Err(ArithError::TypeMismatch),
+        }
+    }
+}
+
+// Exact powers larger than this are overflows, even in the exact mode.
+const MAX_POW_BITS: f64 = 1_000_000.0;
+
+fn pow(base: &Value, exp: &Value, mode: NumericMode) -> Result<Value, ArithError> {
+    match (base, exp) {
+        (Value::I64(b), Value::I64(e)) if *e >= 0 => {
+            let exact = u32::try_from(*e).ok().and_then(|e| b.checked_pow(e));
+            match (exact, mode) {
+                (Some(v), _) => Ok(Value::I64(v)),
+                (None, NumericMode::Checked) => Err(ArithError::Overflow),
+                (None, NumericMode::Exact) => exact_pow(base, *e),
+            }
+        }
+        // Negative exponents of integers are exact in the exact mode only.
+        (Value::I64(_), Value::I64(e)) if mode == NumericMode::Exact => exact_pow(base, *e),
+        (Value::Rational(_), Value::I64(e)) => exact_pow(base, *e),
+        (Value::Quantity(q), Value::I64(e)) => {
+            let dim = i32::try_from(*e)
+                .ok()
+                .and_then(|e| q.dimension().pow(e))
+                .ok_or(ArithError::Overflow)?;
+            Ok(units::from_si(q.si_value().powf(*e as f64), dim))
+        }
+        (Value::Quantity(_), _) => Err(ArithError::DimensionMismatch),
+        (base, exp) => Ok(Value::F64(base.numeric()?.powf(exp.numeric()?))),
+    }
+}
+
+fn exact_pow(base: &Value, exp: i64) -> Result<Value, ArithError> {
+    let base = base.to_rational().unwrap();
+    if base.is_zero() && exp < 0 {
+        return Err(ArithError::DivisionByZero);
+    }
+    let bits = |i: &BigInt| i.bits() as f64;
+    let size = bits(base.numer()).max(bits(base.denom()));
+    let exp = i32::try_from(exp).map_err(|_| ArithError::Overflow)?;
+    if size * f64::from(exp).abs() > MAX_POW_BITS {
+        return Err(ArithError::Overflow);
+    }
+    Ok(Value::from_rational(base.pow(exp)))
+}
+
+fn float_fn(v: &Value, f: fn(f64) -> f64) -> Result<Value, ArithError> {
+    Ok(Value::F64(f(v.numeric()?)))
+}
+
+// Integers are already rounded, fractions are rounded exactly.
+fn round_fn(
+    v: &Value,
+    f: fn(f64) -> f64,
+    exact: fn(&BigRational) -> BigRational,
+) -> Result<Value, ArithError> {
+    match v {
+        Value::I64(_) => Ok(v.clone()),
+        Value::Rational(r) => Ok(Value::from_rational(exact(r))),
+        Value::Quantity(q) => Ok(Value::from(q.with_value(f(q.value)))),
+        v => Ok(Value::F64(f(v.numeric()?))),
+    }
+}
+
+// The elements of the only list argument, or all the arguments.
+fn elements(args: &[Value]) -> &[Value] {
+    match args {
+        [Value::List(list)] => list,
+        _ => args,
+    }
+}
+
+// The result is `F64` if any of the arguments is `F64`.
+fn extremum(args: &[Value], wanted: Ordering) -> Result<Value, ArithError> {
+    let mut result = match args.first() {
+        Some(first) => first,
+        None => return Ok(Value::Null),
+    };
+    let mut is_float = false;
+    for v in args {
+        if !matches!(v, Value::Quantity(_)) {
+            v.numeric()?;
+        }
+        is_float |= matches!(v, Value::F64(_));
+        if v.checked_cmp(result)? == Some(wanted) {
+            result = v;
+        }
+    }
+
+    if is_float && !matches!(result, Value::Quantity(_)) {
+        Ok(Value::F64(result.as_f64()))
+    } else {
+        Ok(result.clone())
+    }
+}
@@ -1,158 +1,401 @@
 use std::cmp::Ordering;
-use std::collections::{HashMap, LinkedList};
+use std::collections::LinkedList;
+use std::convert::TryFrom;
 use std::fmt::Debug;
 use std::rc::Rc;
-use std::sync::{Arc, Mutex};
 
-/// Note: we need to represent not only integers.
-#[derive(Clone, Copy, Debug, PartialEq)]
-pub enum Number {
+use num_bigint::BigInt;
+use num_rational::BigRational;
+use num_traits::{Signed, ToPrimitive, Zero};
+use serde::{Deserialize, Serialize};
+
+use crate::builtin::BuiltinFunc;
+use crate::environment::Environment;
+use crate::error::{ArithError, EvalError, Result};
+use crate::units::Quantity;
+use crate::{list, numeric, units};
+
+/// A value of the language, the numbers are `I64`, `F64` and `Rational`,
+/// and `Quantity` is a number with a unit.
+#[derive(Clone, Debug)]
+pub enum Value {
     I64(i64),
     F64(f64),
 
     Bool(bool),
+    Str(Rc<str>),
+    Null,
+    List(Rc<Vec<Value>>),
+    // A function made by `fn(x) { ... }`, it's called by the built-in
+    // functions like `map`.
+    Func(Rc<FuncDef>),
+
+    // Exact integers out of the range of `I64`, and fractions. It's never
+    // an integer in the range of `I64`, see `Value::from_rational`.
+    Rational(Box<BigRational>),
+
+    // `3 km`, see `units`.
+    Quantity(Box<Quantity>),
+}
+
+/// The structural equality, `I64(1)` and `F64(1.0)` differ, see
+/// `Value::equals` for `==` of the language. The functions are equal only
+/// if they are made by the same expression.
+impl PartialEq for Value {
+    fn eq(&self, other: &Self) -> bool {
+        match (self, other) {
+            (Value::I64(i1), Value::I64(i2)) => i1 == i2,
+            (Value::F64(f1), Value::F64(f2)) => f1 == f2,
+            (Value::Bool(b1), Value::Bool(b2)) => b1 == b2,
+            (Value::Str(s1), Value::Str(s2)) => s1 == s2,
+            (Value::Null, Value::Null) => true,
+            (Value::List(l1), Value::List(l2)) => l1 == l2,
+            (Value::Func(f1), Value::Func(f2)) => Rc::ptr_eq(f1, f2),
+            (Value::Rational(r1), Value::Rational(r2)) => r1 == r2,
+            (Value::Quantity(q1), Value::Quantity(q2)) => q1 == q2,
+            _ => false,
+        }
+    }
+}
+
+/// How the arithmetic on `I64` handles overflows and divisions.
+///
+/// The arithmetic on `Rational` is always exact, and any arithmetic
+/// involving `F64` makes `F64`.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum NumericMode {
+    /// Overflows are errors, and divisions truncate.
+    #[default]
+    Checked,
+    /// Overflows and divisions make `Rational` if the result doesn't fit,
+    /// so integers and fractions never lose precision.
+    Exact,
+}
+
+impl From<i64> for Value {
+    fn from(i: i64) -> Self {
+        Value::I64(i)
+    }
+}
+
+impl From<f64> for Value {
+    fn from(f: f64) -> Self {
+        Value::F64(f)
+    }
+}
+
+impl From<bool> for Value {
+    fn from(b: bool) -> Self {
+        Value::Bool(b)
+    }
+}
+
+impl From<&str> for Value {
+    fn from(s: &str) -> Self {
+        Value::Str(s.into())
+    }
+}
+
+impl From<String> for Value {
+    fn from(s: String) -> Self {
+        Value::Str(s.into())
+    }
 }
 
-impl Default for Number {
+impl From<Vec<Value>> for Value {
+    fn from(list: Vec<Value>) -> Self {
+        Value::List(Rc::new(list))
+    }
+}
+
+impl From<BigRational> for Value {
+    fn from(r: BigRational) -> Self {
+        Value::from_rational(r)
+    }
+}
+
+impl Default for Value {
     fn default() -> Self {
-        Number::I64(0)
+        Value::I64(0)
     }
 }
 
-impl std::fmt::Display for Number {
+/// Display the plain value, `Debug` shows the type as well.
+impl std::fmt::Display for Value {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
         match self {
-            Number::F64(fv) => {
-                write!(f, "F64({})", fv)
+            Value::F64(fv) => {
+                write!(f, "{}", fv)
+            }
+            Value::I64(iv) => {
+                write!(f, "{}", iv)
+            }
+            Value::Bool(b) => {
+                write!(f, "{}", b)
+            }
+            Value::Str(s) => {
+                write!(f, "{}", s)
+            }
+            Value::Null => {
+                write!(f, "null")
+            }
+            // The strings in a list are quoted.
+            Value::List(list) => {
+                write!(f, "[")?;
+                for (i, v) in list.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ", ")?;
+                    }
+                    match v {
+                        Value::Str(s) => write!(f, "{:?}", s)?,
+                        v => write!(f, "{}", v)?,
+                    }
+                }
+                write!(f, "]")
+            }
+            Value::Func(func) => {
+                write!(f, "fn({})", func.params.join(", "))
             }
-            Number::I64(iv) => {
-                write!(f, "I64({})", iv)
+            // `7/2`, or only the numerator for integers.
+            Value::Rational(r) => {
+                write!(f, "{}", r)
             }
-            Number::Bool(b) => {
-                write!(f, "Bool({})", b)
+            Value::Quantity(q) => {
+                write!(f, "{}", q)
             }
         }
     }
 }
 
-impl Number {
+impl Value {
     pub fn from_i64(i: i64) -> Self {
-        Number::I64(i)
+        Value::I64(i)
     }
 
     pub fn from_f64(f: f64) -> Self {
-        Number::F64(f)
+        Value::F64(f)
     }
 
     pub fn from_bool(b: bool) -> Self {
-        Number::Bool(b)
+        Value::Bool(b)
     }
 
-    pub fn as_f64(self) -> f64 {
-        match self {
-            Number::I64(i) => i as f64,
-            Number::F64(f) => f,
-            Number::Bool(_) => {
-                unimplemented!()
+    /// A string literal with the quotes, `\"`, `\\`, `\n` and `\t` are
+    /// unescaped.
+    pub fn from_quoted(literal: &str) -> Self {
+        let mut s = String::new();
+        let mut chars = literal[1..literal.len() - 1].chars();
+        while let Some(c) = chars.next() {
+            if c != '\\' {
+                s.push(c);
+                continue;
+            }
+            match chars.next() {
+                Some('n') => s.push('\n'),
+                Some('t') => s.push('\t'),
+                Some(c) => s.push(c),
+                None => {}
             }
         }
+        Value::Str(s.into())
     }
 
-    pub fn as_i64(self) -> i64 {
+    /// Integers in the range of `I64` are made `I64`.
+    pub fn from_rational(r: BigRational) -> Self {
+        match r.is_integer().then(|| r.to_integer().to_i64()).flatten() {
+            Some(i) => Value::I64(i),
+            None => Value::Rational(Box::new(r)),
+        }
+    }
+
+    /// An integer literal, it's `Rational` if it doesn't fit in `I64`.
+    pub fn from_digits(digits: &str) -> Self {
+        match digits.parse::<i64>() {
+            Ok(i) => Value::I64(i),
+            Err(_) => Value::from_rational(BigRational::from_integer(
+                digits.parse::<BigInt>().expect("integer literal"),
+            )),
+        }
+    }
+
+    /// `Bool` is converted to 1.0 or 0.0, a quantity is its magnitude, the
+    /// other values which are not numbers are NaN.
+    pub fn as_f64(&self) -> f64 {
         match self {
-            Number::I64(i) => i,
-            Number::F64(f) => f as i64,
-            Number::Bool(_) => {
-                unimplemented!()
+            Value::I64(i) => *i as f64,
+            Value::F64(f) => *f,
+            Value::Bool(b) => *b as i64 as f64,
+            Value::Str(_) | Value::Null | Value::List(_) | Value::Func(_) => f64::NAN,
+            Value::Rational(r) => r.to_f64().unwrap_or(f64::NAN),
+            Value::Quantity(q) => q.value,
+        }
+    }
+
+    /// `Bool` is converted to 1 or 0, fractions are truncated and
+    /// out of range integers saturate. The other values which are not
+    /// numbers are 0.
+    pub fn as_i64(&self) -> i64 {
+        match self {
+            Value::I64(i) => *i,
+            Value::F64(f) => *f as i64,
+            Value::Bool(b) => *b as i64,
+            Value::Str(_) | Value::Null | Value::List(_) | Value::Func(_) => 0,
+            Value::Rational(r) => {
+                r.to_integer()
+                    .to_i64()
+                    .unwrap_or(if r.is_negative() { i64::MIN } else { i64::MAX })
             }
+            Value::Quantity(q) => q.value as i64,
         }
     }
 
-    pub fn as_bool(self) -> bool {
+    /// The truth of the value in conditions, `Null`, the empty string and
+    /// the empty list are false.
+    pub fn as_bool(&self) -> bool {
         match self {
-            Number::I64(i) => i != 0,
-            Number::F64(f) => f != 0f64,
-            Number::Bool(b) => b,
+            Value::I64(i) => *i != 0,
+            Value::F64(f) => *f != 0f64,
+            Value::Bool(b) => *b,
+            Value::Str(s) => !s.is_empty(),
+            Value::Null => false,
+            Value::List(list) => !list.is_empty(),
+            Value::Func(_) => true,
+            Value::Rational(r) => !r.is_zero(),
+            Value::Quantity(q) => q.value != 0f64,
         }
     }
-}
 
-impl std::ops::Add for Number {
-    type Output = Self;
+    pub fn checked_add(&self, other: &Self) -> std::result::Result<Self, ArithError> {
+        Opcode::Add.apply(self, other, NumericMode::Checked)
+    }
 
-    fn add(self, other: Self) -> Self {
-        match (self, other) {
-            (Number::I64(i1), Number::I64(i2)) => Number::I64(i1 + i2),
-            (v1, v2) => Number::F64(v1.as_f64() + v2.as_f64()),
-        }
+    pub fn checked_sub(&self, other: &Self) -> std::result::Result<Self, ArithError> {
+        Opcode::Sub.apply(self, other, NumericMode::Checked)
     }
-}
 
-impl std::ops::Mul for Number {
-    type Output = Self;
+    pub fn checked_mul(&self, other: &Self) -> std::result::Result<Self, ArithError> {
+        Opcode::Mul.apply(self, other, NumericMode::Checked)
+    }
 
-    fn mul(self, other: Self) -> Self {
-        match (self, other) {
-            (Number::I64(i1), Number::I64(i2)) => Number::I64(i1 * i2),
-            (v1, v2) => Number::F64(v1.as_f64() * v2.as_f64()),
-        }
+    /// Integer division truncates, and fails if dividing by zero.
+    pub fn checked_div(&self, other: &Self) -> std::result::Result<Self, ArithError> {
+        Opcode::Div.apply(self, other, NumericMode::Checked)
+    }
+
+    pub fn checked_neg(&self) -> std::result::Result<Self, ArithError> {
+        self.neg_in(NumericMode::Checked)
     }
-}
 
-impl std::ops::Div for Number {
-    type Output = Self;
+    pub fn neg_in(&self, mode: NumericMode) -> std::result::Result<Self, ArithError> {
+        match self {
+            Value::I64(i) => match (i.checked_neg(), mode) {
+                (Some(v), _) => Ok(Value::I64(v)),
+                (None, NumericMode::Checked) => Err(ArithError::Overflow),
+                (None, NumericMode::Exact) => {
+                    Ok(Value::from_rational(-BigRational::from(BigInt::from(*i))))
+                }
+            },
+            Value::F64(f) => Ok(Value::F64(-f)),
+            Value::Bool(_) | Value::Str(_) | Value::Null | Value::List(_) | Value::Func(_) => {
+                Err(ArithError::TypeMismatch)
+            }
+            Value::Rational(r) => Ok(Value::from_rational(-r.as_ref().clone())),
+            Value::Quantity(q) => Ok(Value::from(q.with_value(-q.value))),
+        }
+    }
 
-    fn div(self, other: Self) -> Self {
+    /// `==` of the language, the numbers are equal by value, like `1` and
+    /// `1.0`, and the lists element by element.
+    pub fn equals(&self, other: &Self) -> bool {
         match (self, other) {
-            (Number::I64(i1), Number::I64(i2)) => Number::I64(i1 / i2),
-            (v1, v2) => Number::F64(v1.as_f64() / v2.as_f64()),
+            (Value::List(l1), Value::List(l2)) => {
+                l1.len() == l2.len() && l1.iter().zip(l2.iter()).all(|(v1, v2)| v1.equals(v2))
+            }
+            (
+                Value::I64(_) | Value::F64(_) | Value::Rational(_),
+                Value::I64(_) | Value::F64(_) | Value::Rational(_),
+            ) => matches!(self.checked_cmp(other), Ok(Some(Ordering::Equal))),
+            _ => self == other,
         }
     }
-}
-
-impl std::ops::Sub for Number {
-    type Output = Self;
 
-    fn sub(self, other: Self) -> Self {
+    /// Compare two values, `Bool` can only be compared with `Bool`, `Str`
+    /// with `Str`, and `Quantity` with the same dimension. Returns `None`
+    /// if any of them is NaN.
+    pub fn checked_cmp(&self, other: &Self) -> std::result::Result<Option<Ordering>, ArithError> {
         match (self, other) {
-            (Number::I64(i1), Number::I64(i2)) => Number::I64(i1 - i2),
-            (v1, v2) => Number::F64(v1.as_f64() - v2.as_f64()),
+            (Value::Bool(b1), Value::Bool(b2)) => Ok(Some(b1.cmp(b2))),
+            (Value::Str(s1), Value::Str(s2)) => Ok(Some(s1.cmp(s2))),
+            (Value::I64(i1), Value::I64(i2)) => Ok(Some(i1.cmp(i2))),
+            (Value::Quantity(q1), Value::Quantity(q2)) => match q1.dimension() == q2.dimension() {
+                true => Ok(q1.si_value().partial_cmp(&q2.si_value())),
+                false => Err(ArithError::DimensionMismatch),
+            },
+            (v1, v2) => match (v1.to_rational(), v2.to_rational()) {
+                (Some(r1), Some(r2)) => Ok(Some(r1.cmp(&r2))),
+                _ => Ok(v1.numeric()?.partial_cmp(&v2.numeric()?)),
+            },
         }
     }
-}
 
-impl std::ops::Neg for Number {
-    type Output = Self;
+    // The value for arithmetic, a quantity is not a plain number.
+    pub(crate) fn numeric(&self) -> std::result::Result<f64, ArithError> {
+        match self {
+            Value::I64(_) | Value::F64(_) | Value::Rational(_) => Ok(self.as_f64()),
+            Value::Quantity(_) => Err(ArithError::DimensionMismatch),
+            _ => Err(ArithError::TypeMismatch),
+        }
+    }
 
-    fn neg(self) -> Self {
+    /// The element at `index` of a list or a string, a negative index
+    /// counts from the end.
+    pub fn index(&self, index: &Value) -> std::result::Result<Value, ArithError> {
+        let i = match index {
+            Value::I64(i) => *i,
+            _ => return Err(ArithError::TypeMismatch),
+        };
+        let at = |len: usize| {
+            let i = if i < 0 { i + len as i64 } else { i };
+            usize::try_from(i)
+                .ok()
+                .filter(|i| *i < len)
+                .ok_or(ArithError::OutOfRange)
+        };
         match self {
-            Number::I64(i) => Number::I64(-i),
-            Number::F64(f) => Number::F64(-f),
-            Number::Bool(_) => {
-                unimplemented!()
+            Value::List(list) => Ok(list[at(list.len())?].clone()),
+            Value::Str(s) => {
+                let i = at(s.chars().count())?;
+                Ok(Value::from(s.chars().nth(i).unwrap().to_string()))
             }
+            _ => Err(ArithError::TypeMismatch),
         }
     }
-}
 
-impl PartialOrd for Number {
-    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
-        match (self, other) {
-            (_, Number::Bool(_)) => {
-                unimplemented!()
-            }
-            (Number::Bool(_), _) => {
-                unimplemented!()
-            }
-            (a, b) => a.as_f64().partial_cmp(&b.as_f64()),
+    /// The number of the elements of a list, or the characters of a string.
+    pub fn length(&self) -> std::result::Result<usize, ArithError> {
+        match self {
+            Value::List(list) => Ok(list.len()),
+            Value::Str(s) => Ok(s.chars().count()),
+            _ => Err(ArithError::TypeMismatch),
+        }
+    }
+
+    // The exact value of the integers and fractions.
+    pub(crate) fn to_rational(&self) -> Option<BigRational> {
+        match self {
+            Value::I64(i) => Some(BigRational::from(BigInt::from(*i))),
+            Value::Rational(r) => Some(r.as_ref().clone()),
+            _ => None,
         }
     }
 }
 
-#[derive(Clone, Copy, Debug, PartialEq)]
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
 pub enum Opcode {
     Mul,
     Div,
+    // `%`, the remainder has the sign of the dividend.
+    Rem,
     Add,
     Sub,
 
@@ -162,45 +405,234 @@ pub enum Opcode {
 
     // comparing
     Equal,
+    NotEqual,
     LargerOrEqual,
     LargerThan,
     LessOrEqual,
     LessThan,
+
+    // logic, `And` and `Or` only evaluate the right side if needed.
+    And,
+    Or,
+    Not,
+}
+
+impl Opcode {
+    /// Apply the unary operator, `Sub`, `Add` or `Not`, on the value.
+    pub fn apply_unary(
+        self,
+        v: &Value,
+        mode: NumericMode,
+    ) -> std::result::Result<Value, ArithError> {
+        match self {
+            Opcode::Sub => v.neg_in(mode),
+            // `+x` is `x` if it's a number, it's never negated.
+            Opcode::Add => match v {
+                Value::I64(_) | Value::F64(_) | Value::Rational(_) | Value::Quantity(_) => {
+                    Ok(v.clone())
+                }
+                _ => Err(ArithError::TypeMismatch),
+            },
+            Opcode::Not => Ok(Value::from_bool(!v.as_bool())),
+            _ => unreachable!(),
+        }
+    }
+
+    /// Apply the binary operator on the values. Both of the sides of `And`
+    /// and `Or` are taken, the short circuit is made by the caller.
+    pub fn apply(
+        self,
+        l: &Value,
+        r: &Value,
+        mode: NumericMode,
+    ) -> std::result::Result<Value, ArithError> {
+        match self {
+            Opcode::Mul | Opcode::Div | Opcode::Rem | Opcode::Add | Opcode::Sub => {
+                self.arith(l, r, mode)
+            }
+            Opcode::Equal => Ok(Value::from_bool(l.equals(r))),
+            Opcode::NotEqual => Ok(Value::from_bool(!l.equals(r))),
+            Opcode::And => Ok(Value::from_bool(l.as_bool() && r.as_bool())),
+            Opcode::Or => Ok(Value::from_bool(l.as_bool() || r.as_bool())),
+            Opcode::LargerOrEqual => l.checked_cmp(r).map(|ord| {
+                Value::from_bool(matches!(ord, Some(Ordering::Greater | Ordering::Equal)))
+            }),
+            Opcode::LargerThan => l
+                .checked_cmp(r)
+                .map(|ord| Value::from_bool(ord == Some(Ordering::Greater))),
+            Opcode::LessOrEqual => l
+                .checked_cmp(r)
+                .map(|ord| Value::from_bool(matches!(ord, Some(Ordering::Less | Ordering::Equal)))),
+            Opcode::LessThan => l
+                .checked_cmp(r)
+                .map(|ord| Value::from_bool(ord == Some(Ordering::Less))),
+
+            Opcode::Assign | Opcode::Ref | Opcode::Not => {
+                unreachable!()
+            }
+        }
+    }
+
+    /// `apply` on two floats, the binary operators except `And` and `Or`
+    /// can't fail on them.
+    pub fn apply_f64(self, a: f64, b: f64) -> Value {
+        match self {
+            Opcode::Add => Value::F64(a + b),
+            Opcode::Sub => Value::F64(a - b),
+            Opcode::Mul => Value::F64(a * b),
+            Opcode::Div => Value::F64(a / b),
+            Opcode::Rem => Value::F64(a % b),
+            Opcode::Equal => Value::from_bool(a == b),
+            Opcode::NotEqual => Value::from_bool(a != b),
+            Opcode::LargerOrEqual => Value::from_bool(a >= b),
+            Opcode::LargerThan => Value::from_bool(a > b),
+            Opcode::LessOrEqual => Value::from_bool(a <= b),
+            Opcode::LessThan => Value::from_bool(a < b),
+            _ => unreachable!(),
+        }
+    }
+
+    fn arith(
+        self,
+        l: &Value,
+        r: &Value,
+        mode: NumericMode,
+    ) -> std::result::Result<Value, ArithError> {
+        match (l, r) {
+            (Value::Str(s1), Value::Str(s2)) if self == Opcode::Add => {
+                Ok(Value::Str([s1.as_ref(), s2.as_ref()].concat().into()))
+            }
+            (Value::List(l1), Value::List(l2)) if self == Opcode::Add => {
+                Ok(Value::from([l1.as_slice(), l2.as_slice()].concat()))
+            }
+            (Value::Str(_) | Value::Null | Value::List(_) | Value::Func(_), _)
+            | (_, Value::Str(_) | Value::Null | Value::List(_) | Value::Func(_)) => {
+                Err(ArithError::TypeMismatch)
+            }
+            (Value::Quantity(_), _) | (_, Value::Quantity(_)) => units::arith(self, l, r),
+            (Value::I64(i1), Value::I64(i2)) => {
+                let (i1, i2) = (*i1, *i2);
+                if matches!(self, Opcode::Div | Opcode::Rem) && i2 == 0 {
+                    return Err(ArithError::DivisionByZero);
+                }
+                let v = match self {
+                    Opcode::Add => i1.checked_add(i2),
+                    Opcode::Sub => i1.checked_sub(i2),
+                    Opcode::Mul => i1.checked_mul(i2),
+                    Opcode::Rem => i1.checked_rem(i2),
+                    // Only exact quotients are `I64` in the exact mode.
+                    _ if mode == NumericMode::Exact && i1.checked_rem(i2) != Some(0) => None,
+                    _ => i1.checked_div(i2),
+                };
+                match (v, mode) {
+                    (Some(v), _) => Ok(Value::I64(v)),
+                    (None, NumericMode::Checked) => Err(ArithError::Overflow),
+                    (None, NumericMode::Exact) => self.exact(l, r),
+                }
+            }
+            (Value::F64(_), _) | (_, Value::F64(_)) | (Value::Bool(_), _) | (_, Value::Bool(_)) => {
+                let (f1, f2) = (l.numeric()?, r.numeric()?);
+                Ok(Value::F64(match self {
+                    Opcode::Add => f1 + f2,
+                    Opcode::Sub => f1 - f2,
+                    Opcode::Mul => f1 * f2,
+                    Opcode::Rem => f1 % f2,
+                    _ => f1 / f2,
+                }))
+            }
+            _ => self.exact(l, r),
+        }
+    }
+
+    // Both of the values are integers or fractions.
+    fn exact(self, l: &Value, r: &Value) -> std::result::Result<Value, ArithError> {
+        let (r1, r2) = (l.to_rational().unwrap(), r.to_rational().unwrap());
+        Ok(Value::from_rational(match self {
+            Opcode::Add => r1 + r2,
+            Opcode::Sub => r1 - r2,
+            Opcode::Mul => r1 * r2,
+            _ if r2.is_zero() => return Err(ArithError::DivisionByZero),
+            Opcode::Rem => {
+                let quotient = (&r1 / &r2).trunc();
+                r1 - r2 * quotient
+            }
+            _ => r1 / r2,
+        }))
+    }
 }
 
-// #[derive(Clone, Copy, Debug, PartialEq)]
-// enum BuiltinFunc {
-//     Sqrt,
-//     Exp,
-//     Log,
-//     Print,
-// }
+/// Byte offsets of a node in the source, `end` is exclusive.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct Span {
+    pub start: usize,
+    pub end: usize,
+}
+
+impl Span {
+    pub fn new(start: usize, end: usize) -> Self {
+        Span { start, end }
+    }
+}
+
+#[derive(Clone)]
+pub struct Expr {
+    pub kind: ExprKind,
+    pub span: Span,
+}
 
-pub enum Expr {
-    Number(Number),
+#[derive(Clone)]
+pub enum ExprKind {
+    Literal(Value),
+    // `-`, `+` and `!`.
     OneOp(Opcode, Box<Expr>),
     // Include:
-    // "+" "-" "*" "/" and comparing.
+    // "+" "-" "*" "/" "%", comparing, "&&" and "||".
     TwoOp(Opcode, Box<Expr>, Box<Expr>),
     VarRef(String),
     Assign(String, Box<Expr>),
     Flow(ControlFlow),
+    // `fn name(a, b) { ... }`, the function is registered when it's evaluated.
+    FuncDef(Rc<FuncDef>),
+    // Calling a function defined by `FuncDef`.
+    Call(String, Vec<Expr>),
+    // Calling a built-in function, they are resolved when parsing so
+    // they can't be overridden by `FuncDef`.
+    Builtin(BuiltinFunc, Vec<Expr>),
+    // `[a, b, c]`
+    List(Vec<Expr>),
+    // `list[index]`
+    Index(Box<Expr>, Box<Expr>),
+    // `fn(a, b) { ... }`, it makes a `Value::Func` named `lambda`.
+    Lambda(Rc<FuncDef>),
 }
 
 #[derive(Clone)]
+#[cfg_attr(test, derive(PartialEq))]
 pub struct ExprList(pub Option<LinkedList<Rc<Expr>>>);
 
 impl ExprList {
     /// Executing all the expressions, and return the last one.
     /// If no expression provided, return I64(0).
-    pub fn eval(&self) -> Number {
-        self.0.as_ref().map_or(Number::default(), |list| {
-            let mut n = Number::default();
-            for expr_rc in list.iter() {
-                n = expr_rc.as_ref().eval();
+    pub fn eval(&self, env: &mut Environment) -> Result<Value> {
+        let span = self
+            .0
+            .iter()
+            .flatten()
+            .next()
+            .map_or(Span::default(), |e| e.span);
+        env.enter(span)?;
+        let mut eval = || {
+            let mut n = Value::default();
+            if let Some(list) = self.0.as_ref() {
+                for expr_rc in list.iter() {
+                    n = expr_rc.as_ref().eval(env)?;
+                }
             }
-            n
-        })
+            Ok(n)
+        };
+        let n = eval();
+        env.leave();
+        n
     }
 }
 
@@ -214,7 +646,8 @@ impl Debug for ExprList {
     }
 }
 
-#[derive(Debug)]
+#[derive(Clone, Debug)]
+#[cfg_attr(test, derive(PartialEq))]
 pub struct IfCondition {
     pub cond: Box<Expr>,
     pub if_branch: ExprList,
@@ -222,127 +655,387 @@ pub struct IfCondition {
 }
 
 #[derive(Debug)]
+#[cfg_attr(test, derive(PartialEq))]
+pub struct FuncDef {
+    pub name: String,
+    pub params: Vec<String>,
+    pub body: ExprList,
+}
+
+#[derive(Clone, Debug)]
+#[cfg_attr(test, derive(PartialEq))]
+pub struct WhileLoop {
+    pub cond: Box<Expr>,
+    pub body: ExprList,
+}
+
+/// `for (init; cond; step) { body }`, all of the three parts are optional.
+#[derive(Clone, Debug)]
+#[cfg_attr(test, derive(PartialEq))]
+pub struct ForLoop {
+    pub init: Option<Box<Expr>>,
+    pub cond: Option<Box<Expr>>,
+    pub step: Option<Box<Expr>>,
+    pub body: ExprList,
+}
+
+#[derive(Clone, Debug)]
+#[cfg_attr(test, derive(PartialEq))]
 pub enum ControlFlow {
     Condition(IfCondition),
+    While(WhileLoop),
+    For(ForLoop),
+    Break,
+    Continue,
 }
 
 impl Expr {
-    pub fn eval(&self) -> Number {
-        match *self {
-            Expr::Number(n) => n,
-            Expr::OneOp(op, ref node) => match op {
-                Opcode::Sub => -node.eval(),
-                _ => {
-                    unreachable!();
+    pub fn new(kind: ExprKind, span: Span) -> Self {
+        Expr { kind, span }
+    }
+
+    /// The sub-expressions in the source order, including the statements
+    /// of the blocks.
+    pub fn children(&self) -> Vec<&Expr> {
+        let stmts: fn(&ExprList) -> Vec<&Expr> =
+            |list| list.0.iter().flatten().map(|expr| &**expr).collect();
+        match self.kind {
+            ExprKind::Literal(_) | ExprKind::VarRef(_) => vec![],
+            ExprKind::OneOp(_, ref node) | ExprKind::Assign(_, ref node) => vec![node],
+            ExprKind::TwoOp(_, ref lnode, ref rnode) | ExprKind::Index(ref lnode, ref rnode) => {
+                vec![lnode, rnode]
+            }
+            ExprKind::Flow(ref flow) => match flow {
+                ControlFlow::Condition(cond) => {
+                    let mut children = vec![&*cond.cond];
+                    children.extend(stmts(&cond.if_branch));
+                    children.extend(cond.else_branch.iter().flat_map(stmts));
+                    children
                 }
-            },
-            Expr::TwoOp(op, ref lnode, ref rnode) => match op {
-                Opcode::Mul => lnode.eval() * rnode.eval(),
-                Opcode::Div => lnode.eval() / rnode.eval(),
-                Opcode::Add => lnode.eval() + rnode.eval(),
-                Opcode::Sub => lnode.eval() - rnode.eval(),
-                Opcode::Equal => Number::from_bool(lnode.eval() == rnode.eval()),
-                Opcode::LargerOrEqual => Number::from_bool(lnode.eval() >= rnode.eval()),
-                Opcode::LargerThan => Number::from_bool(lnode.eval() > rnode.eval()),
-                Opcode::LessOrEqual => Number::from_bool(lnode.eval() <= rnode.eval()),
-                Opcode::LessThan => Number::from_bool(lnode.eval() < rnode.eval()),
-
-                _ => {
-                    unreachable!()
+                ControlFlow::While(while_loop) => {
+                    let mut children = vec![&*while_loop.cond];
+                    children.extend(stmts(&while_loop.body));
+                    children
+                }
+                ControlFlow::For(for_loop) => {
+                    let parts = [&for_loop.init, &for_loop.cond, &for_loop.step];
+                    let mut children: Vec<_> = parts.iter().filter_map(|p| p.as_deref()).collect();
+                    children.extend(stmts(&for_loop.body));
+                    children
                 }
+                ControlFlow::Break | ControlFlow::Continue => vec![],
             },
-            Expr::VarRef(ref name) => {
-                let table = SYMBOL_TABLE.lock().unwrap();
-                match table.get(name) {
-                    Some(symbol) => symbol.value,
-                    None => {
-                        unimplemented!();
-                    }
+            ExprKind::FuncDef(ref func) | ExprKind::Lambda(ref func) => stmts(&func.body),
+            ExprKind::Call(_, ref args)
+            | ExprKind::Builtin(_, ref args)
+            | ExprKind::List(ref args) => args.iter().collect(),
+        }
+    }
+
+    /// Evaluate the expression, the limits of `env` are checked, see
+    /// `Limits`.
+    pub fn eval(&self, env: &mut Environment) -> Result<Value> {
+        env.enter(self.span)?;
+        let v = self.eval_kind(env);
+        env.leave();
+        let v = v?;
+        env.check_size(&v, self.span)?;
+        Ok(v)
+    }
+
+    fn eval_kind(&self, env: &mut Environment) -> Result<Value> {
+        match self.kind {
+            ExprKind::Literal(ref n) => Ok(n.clone()),
+            ExprKind::OneOp(op, ref node) => {
+                let v = node.eval(env)?;
+                op.apply_unary(&v, env.mode()).map_err(|e| e.at(self))
+            }
+            ExprKind::TwoOp(op @ (Opcode::And | Opcode::Or), ref lnode, ref rnode) => {
+                // The right side is skipped if the left one decides the result.
+                let l = lnode.eval(env)?.as_bool();
+                if l == (op == Opcode::Or) {
+                    return Ok(Value::from_bool(l));
                 }
+                Ok(Value::from_bool(rnode.eval(env)?.as_bool()))
             }
-            Expr::Assign(ref name, ref rnode) => {
-                let mut table = SYMBOL_TABLE.lock().unwrap();
-                let v = rnode.eval();
-                let mut v_ref = table.entry(name.into()).or_insert(Symbol {
-                    name: name.into(),
-                    value: v,
-                });
-                v_ref.value = v;
-                v
-            }
-            Expr::Flow(ref flow) => {
+            ExprKind::TwoOp(op, ref lnode, ref rnode) => {
+                let l = lnode.eval(env)?;
+                let r = rnode.eval(env)?;
+                op.apply(&l, &r, env.mode()).map_err(|e| e.at(self))
+            }
+            ExprKind::VarRef(ref name) => {
+                env.get(name).ok_or_else(|| EvalError::UndefinedVariable {
+                    name: name.clone(),
+                    span: self.span,
+                })
+            }
+            ExprKind::Assign(ref name, ref rnode) => {
+                // Evaluate first, the right side may refer to the symbol itself.
+                let v = rnode.eval(env)?;
+                env.set(name, v.clone());
+                Ok(v)
+            }
+            ExprKind::FuncDef(ref func) => {
+                env.define_function(func.clone());
+                Ok(Value::default())
+            }
+            ExprKind::Call(ref name, ref args) => {
+                let func = match env.function(name) {
+                    Some(func) => func,
+                    None => return self.call_native(env, name, args),
+                };
+                if func.params.len() != args.len() {
+                    return Err(EvalError::ArityMismatch {
+                        name: name.clone(),
+                        expected: func.params.len(),
+                        found: args.len(),
+                        span: self.span,
+                    });
+                }
+
+                // Arguments are evaluated in the frame of the caller.
+                let values = args
+                    .iter()
+                    .map(|arg| arg.eval(env))
+                    .collect::<Result<Vec<_>>>()?;
+
+                call_function(env, &func, values, self.span)
+            }
+            ExprKind::Builtin(func, ref args) => {
+                let arity = func.arity();
+                if !arity.accepts(args.len()) {
+                    return Err(EvalError::ArityMismatch {
+                        name: func.name().into(),
+                        expected: arity.min(),
+                        found: args.len(),
+                        span: self.span,
+                    });
+                }
+                if func.is_numeric() {
+                    return numeric::eval(func, args, self, env);
+                }
+                if func.is_higher_order() {
+                    return list::eval(func, args, self, env);
+                }
+
+                let values = args
+                    .iter()
+                    .map(|arg| arg.eval(env))
+                    .collect::<Result<Vec<_>>>()?;
+                func.call(&values, env.mode()).map_err(|e| e.at(self))
+            }
+            ExprKind::List(ref items) => {
+                let values = items
+                    .iter()
+                    .map(|item| item.eval(env))
+                    .collect::<Result<Vec<_>>>()?;
+                Ok(Value::from(values))
+            }
+            ExprKind::Index(ref lnode, ref index) => {
+                let l = lnode.eval(env)?;
+                let index = index.eval(env)?;
+                l.index(&index).map_err(|e| e.at(self))
+            }
+            ExprKind::Lambda(ref func) => Ok(Value::Func(func.clone())),
+            ExprKind::Flow(ref flow) => {
                 match flow {
                     ControlFlow::Condition(ref flow) => {
                         // It must be a boolean value.
-                        let cond = flow.cond.eval().as_bool();
+                        let cond = flow.cond.eval(env)?.as_bool();
                         if cond {
-                            flow.if_branch.eval()
+                            flow.if_branch.eval(env)
                         } else {
                             flow.else_branch
                                 .as_ref()
-                                .map_or(Number::default(), |branch| branch.eval())
+                                .map_or(Ok(Value::default()), |branch| branch.eval(env))
                         }
                     }
+                    ControlFlow::While(ref flow) => {
+                        run_loop(env, Some(&flow.cond), None, &flow.body, self.span)
+                    }
+                    ControlFlow::For(ref flow) => {
+                        if let Some(init) = flow.init.as_ref() {
+                            init.eval(env)?;
+                        }
+                        run_loop(
+                            env,
+                            flow.cond.as_deref(),
+                            flow.step.as_deref(),
+                            &flow.body,
+                            self.span,
+                        )
+                    }
+                    ControlFlow::Break | ControlFlow::Continue => {
+                        let is_break = matches!(flow, ControlFlow::Break);
+                        Err(if !env.in_loop() {
+                            EvalError::OutsideLoop {
+                                keyword: if is_break { "break" } else { "continue" },
+                                span: self.span,
+                            }
+                        } else if is_break {
+                            EvalError::Break(self.span)
+                        } else {
+                            EvalError::Continue(self.span)
+                        })
+                    }
                 }
             }
         }
     }
 }
 
+impl Expr {
+    fn call_native(&self, env: &mut Environment, name: &str, args: &[Expr]) -> Result<Value> {
+        let func = env
+            .native(name)
+            .ok_or_else(|| EvalError::UndefinedFunction {
+                name: name.into(),
+                span: self.span,
+            })?;
+        let values = args
+            .iter()
+            .map(|arg| arg.eval(env))
+            .collect::<Result<Vec<_>>>()?;
+
+        env.tick(self.span)?;
+        func.call(&values).map_err(|e| e.or_span(self.span))
+    }
+}
+
+/// Call a function defined by the sources, the arity should be checked by
+/// the caller. The function takes a step.
+pub(crate) fn call_function(
+    env: &mut Environment,
+    func: &FuncDef,
+    values: Vec<Value>,
+    span: Span,
+) -> Result<Value> {
+    env.tick(span)?;
+    env.push_frame();
+    for (param, v) in func.params.iter().zip(values) {
+        env.define(param, v);
+    }
+    let v = func.body.eval(env);
+    env.pop_scope();
+    v
+}
+
+/// Run the body until `cond` is false, `step` is evaluated after every
+/// iteration. Returns the value of the last iteration.
+fn run_loop(
+    env: &mut Environment,
+    cond: Option<&Expr>,
+    step: Option<&Expr>,
+    body: &ExprList,
+    span: Span,
+) -> Result<Value> {
+    env.enter_loop();
+    let mut run = || {
+        let mut n = Value::default();
+        loop {
+            if let Some(cond) = cond {
+                if !cond.eval(env)?.as_bool() {
+                    break;
+                }
+            }
+            env.tick(span)?;
+            match body.eval(env) {
+                Ok(v) => n = v,
+                Err(EvalError::Break(_)) => break,
+                Err(EvalError::Continue(_)) => {}
+                Err(e) => return Err(e),
+            }
+            if let Some(step) = step {
+                step.eval(env)?;
+            }
+        }
+        Ok(n)
+    };
+    let v = run();
+    env.exit_loop();
+    v
+}
+
 impl Debug for Expr {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
-        use self::Expr::*;
+        use self::ExprKind::*;
 
-        match *self {
-            Number(n) => write!(f, "{:?}", n),
+        match self.kind {
+            Literal(ref n) => write!(f, "{:?}", n),
             OneOp(op, ref node) => write!(f, "({:?}: {:?})", op, node),
             TwoOp(op, ref lnode, ref rnode) => write!(f, "({:?}: <{:?}, {:?}>)", op, lnode, rnode),
             VarRef(ref v) => write!(f, "var({:?})", v),
             Assign(ref name, ref rnode) => write!(f, "({:?} = {:?})", name, rnode),
+            FuncDef(ref func) => write!(f, "Fn({:?})", func),
+            Call(ref name, ref args) => write!(f, "Call({:?}: {:?})", name, args),
+            Builtin(func, ref args) => write!(f, "Builtin({:?}: {:?})", func, args),
+            List(ref items) => write!(f, "List({:?})", items),
+            Index(ref lnode, ref index) => write!(f, "Index({:?}[{:?}])", lnode, index),
+            Lambda(ref func) => write!(f, "Lambda({:?})", func),
             Flow(ref flow) => match flow {
                 ControlFlow::Condition(if_cond) => {
                     write!(f, "Flow({:?})", if_cond)
                 }
+                ControlFlow::While(while_loop) => write!(f, "Flow({:?})", while_loop),
+                ControlFlow::For(for_loop) => write!(f, "Flow({:?})", for_loop),
+                ControlFlow::Break => write!(f, "Flow(Break)"),
+                ControlFlow::Continue => write!(f, "Flow(Continue)"),
             },
         }
     }
 }
 
 // unit_test in lalrpop config will require Eq for testing.
+// Spans are ignored, only the structures are compared, the functions
+// by their definitions.
 #[cfg(test)]
 impl PartialEq for Expr {
     fn eq(&self, exp: &Expr) -> bool {
+        self.kind == exp.kind
+    }
+}
+
+#[cfg(test)]
+impl PartialEq for ExprKind {
+    fn eq(&self, exp: &ExprKind) -> bool {
         match (self, exp) {
-            (Expr::Number(n1), Expr::Number(n2)) => n1 == n2,
-            (Expr::OneOp(opc1, node1), Expr::OneOp(opc2, node2)) => {
+            // The units are kept as written, `1 km` isn't `1000 m`.
+            (ExprKind::Literal(Value::Quantity(q1)), ExprKind::Literal(Value::Quantity(q2))) => {
+                q1.value == q2.value && q1.unit == q2.unit
+            }
+            (ExprKind::Literal(n1), ExprKind::Literal(n2)) => n1 == n2,
+            (ExprKind::OneOp(opc1, node1), ExprKind::OneOp(opc2, node2)) => {
                 if opc1 != opc2 {
                     false
                 } else {
                     node1.eq(node2)
                 }
             }
-            (Expr::TwoOp(opc1, lnode1, rnode1), Expr::TwoOp(opc2, lnode2, rnode2)) => {
+            (ExprKind::TwoOp(opc1, lnode1, rnode1), ExprKind::TwoOp(opc2, lnode2, rnode2)) => {
                 if opc1 != opc2 {
                     false
                 } else {
                     lnode1.eq(lnode2) && rnode1.eq(rnode2)
                 }
             }
+            (ExprKind::VarRef(name1), ExprKind::VarRef(name2)) => name1 == name2,
+            (ExprKind::Assign(name1, node1), ExprKind::Assign(name2, node2)) => {
+                name1 == name2 && node1.eq(node2)
+            }
+            (ExprKind::Call(name1, args1), ExprKind::Call(name2, args2)) => {
+                name1 == name2 && args1 == args2
+            }
+            (ExprKind::Builtin(func1, args1), ExprKind::Builtin(func2, args2)) => {
+                func1 == func2 && args1 == args2
+            }
+            (ExprKind::List(items1), ExprKind::List(items2)) => items1 == items2,
+            (ExprKind::Index(lnode1, rnode1), ExprKind::Index(lnode2, rnode2)) => {
+                lnode1.eq(lnode2) && rnode1.eq(rnode2)
+            }
+            (ExprKind::Flow(flow1), ExprKind::Flow(flow2)) => flow1 == flow2,
+            (ExprKind::FuncDef(func1), ExprKind::FuncDef(func2))
+            | (ExprKind::Lambda(func1), ExprKind::Lambda(func2)) => func1 == func2,
             _ => false,
         }
     }
 }
-
-// Below are fields for symbol
-#[derive(Clone, Debug)]
-struct Symbol {
-    name: String,
-    value: Number,
-}
-
-lazy_static! {
-    // Note: maybe using dashmap is better.
-    static ref SYMBOL_TABLE: Arc<Mutex<HashMap<String, Symbol>>> = {
-        Arc::new(Mutex::new(HashMap::new()))
-    };
-}
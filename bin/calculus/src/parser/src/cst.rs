@@ -0,0 +1,612 @@
+//! A lossless syntax tree of the source for the tools working on the text,
+//! like the editors. The parser skips the whitespaces and the comments,
+//! here every byte of the source belongs to a token, so printing the tree
+//! gives the source back.
+//!
+//! The tree is built alongside the AST: there is a node for every `Expr`
+//! with the same span, and a token belongs to the innermost node covering
+//! it. So the whitespaces and the comments around a node, the trivia,
+//! belong to its parent, and the ones around the statements to the root.
+
+use std::collections::HashSet;
+use std::fmt::{self, Display};
+
+use crate::calculator::ListParser;
+use crate::calculator_ast::{ControlFlow, Expr, ExprKind, ExprList, Span};
+use crate::diagnostic::Diagnostic;
+use crate::error::RenameError;
+use crate::pretty;
+
+/// The keywords of the grammar, they are not names.
+pub const KEYWORDS: [&str; 12] = [
+    "if", "then", "else", "fn", "while", "for", "break", "continue", "true", "false", "null", "in",
+];
+
+// The longer ones first.
+const OPERATORS: [&str; 21] = [
+    "==", "!=", ">=", "<=", "&&", "||", "+=", "-=", "*=", "/=", "**", "+", "-", "*", "/", "%", "!",
+    "<", ">", "=", "^",
+];
+
+/// The lexical kind of a token.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TokenKind {
+    Whitespace,
+    LineComment,
+    BlockComment,
+    Ident,
+    Keyword,
+    Number,
+    Str,
+    // `+`, `==`, `+=` and so on.
+    Operator,
+    // The brackets, `,` and `;`.
+    Punct,
+    // A character the grammar doesn't know, or an unterminated string or
+    // comment.
+    Unknown,
+}
+
+impl TokenKind {
+    /// The whitespaces and the comments, the parser skips them.
+    pub fn is_trivia(self) -> bool {
+        self == TokenKind::Whitespace || self.is_comment()
+    }
+
+    pub fn is_comment(self) -> bool {
+        matches!(self, TokenKind::LineComment | TokenKind::BlockComment)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Token {
+    pub kind: TokenKind,
+    pub span: Span,
+}
+
+impl Token {
+    /// The class of the token for the syntax highlighting, there is none
+    /// for the whitespaces. All the identifiers are variables, see
+    /// `SyntaxTree::highlights` to tell them apart.
+    pub fn highlight(&self) -> Option<Highlight> {
+        let class = match self.kind {
+            TokenKind::Whitespace => return None,
+            TokenKind::LineComment | TokenKind::BlockComment => Highlight::Comment,
+            TokenKind::Ident => Highlight::Variable,
+            TokenKind::Keyword => Highlight::Keyword,
+            TokenKind::Number => Highlight::Number,
+            TokenKind::Str => Highlight::String,
+            TokenKind::Operator => Highlight::Operator,
+            TokenKind::Punct => Highlight::Punctuation,
+            TokenKind::Unknown => Highlight::Unknown,
+        };
+        Some(class)
+    }
+}
+
+/// The classes of the tokens for the syntax highlighting.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Highlight {
+    Comment,
+    Keyword,
+    Number,
+    String,
+    Operator,
+    Punctuation,
+    Variable,
+    Parameter,
+    // The functions defined by `fn`.
+    Function,
+    Builtin,
+    Unit,
+    Unknown,
+}
+
+/// Whether the text is a name of a variable or a function, the keywords
+/// are not.
+pub fn is_name(text: &str) -> bool {
+    matches!(
+        tokenize(text)[..],
+        [Token {
+            kind: TokenKind::Ident,
+            ..
+        }]
+    )
+}
+
+/// Split the source into tokens, their texts put together are the
+/// source. It never fails, what the grammar doesn't know is a token of
+/// `TokenKind::Unknown`.
+pub fn tokenize(source: &str) -> Vec<Token> {
+    let mut tokens = Vec::new();
+    let mut start = 0;
+    while start < source.len() {
+        let (kind, len) = lex(&source[start..]);
+        tokens.push(Token {
+            kind,
+            span: Span::new(start, start + len),
+        });
+        start += len;
+    }
+    tokens
+}
+
+// The kind and the length of the first token, it follows the lexer of
+// `calculator.lalrpop`.
+fn lex(rest: &str) -> (TokenKind, usize) {
+    let bytes = rest.as_bytes();
+    let c = rest.chars().next().unwrap();
+    match c {
+        _ if c.is_whitespace() => {
+            let len = rest.find(|c: char| !c.is_whitespace());
+            (TokenKind::Whitespace, len.unwrap_or(rest.len()))
+        }
+        '/' if rest.starts_with("//") => {
+            let len = rest.find(['\n', '\r']);
+            (TokenKind::LineComment, len.unwrap_or(rest.len()))
+        }
+        '/' if rest.starts_with("/*") => match rest[2..].find("*/") {
+            Some(pos) => (TokenKind::BlockComment, pos + 4),
+            None => (TokenKind::Unknown, rest.len()),
+        },
+        'a'..='z' | 'A'..='Z' => {
+            let len = 1 + bytes[1..]
+                .iter()
+                .take_while(|b| b.is_ascii_alphanumeric())
+                .count();
+            match KEYWORDS.contains(&&rest[..len]) {
+                true => (TokenKind::Keyword, len),
+                false => (TokenKind::Ident, len),
+            }
+        }
+        '0'..='9' | '.' => match number(bytes) {
+            Some(len) => (TokenKind::Number, len),
+            None => (TokenKind::Unknown, 1),
+        },
+        '"' => match string(bytes) {
+            Some(len) => (TokenKind::Str, len),
+            None => (TokenKind::Unknown, rest.len()),
+        },
+        _ => match OPERATORS.iter().find(|op| rest.starts_with(*op)) {
+            Some(op) => (TokenKind::Operator, op.len()),
+            None if "()[]{},;".contains(c) => (TokenKind::Punct, 1),
+            None => (TokenKind::Unknown, c.len_utf8()),
+        },
+    }
+}
+
+// `12`, `1.5`, `1.`, `.5` and `2e-3`, a lone `.` is not a number.
+fn number(bytes: &[u8]) -> Option<usize> {
+    let digits = |from: usize| {
+        from + bytes[from.min(bytes.len())..]
+            .iter()
+            .take_while(|b| b.is_ascii_digit())
+            .count()
+    };
+    let mut len = digits(0);
+    if bytes.get(len) == Some(&b'.') {
+        let end = digits(len + 1);
+        if len == 0 && end == 1 {
+            return None;
+        }
+        len = end;
+    }
+    // The exponent belongs to the number only if it has digits.
+    if matches!(bytes.get(len), Some(b'e' | b'E')) {
+        let sign = matches!(bytes.get(len + 1), Some(b'+' | b'-')) as usize;
+        let end = digits(len + 1 + sign);
+        if end > len + 1 + sign {
+            len = end;
+        }
+    }
+    Some(len)
+}
+
+fn string(bytes: &[u8]) -> Option<usize> {
+    let mut i = 1;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'"' => return Some(i + 1),
+            b'\\' => i += 2,
+            _ => i += 1,
+        }
+    }
+    None
+}
+
+/// The kind of a node, there is one for each kind of `Expr`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum NodeKind {
+    // The root, all the statements.
+    Program,
+    Literal,
+    Unary,
+    Binary,
+    Variable,
+    Assign,
+    If,
+    While,
+    For,
+    Break,
+    Continue,
+    FuncDef,
+    Call,
+    // Including `x in km`.
+    Builtin,
+    List,
+    Index,
+    Lambda,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum SyntaxElement {
+    Node(SyntaxNode),
+    Token(Token),
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct SyntaxNode {
+    pub kind: NodeKind,
+    pub span: Span,
+    pub children: Vec<SyntaxElement>,
+}
+
+impl SyntaxNode {
+    /// All the tokens of the node in the order of the source.
+    pub fn tokens(&self) -> Vec<&Token> {
+        let mut tokens = Vec::new();
+        self.collect_tokens(&mut tokens);
+        tokens
+    }
+
+    fn collect_tokens<'a>(&'a self, tokens: &mut Vec<&'a Token>) {
+        for child in &self.children {
+            match child {
+                SyntaxElement::Node(node) => node.collect_tokens(tokens),
+                SyntaxElement::Token(token) => tokens.push(token),
+            }
+        }
+    }
+}
+
+/// The source with its lossless tree and its AST.
+///
+/// ```
+/// use calculus_parser::cst::SyntaxTree;
+///
+/// let tree = SyntaxTree::parse("a = 1; // one\nb = a + 1").unwrap();
+/// assert_eq!("a = 1; // one\nb = a + 1", tree.to_string());
+/// assert_eq!("x = 1; // one\nb = x + 1", tree.rename(0, "x").unwrap());
+/// ```
+#[derive(Clone)]
+pub struct SyntaxTree {
+    source: String,
+    list: ExprList,
+    root: SyntaxNode,
+}
+
+impl SyntaxTree {
+    pub fn parse(source: &str) -> Result<Self, Diagnostic> {
+        let list = ListParser::new()
+            .parse(source)
+            .map_err(|e| Diagnostic::from_parse_error(&e))?;
+        let mut builder = Builder {
+            tokens: tokenize(source),
+            next: 0,
+        };
+        let root = builder.node(
+            NodeKind::Program,
+            Span::new(0, source.len()),
+            stmts(&list).collect(),
+        );
+        debug_assert_eq!(builder.next, builder.tokens.len());
+
+        Ok(SyntaxTree {
+            source: source.into(),
+            list,
+            root,
+        })
+    }
+
+    pub fn source(&self) -> &str {
+        &self.source
+    }
+
+    pub fn ast(&self) -> &ExprList {
+        &self.list
+    }
+
+    pub fn root(&self) -> &SyntaxNode {
+        &self.root
+    }
+
+    pub fn tokens(&self) -> Vec<&Token> {
+        self.root.tokens()
+    }
+
+    pub fn text(&self, token: &Token) -> &str {
+        &self.source[token.span.start..token.span.end]
+    }
+
+    /// The classes of all the tokens but the whitespaces, the identifiers
+    /// are told apart by where they are.
+    pub fn highlights(&self) -> Vec<(Span, Highlight)> {
+        let mut classes = Vec::new();
+        classify(&self.root, &mut classes);
+        classes
+            .into_iter()
+            .filter_map(|(token, class)| class.map(|class| (token.span, class)))
+            .collect()
+    }
+
+    /// The spans of the variable at `offset` and all the references to it.
+    ///
+    /// The variables of a function are its parameters and the names
+    /// assigned in its body, the other names in it are the global
+    /// variables, like they are looked up when it's called.
+    pub fn references(&self, offset: usize) -> Option<Vec<Span>> {
+        let vars = self.references_at(offset)?;
+        Some(vars.iter().map(|var| var.token.span).collect())
+    }
+
+    /// The spans of the definitions of the variable at `offset`, which are
+    /// its parameter or the assignments to it, see `references`.
+    pub fn definitions(&self, offset: usize) -> Option<Vec<Span>> {
+        let vars = self.references_at(offset)?;
+        let spans = vars
+            .iter()
+            .filter(|var| var.is_def)
+            .map(|var| var.token.span)
+            .collect();
+        Some(spans)
+    }
+
+    /// Rename the variable at `offset` with all the references to it, the
+    /// rest of the source is kept as it is.
+    pub fn rename(&self, offset: usize, name: &str) -> Result<String, RenameError> {
+        if !is_name(name) {
+            return Err(RenameError::InvalidName(name.into()));
+        }
+        let spans = self
+            .references(offset)
+            .ok_or(RenameError::NoVariable(offset))?;
+
+        let mut renamed = String::with_capacity(self.source.len());
+        let mut last = 0;
+        for span in spans {
+            renamed.push_str(&self.source[last..span.start]);
+            renamed.push_str(name);
+            last = span.end;
+        }
+        renamed.push_str(&self.source[last..]);
+        Ok(renamed)
+    }
+
+    /// Format the source like `calculus fmt`, keeping the comments, see
+    /// `pretty::format_tree`.
+    pub fn format(&self) -> String {
+        pretty::format_tree(self)
+    }
+
+    fn references_at(&self, offset: usize) -> Option<Vec<Variable<'_>>> {
+        let mut assigns = HashSet::new();
+        assign_starts(&self.root, &mut assigns);
+        let mut vars = Vec::new();
+        self.variables(&self.root, &assigns, &mut vec![], &mut 0, &mut vars);
+        let var = vars
+            .iter()
+            .find(|var| var.token.span.start <= offset && offset <= var.token.span.end)?;
+        let (name, scope) = (self.text(var.token), var.scope);
+        vars.retain(|other| other.scope == scope && self.text(other.token) == name);
+        Some(vars)
+    }
+
+    // The variables and the parameters with the scopes they belong to.
+    // `scopes` are the functions enclosing `node` with their variables.
+    fn variables<'a>(
+        &'a self,
+        node: &'a SyntaxNode,
+        assigns: &HashSet<usize>,
+        scopes: &mut Vec<(usize, HashSet<&'a str>)>,
+        count: &mut usize,
+        vars: &mut Vec<Variable<'a>>,
+    ) {
+        let mut classes = Vec::new();
+        classify_own(node, &mut classes);
+        let is_func = matches!(node.kind, NodeKind::FuncDef | NodeKind::Lambda);
+        if is_func {
+            *count += 1;
+            let mut locals: HashSet<_> = classes
+                .iter()
+                .filter(|(_, class)| *class == Some(Highlight::Parameter))
+                .map(|(token, _)| self.text(token))
+                .collect();
+            self.assigned(node, &mut locals);
+            scopes.push((*count, locals));
+        }
+
+        let mut classes = classes.into_iter();
+        for child in &node.children {
+            match child {
+                SyntaxElement::Node(child) => self.variables(child, assigns, scopes, count, vars),
+                SyntaxElement::Token(token) => {
+                    let class = classes.next().and_then(|(_, class)| class);
+                    if matches!(class, Some(Highlight::Variable | Highlight::Parameter)) {
+                        let name = self.text(token);
+                        let scope = scopes
+                            .iter()
+                            .rev()
+                            .find(|(_, locals)| locals.contains(name))
+                            .map_or(0, |(scope, _)| *scope);
+                        // `a = e` and `a += e` start with the name.
+                        let is_def = class == Some(Highlight::Parameter)
+                            || assigns.contains(&token.span.start);
+                        vars.push(Variable {
+                            token,
+                            scope,
+                            is_def,
+                        });
+                    }
+                }
+            }
+        }
+
+        if is_func {
+            scopes.pop();
+        }
+    }
+
+    // The names assigned in the body of a function, but not in the
+    // functions inside it.
+    fn assigned<'a>(&'a self, node: &'a SyntaxNode, names: &mut HashSet<&'a str>) {
+        for child in &node.children {
+            if let SyntaxElement::Node(child) = child {
+                if child.kind == NodeKind::Assign {
+                    // `a = e` or `a += e`, the name comes first.
+                    let name = child
+                        .tokens()
+                        .into_iter()
+                        .find(|t| t.kind == TokenKind::Ident);
+                    names.extend(name.map(|token| self.text(token)));
+                }
+                if !matches!(child.kind, NodeKind::FuncDef | NodeKind::Lambda) {
+                    self.assigned(child, names);
+                }
+            }
+        }
+    }
+}
+
+/// The source, from the tokens of the tree.
+impl Display for SyntaxTree {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for token in self.tokens() {
+            f.write_str(self.text(token))?;
+        }
+        Ok(())
+    }
+}
+
+// A variable or a parameter, `scope` is the number of the function it
+// belongs to in the source order, or 0 for the global variables.
+struct Variable<'a> {
+    token: &'a Token,
+    scope: usize,
+    is_def: bool,
+}
+
+// Where the assignments start, at the names assigned.
+fn assign_starts(node: &SyntaxNode, starts: &mut HashSet<usize>) {
+    if node.kind == NodeKind::Assign {
+        starts.insert(node.span.start);
+    }
+    for child in &node.children {
+        if let SyntaxElement::Node(child) = child {
+            assign_starts(child, starts);
+        }
+    }
+}
+
+// The tokens of the node and of its children with their classes.
+fn classify<'a>(node: &'a SyntaxNode, classes: &mut Vec<(&'a Token, Option<Highlight>)>) {
+    let mut own = Vec::new();
+    classify_own(node, &mut own);
+    let mut own = own.into_iter();
+    for child in &node.children {
+        match child {
+            SyntaxElement::Node(child) => classify(child, classes),
+            SyntaxElement::Token(_) => classes.extend(own.next()),
+        }
+    }
+}
+
+// The tokens directly in the node with their classes, an identifier is
+// classed by the node it's in.
+fn classify_own<'a>(node: &'a SyntaxNode, classes: &mut Vec<(&'a Token, Option<Highlight>)>) {
+    let mut idents = 0;
+    for child in &node.children {
+        if let SyntaxElement::Token(token) = child {
+            let class = match token.kind {
+                TokenKind::Ident => {
+                    idents += 1;
+                    Some(match node.kind {
+                        NodeKind::Call => Highlight::Function,
+                        NodeKind::Builtin => Highlight::Builtin,
+                        NodeKind::FuncDef if idents == 1 => Highlight::Function,
+                        NodeKind::FuncDef | NodeKind::Lambda => Highlight::Parameter,
+                        NodeKind::Literal => Highlight::Unit,
+                        _ => Highlight::Variable,
+                    })
+                }
+                _ => token.highlight(),
+            };
+            classes.push((token, class));
+        }
+    }
+}
+
+// Builds the nodes of the expressions, taking the tokens in order.
+struct Builder {
+    tokens: Vec<Token>,
+    next: usize,
+}
+
+impl Builder {
+    fn expr(&mut self, expr: &Expr) -> SyntaxNode {
+        let kind = match expr.kind {
+            ExprKind::Literal(_) => NodeKind::Literal,
+            ExprKind::OneOp(..) => NodeKind::Unary,
+            ExprKind::TwoOp(..) => NodeKind::Binary,
+            ExprKind::VarRef(_) => NodeKind::Variable,
+            ExprKind::Assign(..) => NodeKind::Assign,
+            ExprKind::Flow(ref flow) => match flow {
+                ControlFlow::Condition(_) => NodeKind::If,
+                ControlFlow::While(_) => NodeKind::While,
+                ControlFlow::For(_) => NodeKind::For,
+                ControlFlow::Break => NodeKind::Break,
+                ControlFlow::Continue => NodeKind::Continue,
+            },
+            ExprKind::FuncDef(_) => NodeKind::FuncDef,
+            ExprKind::Call(..) => NodeKind::Call,
+            ExprKind::Builtin(..) => NodeKind::Builtin,
+            ExprKind::List(_) => NodeKind::List,
+            ExprKind::Index(..) => NodeKind::Index,
+            ExprKind::Lambda(_) => NodeKind::Lambda,
+        };
+        self.node(kind, expr.span, expr.children())
+    }
+
+    // The tokens before the end of the span are taken, the ones from the
+    // start of a child on are left to the child.
+    fn node(&mut self, kind: NodeKind, span: Span, exprs: Vec<&Expr>) -> SyntaxNode {
+        let mut exprs = exprs.into_iter().peekable();
+        let mut children = Vec::new();
+        loop {
+            let token = self
+                .tokens
+                .get(self.next)
+                .filter(|token| token.span.end <= span.end)
+                .copied();
+            match (exprs.peek(), token) {
+                (Some(expr), _) if token.is_none_or(|t| expr.span.start <= t.span.start) => {
+                    let expr = exprs.next().unwrap();
+                    children.push(SyntaxElement::Node(self.expr(expr)));
+                }
+                (_, Some(token)) => {
+                    children.push(SyntaxElement::Token(token));
+                    self.next += 1;
+                }
+                _ => break,
+            }
+        }
+        SyntaxNode {
+            kind,
+            span,
+            children,
+        }
+    }
+}
+
+fn stmts(list: &ExprList) -> impl Iterator<Item = &Expr> {
+    list.0.iter().flatten().map(|expr| &**expr)
+}
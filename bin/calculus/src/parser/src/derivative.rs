@@ -0,0 +1,317 @@
+use crate::builtin::BuiltinFunc;
+use crate::calculator_ast::{Expr, ExprKind, Opcode, Span, Value};
+use crate::error::DeriveError;
+use crate::optimizer::{is_float, optimize};
+use crate::pretty::pretty;
+
+/// The derivative of `expr` with respect to the variable `var`, simplified
+/// by `optimize`.
+///
+/// The derivative is taken over the reals: the constants and the divisions
+/// it makes are floats, so `d/dx (x / 2)` is `0.5`, and `0 * u` is dropped
+/// and `u / u` is `1.0` even if `u` could be NaN or zero. The integer powers
+/// stay integers, `d/dx x ^ 3` is `3 * x ^ 2`. `floor`, `ceil` and `round` are taken as
+/// constants, so `u % c` is taken as `u` if `c` doesn't depend on the
+/// variable.
+pub fn derive(expr: &Expr, var: &str) -> Result<Expr, DeriveError> {
+    Ok(optimize(&Deriver { var }.derive(expr)?))
+}
+
+struct Deriver<'a> {
+    var: &'a str,
+}
+
+impl Deriver<'_> {
+    fn derive(&self, expr: &Expr) -> Result<Expr, DeriveError> {
+        let span = expr.span;
+        let unsupported = |what: &str| {
+            Err(DeriveError::Unsupported {
+                what: what.into(),
+                span,
+            })
+        };
+
+        match expr.kind {
+            ExprKind::Literal(Value::Bool(_)) => unsupported("a boolean"),
+            ExprKind::Literal(Value::Str(_)) => unsupported("a string"),
+            ExprKind::Literal(Value::Null) => unsupported("`null`"),
+            ExprKind::Literal(_) => Ok(constant(0.0, span)),
+            ExprKind::VarRef(ref name) if name == self.var => Ok(constant(1.0, span)),
+            ExprKind::VarRef(_) => Ok(constant(0.0, span)),
+            ExprKind::OneOp(Opcode::Not, _) => unsupported("a logical operator"),
+            ExprKind::OneOp(Opcode::Add, ref node) => self.derive(node),
+            ExprKind::OneOp(_, ref node) => Ok(neg(self.derive(node)?, span)),
+            ExprKind::TwoOp(op, ref u, ref v) => match op {
+                Opcode::Add => Ok(add(self.derive(u)?, self.derive(v)?, span)),
+                Opcode::Sub => Ok(sub(self.derive(u)?, self.derive(v)?, span)),
+                // (uv)' = u'v + uv'
+                Opcode::Mul => {
+                    let du = self.derive(u)?;
+                    let dv = self.derive(v)?;
+                    Ok(add(
+                        mul(du, v.as_ref().clone(), span),
+                        mul(u.as_ref().clone(), dv, span),
+                        span,
+                    ))
+                }
+                // (u/v)' = (u'v - uv') / v^2
+                Opcode::Div => {
+                    let du = self.derive(u)?;
+                    if !self.depends(v) {
+                        return Ok(div(du, v.as_ref().clone(), span));
+                    }
+                    let dv = self.derive(v)?;
+                    Ok(div(
+                        sub(
+                            mul(du, v.as_ref().clone(), span),
+                            mul(u.as_ref().clone(), dv, span),
+                            span,
+                        ),
+                        mul(v.as_ref().clone(), v.as_ref().clone(), span),
+                        span,
+                    ))
+                }
+                Opcode::Rem if !self.depends(v) => self.derive(u),
+                Opcode::Rem => unsupported("a remainder"),
+                Opcode::And | Opcode::Or => unsupported("a logical operator"),
+                _ => unsupported("a comparison"),
+            },
+            ExprKind::Builtin(func, ref args) => self.builtin(func, args, span),
+            ExprKind::Call(ref name, _) => unsupported(&format!("the function `{}`", name)),
+            ExprKind::Assign(..) => unsupported("an assignment"),
+            ExprKind::Flow(_) | ExprKind::FuncDef(_) => unsupported("a statement"),
+            ExprKind::List(_) | ExprKind::Index(..) => unsupported("a list"),
+            ExprKind::Lambda(_) => unsupported("a function value"),
+        }
+    }
+
+    // The chain rule: f(u)' = f'(u) * u'.
+    fn builtin(&self, func: BuiltinFunc, args: &[Expr], span: Span) -> Result<Expr, DeriveError> {
+        if !func.arity().accepts(args.len()) {
+            return Err(DeriveError::Unsupported {
+                what: format!("`{}` with {} arguments", func.name(), args.len()),
+                span,
+            });
+        }
+        let u = || args[0].clone();
+        let du = self.derive(&args[0]);
+        let call = |func, args| Expr::new(ExprKind::Builtin(func, args), span);
+        // sqrt(1 - u^2)
+        let sqrt_one_minus_sq = || {
+            call(
+                BuiltinFunc::Sqrt,
+                vec![sub(constant(1.0, span), mul(u(), u(), span), span)],
+            )
+        };
+
+        let d = match func {
+            BuiltinFunc::Sqrt => div(
+                du?,
+                mul(
+                    constant(2.0, span),
+                    call(BuiltinFunc::Sqrt, vec![u()]),
+                    span,
+                ),
+                span,
+            ),
+            BuiltinFunc::Exp => mul(du?, call(BuiltinFunc::Exp, vec![u()]), span),
+            BuiltinFunc::Ln => div(du?, u(), span),
+            BuiltinFunc::Log => div(
+                du?,
+                mul(u(), constant(std::f64::consts::LN_10, span), span),
+                span,
+            ),
+            BuiltinFunc::Pow => return self.pow(&args[0], &args[1], span),
+            // `u / abs(u)` is exact on integers.
+            BuiltinFunc::Abs => mul(
+                du?,
+                two_op(Opcode::Div, u(), call(BuiltinFunc::Abs, vec![u()]), span),
+                span,
+            ),
+            BuiltinFunc::Floor | BuiltinFunc::Ceil | BuiltinFunc::Round => constant(0.0, span),
+            BuiltinFunc::Sin => mul(du?, call(BuiltinFunc::Cos, vec![u()]), span),
+            BuiltinFunc::Cos => neg(mul(du?, call(BuiltinFunc::Sin, vec![u()]), span), span),
+            BuiltinFunc::Tan => {
+                let cos = || call(BuiltinFunc::Cos, vec![u()]);
+                div(du?, mul(cos(), cos(), span), span)
+            }
+            BuiltinFunc::Asin => div(du?, sqrt_one_minus_sq(), span),
+            BuiltinFunc::Acos => neg(div(du?, sqrt_one_minus_sq(), span), span),
+            BuiltinFunc::Atan => div(
+                du?,
+                add(constant(1.0, span), mul(u(), u(), span), span),
+                span,
+            ),
+            BuiltinFunc::Min
+            | BuiltinFunc::Max
+            | BuiltinFunc::Print
+            | BuiltinFunc::Integrate
+            | BuiltinFunc::Solve
+            | BuiltinFunc::Len
+            | BuiltinFunc::Sum
+            | BuiltinFunc::Map
+            | BuiltinFunc::Filter
+            | BuiltinFunc::Reduce
+            | BuiltinFunc::Convert => {
+                return Err(DeriveError::Unsupported {
+                    what: format!("`{}`", func.name()),
+                    span,
+                })
+            }
+        };
+        Ok(d)
+    }
+
+    fn pow(&self, u: &Expr, v: &Expr, span: Span) -> Result<Expr, DeriveError> {
+        let pow = || {
+            Expr::new(
+                ExprKind::Builtin(BuiltinFunc::Pow, vec![u.clone(), v.clone()]),
+                span,
+            )
+        };
+        let ln_u = || Expr::new(ExprKind::Builtin(BuiltinFunc::Ln, vec![u.clone()]), span);
+
+        let d = match (self.depends(u), self.depends(v)) {
+            (_, false) => {
+                // (u^c)' = c * u^(c - 1) * u', an integer `c` makes an
+                // integer power.
+                let exp = integer(v).and_then(|c| c.checked_sub(1)).map(Value::I64);
+                let power = match exp {
+                    Some(Value::I64(0)) => constant(1.0, span),
+                    Some(Value::I64(1)) => u.clone(),
+                    Some(exp) => Expr::new(
+                        ExprKind::Builtin(
+                            BuiltinFunc::Pow,
+                            vec![u.clone(), Expr::new(ExprKind::Literal(exp), span)],
+                        ),
+                        span,
+                    ),
+                    None => Expr::new(
+                        ExprKind::Builtin(
+                            BuiltinFunc::Pow,
+                            vec![u.clone(), sub(v.clone(), constant(1.0, span), span)],
+                        ),
+                        span,
+                    ),
+                };
+                mul(mul(v.clone(), power, span), self.derive(u)?, span)
+            }
+            // (c^v)' = c^v * ln(c) * v'
+            (false, true) => mul(mul(pow(), ln_u(), span), self.derive(v)?, span),
+            // (u^v)' = u^v * (v' * ln(u) + v * u' / u)
+            (true, true) => mul(
+                pow(),
+                add(
+                    mul(self.derive(v)?, ln_u(), span),
+                    div(mul(v.clone(), self.derive(u)?, span), u.clone(), span),
+                    span,
+                ),
+                span,
+            ),
+        };
+        Ok(d)
+    }
+
+    // Whether the expression refers to the variable.
+    fn depends(&self, expr: &Expr) -> bool {
+        match expr.kind {
+            ExprKind::Literal(_) => false,
+            ExprKind::VarRef(ref name) => name == self.var,
+            ExprKind::OneOp(_, ref node) => self.depends(node),
+            ExprKind::TwoOp(_, ref l, ref r) => self.depends(l) || self.depends(r),
+            ExprKind::Builtin(_, ref args)
+            | ExprKind::Call(_, ref args)
+            | ExprKind::List(ref args) => args.iter().any(|arg| self.depends(arg)),
+            ExprKind::Index(ref l, ref r) => self.depends(l) || self.depends(r),
+            // They are not differentiable anyway.
+            ExprKind::Assign(..)
+            | ExprKind::Flow(_)
+            | ExprKind::FuncDef(_)
+            | ExprKind::Lambda(_) => true,
+        }
+    }
+}
+
+// The constructors fold the constants and drop the identities, so the
+// derivative doesn't grow with `0 * u` and `1 * u` terms.
+
+fn constant(v: f64, span: Span) -> Expr {
+    Expr::new(ExprKind::Literal(Value::F64(v)), span)
+}
+
+fn value(expr: &Expr) -> Option<f64> {
+    match expr.kind {
+        ExprKind::Literal(Value::I64(i)) => Some(i as f64),
+        ExprKind::Literal(Value::F64(f)) => Some(f),
+        _ => None,
+    }
+}
+
+// The integer literal, `-2` is parsed as a negation.
+fn integer(expr: &Expr) -> Option<i64> {
+    match expr.kind {
+        ExprKind::Literal(Value::I64(i)) => Some(i),
+        ExprKind::OneOp(Opcode::Sub, ref node) => integer(node)?.checked_neg(),
+        _ => None,
+    }
+}
+
+fn two_op(op: Opcode, l: Expr, r: Expr, span: Span) -> Expr {
+    Expr::new(ExprKind::TwoOp(op, Box::new(l), Box::new(r)), span)
+}
+
+fn neg(u: Expr, span: Span) -> Expr {
+    match (value(&u), u.kind) {
+        (Some(c), _) => constant(-c, span),
+        (_, ExprKind::OneOp(Opcode::Sub, node)) => *node,
+        (_, kind) => Expr::new(
+            ExprKind::OneOp(Opcode::Sub, Box::new(Expr::new(kind, u.span))),
+            span,
+        ),
+    }
+}
+
+fn add(l: Expr, r: Expr, span: Span) -> Expr {
+    match (value(&l), value(&r)) {
+        (Some(a), Some(b)) => constant(a + b, span),
+        (Some(0.0), _) => r,
+        (_, Some(0.0)) => l,
+        _ => two_op(Opcode::Add, l, r, span),
+    }
+}
+
+fn sub(l: Expr, r: Expr, span: Span) -> Expr {
+    match (value(&l), value(&r)) {
+        (Some(a), Some(b)) => constant(a - b, span),
+        (Some(0.0), _) => neg(r, span),
+        (_, Some(0.0)) => l,
+        _ => two_op(Opcode::Sub, l, r, span),
+    }
+}
+
+fn mul(l: Expr, r: Expr, span: Span) -> Expr {
+    match (value(&l), value(&r)) {
+        (Some(a), Some(b)) => constant(a * b, span),
+        (Some(0.0), _) | (_, Some(0.0)) => constant(0.0, span),
+        (Some(1.0), _) => r,
+        (_, Some(1.0)) => l,
+        _ => two_op(Opcode::Mul, l, r, span),
+    }
+}
+
+// The division is made on floats, `u * 1.0` is used if both of the
+// operands could be integers.
+fn div(l: Expr, r: Expr, span: Span) -> Expr {
+    match (value(&l), value(&r)) {
+        (Some(a), Some(b)) if b != 0.0 => constant(a / b, span),
+        (Some(0.0), _) => constant(0.0, span),
+        (_, Some(1.0)) => l,
+        _ if pretty(&l) == pretty(&r) => constant(1.0, span),
+        (_, Some(b)) => two_op(Opcode::Div, l, constant(b, span), span),
+        _ if is_float(&l) || is_float(&r) => two_op(Opcode::Div, l, r, span),
+        _ => {
+            let r = two_op(Opcode::Mul, r, constant(1.0, span), span);
+            two_op(Opcode::Div, l, r, span)
+        }
+    }
+}
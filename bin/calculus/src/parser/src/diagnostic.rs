@@ -0,0 +1,133 @@
+use std::fmt::Display;
+
+use lalrpop_util::ParseError;
+
+use crate::calculator_ast::Span;
+use crate::error::{EvalError, SyntaxError};
+
+/// An error located in the source, it can be rendered as a snippet
+/// of the source line with a caret under the error.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Diagnostic {
+    pub message: String,
+    pub span: Span,
+}
+
+impl Diagnostic {
+    pub fn new(message: impl Into<String>, span: Span) -> Self {
+        Diagnostic {
+            message: message.into(),
+            span,
+        }
+    }
+
+    pub fn from_parse_error<T: Display>(err: &ParseError<usize, T, SyntaxError>) -> Self {
+        match err {
+            ParseError::InvalidToken { location } => {
+                Diagnostic::new("invalid token", Span::new(*location, *location + 1))
+            }
+            ParseError::UnrecognizedEOF { location, expected } => Diagnostic::new(
+                format!("unexpected end of input{}", expected_message(expected)),
+                Span::new(*location, *location),
+            ),
+            ParseError::UnrecognizedToken {
+                token: (start, token, end),
+                expected,
+            } => Diagnostic::new(
+                format!("unexpected token `{}`{}", token, expected_message(expected)),
+                Span::new(*start, *end),
+            ),
+            ParseError::ExtraToken {
+                token: (start, token, end),
+            } => Diagnostic::new(format!("extra token `{}`", token), Span::new(*start, *end)),
+            ParseError::User { error } => Diagnostic::from(error),
+        }
+    }
+
+    /// The error raised by evaluating the list parsed from `source`, the
+    /// message quotes the source, see `EvalError::message`.
+    pub fn from_eval_error(err: &EvalError, source: &str) -> Self {
+        Diagnostic::new(err.message(source), err.span())
+    }
+
+    /// Render the error like:
+    ///
+    /// ```text
+    /// error: unexpected token `)`, expected one of "!", "(", "-", "[", string, number, "false", "fn", "null", "true", identifier
+    ///  --> 1:5
+    ///   |
+    /// 1 | a = ) + 2
+    ///   |     ^
+    /// ```
+    pub fn render(&self, source: &str) -> String {
+        let start = self.span.start.min(source.len());
+        let end = self.span.end.clamp(start, source.len());
+
+        let line_start = source[..start].rfind('\n').map_or(0, |pos| pos + 1);
+        let line_end = source[start..]
+            .find('\n')
+            .map_or(source.len(), |pos| start + pos);
+        let line_no = source[..start].matches('\n').count() + 1;
+        let line = source[line_start..line_end].trim_end_matches('\r');
+
+        let column = source[line_start..start].chars().count();
+        // Only the first line of the span is marked.
+        let width = source[start..end.min(line_end)].chars().count().max(1);
+
+        let gutter = " ".repeat(line_no.to_string().len());
+        format!(
+            "error: {}\n{}--> {}:{}\n{} |\n{} | {}\n{} | {}{}",
+            self.message,
+            gutter,
+            line_no,
+            column + 1,
+            gutter,
+            line_no,
+            line,
+            gutter,
+            " ".repeat(column),
+            "^".repeat(width),
+        )
+    }
+}
+
+// Without the source, the message prints the expression.
+impl From<&EvalError> for Diagnostic {
+    fn from(err: &EvalError) -> Self {
+        Diagnostic::new(err.to_string(), err.span())
+    }
+}
+
+fn expected_message(expected: &[String]) -> String {
+    let mut names: Vec<String> = Vec::new();
+    for name in expected.iter().map(|terminal| terminal_name(terminal)) {
+        if !names.contains(&name) {
+            names.push(name);
+        }
+    }
+
+    match names.len() {
+        0 => String::new(),
+        1 => format!(", expected {}", names[0]),
+        _ => format!(", expected one of {}", names.join(", ")),
+    }
+}
+
+// Readable names of the terminals in `calculator.lalrpop`.
+fn terminal_name(terminal: &str) -> String {
+    match terminal {
+        "VarName" => "identifier".into(),
+        "Equal" => "\"==\"".into(),
+        "NotEqual" => "\"!=\"".into(),
+        "LargerOrEqual" => "\">=\"".into(),
+        "LargerThan" => "\">\"".into(),
+        "LessOrEqual" => "\"<=\"".into(),
+        "LessThan" => "\"<\"".into(),
+        "IF" | "THEN" | "ELSE" | "FN" | "WHILE" | "FOR" | "BREAK" | "CONTINUE" | "TRUE"
+        | "FALSE" | "NULL" | "IN" => format!("\"{}\"", terminal.to_lowercase()),
+        // Only the strings and the numbers are matched by regex.
+        t if t.starts_with("r#\"\\\"") => "string".into(),
+        t if t.starts_with("r#") => "number".into(),
+        t => t.into(),
+    }
+}
@@ -0,0 +1,334 @@
+use std::collections::HashMap;
+use std::fmt::Debug;
+use std::mem;
+use std::rc::Rc;
+use std::time::Instant;
+
+use crate::calculator_ast::{FuncDef, NumericMode, Span, Value};
+use crate::error::{EvalError, Result};
+use crate::limits::{Limit, Limits};
+
+type NativeFn = dyn Fn(&[Value]) -> Result<Value>;
+
+/// A function registered by the host, it's called with the evaluated arguments.
+#[derive(Clone)]
+pub struct NativeFunc(Rc<NativeFn>);
+
+impl NativeFunc {
+    pub fn new<F>(func: F) -> Self
+    where
+        F: Fn(&[Value]) -> Result<Value> + 'static,
+    {
+        NativeFunc(Rc::new(func))
+    }
+
+    pub fn call(&self, args: &[Value]) -> Result<Value> {
+        (self.0)(args)
+    }
+}
+
+impl Debug for NativeFunc {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "NativeFunc")
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+struct Scope {
+    vars: HashMap<String, Value>,
+    // A frame scope is pushed by a function call, lookups will not go
+    // through it into the scopes of the caller.
+    is_frame: bool,
+}
+
+/// Environment owns all the symbols of an evaluation.
+///
+/// The bottom scope holds the global variables. Nested scopes can be pushed
+/// on top of it, a function call pushes a frame which hides the scopes of
+/// its caller, so only its own scopes and the global scope are visible.
+///
+/// Every evaluation should use its own `Environment`, they don't share
+/// anything with each other.
+///
+/// A step limit can be set to abort runaway loops and recursions, every
+/// loop iteration and function call takes a step, they are counted from
+/// zero for every evaluation of an outermost expression or list. The
+/// other limits of
+/// `Limits` are checked while evaluating the expressions, see `enter`.
+///
+/// The numeric mode chooses how the integer arithmetic handles overflows
+/// and divisions, see `NumericMode`.
+#[derive(Clone, Debug)]
+pub struct Environment {
+    scopes: Vec<Scope>,
+    functions: HashMap<String, Rc<FuncDef>>,
+    natives: HashMap<String, NativeFunc>,
+    // Depth of the running loops in every frame.
+    loops: Vec<usize>,
+
+    limits: Limits,
+    steps: u64,
+    // The nesting of the expressions being evaluated, the clock and the
+    // steps start when the outermost one is entered.
+    nesting: usize,
+    entered: u64,
+    deadline: Option<Instant>,
+    mode: NumericMode,
+}
+
+impl Default for Environment {
+    fn default() -> Self {
+        Environment {
+            scopes: vec![Scope::default()],
+            functions: HashMap::new(),
+            natives: HashMap::new(),
+            loops: vec![0],
+            limits: Limits::default(),
+            steps: 0,
+            nesting: 0,
+            entered: 0,
+            deadline: None,
+            mode: NumericMode::default(),
+        }
+    }
+}
+
+impl Environment {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Drop all the variables and functions defined by the scripts,
+    /// the native functions, the limits and the numeric mode are kept.
+    pub fn reset(&mut self) {
+        let natives = mem::take(&mut self.natives);
+        let limits = self.limits;
+        let mode = self.mode;
+        *self = Self::default();
+        self.natives = natives;
+        self.limits = limits;
+        self.mode = mode;
+    }
+
+    pub fn set_mode(&mut self, mode: NumericMode) {
+        self.mode = mode;
+    }
+
+    pub fn mode(&self) -> NumericMode {
+        self.mode
+    }
+
+    /// Set the steps an evaluation may take, `None` means no limit. Each
+    /// evaluation counts its steps from zero.
+    pub fn set_step_limit(&mut self, limit: Option<u64>) {
+        self.limits.max_steps = limit;
+        self.steps = 0;
+    }
+
+    pub fn step_limit(&self) -> Option<u64> {
+        self.limits.max_steps
+    }
+
+    /// Set all the limits, they apply to each evaluation from its start.
+    pub fn set_limits(&mut self, limits: Limits) {
+        self.limits = limits;
+        self.steps = 0;
+    }
+
+    pub fn limits(&self) -> Limits {
+        self.limits
+    }
+
+    pub fn steps(&self) -> u64 {
+        self.steps
+    }
+
+    // Steps taken by the VM.
+    pub(crate) fn set_steps(&mut self, steps: u64) {
+        self.steps = steps;
+    }
+
+    /// Take a step, fails if the step limit or the time limit is exceeded.
+    pub fn tick(&mut self, span: Span) -> Result<()> {
+        self.steps += 1;
+        match self.limits.max_steps {
+            Some(limit) if self.steps > limit => Err(EvalError::LimitExceeded {
+                limit: Limit::Steps(limit),
+                span,
+            }),
+            _ => self.check_time(span),
+        }
+    }
+
+    // Enter evaluating an expression or a block, fails if the nesting or
+    // the time limit is exceeded. `leave` must be called after it if it
+    // succeeds.
+    pub(crate) fn enter(&mut self, span: Span) -> Result<()> {
+        if self.nesting == 0 {
+            self.deadline = self.limits.timeout.map(|timeout| Instant::now() + timeout);
+            self.steps = 0;
+        }
+        if let Some(limit) = self.limits.max_depth {
+            if self.nesting >= limit {
+                return Err(EvalError::LimitExceeded {
+                    limit: Limit::Depth(limit),
+                    span,
+                });
+            }
+        }
+        // Reading the clock is not free, it's read once in a while.
+        self.entered += 1;
+        if self.entered.is_multiple_of(256) {
+            self.check_time(span)?;
+        }
+        self.nesting += 1;
+        Ok(())
+    }
+
+    pub(crate) fn leave(&mut self) {
+        self.nesting -= 1;
+    }
+
+    // Fails if the value is a string or a list larger than the memory
+    // limit.
+    pub(crate) fn check_size(&self, v: &Value, span: Span) -> Result<()> {
+        let limit = match self.limits.max_memory {
+            Some(limit) => limit,
+            None => return Ok(()),
+        };
+        let size = match v {
+            Value::Str(s) => s.len(),
+            Value::List(list) => list.len() * mem::size_of::<Value>(),
+            _ => 0,
+        };
+        if size > limit {
+            return Err(EvalError::LimitExceeded {
+                limit: Limit::Memory(limit),
+                span,
+            });
+        }
+        Ok(())
+    }
+
+    fn check_time(&self, span: Span) -> Result<()> {
+        match (self.deadline, self.limits.timeout) {
+            (Some(deadline), Some(timeout)) if Instant::now() > deadline => {
+                Err(EvalError::LimitExceeded {
+                    limit: Limit::Time(timeout),
+                    span,
+                })
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// The number of scopes, including the global scope.
+    pub fn depth(&self) -> usize {
+        self.scopes.len()
+    }
+
+    pub fn push_scope(&mut self) {
+        self.scopes.push(Scope::default());
+    }
+
+    /// Push a scope for calling a function.
+    pub fn push_frame(&mut self) {
+        self.scopes.push(Scope {
+            vars: HashMap::new(),
+            is_frame: true,
+        });
+        self.loops.push(0);
+    }
+
+    /// Pop the innermost scope, the global scope will never be popped.
+    pub fn pop_scope(&mut self) {
+        if self.scopes.len() > 1 {
+            if let Some(scope) = self.scopes.pop() {
+                if scope.is_frame {
+                    self.loops.pop();
+                }
+            }
+        }
+    }
+
+    pub fn enter_loop(&mut self) {
+        *self.loops.last_mut().unwrap() += 1;
+    }
+
+    pub fn exit_loop(&mut self) {
+        let depth = self.loops.last_mut().unwrap();
+        *depth = depth.saturating_sub(1);
+    }
+
+    /// Whether a loop is running in the current frame.
+    pub fn in_loop(&self) -> bool {
+        self.loops.last().is_some_and(|depth| *depth > 0)
+    }
+
+    /// Find the variable from the innermost scope to the scope of
+    /// the current frame, then the global scope.
+    pub fn get(&self, name: &str) -> Option<Value> {
+        self.visible_scopes()
+            .find_map(|scope| scope.vars.get(name))
+            .or_else(|| self.scopes[0].vars.get(name))
+            .cloned()
+    }
+
+    /// Update the variable if it's visible in the current frame,
+    /// otherwise define it in the innermost scope.
+    pub fn set(&mut self, name: &str, value: Value) {
+        let pos = self
+            .visible_scopes()
+            .position(|scope| scope.vars.contains_key(name))
+            .map_or(self.scopes.len() - 1, |pos| self.scopes.len() - 1 - pos);
+        self.scopes[pos].vars.insert(name.into(), value);
+    }
+
+    /// Define the variable in the innermost scope, shadowing the outer ones.
+    pub fn define(&mut self, name: &str, value: Value) {
+        self.scopes
+            .last_mut()
+            .unwrap()
+            .vars
+            .insert(name.into(), value);
+    }
+
+    /// All the global variables, sorted by name.
+    pub fn globals(&self) -> Vec<(&str, Value)> {
+        let mut vars: Vec<_> = self.scopes[0]
+            .vars
+            .iter()
+            .map(|(name, v)| (name.as_str(), v.clone()))
+            .collect();
+        vars.sort_by(|a, b| a.0.cmp(b.0));
+        vars
+    }
+
+    pub fn define_function(&mut self, func: Rc<FuncDef>) {
+        self.functions.insert(func.name.clone(), func);
+    }
+
+    pub fn function(&self, name: &str) -> Option<Rc<FuncDef>> {
+        self.functions.get(name).cloned()
+    }
+
+    /// Register a native function, functions defined by the scripts
+    /// with the same name take precedence over it.
+    pub fn define_native(&mut self, name: &str, func: NativeFunc) {
+        self.natives.insert(name.into(), func);
+    }
+
+    pub fn native(&self, name: &str) -> Option<NativeFunc> {
+        self.natives.get(name).cloned()
+    }
+
+    // Scopes from the innermost one to the first frame.
+    fn visible_scopes(&self) -> impl Iterator<Item = &Scope> {
+        let mut reached_frame = false;
+        self.scopes.iter().rev().take_while(move |scope| {
+            let take = !reached_frame;
+            reached_frame = scope.is_frame;
+            take
+        })
+    }
+}
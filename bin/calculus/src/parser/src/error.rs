@@ -0,0 +1,288 @@
+use thiserror::Error;
+
+use crate::calculator::ExprParser;
+use crate::calculator_ast::{Expr, Span};
+use crate::diagnostic::Diagnostic;
+use crate::limits::Limit;
+use crate::pretty::pretty;
+
+/// Errors raised while evaluating, the offending sub-expression
+/// is carried if there is one, and printed as source in the message.
+#[derive(Error, Debug, Clone)]
+pub enum EvalError {
+    #[error("undefined variable `{name}`")]
+    UndefinedVariable { name: String, span: Span },
+
+    #[error("undefined function `{name}`")]
+    UndefinedFunction { name: String, span: Span },
+
+    #[error("function `{name}` takes {expected} arguments but {found} were supplied")]
+    ArityMismatch {
+        name: String,
+        expected: usize,
+        found: usize,
+        span: Span,
+    },
+
+    #[error("type mismatch in `{}`", pretty(.0))]
+    TypeMismatch(Box<Expr>),
+
+    #[error("division by zero in `{}`", pretty(.0))]
+    DivisionByZero(Box<Expr>),
+
+    #[error("integer overflow in `{}`", pretty(.0))]
+    Overflow(Box<Expr>),
+
+    #[error("index out of range in `{}`", pretty(.0))]
+    OutOfRange(Box<Expr>),
+
+    #[error("dimension mismatch in `{}`", pretty(.0))]
+    DimensionMismatch(Box<Expr>),
+
+    /// Raised by the native functions of the host.
+    #[error("{message}")]
+    Host { message: String, span: Span },
+
+    #[error("`{keyword}` outside of a loop")]
+    OutsideLoop { keyword: &'static str, span: Span },
+
+    #[error("the second argument of `{name}` must be a variable")]
+    ExpectedVariable { name: &'static str, span: Span },
+
+    #[error("`{name}` does not converge")]
+    NoConvergence { name: &'static str, span: Span },
+
+    #[error("evaluation exceeds the limit of {limit}")]
+    LimitExceeded { limit: Limit, span: Span },
+
+    // `break` and `continue` unwind to the innermost loop as errors, they
+    // are only raised inside loops so the caller never sees them.
+    #[error("`break` outside of a loop")]
+    Break(Span),
+
+    #[error("`continue` outside of a loop")]
+    Continue(Span),
+}
+
+impl EvalError {
+    /// Where the error is raised in the source.
+    pub fn span(&self) -> Span {
+        match self {
+            EvalError::UndefinedVariable { span, .. }
+            | EvalError::UndefinedFunction { span, .. }
+            | EvalError::ArityMismatch { span, .. }
+            | EvalError::Host { span, .. }
+            | EvalError::OutsideLoop { span, .. }
+            | EvalError::ExpectedVariable { span, .. }
+            | EvalError::NoConvergence { span, .. }
+            | EvalError::LimitExceeded { span, .. }
+            | EvalError::Break(span)
+            | EvalError::Continue(span) => *span,
+            EvalError::TypeMismatch(expr)
+            | EvalError::DivisionByZero(expr)
+            | EvalError::Overflow(expr)
+            | EvalError::OutOfRange(expr)
+            | EvalError::DimensionMismatch(expr) => expr.span,
+        }
+    }
+}
+
+impl EvalError {
+    /// The message with the expression as it's written in `source`, the
+    /// source the list was parsed from. `to_string` prints the expression
+    /// from the AST, like `1.0 m` for `1 m`.
+    pub fn message(&self, source: &str) -> String {
+        let (what, expr) = match self {
+            EvalError::TypeMismatch(expr) => ("type mismatch", expr),
+            EvalError::DivisionByZero(expr) => ("division by zero", expr),
+            EvalError::Overflow(expr) => ("integer overflow", expr),
+            EvalError::OutOfRange(expr) => ("index out of range", expr),
+            EvalError::DimensionMismatch(expr) => ("dimension mismatch", expr),
+            _ => return self.to_string(),
+        };
+        // The span may be in another source, like the body of a function
+        // defined by an earlier line of the REPL.
+        let text = source
+            .get(expr.span.start..expr.span.end)
+            .filter(|text| match ExprParser::new().parse(text) {
+                Ok(parsed) => pretty(&parsed) == pretty(expr),
+                Err(_) => false,
+            });
+        match text {
+            Some(text) => {
+                let text = text.split_whitespace().collect::<Vec<_>>().join(" ");
+                format!("{} in `{}`", what, text)
+            }
+            None => self.to_string(),
+        }
+    }
+
+    /// An error raised by the host, the span is filled by the evaluator.
+    pub fn host(message: impl Into<String>) -> Self {
+        EvalError::Host {
+            message: message.into(),
+            span: Span::default(),
+        }
+    }
+
+    // Set the span of a host error if it's not set.
+    pub(crate) fn or_span(self, at: Span) -> Self {
+        match self {
+            EvalError::Host { message, span } if span == Span::default() => {
+                EvalError::Host { message, span: at }
+            }
+            e => e,
+        }
+    }
+}
+
+pub type Result<T> = std::result::Result<T, EvalError>;
+
+/// Errors of parsing and evaluating a source.
+#[derive(Error, Debug, Clone)]
+pub enum Error {
+    #[error("{}", .0.message)]
+    Parse(Diagnostic),
+
+    #[error(transparent)]
+    Eval(#[from] EvalError),
+}
+
+impl Error {
+    pub fn diagnostic(&self) -> Diagnostic {
+        match self {
+            Error::Parse(diagnostic) => diagnostic.clone(),
+            Error::Eval(e) => Diagnostic::from(e),
+        }
+    }
+
+    /// Render the error with the source, see `Diagnostic::render`.
+    pub fn render(&self, source: &str) -> String {
+        let diagnostic = match self {
+            Error::Parse(diagnostic) => diagnostic.clone(),
+            Error::Eval(e) => Diagnostic::from_eval_error(e, source),
+        };
+        diagnostic.render(source)
+    }
+}
+
+/// Errors of the arithmetic on `Value`, they don't know which
+/// expression they come from.
+#[derive(Error, Debug, Clone, Copy, PartialEq)]
+pub enum ArithError {
+    #[error("type mismatch")]
+    TypeMismatch,
+
+    #[error("division by zero")]
+    DivisionByZero,
+
+    #[error("integer overflow")]
+    Overflow,
+
+    #[error("index out of range")]
+    OutOfRange,
+
+    #[error("dimension mismatch")]
+    DimensionMismatch,
+}
+
+impl ArithError {
+    /// Attach the expression raising the error.
+    pub fn at(self, expr: &Expr) -> EvalError {
+        let expr = Box::new(expr.clone());
+        match self {
+            ArithError::TypeMismatch => EvalError::TypeMismatch(expr),
+            ArithError::DivisionByZero => EvalError::DivisionByZero(expr),
+            ArithError::Overflow => EvalError::Overflow(expr),
+            ArithError::OutOfRange => EvalError::OutOfRange(expr),
+            ArithError::DimensionMismatch => EvalError::DimensionMismatch(expr),
+        }
+    }
+}
+
+/// Errors raised by the actions of the grammar.
+#[derive(Error, Debug, Clone, PartialEq)]
+pub enum SyntaxError {
+    #[error("unknown unit `{name}`")]
+    UnknownUnit { name: String, span: Span },
+}
+
+impl SyntaxError {
+    pub fn span(&self) -> Span {
+        match self {
+            SyntaxError::UnknownUnit { span, .. } => *span,
+        }
+    }
+}
+
+impl From<&SyntaxError> for Diagnostic {
+    fn from(err: &SyntaxError) -> Self {
+        Diagnostic::new(err.to_string(), err.span())
+    }
+}
+
+/// Errors of the symbolic differentiation.
+#[derive(Error, Debug, Clone, PartialEq)]
+pub enum DeriveError {
+    #[error("cannot differentiate {what}")]
+    Unsupported { what: String, span: Span },
+}
+
+impl DeriveError {
+    pub fn span(&self) -> Span {
+        match self {
+            DeriveError::Unsupported { span, .. } => *span,
+        }
+    }
+}
+
+impl From<&DeriveError> for Diagnostic {
+    fn from(err: &DeriveError) -> Self {
+        Diagnostic::new(err.to_string(), err.span())
+    }
+}
+
+/// Errors of renaming a variable in the source.
+#[derive(Error, Debug, Clone, PartialEq)]
+pub enum RenameError {
+    #[error("no variable at offset {0}")]
+    NoVariable(usize),
+
+    #[error("`{0}` is not a valid variable name")]
+    InvalidName(String),
+}
+
+/// Errors of decoding a serialized AST, see `wire`.
+#[derive(Error, Debug, Clone, PartialEq)]
+pub enum DecodeError {
+    #[error("not a serialized AST")]
+    NotAnAst,
+
+    #[error("unsupported version {found}, expected {expected}")]
+    Version { found: u32, expected: u32 },
+
+    #[error("malformed data: {0}")]
+    Malformed(String),
+
+    /// The data is well-formed, but it's not a tree the parser makes.
+    #[error("invalid entry {index}: {reason}")]
+    Invalid { index: usize, reason: String },
+}
+
+/// Errors of evaluating a batch of rows, see `batch`.
+#[derive(Error, Debug, Clone)]
+pub enum BatchError {
+    #[error("cannot evaluate {what} in a batch")]
+    Unsupported { what: String, span: Span },
+
+    #[error("the column `{name}` has {found} rows but the batch has {expected}")]
+    Length {
+        name: String,
+        found: usize,
+        expected: usize,
+    },
+
+    /// The error of evaluating the row alone.
+    #[error("{error} at row {row}")]
+    Row { row: usize, error: EvalError },
+}
@@ -0,0 +1,164 @@
+use crate::calculator::ListParser;
+use crate::calculator_ast::{ExprList, NumericMode, Value};
+use crate::diagnostic::Diagnostic;
+use crate::environment::{Environment, NativeFunc};
+use crate::error::{self, Error, EvalError};
+use crate::limits::Limits;
+use crate::typeck::{self, Types};
+use crate::vm::Program;
+
+/// Embedding API of the calculator.
+///
+/// The host can bind variables and register native functions before
+/// evaluating the sources, and read the variables back after it. All the
+/// sources evaluated by an `Interpreter` share the same environment.
+///
+/// ```
+/// use calculus_parser::error::EvalError;
+/// use calculus_parser::interpreter::Interpreter;
+///
+/// let mut interp = Interpreter::new();
+/// interp
+///     .set_var("price", 100)
+///     .register_fn("discount", |args| match args {
+///         [rate] => Ok((1.0 - rate.as_f64()).into()),
+///         _ => Err(EvalError::host("discount takes one argument")),
+///     });
+///
+/// interp.eval("total = price * discount(0.2)").unwrap();
+/// assert_eq!(Some(80.0.into()), interp.get_var("total"));
+/// ```
+///
+/// The sources which are not trusted are evaluated with limits, they fail
+/// with `EvalError::LimitExceeded` instead of running away:
+///
+/// ```
+/// use calculus_parser::error::{Error, EvalError};
+/// use calculus_parser::interpreter::Interpreter;
+/// use calculus_parser::limits::Limits;
+///
+/// let mut interp = Interpreter::new();
+/// interp.set_limits(Limits::sandbox());
+/// let nested = format!("{}1{}", "-(".repeat(5000), ")".repeat(5000));
+/// assert!(matches!(
+///     interp.eval(&nested),
+///     Err(Error::Eval(EvalError::LimitExceeded { .. }))
+/// ));
+/// ```
+pub struct Interpreter {
+    parser: ListParser,
+    env: Environment,
+}
+
+impl Default for Interpreter {
+    fn default() -> Self {
+        Interpreter {
+            parser: ListParser::new(),
+            env: Environment::new(),
+        }
+    }
+}
+
+impl Interpreter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Bind a global variable.
+    pub fn set_var(&mut self, name: &str, value: impl Into<Value>) -> &mut Self {
+        self.env.set(name, value.into());
+        self
+    }
+
+    /// Read a global variable.
+    pub fn get_var(&self, name: &str) -> Option<Value> {
+        self.env.get(name)
+    }
+
+    /// All the global variables, sorted by name.
+    pub fn vars(&self) -> Vec<(&str, Value)> {
+        self.env.globals()
+    }
+
+    /// Choose how the integer arithmetic handles overflows and divisions.
+    pub fn set_mode(&mut self, mode: NumericMode) -> &mut Self {
+        self.env.set_mode(mode);
+        self
+    }
+
+    /// Limit the resources taken by the sources, see `Limits`. The source
+    /// is checked before it's parsed, and the evaluation fails as soon as
+    /// a limit is exceeded.
+    pub fn set_limits(&mut self, limits: Limits) -> &mut Self {
+        self.env.set_limits(limits);
+        self
+    }
+
+    /// Register a native function which can be called by the sources.
+    pub fn register_fn<F>(&mut self, name: &str, func: F) -> &mut Self
+    where
+        F: Fn(&[Value]) -> error::Result<Value> + 'static,
+    {
+        self.env.define_native(name, NativeFunc::new(func));
+        self
+    }
+
+    /// Parse the source, it fails if the limits of the source are
+    /// exceeded.
+    pub fn parse(&self, source: &str) -> Result<ExprList, Error> {
+        let limits = self.env.limits();
+        limits.check_source(source)?;
+        let list = self
+            .parser
+            .parse(source)
+            .map_err(|e| Error::Parse(Diagnostic::from_parse_error(&e)))?;
+        limits.check_ast(&list)?;
+        Ok(list)
+    }
+
+    /// Parse and evaluate the source, returns the value of the last statement.
+    pub fn eval(&mut self, source: &str) -> Result<Value, Error> {
+        let list = self.parse(source)?;
+        Ok(self.eval_list(&list)?)
+    }
+
+    /// Evaluate a parsed list, it can be evaluated for many times.
+    pub fn eval_list(&mut self, list: &ExprList) -> error::Result<Value> {
+        list.eval(&mut self.env)
+    }
+
+    /// Check the types of a parsed list with the current variables and
+    /// functions, returns all the errors found, see `typeck`.
+    pub fn check<'a>(&self, list: &'a ExprList) -> Result<Types<'a>, Vec<EvalError>> {
+        typeck::check(list, &self.env)
+    }
+
+    /// Check the source before evaluating it, nothing is evaluated if it
+    /// has type errors, the first one is returned. The checked list skips
+    /// the runtime checks where it can.
+    ///
+    /// The VM running the checked list only takes the step limit, the
+    /// list is evaluated as `eval` does if the other limits are set.
+    pub fn eval_checked(&mut self, source: &str) -> Result<Value, Error> {
+        let list = self.parse(source)?;
+        let types = self.check(&list).map_err(|mut errors| errors.remove(0))?;
+        let limits = self.env.limits();
+        let steps_only = Limits {
+            max_steps: limits.max_steps,
+            ..Limits::default()
+        };
+        if limits != steps_only {
+            return Ok(list.eval(&mut self.env)?);
+        }
+        let program = Program::compile_checked(&list, &self.env, &types);
+        Ok(program.eval(&mut self.env)?)
+    }
+
+    pub fn env(&self) -> &Environment {
+        &self.env
+    }
+
+    pub fn env_mut(&mut self) -> &mut Environment {
+        &mut self.env
+    }
+}
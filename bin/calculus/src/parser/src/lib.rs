@@ -1,17 +1,41 @@
-#[macro_use]
-extern crate lalrpop_util;
+// The tests spell the expected values like the sources they parse, like
+// `22 + 22 * (22 - 22) / 2`.
+#![cfg_attr(
+    test,
+    allow(clippy::bool_assert_comparison, clippy::erasing_op, clippy::identity_op)
+)]
 
 #[macro_use]
-extern crate lazy_static;
+extern crate lalrpop_util;
 
-lalrpop_mod!(pub calculator); // synthesized by LALRPOP
+lalrpop_mod!(#[allow(clippy::all)] pub calculator); // synthesized by LALRPOP
 
+pub mod batch;
+pub mod builtin;
 pub mod calculator_ast;
+pub mod cst;
+pub mod derivative;
+pub mod diagnostic;
+pub mod environment;
+pub mod error;
+pub mod interpreter;
+pub mod limits;
+pub mod list;
+pub mod numeric;
+pub mod optimizer;
+pub mod pretty;
+pub mod typeck;
+pub mod units;
+pub mod vm;
+pub mod wire;
+
+#[cfg(test)]
+use environment::Environment;
 
 #[test]
 fn expr_calculator() {
-    assert!(calculator::NumParser::new().parse("22").is_ok());
-    assert!(calculator::FactorParser::new().parse("22").is_ok());
+    assert!(calculator::UnaryExprParser::new().parse("22").is_ok());
+    assert!(calculator::MulExprParser::new().parse("22").is_ok());
     assert!(calculator::ExprParser::new().parse("22").is_ok());
 
     assert_eq!(
@@ -19,7 +43,8 @@ fn expr_calculator() {
         calculator::ExprParser::new()
             .parse("22")
             .unwrap()
-            .eval()
+            .eval(&mut Environment::new())
+            .unwrap()
             .as_i64()
     );
     assert_eq!(
@@ -27,7 +52,8 @@ fn expr_calculator() {
         calculator::ExprParser::new()
             .parse("22 + 22 * 22")
             .unwrap()
-            .eval()
+            .eval(&mut Environment::new())
+            .unwrap()
             .as_i64()
     );
     assert_eq!(
@@ -35,7 +61,8 @@ fn expr_calculator() {
         calculator::ExprParser::new()
             .parse("22 + 22 * 22 - 22 / 2")
             .unwrap()
-            .eval()
+            .eval(&mut Environment::new())
+            .unwrap()
             .as_i64()
     );
 
@@ -44,7 +71,8 @@ fn expr_calculator() {
         calculator::ExprParser::new()
             .parse("22 + 22 * (22 - 22) / 2")
             .unwrap()
-            .eval()
+            .eval(&mut Environment::new())
+            .unwrap()
             .as_i64()
     );
     assert_eq!(
@@ -52,7 +80,8 @@ fn expr_calculator() {
         calculator::ExprParser::new()
             .parse("22 + 22 * ((((((22 - 22)))))) / 2")
             .unwrap()
-            .eval()
+            .eval(&mut Environment::new())
+            .unwrap()
             .as_i64()
     );
 
@@ -61,7 +90,8 @@ fn expr_calculator() {
         calculator::ExprParser::new()
             .parse("-22 + 22 * ((((((22 - 22)))))) / 2")
             .unwrap()
-            .eval()
+            .eval(&mut Environment::new())
+            .unwrap()
             .as_i64()
     );
 
@@ -70,7 +100,8 @@ fn expr_calculator() {
         calculator::ExprParser::new()
             .parse("a = -22")
             .unwrap()
-            .eval()
+            .eval(&mut Environment::new())
+            .unwrap()
             .as_i64()
     );
 
@@ -79,7 +110,8 @@ fn expr_calculator() {
         calculator::ExprParser::new()
             .parse("a = 1 -22 * 33")
             .unwrap()
-            .eval()
+            .eval(&mut Environment::new())
+            .unwrap()
             .as_i64()
     );
 
@@ -88,11 +120,127 @@ fn expr_calculator() {
         calculator::ExprParser::new()
             .parse("-100 -200 -85 * 5 == 1 -22 * 33")
             .unwrap()
-            .eval()
+            .eval(&mut Environment::new())
+            .unwrap()
             .as_bool()
     );
 }
 
+#[test]
+fn precedence_test() {
+    use calculator_ast::Value;
+
+    let parse = |src: &str| calculator::ExprParser::new().parse(src).unwrap();
+
+    // The binary operators by their levels from the loosest one, and
+    // whether the level is right associative.
+    let levels: [(&[&str], bool); 7] = [
+        (&["||"], false),
+        (&["&&"], false),
+        (&["==", "!="], false),
+        (&["<", "<=", ">", ">="], false),
+        (&["+", "-"], false),
+        (&["*", "/", "%"], false),
+        (&["^", "**"], true),
+    ];
+    let power = levels.len() - 1;
+    let ops: Vec<_> = levels
+        .iter()
+        .enumerate()
+        .flat_map(|(level, &(ops, right))| ops.iter().map(move |op| (*op, level, right)))
+        .collect();
+
+    for &(op1, level1, right) in &ops {
+        // Every pair of the binary operators.
+        for &(op2, level2, _) in &ops {
+            let src = format!("a {} b {} c", op1, op2);
+            let grouped = if level1 > level2 || (level1 == level2 && !right) {
+                format!("(a {} b) {} c", op1, op2)
+            } else {
+                format!("a {} (b {} c)", op1, op2)
+            };
+            assert_eq!(parse(&grouped), parse(&src), "{}", src);
+        }
+
+        // The unary operators are looser than the power only.
+        for unary in ["-", "+", "!"] {
+            let src = format!("{}a {} b", unary, op1);
+            let grouped = if level1 == power {
+                format!("{}(a {} b)", unary, op1)
+            } else {
+                format!("({}a) {} b", unary, op1)
+            };
+            assert_eq!(parse(&grouped), parse(&src), "{}", src);
+
+            let src = format!("a {} {}b", op1, unary);
+            let grouped = format!("a {} ({}b)", op1, unary);
+            assert_eq!(parse(&grouped), parse(&src), "{}", src);
+        }
+
+        // The assignment and the conversion are looser, the indexing is
+        // tighter.
+        let src = format!("x = a {} b", op1);
+        assert_eq!(parse(&format!("x = (a {} b)", op1)), parse(&src), "{}", src);
+        let src = format!("x += a {} b", op1);
+        assert_eq!(
+            parse(&format!("x += (a {} b)", op1)),
+            parse(&src),
+            "{}",
+            src
+        );
+        let src = format!("a {} b in km", op1);
+        assert_eq!(
+            parse(&format!("(a {} b) in km", op1)),
+            parse(&src),
+            "{}",
+            src
+        );
+        let src = format!("a[0] {} b[1]", op1);
+        assert_eq!(
+            parse(&format!("(a[0]) {} (b[1])", op1)),
+            parse(&src),
+            "{}",
+            src
+        );
+    }
+
+    let cases = [
+        ("x = y = 2 ^ 3 ^ 2", Value::I64(512)),
+        ("2 ** 3 ** 2", Value::I64(512)),
+        ("(2 ^ 3) ^ 2", Value::I64(64)),
+        ("-2 ^ 2", Value::I64(-4)),
+        ("(-2) ^ 2", Value::I64(4)),
+        ("2 ^ -1", Value::F64(0.5)),
+        ("1 + 2 * 3 ^ 2", Value::I64(19)),
+        ("2 * -3", Value::I64(-6)),
+        ("a = 2; b = 3; c = 4; a * (b) * c", Value::I64(24)),
+        ("a = 2; a * -a ^ 2", Value::I64(-8)),
+        ("+3 - +2", Value::I64(1)),
+        ("- -3", Value::I64(3)),
+        ("7 % 3", Value::I64(1)),
+        ("-7 % 3", Value::I64(-1)),
+        ("7 % -3", Value::I64(1)),
+        ("7.5 % 2", Value::F64(1.5)),
+        ("10 - 7 % 4 * 2", Value::I64(4)),
+        ("1 + 1 == 2", Value::Bool(true)),
+        ("1 < 2 == 2 < 3", Value::Bool(true)),
+        ("!(1 > 2) && 4 % 2 == 0", Value::Bool(true)),
+        ("!true || true", Value::Bool(true)),
+        ("[1, 2, 3][1] ^ 2", Value::I64(4)),
+    ];
+    for (src, value) in cases {
+        let result = calculator::ListParser::new()
+            .parse(src)
+            .unwrap()
+            .eval(&mut Environment::new());
+        assert_eq!(value, result.unwrap(), "{}", src);
+    }
+
+    // `km^2` is a unit, `(3 km) ^ 2` is a power.
+    assert!(calculator::ExprParser::new().parse("3 km ^ x").is_err());
+    assert_eq!(parse("pow(3 km, 2)"), parse("(3 km) ^ 2"));
+}
+
 #[test]
 fn stmt_test() {
     assert_eq!(
@@ -100,7 +248,8 @@ fn stmt_test() {
         calculator::ListParser::new()
             .parse("a = 2 ; if ( a == 2 )  then { 3 }")
             .unwrap()
-            .eval()
+            .eval(&mut Environment::new())
+            .unwrap()
             .as_i64()
     );
 
@@ -109,7 +258,8 @@ fn stmt_test() {
         calculator::ListParser::new()
             .parse("a = 2 ; if ( a == 2 )  then { 3 } else { 5 }")
             .unwrap()
-            .eval()
+            .eval(&mut Environment::new())
+            .unwrap()
             .as_i64()
     );
 
@@ -118,5 +268,1683 @@ fn stmt_test() {
         .unwrap();
     println!("Debugging: Expr is :{:?}", expr);
 
-    assert_eq!(5, expr.eval().as_i64());
+    assert_eq!(5, expr.eval(&mut Environment::new()).unwrap().as_i64());
+
+    // A trailing `;` is allowed, the lists and the blocks may be empty.
+    let eval = |src: &str| {
+        calculator::ListParser::new()
+            .parse(src)
+            .map(|list| list.eval(&mut Environment::new()).unwrap().as_i64())
+            .ok()
+    };
+    assert_eq!(Some(2), eval("a = 1; a + 1;\n"));
+    assert_eq!(Some(0), eval(""));
+    assert_eq!(Some(0), eval("if 1 then {} else { 2; }"));
+    assert!(eval("a = 1;;").is_none());
+    assert!(eval(";").is_none());
+}
+
+#[test]
+fn func_test() {
+    assert_eq!(
+        120,
+        calculator::ListParser::new()
+            .parse("fn fact(n) { if (n <= 1) then { 1 } else { n * fact(n - 1) } }; fact(5)")
+            .unwrap()
+            .eval(&mut Environment::new())
+            .unwrap()
+            .as_i64()
+    );
+
+    assert_eq!(
+        55,
+        calculator::ListParser::new()
+            .parse("fn fib(n) { if (n < 2) then { n } else { fib(n - 1) + fib(n - 2) } }; fib(10)")
+            .unwrap()
+            .eval(&mut Environment::new())
+            .unwrap()
+            .as_i64()
+    );
+
+    // parameters and assignments are local to the call.
+    assert_eq!(
+        1,
+        calculator::ListParser::new()
+            .parse("a = 1; b = 2; fn f(a) { b = a * 10; b }; f(5); a")
+            .unwrap()
+            .eval(&mut Environment::new())
+            .unwrap()
+            .as_i64()
+    );
+    assert_eq!(
+        54,
+        calculator::ListParser::new()
+            .parse("a = 1; b = 2; fn f(a) { b = a * 10; b + c }; c = 2; f(5) + b")
+            .unwrap()
+            .eval(&mut Environment::new())
+            .unwrap()
+            .as_i64()
+    );
+}
+
+#[test]
+fn environment_test() {
+    let list = calculator::ListParser::new().parse("a = a + 1; a").unwrap();
+
+    // environments are independent with each other.
+    let mut env1 = Environment::new();
+    let mut env2 = Environment::new();
+    env1.set("a", calculator_ast::Value::from_i64(10));
+    env2.set("a", calculator_ast::Value::from_i64(20));
+    assert_eq!(11, list.eval(&mut env1).unwrap().as_i64());
+    assert_eq!(12, list.eval(&mut env1).unwrap().as_i64());
+    assert_eq!(21, list.eval(&mut env2).unwrap().as_i64());
+
+    env1.reset();
+    assert!(env1.get("a").is_none());
+    assert_eq!(Some(calculator_ast::Value::from_i64(21)), env2.get("a"));
+
+    // nested scopes shadow the outer ones, and frames hide the caller's scopes.
+    let mut env = Environment::new();
+    env.set("a", calculator_ast::Value::from_i64(1));
+    env.push_scope();
+    env.define("a", calculator_ast::Value::from_i64(2));
+    env.set("b", calculator_ast::Value::from_i64(3));
+    assert_eq!(Some(calculator_ast::Value::from_i64(2)), env.get("a"));
+    env.push_frame();
+    assert_eq!(Some(calculator_ast::Value::from_i64(1)), env.get("a"));
+    assert!(env.get("b").is_none());
+    env.pop_scope();
+    env.pop_scope();
+    assert_eq!(Some(calculator_ast::Value::from_i64(1)), env.get("a"));
+    assert!(env.get("b").is_none());
+    assert_eq!(1, env.depth());
+}
+
+#[test]
+fn concurrent_eval_test() {
+    let handles: Vec<_> = (0..8)
+        .map(|i| {
+            std::thread::spawn(move || {
+                let mut env = Environment::new();
+                let src = format!("a = {}; fn f(x) {{ x * a }}; f(a)", i);
+                let list = calculator::ListParser::new().parse(&src).unwrap();
+                list.eval(&mut env).unwrap().as_i64()
+            })
+        })
+        .collect();
+
+    for (i, handle) in handles.into_iter().enumerate() {
+        let i = i as i64;
+        assert_eq!(i * i, handle.join().unwrap());
+    }
+}
+
+#[test]
+fn eval_error_test() {
+    use error::EvalError;
+    use interpreter::Interpreter;
+
+    let eval = |src: &str| {
+        calculator::ListParser::new()
+            .parse(src)
+            .unwrap()
+            .eval(&mut Environment::new())
+    };
+
+    assert!(matches!(eval("a + 1"), Err(EvalError::UndefinedVariable { name, .. }) if name == "a"));
+    assert!(matches!(eval("f(1)"), Err(EvalError::UndefinedFunction { name, .. }) if name == "f"));
+    assert!(matches!(
+        eval("fn f(a) { a }; f(1, 2)"),
+        Err(EvalError::ArityMismatch {
+            expected: 1,
+            found: 2,
+            ..
+        })
+    ));
+
+    let bad = calculator::ExprParser::new().parse("(1 == 1) + 2").unwrap();
+    match eval("(1 == 1) + 2") {
+        Err(EvalError::TypeMismatch(expr)) => assert_eq!(bad, *expr),
+        other => panic!("unexpected {:?}", other),
+    }
+    assert_eq!(
+        "type mismatch in `(1 == 1) + 2`",
+        eval("(1 == 1) + 2").unwrap_err().to_string()
+    );
+    assert!(matches!(eval("-(1 == 1)"), Err(EvalError::TypeMismatch(_))));
+    assert!(matches!(
+        eval("(1 == 1) < 2"),
+        Err(EvalError::TypeMismatch(_))
+    ));
+
+    // the error points to the innermost failing expression.
+    let bad = calculator::ExprParser::new().parse("1 / (a - 1)").unwrap();
+    match eval("a = 1; 2 + 1 / (a - 1)") {
+        Err(EvalError::DivisionByZero(expr)) => assert_eq!(bad, *expr),
+        other => panic!("unexpected {:?}", other),
+    }
+    assert_eq!(
+        "division by zero in `1 / (a - 1)`",
+        eval("a = 1; 2 + 1 / (a - 1)").unwrap_err().to_string()
+    );
+    assert!(matches!(
+        eval("4611686018427387904 * 2"),
+        Err(EvalError::Overflow(_))
+    ));
+    // `+x` doesn't negate, the smallest integer is kept.
+    assert!(matches!(
+        eval("x = -9223372036854775807 - 1; +x"),
+        Ok(calculator_ast::Value::I64(i64::MIN))
+    ));
+    assert!(matches!(eval("+true"), Err(EvalError::TypeMismatch(_))));
+    // The message quotes the source, `to_string` prints the expression.
+    let src = "x = 1 m +\n    1 s";
+    let err = eval(src).unwrap_err();
+    assert_eq!("dimension mismatch in `1 m + 1 s`", err.message(src));
+    assert_eq!("dimension mismatch in `1.0 m + 1.0 s`", err.to_string());
+    assert_eq!(
+        "dimension mismatch in `1.0 m + 1.0 s`",
+        err.message("y = 2 m + 2 s")
+    );
+    assert_eq!(
+        "error: dimension mismatch in `1 m + 1 s`",
+        Interpreter::new()
+            .eval(src)
+            .unwrap_err()
+            .render(src)
+            .lines()
+            .next()
+            .unwrap()
+    );
+    assert_eq!(
+        "index out of range in `[1, 2][2]`",
+        eval("[1, 2][2]").unwrap_err().to_string()
+    );
+
+    // floats keep the IEEE semantics.
+    assert!(eval("1.0 / 0").unwrap().as_f64().is_infinite());
+    assert!(eval("(1 == 1) == (2 == 2)").unwrap().as_bool());
+
+    // the frame is popped when the call fails.
+    let mut env = Environment::new();
+    let list = calculator::ListParser::new()
+        .parse("fn f(a) { a / 0 }; f(1)")
+        .unwrap();
+    assert!(list.eval(&mut env).is_err());
+    assert_eq!(1, env.depth());
+}
+
+#[test]
+fn diagnostic_test() {
+    use diagnostic::Diagnostic;
+
+    let src = "a = 1;\nb = ) + 2";
+    let err = calculator::ListParser::new().parse(src).unwrap_err();
+    assert_eq!(
+        "error: unexpected token `)`, expected one of \"!\", \"(\", \"+\", \"-\", \"[\", \
+         string, number, \"false\", \"fn\", \"null\", \"true\", identifier\n --> 2:5\n  |\n2 | b = ) + 2\n  |     ^",
+        Diagnostic::from_parse_error(&err).render(src)
+    );
+
+    let src = "if (a) then { 1 } else";
+    let err = calculator::ListParser::new().parse(src).unwrap_err();
+    assert_eq!(
+        "error: unexpected end of input, expected \"{\"\n \
+         --> 1:23\n  |\n1 | if (a) then { 1 } else\n  |                       ^",
+        Diagnostic::from_parse_error(&err).render(src)
+    );
+
+    // every node knows where it is.
+    let src = "a = 2;\nc = a * (b + 1)";
+    let err = calculator::ListParser::new()
+        .parse(src)
+        .unwrap()
+        .eval(&mut Environment::new())
+        .unwrap_err();
+    assert_eq!(calculator_ast::Span::new(16, 17), err.span());
+    assert_eq!(
+        "error: undefined variable `b`\n --> 2:10\n  |\n2 | c = a * (b + 1)\n  |          ^",
+        Diagnostic::from_eval_error(&err, src).render(src)
+    );
+
+    let src = "x = (1 == 1) + 2";
+    let err = calculator::ListParser::new()
+        .parse(src)
+        .unwrap()
+        .eval(&mut Environment::new())
+        .unwrap_err();
+    assert!(Diagnostic::from_eval_error(&err, src)
+        .render(src)
+        .ends_with("1 | x = (1 == 1) + 2\n  |     ^^^^^^^^^^^^"));
+}
+
+#[test]
+fn loop_test() {
+    use error::EvalError;
+
+    let eval = |src: &str| {
+        calculator::ListParser::new()
+            .parse(src)
+            .unwrap()
+            .eval(&mut Environment::new())
+    };
+
+    assert_eq!(
+        55,
+        eval("i = 0; s = 0; while (i < 10) { i += 1; s += i }; s")
+            .unwrap()
+            .as_i64()
+    );
+    assert_eq!(
+        3628800,
+        eval("p = 1; for (i = 1; i <= 10; i += 1) { p *= i }; p")
+            .unwrap()
+            .as_i64()
+    );
+    assert_eq!(2.5, eval("a = 10; a /= 4.0; a -= 0; a").unwrap().as_f64());
+
+    // sum of the odd numbers below 10.
+    assert_eq!(
+        25,
+        eval(
+            "s = 0; for (i = 0; ; i += 1) { \
+                if (i >= 10) then { break }; \
+                if (i - i / 2 * 2 == 0) then { continue }; \
+                s += i \
+            }; s"
+        )
+        .unwrap()
+        .as_i64()
+    );
+
+    // break only leaves the innermost loop.
+    assert_eq!(
+        30,
+        eval(
+            "n = 0; for (i = 0; i < 10; i += 1) { \
+                j = 0; while (1 == 1) { if (j == 3) then { break }; j += 1; n += 1 } \
+            }; n"
+        )
+        .unwrap()
+        .as_i64()
+    );
+
+    assert!(matches!(
+        eval("break"),
+        Err(EvalError::OutsideLoop {
+            keyword: "break",
+            ..
+        })
+    ));
+    assert!(matches!(
+        eval("fn f() { continue }; while (1 == 1) { f() }"),
+        Err(EvalError::OutsideLoop {
+            keyword: "continue",
+            ..
+        })
+    ));
+
+    let mut env = Environment::new();
+    env.set_step_limit(Some(1000));
+    let runaway = calculator::ListParser::new()
+        .parse("i = 0; while (1 == 1) { i += 1 }")
+        .unwrap();
+    assert!(matches!(
+        runaway.eval(&mut env),
+        Err(EvalError::LimitExceeded {
+            limit: limits::Limit::Steps(1000),
+            ..
+        })
+    ));
+    assert_eq!(Some(calculator_ast::Value::from_i64(1000)), env.get("i"));
+    assert!(!env.in_loop());
+
+    env.set_step_limit(Some(100));
+    let recursion = calculator::ListParser::new()
+        .parse("fn f(n) { f(n + 1) }; f(0)")
+        .unwrap();
+    assert!(matches!(
+        recursion.eval(&mut env),
+        Err(EvalError::LimitExceeded { .. })
+    ));
+    assert_eq!(1, env.depth());
+}
+
+#[test]
+fn limits_test() {
+    use calculator_ast::Value;
+    use error::{Error, EvalError};
+    use interpreter::Interpreter;
+    use limits::{Limit, Limits};
+    use std::time::Duration;
+
+    let limit = |limits: Limits, src: &str| {
+        let mut interp = Interpreter::new();
+        interp.set_limits(limits);
+        match interp.eval(src) {
+            Err(Error::Eval(EvalError::LimitExceeded { limit, .. })) => Some(limit),
+            _ => None,
+        }
+    };
+    let sandbox = Limits::sandbox();
+
+    // The parentheses don't nest the expressions.
+    let mut interp = Interpreter::new();
+    interp.set_limits(sandbox);
+    let src = format!("{}1{}", "(".repeat(4000), ")".repeat(4000));
+    assert_eq!(Value::I64(1), interp.eval(&src).unwrap());
+    let src = "xs = map([1, 2, 3], fn(x) { x ^ 2 }); sum(xs) + xs[2] % 5";
+    assert_eq!(Value::I64(18), interp.eval(src).unwrap());
+
+    // Checked before parsing.
+    let depth = Some(Limit::Depth(200));
+    let nested = |open: &str, close: &str| format!("{}1{}", open.repeat(5000), close.repeat(5000));
+    assert_eq!(depth, limit(sandbox, &nested("-(", ")")));
+    assert_eq!(depth, limit(sandbox, &nested("[", "]")));
+    assert_eq!(depth, limit(sandbox, &nested("f(", ")")));
+    assert_eq!(depth, limit(sandbox, &"-".repeat(5000)));
+    assert_eq!(depth, limit(sandbox, &format!("1{}", " + 1".repeat(300))));
+    assert_eq!(depth, limit(sandbox, &format!("a{}", "[0]".repeat(300))));
+    let src = format!("x = 0{}", "; x += 1".repeat(3000));
+    assert_eq!(Some(Limit::Nodes(10_000)), limit(sandbox, &src));
+    let src = format!("[{}]", "1 + 1, ".repeat(1000));
+    assert_eq!(None, limit(sandbox, &src));
+    let small = Limits {
+        max_nodes: Some(5),
+        ..Limits::default()
+    };
+    assert_eq!(Some(Limit::Nodes(5)), limit(small, "x = 1; x += 1"));
+    assert_eq!(None, limit(small, "x = 1; x + 1"));
+
+    // Checked while evaluating.
+    let src = "fn f(n) { f(n + 1) }; f(0)";
+    assert_eq!(depth, limit(sandbox, src));
+    let src = "fn f(n) { if n > 0 then { n + f(n - 1) } else { 0 } }; f(1000)";
+    assert_eq!(depth, limit(sandbox, src));
+    assert_eq!(
+        Some(Limit::Steps(1_000_000)),
+        limit(sandbox, "while true { 0 }")
+    );
+    let timeout = Limits {
+        timeout: Some(Duration::from_millis(20)),
+        ..Limits::default()
+    };
+    let time = Some(Limit::Time(Duration::from_millis(20)));
+    assert_eq!(time, limit(timeout, "while true { 0 }"));
+    let src = "fn fib(n) { if n < 2 then { n } else { fib(n - 1) + fib(n - 2) } }; fib(40)";
+    assert_eq!(time, limit(timeout, src));
+    let memory = Some(Limit::Memory(1 << 20));
+    assert_eq!(
+        memory,
+        limit(sandbox, "s = \"ab\"; while true { s = s + s }")
+    );
+    assert_eq!(memory, limit(sandbox, "l = [1]; while true { l = l + l }"));
+
+    // The limits are kept after an error.
+    let mut interp = Interpreter::new();
+    interp.set_limits(sandbox);
+    let err = interp.eval("fn f(n) { f(n + 1) }; f(0)").unwrap_err();
+    assert_eq!(
+        "evaluation exceeds the limit of 200 nested expressions",
+        err.to_string()
+    );
+    assert_eq!(Value::I64(3), interp.eval("1 + 2").unwrap());
+    assert!(matches!(
+        interp.eval_checked("f(0)"),
+        Err(Error::Eval(EvalError::LimitExceeded { .. }))
+    ));
+
+    // Every evaluation has the whole step budget, the clock is left out
+    // as the loops are slow in the debug builds.
+    let mut interp = Interpreter::new();
+    interp.set_limits(Limits {
+        timeout: None,
+        ..sandbox
+    });
+    let src = "i = 0; while i < 400000 { i += 1 }; i";
+    for _ in 0..5 {
+        assert_eq!(Value::I64(400_000), interp.eval(src).unwrap());
+    }
+    let mut interp = Interpreter::new();
+    interp.set_limits(Limits {
+        max_steps: Some(1000),
+        ..Limits::default()
+    });
+    let src = "i = 0; while i < 600 { i += 1 }; i";
+    for _ in 0..3 {
+        assert_eq!(Value::I64(600), interp.eval(src).unwrap());
+        assert_eq!(Value::I64(600), interp.eval_checked(src).unwrap());
+    }
+}
+
+#[test]
+fn builtin_test() {
+    use calculator_ast::Value;
+    use error::EvalError;
+
+    let eval = |src: &str| {
+        calculator::ListParser::new()
+            .parse(src)
+            .unwrap()
+            .eval(&mut Environment::new())
+    };
+
+    assert_eq!(Value::F64(3.0), eval("sqrt(9)").unwrap());
+    assert_eq!(Value::F64(1.0), eval("exp(0)").unwrap());
+    assert_eq!(Value::F64(1.0), eval("ln(exp(1))").unwrap());
+    assert_eq!(Value::F64(3.0), eval("log(1000)").unwrap());
+    assert_eq!(
+        Value::F64(0.0),
+        eval("sin(0) + tan(0) - 1 + cos(0)").unwrap()
+    );
+    assert!((eval("4 * atan(1)").unwrap().as_f64() - std::f64::consts::PI).abs() < 1e-12);
+    assert!((eval("asin(1) - acos(0)").unwrap().as_f64()).abs() < 1e-12);
+
+    // integers are kept if the result is exact.
+    assert_eq!(Value::I64(1024), eval("pow(2, 10)").unwrap());
+    assert_eq!(Value::F64(0.5), eval("pow(2, -1)").unwrap());
+    assert_eq!(Value::F64(4.0), eval("pow(16, 0.5)").unwrap());
+    assert_eq!(Value::I64(3), eval("abs(-3)").unwrap());
+    assert_eq!(Value::F64(3.5), eval("abs(-3.5)").unwrap());
+    assert_eq!(Value::I64(-2), eval("min(3, -2, 5)").unwrap());
+    assert_eq!(Value::F64(5.0), eval("max(3, 2.5, 5)").unwrap());
+    assert_eq!(
+        Value::I64(7),
+        eval("floor(7) + ceil(0) + round(0)").unwrap()
+    );
+    assert_eq!(Value::F64(-3.0), eval("floor(-2.5)").unwrap());
+    assert_eq!(Value::F64(3.0), eval("ceil(2.1)").unwrap());
+    assert_eq!(Value::F64(3.0), eval("round(2.5)").unwrap());
+    assert_eq!(Value::I64(2), eval("print(1, 2)").unwrap());
+
+    // user functions can call built-ins.
+    assert_eq!(
+        Value::F64(5.0),
+        eval("fn hypot(a, b) { sqrt(a * a + b * b) }; hypot(3, 4)").unwrap()
+    );
+
+    assert!(matches!(
+        eval("sqrt(1, 2)"),
+        Err(EvalError::ArityMismatch {
+            expected: 1,
+            found: 2,
+            ..
+        })
+    ));
+    assert!(matches!(
+        eval("max()"),
+        Err(EvalError::ArityMismatch {
+            expected: 1,
+            found: 0,
+            ..
+        })
+    ));
+    assert!(matches!(eval("pow(10, 100)"), Err(EvalError::Overflow(_))));
+    assert!(matches!(
+        eval("sqrt(1 == 1)"),
+        Err(EvalError::TypeMismatch(_))
+    ));
+}
+
+#[test]
+fn embedding_test() {
+    use calculator_ast::Value;
+    use error::{Error, EvalError};
+    use interpreter::Interpreter;
+
+    let mut interp = Interpreter::new();
+    interp
+        .set_var("quantity", 3)
+        .set_var("price", 2.5)
+        .set_var("vip", true)
+        .register_fn("tier", |args| {
+            let n = args.first().map_or(0, |v| v.as_i64());
+            Ok(Value::from(if n >= 3 { 0.9 } else { 1.0 }))
+        })
+        .register_fn("fail", |_| Err(EvalError::host("rule rejected")));
+
+    let rule = interp
+        .parse("total = quantity * price * tier(quantity); if (vip) then { total -= 1 }")
+        .unwrap();
+    interp.eval_list(&rule).unwrap();
+    assert_eq!(
+        Some(Value::F64(3.0 * 2.5 * 0.9 - 1.0)),
+        interp.get_var("total")
+    );
+
+    // the same rule with other bindings.
+    interp.set_var("quantity", 1).set_var("vip", false);
+    interp.eval_list(&rule).unwrap();
+    assert_eq!(Some(Value::F64(2.5)), interp.get_var("total"));
+    assert_eq!(
+        vec!["price", "quantity", "total", "vip"],
+        interp
+            .vars()
+            .iter()
+            .map(|(name, _)| *name)
+            .collect::<Vec<_>>()
+    );
+
+    // functions of the scripts shadow the native ones.
+    assert_eq!(
+        Value::I64(7),
+        interp.eval("fn tier(n) { 7 }; tier(1)").unwrap()
+    );
+
+    let src = "x = 1 + fail()";
+    match interp.eval(src) {
+        Err(Error::Eval(EvalError::Host { message, span })) => {
+            assert_eq!("rule rejected", message);
+            assert_eq!(calculator_ast::Span::new(8, 14), span);
+        }
+        other => panic!("unexpected {:?}", other),
+    }
+    assert!(matches!(interp.eval("x = "), Err(Error::Parse(_))));
+    assert!(interp
+        .eval("x = 1 + nope()")
+        .unwrap_err()
+        .render("x = 1 + nope()")
+        .starts_with("error: undefined function `nope`"));
+
+    // natives survive resetting the environment.
+    interp.env_mut().reset();
+    assert!(interp.get_var("total").is_none());
+    assert_eq!(Value::F64(0.9), interp.eval("tier(5)").unwrap());
+}
+
+#[test]
+fn optimizer_test() {
+    use optimizer::{optimize, optimize_list};
+    use pretty::{pretty, pretty_list};
+
+    let simplify = |src: &str| {
+        pretty_list(&optimize_list(
+            &calculator::ListParser::new().parse(src).unwrap(),
+        ))
+    };
+
+    let expr = calculator::ExprParser::new()
+        .parse("22 + 22 * ((((((22 - 22)))))) / 2")
+        .unwrap();
+    assert_eq!("22", pretty(&optimize(&expr)));
+
+    assert_eq!("x", simplify("x * 1 + 0"));
+    assert_eq!("x * 1.0", simplify("1 * x * 1.0"));
+    assert_eq!("a = 2.5 * b", simplify("a = (2 + 3) / 2.0 * (b / 1)"));
+    assert_eq!("y = 4.0", simplify("y = sqrt(2 * 8)"));
+    assert_eq!("print(2)", simplify("print(1 + 1)"));
+    assert_eq!(
+        "b = 2; b",
+        simplify("if 1 > 2 then { a } else { b = 2; b * 1 }")
+    );
+    assert_eq!("c; 0", simplify("c; if 0 then { a }"));
+    assert_eq!("0", simplify("while 1 == 2 { x = x + 1 }"));
+    assert_eq!(
+        "fn f(x) { x - (1 - x) }",
+        simplify("fn f(x) { 5; x - (1 - x) * 1 }")
+    );
+    // folding the errors is left to the evaluation.
+    assert_eq!("1 / 0", simplify("1 / (2 - 2)"));
+    assert_eq!("a - -3", simplify("a - (0 - 3)"));
+    assert_eq!("false", simplify("1 > 2 && f()"));
+    assert_eq!(
+        "a || b && c == d < e",
+        simplify("a || (b && (c == (d < e)))")
+    );
+    assert_eq!("(a || b) && !(c != d)", simplify("(a || b) && !(c != d)"));
+    assert_eq!("\"ab\\\"c\"", simplify("\"a\" + \"b\\\"c\""));
+    assert_eq!("[2, x * 1.0][-1]", simplify("[1 + 1, x * 1.0][0 - 1]"));
+    assert_eq!("sqrt(x) + y * 2.0", simplify("sqrt(x) * 1.0 + 1.0 * (y * 2.0)"));
+    assert_eq!("sqrt(x) - x ^ 1.0", simplify("sqrt(x) ^ 1.0 - x ^ 1.0"));
+    assert_eq!(
+        "map(xs, fn(x) { x + 1 })",
+        simplify("map(xs, fn(x) { x * 1 + 1 })")
+    );
+    assert_eq!("(1.5 in m/s)", simplify("3 m / 2 s"));
+    assert_eq!("x in km/h", simplify("x in km/h"));
+    assert_eq!("a || (b in m)", simplify("a || (b in m)"));
+    assert_eq!("(x = 2.0 s) in ms in s", simplify("(x = 2 s) in ms in s"));
+
+    // the simplified source gives the same results.
+    let sources = [
+        "a = 3; b = a * 2 + 0; if a < b then { b - 1 } else { a }",
+        "s = 0; for (i = 0; i < 10 * 1; i += 1) { if 1 then { s += i } }; s",
+        "fn f(n) { if n <= 1 then { 1 } else { n * f(n - 1) } }; f(2 + 3)",
+        "x = 2.5; y = -(-x) * (1 - 1.0) + pow(2, 0.5)",
+        "1.0 / 0 + 9223372036854775807 * 0",
+        "-9223372036854775807 - 1",
+        "s = \"a\\n\" + \"b\"; s != \"x\" && !null || 1 / 0",
+        "xs = [[1], [-2], [3 * 1]]; sum(map(filter(xs, fn(x) { x != [3] }), fn(x) { -x[0 - 0] }))",
+        "reduce([1, 2, 3], fn(a, x) { a * x + 0 }, 1) + len([[1]][0 - 1])",
+        "x = 2 km; y = x / 4 min in km/h; [y, 3 kg * 1 m / 1 s / 1 s, 2 / 1 s, 1 m^2 in cm^2]",
+    ];
+    for src in sources.iter() {
+        let list = calculator::ListParser::new().parse(src).unwrap();
+        let printed = simplify(src);
+        let reparsed = calculator::ListParser::new().parse(&printed).unwrap();
+        assert_eq!(
+            format!("{:?}", list.eval(&mut Environment::new())),
+            format!("{:?}", reparsed.eval(&mut Environment::new())),
+            "{} => {}",
+            src,
+            printed
+        );
+    }
+}
+
+#[test]
+fn derivative_test() {
+    use calculator_ast::Value;
+    use derivative::derive;
+    use pretty::pretty;
+
+    let d = |src: &str| {
+        let expr = calculator::ExprParser::new().parse(src).unwrap();
+        derive(&expr, "x").map(|d| pretty(&d))
+    };
+
+    assert_eq!("x + x", d("x * x + 3").unwrap());
+    assert_eq!("0.5", d("x / 2").unwrap());
+    assert_eq!("a", d("a * x - b").unwrap());
+    assert_eq!("3 * x ^ 2", d("pow(x, 3)").unwrap());
+    assert_eq!("3 * x ^ 2", d("x ^ 3").unwrap());
+    assert_eq!("2 * x", d("x ^ 2 % 7").unwrap());
+    assert_eq!("1.0", d("x ^ 1").unwrap());
+    assert_eq!("-2 * x ^ -3", d("x ^ -2").unwrap());
+    assert_eq!("1.5 * x ^ 0.5", d("x ^ 1.5").unwrap());
+    assert_eq!("2 * sin(x) * cos(x)", d("sin(x) ^ 2").unwrap());
+    assert_eq!("x ^ x * (ln(x) + 1.0)", d("x ^ x").unwrap());
+    assert_eq!("x / abs(x)", d("abs(x)").unwrap());
+    assert_eq!("2.0 * (2 * x / abs(2 * x))", d("abs(2 * x)").unwrap());
+    assert!(d("7 % x").is_err());
+    assert_eq!("2.0 * exp(2 * x)", d("exp(2 * x)").unwrap());
+    assert_eq!("-1.0 / (x * x)", d("1 / x").unwrap());
+    assert_eq!("y / (1.0 + x * y * (x * y))", d("atan(x * y)").unwrap());
+
+    let err = d("x + f(x)").unwrap_err();
+    assert_eq!("cannot differentiate the function `f`", err.to_string());
+    assert_eq!(calculator_ast::Span::new(4, 8), err.span());
+    assert!(d("max(x, 1)").is_err());
+    assert!(d("x > 1").is_err());
+
+    // compare with the central difference, at a float and an integer.
+    let sources = [
+        "x * x * x - 2 * x",
+        "x / (x + 1)",
+        "ln(x * x)",
+        "sin(x) * cos(x)",
+        "pow(x, x) + pow(2, x)",
+        "sqrt(x) / tan(x)",
+        "log(3 * x) - exp(-x)",
+        "abs(x - 5) * atan(x)",
+        "asin(x / 4.0) + acos(x / 5.0)",
+        "sin(x) ^ 2 + x ^ -2 + (x + 1) ^ 3",
+        "pow(x + 1, x) * abs(2 - x)",
+    ];
+    for src in sources.iter() {
+        let expr = calculator::ExprParser::new().parse(src).unwrap();
+        let derivative = derive(&expr, "x").unwrap();
+        for x in [Value::F64(0.7), Value::I64(3)].iter() {
+            let at = |expr: &calculator_ast::Expr, x: Value| {
+                let mut env = Environment::new();
+                env.set("x", x);
+                expr.eval(&mut env).unwrap().as_f64()
+            };
+            let h = 1e-6;
+            let expected = (at(&expr, Value::F64(x.as_f64() + h))
+                - at(&expr, Value::F64(x.as_f64() - h)))
+                / (2.0 * h);
+            let actual = at(&derivative, x.clone());
+            assert!(
+                (expected - actual).abs() < 1e-4 * expected.abs().max(1.0),
+                "d/dx {} at {}: {} != {}",
+                src,
+                x,
+                expected,
+                actual
+            );
+        }
+    }
+}
+
+#[test]
+fn numeric_test() {
+    use calculator_ast::Value;
+    use error::EvalError;
+
+    let eval = |src: &str| {
+        calculator::ListParser::new()
+            .parse(src)
+            .unwrap()
+            .eval(&mut Environment::new())
+    };
+    let close = |src: &str, expected: f64| {
+        let v = eval(src).unwrap().as_f64();
+        assert!((v - expected).abs() < 1e-8, "{} = {}", src, v);
+    };
+
+    close("integrate(x * x, x, 0, 3)", 9.0);
+    close("integrate(sin(t), t, 0, 3.141592653589793)", 2.0);
+    close("integrate(1 / x, x, 1, exp(1))", 1.0);
+    close("integrate(x, x, 2, 0)", -2.0);
+    close("k = 3; integrate(k * x, x, 0, 1)", 1.5);
+    close(
+        "fn area(r) { integrate(2 * sqrt(r * r - x * x), x, -r, r) }; area(1.0)",
+        std::f64::consts::PI,
+    );
+    // double integral of x * y over the unit square.
+    close("integrate(integrate(x * y, y, 0, 1), x, 0, 1)", 0.25);
+
+    close("solve(x * x - 2, x, 1)", 2.0_f64.sqrt());
+    close("solve(cos(x) - x, x, 0)", 0.7390851332151607);
+    close("solve(exp(x) - 10, x, 0)", 10.0_f64.ln());
+    // the derivative is zero at the guess, bisection finds the root.
+    close("solve(x * x * x - 8, x, 0)", 2.0);
+    close("x = 5; solve(x - 1, x, 0); x", 5.0);
+
+    assert!(matches!(
+        eval("solve(x * x + 1, x, 0)"),
+        Err(EvalError::NoConvergence { name: "solve", .. })
+    ));
+    assert!(matches!(
+        eval("integrate(1 / x, x, -1, 1)"),
+        Err(EvalError::NoConvergence {
+            name: "integrate",
+            ..
+        })
+    ));
+    assert_eq!(
+        "`integrate` does not converge",
+        eval("integrate(1 / (x - 2), x, 0, 2)")
+            .unwrap_err()
+            .to_string()
+    );
+    assert!(matches!(
+        eval("integrate(x, 1, 0, 1)"),
+        Err(EvalError::ExpectedVariable {
+            name: "integrate",
+            ..
+        })
+    ));
+    assert!(matches!(
+        eval("integrate(x > 1, x, 0, 1)"),
+        Err(EvalError::TypeMismatch(_))
+    ));
+    assert!(matches!(
+        eval("solve(x, x)"),
+        Err(EvalError::ArityMismatch { expected: 3, .. })
+    ));
+    assert!(matches!(
+        eval("integrate(x, x, 0, y)"),
+        Err(EvalError::UndefinedVariable { .. })
+    ));
+    assert_eq!(Value::F64(0.5), eval("integrate(x, x, 0, 1)").unwrap());
+
+    let mut env = Environment::new();
+    env.set_step_limit(Some(1000));
+    let runaway = calculator::ListParser::new()
+        .parse("integrate(sqrt(abs(x)), x, -1, 1)")
+        .unwrap();
+    assert!(matches!(
+        runaway.eval(&mut env),
+        Err(EvalError::LimitExceeded { .. })
+    ));
+}
+
+#[test]
+fn exact_test() {
+    use calculator_ast::{NumericMode, Value};
+    use error::EvalError;
+
+    let eval = |src: &str, mode: NumericMode| {
+        let mut env = Environment::new();
+        env.set_mode(mode);
+        calculator::ListParser::new()
+            .parse(src)
+            .unwrap()
+            .eval(&mut env)
+    };
+    let exact = |src: &str| eval(src, NumericMode::Exact).unwrap().to_string();
+
+    assert_eq!(Value::I64(3), eval("7 / 2", NumericMode::Checked).unwrap());
+    assert_eq!("7/2", exact("7 / 2"));
+    assert_eq!("2/3", exact("1 / 3 + 1 / 3"));
+    assert_eq!("1", exact("1 / 3 * 3"));
+    assert_eq!(
+        Value::I64(1),
+        eval("1 / 3 * 3", NumericMode::Exact).unwrap()
+    );
+    assert_eq!("-1/6", exact("1 / 3 - 1 / 2"));
+    assert!(matches!(
+        eval("9223372036854775807 + 1", NumericMode::Checked),
+        Err(EvalError::Overflow(_))
+    ));
+    assert_eq!("9223372036854775808", exact("9223372036854775807 + 1"));
+    assert_eq!("1267650600228229401496703205376", exact("pow(2, 100)"));
+    assert_eq!("1/8", exact("pow(2, -3)"));
+    assert_eq!(
+        Value::F64(0.125),
+        eval("pow(2, -3)", NumericMode::Checked).unwrap()
+    );
+    assert_eq!("9/4", exact("pow(3 / 2, 2)"));
+    assert!(matches!(
+        eval("pow(3, 10000000)", NumericMode::Exact),
+        Err(EvalError::Overflow(_))
+    ));
+    assert!(matches!(
+        eval("1 / 0", NumericMode::Exact),
+        Err(EvalError::DivisionByZero(_))
+    ));
+
+    // Big literals are exact in both of the modes.
+    assert_eq!(
+        "100000000000000000000",
+        eval("100000000000000000000", NumericMode::Checked)
+            .unwrap()
+            .to_string()
+    );
+    assert_eq!("1", exact("100000000000000000001 - 100000000000000000000"));
+    assert_eq!("-9223372036854775808", exact("-9223372036854775808"));
+    assert!(matches!(
+        eval("-9223372036854775808 / -1", NumericMode::Checked),
+        Err(EvalError::Overflow(_))
+    ));
+    assert_eq!("9223372036854775808", exact("-9223372036854775808 / -1"));
+    assert_eq!("0", exact("-9223372036854775808 % -1"));
+
+    // Fractions mixed with floats make floats.
+    assert_eq!(
+        Value::F64(0.75),
+        eval("1 / 2 + 0.25", NumericMode::Exact).unwrap()
+    );
+    assert_eq!("3", exact("floor(7 / 2)"));
+    assert_eq!("-3", exact("ceil(-7 / 2)"));
+    assert_eq!("4", exact("round(7 / 2)"));
+    assert_eq!("1/4", exact("min(1 / 2, 1 / 4, 1 / 3)"));
+    assert_eq!("1/2", exact("abs(-1 / 2)"));
+
+    assert_eq!("true", exact("1 / 3 == 2 / 6"));
+    assert_eq!("true", exact("1 / 2 == 0.5 && 1 / 3 != 0.3333"));
+    assert_eq!("true", exact("[1 / 2, 4 / 2] == [0.5, 2.0]"));
+    assert_eq!("true", exact("1 / 3 < 0.34"));
+    assert_eq!("false", exact("1 / 3 > 1 / 2"));
+    assert_eq!(
+        "true",
+        exact("9223372036854775807 + 1 > 9223372036854775807")
+    );
+}
+
+#[test]
+fn value_test() {
+    use calculator_ast::Value;
+    use error::EvalError;
+
+    let eval = |src: &str| {
+        calculator::ListParser::new()
+            .parse(src)
+            .unwrap()
+            .eval(&mut Environment::new())
+    };
+    let value = |src: &str| eval(src).unwrap();
+
+    assert_eq!(
+        Value::from("hello, world"),
+        value("\"hello, \" + \"world\"")
+    );
+    assert_eq!(Value::from("a\"b\\c\n"), value("\"a\\\"b\\\\c\\n\""));
+    assert_eq!("tab\there", value("\"tab\\there\"").to_string());
+    assert_eq!(Value::Bool(true), value("\"abc\" < \"abd\""));
+    assert_eq!(Value::Bool(true), value("\"a\" == \"a\" && \"a\" != \"b\""));
+    assert!(matches!(eval("\"a\" + 1"), Err(EvalError::TypeMismatch(_))));
+    assert!(matches!(eval("\"a\" < 1"), Err(EvalError::TypeMismatch(_))));
+    assert!(matches!(eval("-\"a\""), Err(EvalError::TypeMismatch(_))));
+
+    assert_eq!(Value::Null, value("x = null; x"));
+    assert_eq!("null", value("null").to_string());
+    assert_eq!(Value::Bool(true), value("null == null"));
+    assert_eq!(Value::Bool(true), value("null != 0"));
+    assert!(matches!(eval("null + 1"), Err(EvalError::TypeMismatch(_))));
+
+    assert_eq!(Value::Bool(true), value("true && !false"));
+    assert_eq!(Value::Bool(false), value("!1"));
+    assert_eq!(Value::Bool(true), value("!\"\" && !null"));
+    assert_eq!(Value::Bool(true), value("0 || 2"));
+    assert_eq!(Value::Bool(false), value("\"\" || null"));
+
+    // The right side is skipped if the left one decides the result.
+    assert_eq!(Value::Bool(false), value("false && undefined"));
+    assert_eq!(Value::Bool(true), value("1 || 1 / 0"));
+    assert_eq!(Value::I64(0), value("n = 0; 1 > 2 && (n = 1); n"));
+    assert!(matches!(
+        eval("true && undefined"),
+        Err(EvalError::UndefinedVariable { .. })
+    ));
+
+    // `||` is looser than `&&`, which is looser than the equality, which
+    // is looser than the ordering.
+    assert_eq!(Value::Bool(true), value("true || false && false"));
+    assert_eq!(Value::Bool(true), value("1 < 2 == 3 < 4"));
+    assert_eq!(Value::Bool(true), value("1 == 1 && 2 != 3"));
+
+    // The numbers are equal by value whatever their types.
+    assert_eq!(Value::Bool(true), value("1 == 1.0"));
+    assert_eq!(Value::Bool(false), value("1 != 1.0"));
+    assert_eq!(Value::Bool(true), value("1.5 == 3 / 2.0 && 2.5 != 2"));
+    assert_eq!(Value::Bool(true), value("[1, [2]] == [1.0, [2.0]]"));
+    assert_eq!(Value::Bool(false), value("[1] == [1.0, 2]"));
+    assert_eq!(Value::Bool(false), value("1 == true || \"1\" == 1"));
+    assert_eq!(Value::Bool(false), value("!true == true"));
+    assert_eq!(Value::Bool(true), value("1 + 1 == 2 || x"));
+}
+
+#[test]
+fn list_test() {
+    use calculator_ast::Value;
+    use error::EvalError;
+
+    let eval = |src: &str| {
+        calculator::ListParser::new()
+            .parse(src)
+            .unwrap()
+            .eval(&mut Environment::new())
+    };
+    let show = |src: &str| eval(src).unwrap().to_string();
+
+    assert_eq!(
+        "[1, 2.5, \"a\", [true, null]]",
+        show("[1, 2.5, \"a\", [true, null]]")
+    );
+    assert_eq!("[]", show("[]"));
+    assert_eq!("[1, 2, 3]", show("[1, 2] + [3]"));
+    assert_eq!(Value::Bool(true), eval("[1, [2]] == [1, [2]]").unwrap());
+    assert!(matches!(eval("[1] + 1"), Err(EvalError::TypeMismatch(_))));
+
+    assert_eq!(Value::I64(20), eval("xs = [10, 20, 30]; xs[1]").unwrap());
+    assert_eq!(Value::I64(30), eval("xs = [10, 20, 30]; xs[-1]").unwrap());
+    assert_eq!(Value::I64(4), eval("[[1, 2], [3, 4]][1][1]").unwrap());
+    assert_eq!("b", show("\"abc\"[1]"));
+    assert!(matches!(eval("[1, 2][2]"), Err(EvalError::OutOfRange(_))));
+    assert!(matches!(eval("[1, 2][-3]"), Err(EvalError::OutOfRange(_))));
+    assert!(matches!(
+        eval("[1][\"a\"]"),
+        Err(EvalError::TypeMismatch(_))
+    ));
+    assert!(matches!(eval("1[0]"), Err(EvalError::TypeMismatch(_))));
+
+    assert_eq!(Value::I64(3), eval("len([1, 2, 3])").unwrap());
+    assert_eq!(Value::I64(5), eval("len(\"hello\")").unwrap());
+    assert_eq!(Value::I64(60), eval("sum([10, 20, 30])").unwrap());
+    assert_eq!(Value::I64(6), eval("sum(1, 2, 3)").unwrap());
+    assert_eq!(Value::I64(0), eval("sum([])").unwrap());
+    assert_eq!(Value::I64(7), eval("max([3, 7, 5])").unwrap());
+    assert_eq!(Value::I64(3), eval("min([3, 7, 5])").unwrap());
+    assert_eq!(Value::Null, eval("min([])").unwrap());
+
+    assert_eq!("[2, 4, 6]", show("map([1, 2, 3], fn(x) { x * 2 })"));
+    assert_eq!(
+        "[2, 4]",
+        show("filter([1, 2, 3, 4], fn(x) { x / 2 * 2 == x })")
+    );
+    assert_eq!(
+        Value::I64(10),
+        eval("reduce([1, 2, 3, 4], fn(a, x) { a + x }, 0)").unwrap()
+    );
+    assert_eq!(
+        Value::I64(60),
+        eval("items = [[2, 10], [4, 10]]; sum(map(items, fn(i) { i[0] * i[1] }))").unwrap()
+    );
+    assert_eq!(
+        "[3, 4]",
+        show("fn add(a, b) { a + b }; map([1, 2], fn(x) { add(x, 2) })")
+    );
+    assert_eq!("fn(x)", show("fn(x) { x }"));
+
+    assert!(matches!(
+        eval("map([1], fn(a, b) { a })"),
+        Err(EvalError::ArityMismatch {
+            expected: 2,
+            found: 1,
+            ..
+        })
+    ));
+    assert!(matches!(
+        eval("map(1, fn(x) { x })"),
+        Err(EvalError::TypeMismatch(_))
+    ));
+    assert!(matches!(
+        eval("map([1], 1)"),
+        Err(EvalError::TypeMismatch(_))
+    ));
+    assert!(matches!(
+        eval("fn(x) { x } + 1"),
+        Err(EvalError::TypeMismatch(_))
+    ));
+}
+
+#[test]
+fn units_test() {
+    use calculator_ast::Value;
+    use diagnostic::Diagnostic;
+    use error::EvalError;
+    use units::Unit;
+
+    let eval = |src: &str| {
+        calculator::ListParser::new()
+            .parse(src)
+            .unwrap()
+            .eval(&mut Environment::new())
+    };
+    let show = |src: &str| eval(src).unwrap().to_string();
+
+    assert_eq!("1.5 m/s", show("3 m / 2 s"));
+    assert_eq!("3 km", show("3km"));
+    assert_eq!("1.5 km", show("1 km + 500 m"));
+    assert_eq!("-500 m", show("500 m - 1 km"));
+    assert_eq!("6 kg", show("2 * 3 kg"));
+    assert_eq!("0.5 s", show("1 s / 2"));
+    assert_eq!("2 s^-1", show("4 / 2 s"));
+    assert_eq!("6000000 m^2", show("2 km * 3 km"));
+    assert_eq!("1 kg*m/s^2", show("1 kg * 1 m / 1 s / 1 s"));
+    assert_eq!(Value::F64(1000.0), eval("1 km / 1 m").unwrap());
+
+    assert_eq!("1.5 km", show("1500 m in km"));
+    assert_eq!("250 ms", show("0.25 s in ms"));
+    assert_eq!("36 km/h", show("10 m / 1 s in km/h"));
+    assert_eq!("3 N", show("3 kg * 1 m / 1 s / 1 s in N"));
+    assert_eq!("2 h", show("2 in h"));
+    assert_eq!("0.001 m^3", show("1 L in m^3"));
+    assert_eq!("4 m^2", show("2 m^2 * 2"));
+    assert_eq!("2 m", show("sqrt(4 m^2)"));
+    assert_eq!("8 m^3", show("pow(2 m, 3)"));
+    assert_eq!("1200 m", show("max(1 km, 900 m, 1200 m)"));
+    assert_eq!("1500 m", show("sum([1 km, 500 m]) in m"));
+    assert_eq!("3 m", show("abs(-3 m)"));
+    assert_eq!("2 m", show("round(2.4 m)"));
+    assert_eq!("9 m^2", show("(3 m) ^ 2"));
+    assert_eq!("0.5 km", show("2.5 km % 1000 m"));
+
+    assert_eq!(Value::Bool(true), eval("1 km == 1000 m").unwrap());
+    assert_eq!(Value::Bool(false), eval("1 km == 1000 s").unwrap());
+    assert_eq!(Value::Bool(true), eval("90 min > 1 h").unwrap());
+
+    let mismatch = |src: &str| matches!(eval(src), Err(EvalError::DimensionMismatch(_)));
+    assert!(mismatch("1 m + 1 s"));
+    assert!(mismatch("1 m + 1"));
+    assert!(mismatch("5 m % 2"));
+    assert!(mismatch("1 m < 1 s"));
+    assert!(mismatch("1 m > 0"));
+    assert!(mismatch("1 m in s"));
+    assert!(mismatch("sin(1 m)"));
+    assert!(mismatch("sqrt(2 m)"));
+    assert!(mismatch("max(1 m, 1)"));
+    assert!(mismatch("integrate(x, x, 0 m, 1)"));
+    assert!(matches!(
+        eval("\"a\" + 1 m"),
+        Err(EvalError::TypeMismatch(_))
+    ));
+    assert!(matches!(
+        eval("convert(1 m, 1)"),
+        Err(EvalError::TypeMismatch(_))
+    ));
+
+    let src = "x = 3 furlong";
+    let err = calculator::ListParser::new().parse(src).unwrap_err();
+    assert_eq!(
+        "error: unknown unit `furlong`\n --> 1:7\n  |\n1 | x = 3 furlong\n  |       ^^^^^^^",
+        Diagnostic::from_parse_error(&err).render(src)
+    );
+
+    // The symbols are read back.
+    let unit = Unit::parse("km/h^2*s").unwrap();
+    assert_eq!("km/h^2*s", unit.symbol());
+    assert_eq!(Some(1e3 / 3600.0), Unit::parse("km/h").map(|u| u.scale()));
+    let base = Unit::base(unit.dimension());
+    assert_eq!(Some(base.clone()), Unit::parse(base.symbol()));
+    assert_eq!(None, Unit::parse("km/"));
+    assert_eq!(None, Unit::parse("km^x"));
+    assert_eq!(None, Unit::parse(""));
+}
+
+#[test]
+fn typeck_test() {
+    use calculator_ast::Value;
+    use error::{Error, EvalError};
+    use interpreter::Interpreter;
+    use typeck::Type;
+
+    let parse = |src: &str| calculator::ListParser::new().parse(src).unwrap();
+    let errors = |src: &str| typeck::check(&parse(src), &Environment::new()).unwrap_err();
+    let result = |src: &str| {
+        typeck::check(&parse(src), &Environment::new())
+            .unwrap()
+            .result()
+    };
+
+    let list = parse("a = 1; b = 2.5; c = a * b; d = c > 1; s = \"x\"; e = [a]; m = 2 km");
+    let types = typeck::check(&list, &Environment::new()).unwrap();
+    let vars: Vec<_> = types
+        .vars()
+        .iter()
+        .map(|(name, ty)| format!("{}: {}", name, ty))
+        .collect();
+    assert_eq!(
+        "a: int, b: float, c: float, d: bool, e: list, m: quantity, s: string",
+        vars.join(", ")
+    );
+    assert_eq!(Type::Quantity, types.result());
+
+    assert_eq!(Type::Int, result("fn sq(x) { x * x }; sq(3) > 1; 1"));
+    assert_eq!(Type::Number, result("x = 1; if x then { 1 } else { 2.5 }"));
+    assert_eq!(
+        Type::Number,
+        result("x = 1; for (i = 0; i < 3; i += 1) { x = x * 0.5 }; x")
+    );
+    assert_eq!(Type::Any, result("fn f(x) { x }; f(1)"));
+    assert_eq!(Type::Float, result("integrate(x * x, x, 0, 1)"));
+    // Assigned on one of the paths only, it may be defined.
+    assert_eq!(Type::Int, result("x = 3; if x > 1 then { y = 1 }; y"));
+    // A constant condition takes one branch, the other one is not checked.
+    assert_eq!(Type::Float, result("if 0 then { 1 + true } else { 2.5 }"));
+    assert_eq!(Type::Bool, result("false && (1 + true)"));
+    // The body of a loop may not run, its variables may be the ones of `env`.
+    let mut env = Environment::new();
+    env.set("z", Value::from("s"));
+    for src in ["while 0 { z = 1 }; z", "for (i = 0; i < 0; i += 1) { z = 1 }; z"] {
+        assert_eq!(Type::Any, typeck::check(&parse(src), &env).unwrap().result());
+    }
+
+    match errors("a = 1 == 1; a + 2")[..] {
+        [EvalError::TypeMismatch(ref expr)] => assert_eq!("a + 2", pretty::pretty(expr)),
+        ref errs => panic!("unexpected errors {:?}", errs),
+    }
+    assert!(matches!(
+        errors("b + 1")[..],
+        [EvalError::UndefinedVariable { ref name, .. }] if name == "b"
+    ));
+    assert!(matches!(
+        errors("fn f(x) { x }; f(1, 2)")[..],
+        [EvalError::ArityMismatch {
+            expected: 1,
+            found: 2,
+            ..
+        }]
+    ));
+    assert!(matches!(
+        errors("g(1)")[..],
+        [EvalError::UndefinedFunction { .. }]
+    ));
+    assert!(matches!(
+        errors("break")[..],
+        [EvalError::OutsideLoop { .. }]
+    ));
+    assert!(matches!(
+        errors("1 m + 2")[..],
+        [EvalError::DimensionMismatch(_)]
+    ));
+    assert!(matches!(
+        errors("integrate(x, 1, 0, 1)")[..],
+        [EvalError::ExpectedVariable { .. }]
+    ));
+    // All the errors are reported, loops report them once.
+    assert_eq!(2, errors("\"a\" - 1; fn f() { -true }").len());
+    assert_eq!(1, errors("while 1 { x = \"a\" * 2 }").len());
+
+    // The variables of the environment are known.
+    let mut env = Environment::new();
+    env.set("rate", Value::F64(0.5));
+    let list = parse("rate * 2");
+    assert_eq!(Type::Float, typeck::check(&list, &env).unwrap().result());
+
+    // Nothing is evaluated if the check fails.
+    let mut interp = Interpreter::new();
+    assert!(matches!(
+        interp.eval_checked("x = 1; y = x + true"),
+        Err(Error::Eval(EvalError::TypeMismatch(_)))
+    ));
+    assert_eq!(None, interp.get_var("x"));
+    assert_eq!(
+        Value::F64(3.0),
+        interp.eval_checked("x = 1.5; x * 2.0").unwrap()
+    );
+}
+
+#[test]
+fn cst_test() {
+    use calculator_ast::Span;
+    use cst::{tokenize, Highlight, SyntaxTree, TokenKind};
+    use error::RenameError;
+
+    let src = "a+=1.5e3 // c\n\"s\\\"\" /* d */ #.";
+    let tokens = tokenize(src);
+    let texts: Vec<_> = tokens
+        .iter()
+        .map(|token| &src[token.span.start..token.span.end])
+        .collect();
+    assert_eq!(src, texts.concat());
+    let kinds: Vec<_> = tokens.iter().map(|token| token.kind).collect();
+    assert_eq!(
+        vec![
+            TokenKind::Ident,
+            TokenKind::Operator,
+            TokenKind::Number,
+            TokenKind::Whitespace,
+            TokenKind::LineComment,
+            TokenKind::Whitespace,
+            TokenKind::Str,
+            TokenKind::Whitespace,
+            TokenKind::BlockComment,
+            TokenKind::Whitespace,
+            TokenKind::Unknown,
+            TokenKind::Unknown,
+        ],
+        kinds
+    );
+
+    // A block comment ends at the first `*/`.
+    let list = calculator::ListParser::new()
+        .parse("x = 1 /* a */; y = 2 /* b */")
+        .unwrap();
+    assert_eq!(2, list.0.unwrap().len());
+
+    let src = "fn f(x) { y = sqrt(x); g(y) }; /* km */ d = 2 km in m";
+    let tree = SyntaxTree::parse(src).unwrap();
+    assert_eq!(src, tree.to_string());
+    let classes: Vec<_> = tree
+        .highlights()
+        .into_iter()
+        .filter(|(_, class)| !matches!(class, Highlight::Punctuation | Highlight::Operator))
+        .map(|(span, class)| format!("{}:{:?}", &src[span.start..span.end], class))
+        .collect();
+    assert_eq!(
+        "fn:Keyword f:Function x:Parameter y:Variable sqrt:Builtin x:Variable g:Function \
+         y:Variable /* km */:Comment d:Variable 2:Number km:Unit in:Keyword m:Unit",
+        classes.join(" ")
+    );
+
+    // The parameters and the names assigned in a function are its own.
+    let src = "x = 1; fn f(x) { x * 2 }; g = fn(y) { x + y }; fn h() { x = 2 }; x + f(x)";
+    let tree = SyntaxTree::parse(src).unwrap();
+    assert_eq!(
+        "z = 1; fn f(x) { x * 2 }; g = fn(y) { z + y }; fn h() { x = 2 }; z + f(z)",
+        tree.rename(0, "z").unwrap()
+    );
+    assert_eq!(
+        "x = 1; fn f(n) { n * 2 }; g = fn(y) { x + y }; fn h() { x = 2 }; x + f(x)",
+        tree.rename(src.find("f(x)").unwrap() + 3, "n").unwrap()
+    );
+    let tree = SyntaxTree::parse("a += 1; a").unwrap();
+    assert_eq!(
+        Some(vec![Span::new(0, 1), Span::new(8, 9)]),
+        tree.references(9)
+    );
+    assert_eq!(Some(vec![Span::new(0, 1)]), tree.definitions(9));
+    let func = SyntaxTree::parse("fn f(x) { y = x; y + x }").unwrap();
+    assert_eq!(Some(vec![Span::new(5, 6)]), func.definitions(22));
+    assert_eq!(Some(vec![Span::new(10, 11)]), func.definitions(17));
+    assert_eq!(None, func.definitions(2));
+    assert_eq!(
+        Err(RenameError::InvalidName("if".into())),
+        tree.rename(0, "if")
+    );
+    assert_eq!(Err(RenameError::NoVariable(5)), tree.rename(5, "b"));
+
+    let err = SyntaxTree::parse("a = ;").err().unwrap();
+    assert_eq!(Span::new(4, 5), err.span);
+}
+
+#[test]
+fn wire_test() {
+    use calculator_ast::{ControlFlow, Expr, Value};
+    use error::DecodeError;
+    use interpreter::Interpreter;
+    use optimizer::optimize_list;
+    use pretty::pretty_list;
+
+    let sources = [
+        "x = 1 + 2 * -3 % 4; y = !(x >= 2) || x ^ 2 != 3",
+        "s = \"a\\\"b\\n\"; t = [1, 2.5, true, null, s][-1]; t += \"c\"",
+        "big = 123456789012345678901234567890; big / 7",
+        "v = 3 km in km/h; w = convert(v, 1 m*s^-2); 2 ms",
+        "fn f(a, b) { if a > b then { a } else { b } }; f(1, 2)",
+        "n = 0; while (n < 10) { n += 1; if n == 5 then { break } }; n",
+        "for (i = 0; i < 3; i += 1) { continue }; for (;;) { break }",
+        "xs = map([1, 2, 3], fn(x) { x * 2 }); reduce(xs, fn(a, b) { a + b }, 0)",
+        "integrate(x ^ 2, x, 0, 1)",
+    ];
+    for src in sources.iter() {
+        let list = Interpreter::new().parse(src).unwrap();
+        let decoded = wire::from_json(&wire::to_json(&list)).unwrap();
+        assert_eq!(list, decoded);
+        assert_eq!(pretty_list(&list), pretty_list(&decoded));
+        let bytes = wire::to_bytes(&list);
+        assert_eq!(list, wire::from_bytes(&bytes).unwrap());
+        assert!(bytes.len() < wire::to_json(&list).len());
+    }
+    let list = Interpreter::new().parse(sources[0]).unwrap();
+    let decoded = wire::from_bytes(&wire::to_bytes(&list)).unwrap();
+    assert_eq!(
+        list.0.unwrap().front().unwrap().span,
+        decoded.0.unwrap().front().unwrap().span
+    );
+
+    // The folded literals, the floats which are not finite are strings in
+    // JSON.
+    let src = "a = 1.0 / 0; b = [1, [2, 123456789012345678901234567890 / 4]]; c = -(0.0 / 0)";
+    let list = optimize_list(&Interpreter::new().parse(src).unwrap());
+    let json = wire::to_json(&list);
+    assert!(json.contains("\"inf\"") && json.contains("Rational"));
+    let mut interp = Interpreter::new();
+    interp.eval_list(&wire::from_json(&json).unwrap()).unwrap();
+    assert_eq!(Some(Value::F64(f64::INFINITY)), interp.get_var("a"));
+    assert_eq!(
+        "[1, [2, 61728394506172839450617283945/2]]",
+        interp.get_var("b").unwrap().to_string()
+    );
+    assert!(interp.get_var("c").unwrap().as_f64().is_nan());
+
+    // The other types are serialized in the same form.
+    let value = Value::from(vec![Value::from("x"), Value::from(1.5)]);
+    let json = serde_json::to_string(&value).unwrap();
+    assert_eq!(value, serde_json::from_str::<Value>(&json).unwrap());
+    let expr = calculator::ExprParser::new()
+        .parse("f(1) + [2][0]")
+        .unwrap();
+    let json = serde_json::to_string(&expr).unwrap();
+    let decoded: Expr = serde_json::from_str(&json).unwrap();
+    assert_eq!(expr, decoded);
+    let flow = match Interpreter::new().parse("while (x) { x -= 1 }").unwrap().0 {
+        Some(list) => match list.front().unwrap().kind {
+            calculator_ast::ExprKind::Flow(ref flow) => flow.clone(),
+            _ => unreachable!(),
+        },
+        None => unreachable!(),
+    };
+    let json = serde_json::to_string(&flow).unwrap();
+    let decoded: ControlFlow = serde_json::from_str(&json).unwrap();
+    assert_eq!(flow, decoded);
+    assert!(serde_json::from_str::<ControlFlow>(&serde_json::to_string(&expr).unwrap()).is_err());
+
+    // The data which is not a tree the parser makes is refused.
+    let decode = |entries: &str, root: u32| {
+        let json = format!(r#"{{"version":1,"entries":[{}],"root":{}}}"#, entries, root);
+        wire::from_json(&json).map(|list| pretty_list(&list))
+    };
+    let invalid = |index: usize, reason: &str| {
+        Err(DecodeError::Invalid {
+            index,
+            reason: reason.into(),
+        })
+    };
+    let span = r#"{"start":0,"end":1}"#;
+    let var = |name: &str| format!(r#"{{"Expr":[{{"VarRef":"{}"}},{}]}}"#, name, span);
+    assert!(decode(&format!("{},{{\"Block\":[0]}}", var("x")), 1).is_ok());
+    assert_eq!(
+        invalid(0, "`if` is not a name"),
+        decode(&format!("{},{{\"Block\":[0]}}", var("if")), 1)
+    );
+    assert_eq!(
+        invalid(0, "refers to the entry 0 which is not before it"),
+        decode(r#"{"Block":[0]}"#, 0)
+    );
+    assert_eq!(
+        invalid(1, "uses the entry 0 twice"),
+        decode(&format!(r#"{},{{"Block":[0,0]}}"#, var("x")), 1)
+    );
+    assert_eq!(
+        invalid(0, "not used"),
+        decode(&format!(r#"{},{},{{"Block":[1]}}"#, var("x"), var("y")), 2)
+    );
+    assert_eq!(
+        invalid(1, "the entry 0 is not an expression"),
+        decode(r#"{"Value":"Null"},{"Block":[0]}"#, 1)
+    );
+    let two_op = |op: &str| {
+        format!(
+            r#"{},{},{{"Expr":[{{"TwoOp":["{}",0,1]}},{}]}},{{"Block":[2]}}"#,
+            var("x"),
+            var("y"),
+            op,
+            span
+        )
+    };
+    assert!(decode(&two_op("Rem"), 3).is_ok());
+    assert_eq!(
+        invalid(2, "`Assign` is not a binary operator"),
+        decode(&two_op("Assign"), 3)
+    );
+    assert!(matches!(
+        decode(&two_op("Power"), 3),
+        Err(DecodeError::Malformed(_))
+    ));
+    let call = |node: &str| format!(r#"{{"Expr":[{},{}]}},{{"Block":[0]}}"#, node, span);
+    assert_eq!(
+        invalid(0, "`sqrt` is a built-in function"),
+        decode(&call(r#"{"Call":["sqrt",[]]}"#), 1)
+    );
+    assert_eq!(
+        invalid(0, "unknown built-in function `cbrt`"),
+        decode(&call(r#"{"Builtin":["cbrt",[]]}"#), 1)
+    );
+    let literal = |value: &str| {
+        format!(
+            r#"{{"Value":{}}},{{"Expr":[{{"Literal":0}},{}]}},{{"Block":[1]}}"#,
+            value, span
+        )
+    };
+    assert_eq!(
+        invalid(0, "unknown unit `km/x`"),
+        decode(&literal(r#"{"Quantity":{"value":1.0,"unit":"km/x"}}"#), 2)
+    );
+    assert_eq!(
+        invalid(0, "`1/0` is not a fraction"),
+        decode(&literal(r#"{"Rational":["1","0"]}"#), 2)
+    );
+    assert_eq!(
+        Ok("2".into()),
+        decode(&literal(r#"{"Rational":["6","3"]}"#), 2)
+    );
+    assert_eq!(
+        invalid(0, "the span ends before it starts"),
+        decode(r#"{"Expr":["Break",{"start":1,"end":0}]},{"Block":[0]}"#, 1)
+    );
+    let mut entries = vec![var("x")];
+    for i in 0..wire::MAX_DEPTH {
+        entries.push(format!(
+            r#"{{"Expr":[{{"OneOp":["Sub",{}]}},{}]}}"#,
+            i, span
+        ));
+    }
+    entries.push(format!(r#"{{"Block":[{}]}}"#, wire::MAX_DEPTH));
+    assert_eq!(
+        invalid(wire::MAX_DEPTH, "nested deeper than 2048 levels"),
+        decode(&entries.join(","), wire::MAX_DEPTH as u32 + 1)
+    );
+    // A list is used once, doubling it 40 times would make 2^40 elements.
+    let mut entries = vec![r#"{"Value":{"Int":1}}"#.to_string()];
+    for i in 0..40 {
+        entries.push(format!(r#"{{"Value":{{"List":[{},{}]}}}}"#, i, i));
+    }
+    entries.push(format!(r#"{{"Expr":[{{"Literal":40}},{}]}},{{"Block":[41]}}"#, span));
+    assert_eq!(
+        invalid(2, "uses the entry 1 twice"),
+        decode(&entries.join(","), 42)
+    );
+    let shared = Value::from(vec![Value::from(1)]);
+    let value = Value::from(vec![shared.clone(), shared]);
+    let json = serde_json::to_string(&value).unwrap();
+    assert_eq!(value, serde_json::from_str::<Value>(&json).unwrap());
+
+    let bytes = wire::to_bytes(&Interpreter::new().parse("1 + 2").unwrap());
+    assert!(matches!(
+        wire::from_bytes(b"{}"),
+        Err(DecodeError::NotAnAst)
+    ));
+    let mut newer = bytes.clone();
+    newer[4] = 2;
+    let version = Some(DecodeError::Version {
+        found: 2,
+        expected: 1,
+    });
+    assert_eq!(version, wire::from_bytes(&newer).err());
+    assert_eq!(
+        version,
+        wire::from_json(r#"{"version":2,"entries":"whatever"}"#).err()
+    );
+    for len in 5..bytes.len() {
+        assert!(matches!(
+            wire::from_bytes(&bytes[..len]),
+            Err(DecodeError::Malformed(_))
+        ));
+    }
+    let mut trailing = bytes;
+    trailing.push(0);
+    assert!(wire::from_bytes(&trailing).is_err());
+}
+
+#[test]
+fn batch_test() {
+    use batch::{Batch, Column};
+    use calculator_ast::{ExprList, NumericMode, Value};
+    use error::{BatchError, EvalError};
+    use interpreter::Interpreter;
+
+    let n = 40;
+    let ints: Vec<i64> = (0..n).map(|i| (i * 7) % 11 - 5).collect();
+    let floats: Vec<f64> = (0..n).map(|i| i as f64 * 0.75 - 9.0).collect();
+    let flags: Vec<bool> = (0..n).map(|i| i % 3 == 0).collect();
+    let names: Vec<Value> = (0..n)
+        .map(|i| match i % 4 {
+            0 => Value::from("a"),
+            1 => Value::I64(i),
+            2 => Value::F64(0.5),
+            _ => Value::Null,
+        })
+        .collect();
+    let columns = [
+        ("i", Column::from(ints)),
+        ("f", Column::from(floats)),
+        ("b", Column::from(flags)),
+        ("v", Column::from(names)),
+    ];
+    let batch = |mode| {
+        let mut batch = Batch::new(n as usize);
+        for (name, column) in columns.iter() {
+            batch.set_column(name, column.clone());
+        }
+        batch.set_var("k", 3).set_mode(mode);
+        batch
+    };
+    // The result of every row evaluated alone, the columns of the row are
+    // set before it and the sources assign their variables before reading.
+    let parser = Interpreter::new();
+    let rows = |list: &ExprList, mode| -> Vec<Value> {
+        let mut interp = Interpreter::new();
+        interp.set_var("k", 3).set_mode(mode);
+        (0..n as usize)
+            .map(|row| {
+                for (name, column) in columns.iter() {
+                    interp.set_var(name, column.get(row));
+                }
+                interp.eval_list(list).unwrap()
+            })
+            .collect()
+    };
+
+    let sources = [
+        "i * k + 1",
+        "f * 2 - i / 2",
+        "i / 2 + i % 3 - -i",
+        "+i * +f + +k",
+        "f > 0.0 && i != 0 || b",
+        "!b && i >= -2",
+        "i == f || f == 3.0",
+        "if i != 0 then { 10 / i } else { f }",
+        "if b then { x = i; y = 1 } else { x = f; y = 2 }; x * y",
+        "if i > 0 then { 1 } ; if k > 1 then { i } else { 1 / 0 }",
+        "i != 0 && 100 % i == 0",
+        "sqrt(abs(f)) + round(f) + floor(i) + max(i, f)",
+        "f ^ 2 + i ^ 2 + 2 ^ k",
+        "[i, f, v][i % 3]",
+        "v == \"a\" || v == null",
+        "x = 2.5; if f > x then { x = f }; x",
+        "1 m * i + 2 km",
+    ];
+    for src in sources.iter() {
+        let list = parser.parse(src).unwrap();
+        for mode in [NumericMode::Checked, NumericMode::Exact] {
+            let column = batch(mode).eval(&list).unwrap();
+            assert_eq!(rows(&list, mode), column.values(), "{}", src);
+        }
+    }
+    assert_eq!(
+        Column::I64(vec![5, 5, 5]),
+        Batch::new(3)
+            .eval(&parser.parse("2 + 3").unwrap())
+            .unwrap()
+    );
+    assert_eq!(
+        Column::F64(vec![0.5, 1.5]),
+        Column::from(vec![Value::F64(0.5), Value::F64(1.5)])
+    );
+
+    let error = |src: &str, mode| {
+        let list = parser.parse(src).unwrap();
+        batch(mode).eval(&list).unwrap_err()
+    };
+    // `i` is 0 at the row 7 first.
+    assert!(matches!(
+        error("f + k / i", NumericMode::Checked),
+        BatchError::Row {
+            row: 7,
+            error: EvalError::DivisionByZero { .. }
+        }
+    ));
+    assert!(matches!(
+        error(
+            "if f > 0.0 then { i * 9223372036854775807 } else { 0 }",
+            NumericMode::Checked
+        ),
+        BatchError::Row {
+            row: 13,
+            error: EvalError::Overflow { .. }
+        }
+    ));
+    let wide = parser.parse("i * 9223372036854775807 + 1").unwrap();
+    assert_eq!(
+        rows(&wide, NumericMode::Exact),
+        batch(NumericMode::Exact).eval(&wide).unwrap().values()
+    );
+    assert!(matches!(
+        error("if b then { z = 1 }; z", NumericMode::Checked),
+        BatchError::Row {
+            row: 1,
+            error: EvalError::UndefinedVariable { .. }
+        }
+    ));
+    assert!(matches!(
+        error("-v", NumericMode::Checked),
+        BatchError::Row { row: 0, .. }
+    ));
+    assert_eq!(
+        "cannot evaluate a loop in a batch",
+        error("while (i < 3) { i += 1 }", NumericMode::Checked).to_string()
+    );
+    assert_eq!(
+        "cannot evaluate a call of `g` in a batch",
+        error("if b then { g(1) } else { 0 }", NumericMode::Checked).to_string()
+    );
+    assert_eq!(
+        "cannot evaluate `map` in a batch",
+        error("map([1], fn(x) { x })", NumericMode::Checked).to_string()
+    );
+    let mut short = batch(NumericMode::Checked);
+    short.set_column("f", vec![1.0]);
+    assert_eq!(
+        "the column `f` has 1 rows but the batch has 40",
+        short
+            .eval(&parser.parse("f").unwrap())
+            .unwrap_err()
+            .to_string()
+    );
 }
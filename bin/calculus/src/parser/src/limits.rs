@@ -0,0 +1,195 @@
+//! Resource limits for evaluating the sources which are not trusted.
+//!
+//! The nesting of a source is checked on its tokens before it's parsed, so
+//! a deep tree which overflows the stack is never built. The rest of the
+//! limits are checked on the AST and by the `Environment` while evaluating.
+
+use std::fmt;
+use std::time::Duration;
+
+use crate::calculator_ast::{ExprList, Span};
+use crate::cst::{tokenize, TokenKind};
+use crate::error::EvalError;
+
+/// The limits of evaluating, `None` means no limit.
+///
+/// - `max_depth`: the nesting of the expressions and the blocks, both in
+///   the source and while evaluating it, so the recursions are limited.
+/// - `max_nodes`: the nodes of the AST of a source.
+/// - `max_steps`: the steps taken by an evaluation, see
+///   `Environment::tick`. The steps and the time are counted from the start
+///   of the outermost evaluation, a nested one shares its budget.
+/// - `timeout`: the wall-clock time of an evaluation.
+/// - `max_memory`: the bytes of a string or a list value, an item of a
+///   list takes the size of a `Value`.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Limits {
+    pub max_depth: Option<usize>,
+    pub max_nodes: Option<usize>,
+    pub max_steps: Option<u64>,
+    pub timeout: Option<Duration>,
+    pub max_memory: Option<usize>,
+}
+
+impl Limits {
+    /// The limits of a sandbox for the formulas, they are large enough for
+    /// the hand-written ones.
+    pub fn sandbox() -> Self {
+        Limits {
+            max_depth: Some(200),
+            max_nodes: Some(10_000),
+            max_steps: Some(1_000_000),
+            timeout: Some(Duration::from_secs(1)),
+            max_memory: Some(1 << 20),
+        }
+    }
+
+    /// Check the nesting of a source before parsing it.
+    ///
+    /// The nesting is estimated from the tokens: every operator or keyword
+    /// may nest the rest of its statement, and so may every bracket except
+    /// the parentheses of grouping. The estimate is never less than the
+    /// depth of the AST.
+    pub fn check_source(&self, source: &str) -> Result<(), EvalError> {
+        let exceeded = |limit, span| Err(EvalError::LimitExceeded { limit, span });
+
+        // The brackets not closed yet, the outermost one is the source.
+        let mut groups = vec![Group::new(0, 0, 1)];
+        // The bracket just closed, `f(x)[0]` nests the call in the index.
+        let mut closed = 0;
+        let mut after_name = false;
+        for token in tokenize(source) {
+            if token.kind.is_trivia() {
+                continue;
+            }
+            let text = &source[token.span.start..token.span.end];
+            let group = groups.len() - 1;
+            let after = std::mem::take(&mut closed);
+            let start = token.span.start;
+            match (token.kind, text) {
+                (TokenKind::Punct, "[") => groups.push(Group::new(start, after, 1)),
+                (TokenKind::Punct, "{") => groups.push(Group::new(start, 0, 1)),
+                // The parentheses nest nothing unless they are a call.
+                (TokenKind::Punct, "(") => groups.push(Group::new(start, 0, after_name as usize)),
+                (TokenKind::Punct, ")" | "]" | "}") if group > 0 => {
+                    let group = groups.pop().unwrap();
+                    let depth = group.depth().max(group.indexed) + group.nests;
+                    if let Some(limit) = self.max_depth {
+                        if depth > limit {
+                            let span = Span::new(group.start, token.span.end);
+                            return exceeded(Limit::Depth(limit), span);
+                        }
+                    }
+                    let parent = groups.last_mut().unwrap();
+                    parent.inner = parent.inner.max(depth);
+                    closed = depth;
+                }
+                (TokenKind::Punct, "," | ";") => groups[group].next(),
+                (TokenKind::Operator, "+=" | "-=" | "*=" | "/=") => groups[group].ops += 2,
+                (TokenKind::Operator | TokenKind::Keyword, _) => groups[group].ops += 1,
+                _ => {}
+            }
+            after_name = token.kind == TokenKind::Ident;
+        }
+
+        // The brackets not closed are a syntax error, the parser finds it.
+        // The source nests the leaves.
+        let depth = groups.iter().rev().fold(0, |inner, group| {
+            Group {
+                inner: group.inner.max(inner),
+                ..*group
+            }
+            .depth()
+                + group.nests
+        });
+        match self.max_depth {
+            Some(limit) if depth > limit => {
+                exceeded(Limit::Depth(limit), Span::new(0, source.len()))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Check the number of the nodes of a parsed source.
+    pub fn check_ast(&self, list: &ExprList) -> Result<(), EvalError> {
+        let limit = match self.max_nodes {
+            Some(limit) => limit,
+            None => return Ok(()),
+        };
+        let mut nodes = 0;
+        let mut stack: Vec<_> = list.0.iter().flatten().map(|expr| &**expr).collect();
+        while let Some(expr) = stack.pop() {
+            nodes += 1;
+            if nodes > limit {
+                return Err(EvalError::LimitExceeded {
+                    limit: Limit::Nodes(limit),
+                    span: expr.span,
+                });
+            }
+            stack.extend(expr.children());
+        }
+        Ok(())
+    }
+}
+
+// A bracket in the source, the parts separated by `,` and `;` don't nest
+// each other.
+#[derive(Clone, Copy)]
+struct Group {
+    start: usize,
+    // The bracket indexed by this one.
+    indexed: usize,
+    // The levels of the bracket itself.
+    nests: usize,
+    // The operators and the keywords of the current part.
+    ops: usize,
+    // The deepest bracket in the current part.
+    inner: usize,
+    // The deepest one of the parts before.
+    parts: usize,
+}
+
+impl Group {
+    fn new(start: usize, indexed: usize, nests: usize) -> Self {
+        Group {
+            start,
+            indexed,
+            nests,
+            ops: 0,
+            inner: 0,
+            parts: 0,
+        }
+    }
+
+    fn next(&mut self) {
+        self.parts = self.depth();
+        self.ops = 0;
+        self.inner = 0;
+    }
+
+    fn depth(&self) -> usize {
+        self.parts.max(self.ops + self.inner)
+    }
+}
+
+/// The limit exceeded, see `Limits`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Limit {
+    Depth(usize),
+    Nodes(usize),
+    Steps(u64),
+    Time(Duration),
+    Memory(usize),
+}
+
+impl fmt::Display for Limit {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Limit::Depth(n) => write!(f, "{} nested expressions", n),
+            Limit::Nodes(n) => write!(f, "{} nodes", n),
+            Limit::Steps(n) => write!(f, "{} steps", n),
+            Limit::Time(t) => write!(f, "{:?}", t),
+            Limit::Memory(n) => write!(f, "{} bytes", n),
+        }
+    }
+}
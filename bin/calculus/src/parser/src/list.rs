@@ -0,0 +1,71 @@
+//! The built-in functions taking a function value, `map`, `filter` and
+//! `reduce`.
+
+use std::rc::Rc;
+
+use crate::builtin::BuiltinFunc;
+use crate::calculator_ast::{call_function, Expr, FuncDef, Value};
+use crate::environment::Environment;
+use crate::error::{ArithError, EvalError, Result};
+
+/// Run `map`, `filter` or `reduce` with the evaluated arguments, `f` calls
+/// the function value with the arguments.
+///
+/// - `map(list, f)` makes a list of `f(v)` for every element.
+/// - `filter(list, f)` keeps the elements where `f(v)` is true.
+/// - `reduce(list, f, init)` folds the list by `acc = f(acc, v)`.
+pub fn run<F>(func: BuiltinFunc, args: &[Value], call: &Expr, mut f: F) -> Result<Value>
+where
+    F: FnMut(&Rc<FuncDef>, Vec<Value>) -> Result<Value>,
+{
+    let (list, callee) = match (&args[0], &args[1]) {
+        (Value::List(list), Value::Func(callee)) => (list, callee),
+        _ => return Err(ArithError::TypeMismatch.at(call)),
+    };
+    let argc = if func == BuiltinFunc::Reduce { 2 } else { 1 };
+    if callee.params.len() != argc {
+        return Err(EvalError::ArityMismatch {
+            name: callee.name.clone(),
+            expected: callee.params.len(),
+            found: argc,
+            span: call.span,
+        });
+    }
+
+    match func {
+        BuiltinFunc::Map => list
+            .iter()
+            .map(|v| f(callee, vec![v.clone()]))
+            .collect::<Result<Vec<_>>>()
+            .map(Value::from),
+        BuiltinFunc::Filter => {
+            let mut kept = Vec::new();
+            for v in list.iter() {
+                if f(callee, vec![v.clone()])?.as_bool() {
+                    kept.push(v.clone());
+                }
+            }
+            Ok(Value::from(kept))
+        }
+        BuiltinFunc::Reduce => list
+            .iter()
+            .try_fold(args[2].clone(), |acc, v| f(callee, vec![acc, v.clone()])),
+        _ => unreachable!(),
+    }
+}
+
+/// Evaluate `map`, `filter` or `reduce` with the tree-walker.
+pub(crate) fn eval(
+    func: BuiltinFunc,
+    args: &[Expr],
+    call: &Expr,
+    env: &mut Environment,
+) -> Result<Value> {
+    let values = args
+        .iter()
+        .map(|arg| arg.eval(env))
+        .collect::<Result<Vec<_>>>()?;
+    run(func, &values, call, |callee, values| {
+        call_function(env, callee, values, call.span)
+    })
+}
@@ -0,0 +1,186 @@
+//! Numeric integration and root finding, used by the `integrate` and
+//! `solve` built-in functions.
+
+use crate::builtin::BuiltinFunc;
+use crate::calculator_ast::{Expr, ExprKind, Value};
+use crate::environment::Environment;
+use crate::error::{EvalError, Result};
+
+// Absolute error wanted by `integrate`.
+const EPSILON: f64 = 1e-10;
+// Halving the intervals stops at this depth, it bounds the evaluations.
+const MAX_DEPTH: u32 = 20;
+const MAX_ITERATIONS: u32 = 100;
+
+/// The variable bound by `integrate(f, x, a, b)` or `solve(f, x, guess)`,
+/// the arity should be checked by the caller.
+pub fn variable<'a>(func: BuiltinFunc, args: &'a [Expr], call: &Expr) -> Result<&'a str> {
+    match args[1].kind {
+        ExprKind::VarRef(ref name) => Ok(name),
+        _ => Err(EvalError::ExpectedVariable {
+            name: func.name(),
+            span: call.span,
+        }),
+    }
+}
+
+/// Run `integrate` or `solve` with the evaluated arguments, `f` evaluates
+/// the expression at a point.
+pub fn run<F>(func: BuiltinFunc, args: &[f64], call: &Expr, f: F) -> Result<Value>
+where
+    F: FnMut(f64) -> Result<f64>,
+{
+    let v = match func {
+        BuiltinFunc::Integrate => integrate(f, args[0], args[1])?,
+        BuiltinFunc::Solve => solve(f, args[0])?,
+        _ => unreachable!(),
+    };
+    v.map(Value::F64).ok_or(EvalError::NoConvergence {
+        name: func.name(),
+        span: call.span,
+    })
+}
+
+/// Evaluate `integrate` or `solve` with the tree-walker, the variable is
+/// bound in a new scope while evaluating the expression.
+pub(crate) fn eval(
+    func: BuiltinFunc,
+    args: &[Expr],
+    call: &Expr,
+    env: &mut Environment,
+) -> Result<Value> {
+    let var = variable(func, args, call)?;
+    let values = args[2..]
+        .iter()
+        .map(|arg| arg.eval(env)?.numeric().map_err(|e| e.at(call)))
+        .collect::<Result<Vec<_>>>()?;
+
+    let body = &args[0];
+    run(func, &values, call, |x| {
+        env.tick(call.span)?;
+        env.push_scope();
+        env.define(var, Value::F64(x));
+        let v = body.eval(env);
+        env.pop_scope();
+        v?.numeric().map_err(|e| e.at(body))
+    })
+}
+
+/// The definite integral of `f` from `a` to `b` by adaptive Simpson's rule.
+/// Returns `None` if the estimate is not finite, like the integral of
+/// `1 / x` over a pole.
+pub fn integrate<F>(mut f: F, a: f64, b: f64) -> Result<Option<f64>>
+where
+    F: FnMut(f64) -> Result<f64>,
+{
+    let m = (a + b) / 2.0;
+    let (fa, fm, fb) = (f(a)?, f(m)?, f(b)?);
+    let whole = simpson(a, b, fa, fm, fb);
+    let v = adaptive(&mut f, a, b, fa, fm, fb, whole, EPSILON, MAX_DEPTH)?;
+    Ok(Some(v).filter(|v| v.is_finite()))
+}
+
+fn simpson(a: f64, b: f64, fa: f64, fm: f64, fb: f64) -> f64 {
+    (b - a) / 6.0 * (fa + 4.0 * fm + fb)
+}
+
+#[allow(clippy::too_many_arguments)]
+fn adaptive<F>(
+    f: &mut F,
+    a: f64,
+    b: f64,
+    fa: f64,
+    fm: f64,
+    fb: f64,
+    whole: f64,
+    eps: f64,
+    depth: u32,
+) -> Result<f64>
+where
+    F: FnMut(f64) -> Result<f64>,
+{
+    let m = (a + b) / 2.0;
+    let (lm, rm) = ((a + m) / 2.0, (m + b) / 2.0);
+    let (flm, frm) = (f(lm)?, f(rm)?);
+    let left = simpson(a, m, fa, flm, fm);
+    let right = simpson(m, b, fm, frm, fb);
+    let delta = left + right - whole;
+
+    if depth == 0 || delta.abs() <= 15.0 * eps || !delta.is_finite() {
+        return Ok(left + right + delta / 15.0);
+    }
+    Ok(adaptive(f, a, m, fa, flm, fm, left, eps / 2.0, depth - 1)?
+        + adaptive(f, m, b, fm, frm, fb, right, eps / 2.0, depth - 1)?)
+}
+
+/// A root of `f` near `guess` by Newton's method, falls back to bisection
+/// if Newton's method doesn't converge. Returns `None` if no root is found.
+pub fn solve<F>(mut f: F, guess: f64) -> Result<Option<f64>>
+where
+    F: FnMut(f64) -> Result<f64>,
+{
+    if let Some(x) = newton(&mut f, guess)? {
+        return Ok(Some(x));
+    }
+    bisection(&mut f, guess)
+}
+
+fn newton<F>(f: &mut F, guess: f64) -> Result<Option<f64>>
+where
+    F: FnMut(f64) -> Result<f64>,
+{
+    let mut x = guess;
+    for _ in 0..MAX_ITERATIONS {
+        let fx = f(x)?;
+        if fx == 0.0 {
+            return Ok(Some(x));
+        }
+        // The derivative by central difference.
+        let h = 1e-7 * x.abs().max(1.0);
+        let dfx = (f(x + h)? - f(x - h)?) / (2.0 * h);
+        let next = x - fx / dfx;
+        if !next.is_finite() {
+            return Ok(None);
+        }
+        if (next - x).abs() <= 1e-12 * next.abs().max(1.0) {
+            return Ok(Some(next));
+        }
+        x = next;
+    }
+    Ok(None)
+}
+
+fn bisection<F>(f: &mut F, guess: f64) -> Result<Option<f64>>
+where
+    F: FnMut(f64) -> Result<f64>,
+{
+    // Widen the interval around the guess until the sign changes.
+    let mut width = 1.0;
+    let bracket = loop {
+        let (a, b) = (guess - width, guess + width);
+        let (fa, fb) = (f(a)?, f(b)?);
+        if fa.is_finite() && fb.is_finite() && fa.signum() != fb.signum() {
+            break (a, b, fa);
+        }
+        width *= 2.0;
+        if !width.is_finite() || width > 1e12 {
+            return Ok(None);
+        }
+    };
+
+    let (mut a, mut b, mut fa) = bracket;
+    for _ in 0..200 {
+        let m = (a + b) / 2.0;
+        let fm = f(m)?;
+        if fm == 0.0 || (b - a) / 2.0 <= 1e-15 * m.abs().max(1.0) {
+            return Ok(Some(m));
+        }
+        if fm.signum() == fa.signum() {
+            a = m;
+            fa = fm;
+        } else {
+            b = m;
+        }
+    }
+    Ok(Some((a + b) / 2.0))
+}
@@ -0,0 +1,266 @@
+use std::collections::LinkedList;
+use std::rc::Rc;
+
+use crate::builtin::BuiltinFunc;
+use crate::calculator_ast::{
+    ControlFlow, Expr, ExprKind, ExprList, ForLoop, FuncDef, IfCondition, NumericMode, Opcode,
+    Value, WhileLoop,
+};
+use crate::error::ArithError;
+
+/// Simplify the statements, see `optimize`.
+///
+/// The branch taken by an `if` with a constant condition is spliced into
+/// the list, and the constant statements whose values are not used are
+/// dropped.
+pub fn optimize_list(list: &ExprList) -> ExprList {
+    let mut stmts = Vec::new();
+    for expr in list.0.iter().flatten() {
+        splice(optimize(expr), &mut stmts);
+    }
+
+    // Only the value of the last statement is used.
+    let last = stmts.len().saturating_sub(1);
+    let stmts: LinkedList<_> = stmts
+        .into_iter()
+        .enumerate()
+        .filter(|(i, expr)| *i == last || !matches!(expr.kind, ExprKind::Literal(_)))
+        .map(|(_, expr)| Rc::new(expr))
+        .collect();
+
+    if stmts.is_empty() {
+        ExprList(None)
+    } else {
+        ExprList(Some(stmts))
+    }
+}
+
+/// Simplify the expression, the result evaluates to the same value with
+/// the same errors:
+///
+/// - Constant subtrees are folded, unless evaluating them fails or the
+///   value depends on the `NumericMode`, so `7 / 2` is kept.
+/// - `x * 1`, `1 * x`, `x / 1`, `x + 0`, `0 + x` and `x - 0` become `x`.
+///   Only integer constants are removed, `x * 1.0` still makes a float,
+///   unless `x` always makes a float, like `sqrt(x) * 1.0` and
+///   `sqrt(x) ^ 1.0`.
+/// - `if` with a constant condition becomes the branch taken, and `while`
+///   with a constant false condition becomes `0`.
+/// - `false && x` becomes `false`, and `true || x` becomes `true`.
+///
+/// Note: `x * 1` fails if `x` is a `Bool`, but `x` doesn't.
+pub fn optimize(expr: &Expr) -> Expr {
+    let kind = match expr.kind {
+        ExprKind::Literal(_) | ExprKind::VarRef(_) => return expr.clone(),
+        ExprKind::OneOp(op, ref node) => {
+            let node = optimize(node);
+            if let ExprKind::Literal(ref n) = node.kind {
+                if let Some(v) = fold(|mode| op.apply_unary(n, mode)) {
+                    return Expr::new(ExprKind::Literal(v), expr.span);
+                }
+            }
+            ExprKind::OneOp(op, Box::new(node))
+        }
+        ExprKind::TwoOp(op, ref lnode, ref rnode) => {
+            let lnode = optimize(lnode);
+            // `false && x` and `true || x` don't evaluate `x`.
+            if let (Opcode::And | Opcode::Or, ExprKind::Literal(ref l)) = (op, &lnode.kind) {
+                if l.as_bool() == (op == Opcode::Or) {
+                    return Expr::new(ExprKind::Literal(Value::from_bool(l.as_bool())), expr.span);
+                }
+            }
+            let rnode = optimize(rnode);
+            if let (ExprKind::Literal(l), ExprKind::Literal(r)) = (&lnode.kind, &rnode.kind) {
+                if let Some(v) = fold(|mode| op.apply(l, r, mode)) {
+                    return Expr::new(ExprKind::Literal(v), expr.span);
+                }
+            }
+            match identity(op, &lnode, &rnode) {
+                Some(Keep::Left) => return lnode,
+                Some(Keep::Right) => return rnode,
+                None => ExprKind::TwoOp(op, Box::new(lnode), Box::new(rnode)),
+            }
+        }
+        ExprKind::Assign(ref name, ref rnode) => {
+            ExprKind::Assign(name.clone(), Box::new(optimize(rnode)))
+        }
+        ExprKind::FuncDef(ref func) => ExprKind::FuncDef(Rc::new(FuncDef {
+            name: func.name.clone(),
+            params: func.params.clone(),
+            body: optimize_list(&func.body),
+        })),
+        ExprKind::Call(ref name, ref args) => {
+            ExprKind::Call(name.clone(), args.iter().map(optimize).collect())
+        }
+        ExprKind::List(ref items) => ExprKind::List(items.iter().map(optimize).collect()),
+        ExprKind::Index(ref lnode, ref index) => {
+            ExprKind::Index(Box::new(optimize(lnode)), Box::new(optimize(index)))
+        }
+        ExprKind::Lambda(ref func) => ExprKind::Lambda(Rc::new(FuncDef {
+            name: func.name.clone(),
+            params: func.params.clone(),
+            body: optimize_list(&func.body),
+        })),
+        ExprKind::Builtin(func, ref args) => {
+            let args: Vec<_> = args.iter().map(optimize).collect();
+            if let Some(v) = fold_builtin(func, &args) {
+                return Expr::new(ExprKind::Literal(v), expr.span);
+            }
+            if let (BuiltinFunc::Pow, [base, exp]) = (func, &args[..]) {
+                if is_float_one(exp) && is_float(base) {
+                    return base.clone();
+                }
+            }
+            ExprKind::Builtin(func, args)
+        }
+        ExprKind::Flow(ref flow) => match flow {
+            ControlFlow::Condition(cond) => {
+                let if_cond = IfCondition {
+                    cond: Box::new(optimize(&cond.cond)),
+                    if_branch: optimize_list(&cond.if_branch),
+                    else_branch: cond.else_branch.as_ref().map(optimize_list),
+                };
+                if let Some(branch) = taken_branch(&if_cond) {
+                    // A branch of many statements is spliced by `optimize_list`.
+                    match branch.0.as_ref() {
+                        None => return Expr::new(ExprKind::Literal(Value::default()), expr.span),
+                        Some(stmts) if stmts.len() == 1 => {
+                            return stmts.front().unwrap().as_ref().clone()
+                        }
+                        _ => {}
+                    }
+                }
+                ExprKind::Flow(ControlFlow::Condition(if_cond))
+            }
+            ControlFlow::While(while_loop) => {
+                let cond = optimize(&while_loop.cond);
+                if let ExprKind::Literal(ref n) = cond.kind {
+                    if !n.as_bool() {
+                        return Expr::new(ExprKind::Literal(Value::default()), expr.span);
+                    }
+                }
+                ExprKind::Flow(ControlFlow::While(WhileLoop {
+                    cond: Box::new(cond),
+                    body: optimize_list(&while_loop.body),
+                }))
+            }
+            ControlFlow::For(for_loop) => {
+                let part = |part: &Option<Box<Expr>>| part.as_ref().map(|e| Box::new(optimize(e)));
+                ExprKind::Flow(ControlFlow::For(ForLoop {
+                    init: part(&for_loop.init),
+                    cond: part(&for_loop.cond),
+                    step: part(&for_loop.step),
+                    body: optimize_list(&for_loop.body),
+                }))
+            }
+            ControlFlow::Break | ControlFlow::Continue => return expr.clone(),
+        },
+    };
+    Expr::new(kind, expr.span)
+}
+
+// Push the optimized statement, an `if` with a constant condition is
+// replaced by the statements of the branch taken.
+fn splice(expr: Expr, stmts: &mut Vec<Expr>) {
+    if let ExprKind::Flow(ControlFlow::Condition(ref cond)) = expr.kind {
+        if let Some(branch) = taken_branch(cond) {
+            match branch.0.as_ref() {
+                Some(list) => stmts.extend(list.iter().map(|stmt| stmt.as_ref().clone())),
+                None => stmts.push(Expr::new(ExprKind::Literal(Value::default()), expr.span)),
+            }
+            return;
+        }
+    }
+    stmts.push(expr);
+}
+
+fn taken_branch(cond: &IfCondition) -> Option<&ExprList> {
+    const EMPTY: &ExprList = &ExprList(None);
+    match cond.cond.kind {
+        ExprKind::Literal(ref n) if n.as_bool() => Some(&cond.if_branch),
+        ExprKind::Literal(_) => Some(cond.else_branch.as_ref().unwrap_or(EMPTY)),
+        _ => None,
+    }
+}
+
+enum Keep {
+    Left,
+    Right,
+}
+
+// The operand to keep if the other one is an integer identity.
+fn identity(op: Opcode, lnode: &Expr, rnode: &Expr) -> Option<Keep> {
+    let is = |node: &Expr, i: i64| matches!(node.kind, ExprKind::Literal(Value::I64(n)) if n == i);
+    match op {
+        Opcode::Mul if is(rnode, 1) => Some(Keep::Left),
+        Opcode::Mul if is(lnode, 1) => Some(Keep::Right),
+        Opcode::Div if is(rnode, 1) => Some(Keep::Left),
+        Opcode::Add if is(rnode, 0) => Some(Keep::Left),
+        Opcode::Add if is(lnode, 0) => Some(Keep::Right),
+        Opcode::Sub if is(rnode, 0) => Some(Keep::Left),
+        Opcode::Mul if is_float_one(rnode) && is_float(lnode) => Some(Keep::Left),
+        Opcode::Mul if is_float_one(lnode) && is_float(rnode) => Some(Keep::Right),
+        _ => None,
+    }
+}
+
+fn is_float_one(node: &Expr) -> bool {
+    matches!(node.kind, ExprKind::Literal(Value::F64(f)) if f == 1.0)
+}
+
+// `print` is not folded, it has a side effect.
+fn fold_builtin(func: BuiltinFunc, args: &[Expr]) -> Option<Value> {
+    if func == BuiltinFunc::Print || !func.arity().accepts(args.len()) {
+        return None;
+    }
+    let values = args
+        .iter()
+        .map(|arg| match arg.kind {
+            ExprKind::Literal(ref n) => Some(n.clone()),
+            _ => None,
+        })
+        .collect::<Option<Vec<_>>>()?;
+    fold(|mode| func.call(&values, mode))
+}
+
+// The value is folded only if it's the same in all the numeric modes,
+// as the mode is only known when evaluating.
+fn fold<F>(f: F) -> Option<Value>
+where
+    F: Fn(NumericMode) -> Result<Value, ArithError>,
+{
+    let checked = f(NumericMode::Checked).ok()?;
+    let exact = f(NumericMode::Exact).ok()?;
+    // NaN is not equal to itself, but it doesn't depend on the mode.
+    let is_nan = |v: &Value| matches!(v, Value::F64(f) if f.is_nan());
+    (checked == exact || is_nan(&checked) && is_nan(&exact)).then_some(checked)
+}
+
+// Whether the expression always makes a float, or a quantity.
+pub(crate) fn is_float(expr: &Expr) -> bool {
+    match expr.kind {
+        ExprKind::Literal(ref n) => matches!(n, Value::F64(_)),
+        ExprKind::OneOp(Opcode::Sub, ref node) => is_float(node),
+        ExprKind::TwoOp(Opcode::Mul, ref l, ref r)
+        | ExprKind::TwoOp(Opcode::Div, ref l, ref r)
+        | ExprKind::TwoOp(Opcode::Add, ref l, ref r)
+        | ExprKind::TwoOp(Opcode::Sub, ref l, ref r) => is_float(l) || is_float(r),
+        ExprKind::Builtin(func, ref args) => match func {
+            BuiltinFunc::Pow => args.iter().any(is_float),
+            BuiltinFunc::Abs
+            | BuiltinFunc::Min
+            | BuiltinFunc::Max
+            | BuiltinFunc::Floor
+            | BuiltinFunc::Ceil
+            | BuiltinFunc::Round
+            | BuiltinFunc::Sum => args.iter().any(is_float),
+            BuiltinFunc::Print
+            | BuiltinFunc::Len
+            | BuiltinFunc::Map
+            | BuiltinFunc::Filter
+            | BuiltinFunc::Reduce => false,
+            _ => true,
+        },
+        _ => false,
+    }
+}
@@ -0,0 +1,875 @@
+use num_traits::Signed;
+
+use crate::builtin::BuiltinFunc;
+use crate::calculator_ast::{ControlFlow, Expr, ExprKind, ExprList, FuncDef, Opcode, Span, Value};
+use crate::cst::{SyntaxTree, TokenKind};
+
+/// Print the expression as source, it parses back to the same tree.
+///
+/// Only the parentheses required by the grammar are printed.
+pub fn pretty(expr: &Expr) -> String {
+    let mut printer = Printer::default();
+    printer.expr(expr, Prec::Assign);
+    printer.out
+}
+
+/// Print the statements separated by `; `.
+pub fn pretty_list(list: &ExprList) -> String {
+    let mut printer = Printer::default();
+    printer.list(list);
+    printer.out
+}
+
+/// Print the statements in the canonical layout of `calculus fmt`: one
+/// statement per line, and the blocks indented by four spaces. A block of
+/// a single statement without blocks stays on the line, like `{ x + 1 }`.
+pub fn format_list(list: &ExprList) -> String {
+    let mut printer = Printer {
+        multiline: true,
+        ..Printer::default()
+    };
+    printer.list(list);
+    printer.out
+}
+
+/// Format the source like `format_list`, and keep its comments.
+///
+/// A comment on its own line stays before the statement after it, and a
+/// comment after a statement on the same line stays after it. A block
+/// comment inside an expression stays before the expression after it, a
+/// line comment is moved after its statement. A block with comments is
+/// never on one line. The blank lines between the
+/// statements are kept, but not more than one.
+pub fn format_tree(tree: &SyntaxTree) -> String {
+    let tokens = tree.tokens();
+    let comments = tokens
+        .iter()
+        .filter(|token| token.kind.is_comment())
+        .map(|token| token.span)
+        .collect();
+    let elses = tokens
+        .iter()
+        .filter(|token| token.kind == TokenKind::Keyword && tree.text(token) == "else")
+        .map(|token| token.span.start)
+        .collect();
+    let mut printer = Printer {
+        multiline: true,
+        comments: Some(Comments {
+            source: tree.source(),
+            spans: comments,
+            next: 0,
+            pos: 0,
+            elses,
+        }),
+        ..Printer::default()
+    };
+    printer.list(tree.ast());
+    printer.trailing_comments(usize::MAX);
+    printer.rest_comments(usize::MAX);
+    printer.out
+}
+
+// Precedence levels of the grammar, from the loosest one.
+#[derive(Clone, Copy, PartialEq, PartialOrd)]
+enum Prec {
+    Assign,
+    Convert,
+    Or,
+    And,
+    Equal,
+    Compare,
+    Add,
+    Mul,
+    Unary,
+    Power,
+    Postfix,
+    Atom,
+}
+
+fn prec(expr: &Expr) -> Prec {
+    match expr.kind {
+        ExprKind::Assign(..) | ExprKind::Flow(_) | ExprKind::FuncDef(_) => Prec::Assign,
+        ExprKind::TwoOp(op, ..) => match op {
+            Opcode::Mul | Opcode::Div | Opcode::Rem => Prec::Mul,
+            Opcode::Add | Opcode::Sub => Prec::Add,
+            Opcode::Or => Prec::Or,
+            Opcode::And => Prec::And,
+            Opcode::Equal | Opcode::NotEqual => Prec::Equal,
+            _ => Prec::Compare,
+        },
+        ExprKind::OneOp(..) => Prec::Unary,
+        // `-3` is parsed back as a negation.
+        ExprKind::Literal(ref n) if is_negative(n) => Prec::Unary,
+        ExprKind::Index(..) => Prec::Postfix,
+        _ if conversion(expr).is_some() => Prec::Convert,
+        _ if power(expr).is_some() => Prec::Power,
+        _ => Prec::Atom,
+    }
+}
+
+// Whether the value is printed with a leading `-`.
+fn is_negative(n: &Value) -> bool {
+    match *n {
+        Value::I64(i) => i < 0 && i != i64::MIN,
+        Value::F64(f) => f.is_finite() && f.is_sign_negative(),
+        Value::Rational(ref r) => r.is_integer() && r.is_negative(),
+        Value::Quantity(ref q) => {
+            let symbol = q.unit.symbol();
+            q.value.is_finite() && q.value.is_sign_negative() && !symbol.contains(['*', '/'])
+        }
+        _ => false,
+    }
+}
+
+#[derive(Default)]
+struct Printer<'a> {
+    out: String,
+    // Blocks are broken into lines, see `format_list`.
+    multiline: bool,
+    indent: usize,
+    // The comments of the source, see `format_tree`.
+    comments: Option<Comments<'a>>,
+}
+
+struct Comments<'a> {
+    source: &'a str,
+    spans: Vec<Span>,
+    // The first comment not printed yet.
+    next: usize,
+    // Where the source printed so far ends.
+    pos: usize,
+    // The `else` keywords, they end the `then` blocks.
+    elses: Vec<usize>,
+}
+
+impl Printer<'_> {
+    // Write the expression where the grammar expects `min` or a tighter
+    // level.
+    fn expr(&mut self, expr: &Expr, min: Prec) {
+        if prec(expr) < min {
+            self.out.push('(');
+            self.expr(expr, Prec::Assign);
+            self.out.push(')');
+            return;
+        }
+        self.inline_comments(expr.span.start);
+
+        match expr.kind {
+            ExprKind::Literal(ref n) => self.value(n),
+            ExprKind::OneOp(op, ref node) => {
+                self.out.push_str(operator(op));
+                // `- -x` is not `--x`.
+                let start = self.out.len();
+                self.expr(node, Prec::Unary);
+                if op == Opcode::Sub && self.out[start..].starts_with('-') {
+                    self.out.insert(start, ' ');
+                }
+            }
+            ExprKind::TwoOp(op, ref lnode, ref rnode) => {
+                // All the binary operators are left associative.
+                let level = prec(expr);
+                let right = match level {
+                    Prec::Or => Prec::And,
+                    Prec::And => Prec::Equal,
+                    Prec::Equal => Prec::Compare,
+                    Prec::Compare => Prec::Add,
+                    Prec::Add => Prec::Mul,
+                    _ => Prec::Unary,
+                };
+                self.expr(lnode, level);
+                self.out.push_str(&format!(" {} ", operator(op)));
+                self.expr(rnode, right);
+            }
+            ExprKind::VarRef(ref name) => self.out.push_str(name),
+            ExprKind::Assign(ref name, ref rnode) => {
+                self.out.push_str(name);
+                self.out.push_str(" = ");
+                self.expr(rnode, Prec::Assign);
+            }
+            ExprKind::Call(ref name, ref args) => self.call(name, args),
+            ExprKind::Builtin(func, ref args) => match (conversion(expr), power(expr)) {
+                (Some((value, symbol)), _) => {
+                    self.expr(value, Prec::Convert);
+                    self.out.push_str(" in ");
+                    self.out.push_str(symbol);
+                }
+                (_, Some((base, exp))) => {
+                    // `3 km^2` is a quantity, the base is `(3 km)`.
+                    if let ExprKind::Literal(Value::Quantity(_)) = base.kind {
+                        self.out.push('(');
+                        self.expr(base, Prec::Assign);
+                        self.out.push(')');
+                    } else {
+                        self.expr(base, Prec::Postfix);
+                    }
+                    self.out.push_str(" ^ ");
+                    // It's right associative.
+                    self.expr(exp, Prec::Unary);
+                }
+                _ => self.call(func.name(), args),
+            },
+            ExprKind::FuncDef(ref func) => {
+                let head = format!("fn {}({}) ", func.name, func.params.join(", "));
+                self.out.push_str(&head);
+                self.block(&func.body, expr.span.end);
+            }
+            ExprKind::Lambda(ref func) => self.lambda(func, expr.span.end),
+            ExprKind::List(ref items) => self.items("[", items, "]"),
+            ExprKind::Index(ref lnode, ref index) => {
+                self.expr(lnode, Prec::Postfix);
+                self.out.push('[');
+                self.expr(index, Prec::Assign);
+                self.out.push(']');
+            }
+            ExprKind::Flow(ref flow) => self.flow(flow, expr.span.end),
+        }
+    }
+
+    // `end` is where the statement ends in the source.
+    fn flow(&mut self, flow: &ControlFlow, end: usize) {
+        match flow {
+            ControlFlow::Condition(cond) => {
+                self.out.push_str("if ");
+                self.expr(&cond.cond, Prec::Assign);
+                self.out.push_str(" then ");
+                match cond.else_branch.as_ref() {
+                    Some(branch) => {
+                        let then_end = self.then_end(&cond.if_branch, end);
+                        self.block(&cond.if_branch, then_end);
+                        self.out.push_str(" else ");
+                        self.block(branch, end);
+                    }
+                    None => self.block(&cond.if_branch, end),
+                }
+            }
+            ControlFlow::While(while_loop) => {
+                self.out.push_str("while ");
+                self.expr(&while_loop.cond, Prec::Assign);
+                self.out.push(' ');
+                self.block(&while_loop.body, end);
+            }
+            ControlFlow::For(for_loop) => {
+                self.out.push_str("for (");
+                let parts = [&for_loop.init, &for_loop.cond, &for_loop.step];
+                for (i, part) in parts.iter().enumerate() {
+                    if i > 0 {
+                        self.out.push_str("; ");
+                    }
+                    if let Some(part) = part {
+                        self.expr(part, Prec::Assign);
+                    }
+                }
+                self.out.push_str(") ");
+                self.block(&for_loop.body, end);
+            }
+            ControlFlow::Break => self.out.push_str("break"),
+            ControlFlow::Continue => self.out.push_str("continue"),
+        }
+    }
+
+    fn list(&mut self, list: &ExprList) {
+        let mut empty = true;
+        for expr in list.0.iter().flatten() {
+            if !empty {
+                self.out.push(';');
+                self.trailing_comments(expr.span.start);
+                self.newline();
+            }
+            self.leading_comments(expr.span.start);
+            self.expr(expr, Prec::Assign);
+            if let Some(comments) = self.comments.as_mut() {
+                comments.pos = comments.pos.max(expr.span.end);
+            }
+            empty = false;
+        }
+    }
+
+    // `end` is where the block ends in the source.
+    fn block(&mut self, list: &ExprList, end: usize) {
+        let inline = match list.0.as_ref() {
+            Some(stmts) => stmts.len() == 1 && !stmts.iter().any(|expr| has_block(expr)),
+            None => true,
+        } && self.comment_before(end).is_none();
+        if list.0.is_none() && self.comment_before(end).is_none() {
+            self.out.push_str("{}");
+            return;
+        }
+        if !self.multiline || inline {
+            self.out.push_str("{ ");
+            self.list(list);
+            self.out.push_str(" }");
+            return;
+        }
+
+        self.indent += 1;
+        self.out.push('{');
+        self.newline();
+        self.list(list);
+        self.trailing_comments(end);
+        self.rest_comments(end);
+        self.indent -= 1;
+        self.newline();
+        self.out.push('}');
+    }
+
+    // The next comment not printed if it's before `end`.
+    fn comment_before(&self, end: usize) -> Option<Span> {
+        let comments = self.comments.as_ref()?;
+        let span = *comments.spans.get(comments.next)?;
+        Some(span).filter(|span| span.start < end)
+    }
+
+    fn write_comment(&mut self, span: Span) {
+        let comments = self.comments.as_mut().unwrap();
+        self.out.push_str(&comments.source[span.start..span.end]);
+        comments.next += 1;
+        comments.pos = comments.pos.max(span.end);
+    }
+
+    // The comments before the statement at `start`, each on its own line.
+    fn leading_comments(&mut self, start: usize) {
+        while let Some(span) = self.comment_before(start) {
+            self.blank_line(span.start);
+            self.write_comment(span);
+            self.newline();
+        }
+        self.blank_line(start);
+        if let Some(comments) = self.comments.as_mut() {
+            comments.pos = comments.pos.max(start);
+        }
+    }
+
+    // The block comments before the expression at `start` in a statement,
+    // they stay on its line.
+    fn inline_comments(&mut self, start: usize) {
+        while let Some(span) = self.comment_before(start) {
+            let comments = self.comments.as_ref().unwrap();
+            if comments.source[span.start..].starts_with("//") {
+                break;
+            }
+            self.write_comment(span);
+            self.out.push(' ');
+        }
+    }
+
+    // The comments before `end` on the line of the last statement, and the
+    // ones left inside it which are not in its blocks.
+    fn trailing_comments(&mut self, end: usize) {
+        while let Some(span) = self.comment_before(end) {
+            let comments = self.comments.as_ref().unwrap();
+            if span.start > comments.pos && comments.source[comments.pos..span.start].contains('\n')
+            {
+                break;
+            }
+            self.out.push(' ');
+            self.write_comment(span);
+            let comments = self.comments.as_ref().unwrap();
+            if comments.source[span.start..].starts_with("//") {
+                break;
+            }
+        }
+    }
+
+    // The comments left before `end`, each on its own line.
+    fn rest_comments(&mut self, end: usize) {
+        while let Some(span) = self.comment_before(end) {
+            self.newline();
+            self.blank_line(span.start);
+            self.write_comment(span);
+        }
+    }
+
+    // Keep a blank line of the source before `start`, if the line being
+    // printed is empty and it's not the first one of a block.
+    fn blank_line(&mut self, start: usize) {
+        let comments = match self.comments.as_ref() {
+            Some(comments) if comments.pos < start => comments,
+            _ => return,
+        };
+        let lines: Vec<_> = comments.source[comments.pos..start].split('\n').collect();
+        if !lines[1..]
+            .iter()
+            .rev()
+            .skip(1)
+            .any(|line| line.trim().is_empty())
+        {
+            return;
+        }
+        if let Some(line) = self.out.rfind('\n') {
+            let before = self.out[..line].trim_end();
+            if self.out[line..].trim().is_empty() && !before.is_empty() && !before.ends_with('{') {
+                self.out.insert(line, '\n');
+            }
+        }
+    }
+
+    // Where the `then` block ends in the source, at the `else` after its
+    // last statement.
+    fn then_end(&self, branch: &ExprList, end: usize) -> usize {
+        let last = branch
+            .0
+            .iter()
+            .flatten()
+            .last()
+            .map_or(0, |expr| expr.span.end);
+        self.comments
+            .as_ref()
+            .and_then(|comments| comments.elses.iter().find(|&&pos| pos >= last))
+            .map_or(end, |&pos| pos)
+    }
+
+    // A line break in the multi-line layout, otherwise a space.
+    fn newline(&mut self) {
+        if self.multiline {
+            self.out.push('\n');
+            self.out.push_str(&"    ".repeat(self.indent));
+        } else {
+            self.out.push(' ');
+        }
+    }
+
+    fn call(&mut self, name: &str, args: &[Expr]) {
+        self.out.push_str(name);
+        self.items("(", args, ")");
+    }
+
+    fn items(&mut self, open: &str, items: &[Expr], close: &str) {
+        self.out.push_str(open);
+        for (i, item) in items.iter().enumerate() {
+            if i > 0 {
+                self.out.push_str(", ");
+            }
+            self.expr(item, Prec::Assign);
+        }
+        self.out.push_str(close);
+    }
+
+    fn lambda(&mut self, func: &FuncDef, end: usize) {
+        self.out
+            .push_str(&format!("fn({}) ", func.params.join(", ")));
+        self.block(&func.body, end);
+    }
+
+    // The values without a literal are printed as the expressions making
+    // them. A fraction is printed as a division, it's only the same value
+    // in the exact mode.
+    fn value(&mut self, n: &Value) {
+        match *n {
+            Value::I64(i64::MIN) => self.out.push_str("(-9223372036854775807 - 1)"),
+            Value::I64(i) => self.out.push_str(&i.to_string()),
+            Value::F64(f) if f.is_nan() => self.out.push_str("(0.0 / 0.0)"),
+            Value::F64(f) if f.is_infinite() && f > 0.0 => self.out.push_str("(1.0 / 0.0)"),
+            Value::F64(f) if f.is_infinite() => self.out.push_str("(-1.0 / 0.0)"),
+            // `Debug` keeps the fraction, `1.0` is not printed as `1`.
+            Value::F64(f) => self.out.push_str(&format!("{:?}", f)),
+            Value::Bool(b) => self.out.push_str(&b.to_string()),
+            Value::Str(ref s) => write_str(&mut self.out, s),
+            Value::Null => self.out.push_str("null"),
+            Value::List(ref list) => {
+                self.out.push('[');
+                for (i, v) in list.iter().enumerate() {
+                    if i > 0 {
+                        self.out.push_str(", ");
+                    }
+                    self.value(v);
+                }
+                self.out.push(']');
+            }
+            // It's not from the source, so there are no comments in it.
+            Value::Func(ref func) => self.lambda(func, 0),
+            Value::Rational(ref r) if r.is_integer() => self.out.push_str(&r.numer().to_string()),
+            Value::Rational(ref r) => {
+                self.out
+                    .push_str(&format!("({} / {})", r.numer(), r.denom()))
+            }
+            // `3.0 km`, or `(1.5 in m/s)` if the unit is not a single name.
+            Value::Quantity(ref q) => {
+                let symbol = q.unit.symbol();
+                if q.value.is_finite() && !symbol.contains(['*', '/']) {
+                    self.out.push_str(&format!("{:?} {}", q.value, symbol));
+                } else {
+                    self.out.push('(');
+                    self.value(&Value::F64(q.value));
+                    self.out.push_str(&format!(" in {})", symbol));
+                }
+            }
+        }
+    }
+}
+
+// `convert(x, 1 km)` is printed as `x in km`.
+fn conversion(expr: &Expr) -> Option<(&Expr, &str)> {
+    match expr.kind {
+        ExprKind::Builtin(BuiltinFunc::Convert, ref args) => match args[..] {
+            [ref value, Expr {
+                kind: ExprKind::Literal(Value::Quantity(ref q)),
+                ..
+            }] if q.value == 1.0 => Some((value, q.unit.symbol())),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+// `pow(x, y)` is printed as `x ^ y`.
+fn power(expr: &Expr) -> Option<(&Expr, &Expr)> {
+    match expr.kind {
+        ExprKind::Builtin(BuiltinFunc::Pow, ref args) => match args[..] {
+            [ref base, ref exp] => Some((base, exp)),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+// Whether printing the expression prints a block.
+fn has_block(expr: &Expr) -> bool {
+    match expr.kind {
+        ExprKind::Flow(ControlFlow::Break | ControlFlow::Continue) => false,
+        ExprKind::Flow(_) | ExprKind::FuncDef(_) | ExprKind::Lambda(_) => true,
+        ExprKind::Literal(ref n) => has_func(n),
+        ExprKind::VarRef(_) => false,
+        ExprKind::OneOp(_, ref node) | ExprKind::Assign(_, ref node) => has_block(node),
+        ExprKind::TwoOp(_, ref lnode, ref rnode) | ExprKind::Index(ref lnode, ref rnode) => {
+            has_block(lnode) || has_block(rnode)
+        }
+        ExprKind::Call(_, ref args) | ExprKind::Builtin(_, ref args) | ExprKind::List(ref args) => {
+            args.iter().any(has_block)
+        }
+    }
+}
+
+fn has_func(n: &Value) -> bool {
+    match n {
+        Value::Func(_) => true,
+        Value::List(list) => list.iter().any(has_func),
+        _ => false,
+    }
+}
+
+fn write_str(out: &mut String, s: &str) {
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\t' => out.push_str("\\t"),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+}
+
+fn operator(op: Opcode) -> &'static str {
+    match op {
+        Opcode::Mul => "*",
+        Opcode::Div => "/",
+        Opcode::Rem => "%",
+        Opcode::Add => "+",
+        Opcode::Sub => "-",
+        Opcode::Equal => "==",
+        Opcode::NotEqual => "!=",
+        Opcode::LargerOrEqual => ">=",
+        Opcode::LargerThan => ">",
+        Opcode::LessOrEqual => "<=",
+        Opcode::LessThan => "<",
+        Opcode::And => "&&",
+        Opcode::Or => "||",
+        Opcode::Not => "!",
+        Opcode::Assign | Opcode::Ref => unreachable!(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::LinkedList;
+    use std::rc::Rc;
+
+    use proptest::collection::vec;
+    use proptest::option;
+    use proptest::prelude::*;
+
+    use super::*;
+    use crate::calculator::ListParser;
+    use crate::calculator_ast::{ForLoop, IfCondition, WhileLoop};
+    use crate::cst::tokenize;
+    use crate::units::{Quantity, Unit};
+
+    fn node(kind: ExprKind) -> Expr {
+        Expr::new(kind, Span::default())
+    }
+
+    fn literal(v: impl Into<Value>) -> Expr {
+        node(ExprKind::Literal(v.into()))
+    }
+
+    fn list(stmts: Vec<Expr>) -> ExprList {
+        ExprList(Some(
+            stmts.into_iter().map(Rc::new).collect::<LinkedList<_>>(),
+        ))
+    }
+
+    fn func(name: &str, params: Vec<&str>, body: ExprList) -> Rc<FuncDef> {
+        Rc::new(FuncDef {
+            name: name.into(),
+            params: params.into_iter().map(String::from).collect(),
+            body,
+        })
+    }
+
+    fn var() -> impl Strategy<Value = &'static str> {
+        prop::sample::select(vec!["a", "x", "y2", "m", "total"])
+    }
+
+    fn params() -> impl Strategy<Value = Vec<&'static str>> {
+        prop::sample::subsequence(vec!["a", "b", "x"], 0..3)
+    }
+
+    fn unit() -> impl Strategy<Value = Unit> {
+        prop::sample::select(vec!["m", "km", "s", "h", "kg", "L"])
+            .prop_map(|name| Unit::from_name(name).unwrap())
+    }
+
+    // Units written in `x in unit`, they may be compound.
+    fn unit_expr() -> impl Strategy<Value = Unit> {
+        prop_oneof![
+            unit(),
+            (unit(), unit()).prop_map(|(u1, u2)| u1.div(&u2)),
+            (unit(), unit()).prop_map(|(u1, u2)| u1.mul(&u2)),
+            (unit(), 2..4i32).prop_map(|(u, n)| u.pow(n).unwrap()),
+            unit().prop_map(|u| u.pow(-1).unwrap()),
+        ]
+    }
+
+    fn binary_op() -> impl Strategy<Value = Opcode> {
+        prop::sample::select(vec![
+            Opcode::Mul,
+            Opcode::Div,
+            Opcode::Rem,
+            Opcode::Add,
+            Opcode::Sub,
+            Opcode::Equal,
+            Opcode::NotEqual,
+            Opcode::LargerOrEqual,
+            Opcode::LargerThan,
+            Opcode::LessOrEqual,
+            Opcode::LessThan,
+            Opcode::And,
+            Opcode::Or,
+        ])
+    }
+
+    // The expressions the parser makes, the literals are not negative.
+    fn expr() -> impl Strategy<Value = Expr> {
+        let leaf = prop_oneof![
+            (0..1000i64).prop_map(literal),
+            (0.0..1e6f64).prop_map(literal),
+            prop_oneof![Just(1e-7), Just(2.5e20)].prop_map(literal),
+            "[a-z \"\\\\\n\t]{0,4}".prop_map(literal),
+            any::<bool>().prop_map(literal),
+            Just(literal(Value::Null)),
+            (0.0..100f64, unit()).prop_map(|(v, u)| literal(Quantity::new(v, u))),
+            var().prop_map(|name| node(ExprKind::VarRef(name.into()))),
+        ];
+        leaf.prop_recursive(4, 32, 3, |inner| {
+            let boxed = || inner.clone().prop_map(Box::new);
+            let unary_op = prop_oneof![Just(Opcode::Sub), Just(Opcode::Add), Just(Opcode::Not)];
+            let unary = (unary_op, boxed()).prop_map(|(op, e)| node(ExprKind::OneOp(op, e)));
+            let binary = (binary_op(), boxed(), boxed())
+                .prop_map(|(op, l, r)| node(ExprKind::TwoOp(op, l, r)));
+            let assign =
+                (var(), boxed()).prop_map(|(name, e)| node(ExprKind::Assign(name.into(), e)));
+            let call = (prop_oneof![Just("f"), Just("g2")], vec(inner.clone(), 0..3))
+                .prop_map(|(name, args)| node(ExprKind::Call(name.into(), args)));
+            let builtin = prop_oneof![
+                vec(inner.clone(), 1..3).prop_map(|args| (BuiltinFunc::Max, args)),
+                inner.clone().prop_map(|e| (BuiltinFunc::Sqrt, vec![e])),
+                // `x ^ y`, and `pow(x)` with a wrong arity.
+                vec(inner.clone(), 1..3).prop_map(|args| (BuiltinFunc::Pow, args)),
+            ]
+            .prop_map(|(func, args)| node(ExprKind::Builtin(func, args)));
+            let convert = (inner.clone(), unit_expr()).prop_map(|(e, u)| {
+                let unit = literal(Quantity::new(1.0, u));
+                node(ExprKind::Builtin(BuiltinFunc::Convert, vec![e, unit]))
+            });
+            let items = vec(inner.clone(), 0..3).prop_map(|items| node(ExprKind::List(items)));
+            let index = (boxed(), boxed()).prop_map(|(l, i)| node(ExprKind::Index(l, i)));
+            let lambda = (params(), vec(statement(inner.clone()), 1..3).prop_map(list))
+                .prop_map(|(params, body)| node(ExprKind::Lambda(func("lambda", params, body))));
+
+            prop_oneof![
+                2 => unary,
+                6 => binary,
+                1 => assign,
+                1 => call,
+                1 => builtin,
+                1 => convert,
+                1 => items,
+                1 => index,
+                1 => lambda,
+            ]
+        })
+    }
+
+    // The control flow and the function definitions are statements only.
+    fn statement(expr: BoxedStrategy<Expr>) -> impl Strategy<Value = Expr> {
+        let block = || vec(expr.clone(), 1..3).prop_map(list);
+        let flow = |flow| node(ExprKind::Flow(flow));
+        let condition = (expr.clone(), block(), option::of(block())).prop_map(
+            move |(cond, if_branch, else_branch)| {
+                flow(ControlFlow::Condition(IfCondition {
+                    cond: Box::new(cond),
+                    if_branch,
+                    else_branch,
+                }))
+            },
+        );
+        let while_loop = (expr.clone(), block()).prop_map(move |(cond, body)| {
+            flow(ControlFlow::While(WhileLoop {
+                cond: Box::new(cond),
+                body,
+            }))
+        });
+        let part = || option::of(expr.clone().prop_map(Box::new));
+        let for_loop =
+            (part(), part(), part(), block()).prop_map(move |(init, cond, step, body)| {
+                flow(ControlFlow::For(ForLoop {
+                    init,
+                    cond,
+                    step,
+                    body,
+                }))
+            });
+        let func_def = (prop_oneof![Just("f"), Just("g2")], params(), block())
+            .prop_map(|(name, params, body)| node(ExprKind::FuncDef(func(name, params, body))));
+
+        prop_oneof![
+            4 => expr.clone(),
+            1 => condition,
+            1 => while_loop,
+            1 => for_loop,
+            1 => Just(flow(ControlFlow::Break)),
+            1 => Just(flow(ControlFlow::Continue)),
+            1 => func_def,
+        ]
+    }
+
+    fn program() -> impl Strategy<Value = ExprList> {
+        vec(statement(expr().boxed()), 1..4).prop_map(list)
+    }
+
+    fn parse(src: &str) -> ExprList {
+        match ListParser::new().parse(src) {
+            Ok(list) => list,
+            Err(e) => panic!("{:?} in {:?}", e, src),
+        }
+    }
+
+    proptest! {
+        #[test]
+        fn pretty_round_trip(list in program()) {
+            let src = pretty_list(&list);
+            let parsed = parse(&src);
+            prop_assert_eq!(&list, &parsed, "{}", src);
+            prop_assert_eq!(&src, &pretty_list(&parsed));
+        }
+
+        #[test]
+        fn format_round_trip(list in program()) {
+            let src = format_list(&list);
+            let parsed = parse(&src);
+            prop_assert_eq!(&list, &parsed, "{}", src);
+            prop_assert_eq!(&src, &format_list(&parsed));
+        }
+
+        // The comments are put between any tokens, they are all kept in
+        // the same order.
+        #[test]
+        fn format_tree_round_trip(
+            list in program(),
+            comments in vec((any::<prop::sample::Index>(), any::<bool>()), 1..5),
+        ) {
+            let src = format_list(&list);
+            let tokens = tokenize(&src);
+            let mut cuts: Vec<_> = comments
+                .iter()
+                .map(|(index, line)| (tokens[index.index(tokens.len())].span.end, *line))
+                .collect();
+            cuts.sort_by_key(|(pos, _)| *pos);
+
+            let mut commented = String::new();
+            let mut last = 0;
+            let mut texts = Vec::new();
+            for (i, (pos, line)) in cuts.into_iter().enumerate() {
+                commented.push_str(&src[last..pos]);
+                let text = match line {
+                    true => format!("// c{}", i),
+                    false => format!("/* c{} */", i),
+                };
+                commented.push_str(&format!(" {}{}", text, if line { "\n" } else { " " }));
+                texts.push(text);
+                last = pos;
+            }
+            commented.push_str(&src[last..]);
+
+            let tree = SyntaxTree::parse(&commented).unwrap();
+            let formatted = tree.format();
+            let parsed = SyntaxTree::parse(&formatted).unwrap();
+            prop_assert_eq!(&list, parsed.ast(), "{}", formatted);
+            let kept: Vec<_> = parsed
+                .tokens()
+                .into_iter()
+                .filter(|token| token.kind.is_comment())
+                .map(|token| parsed.text(token))
+                .collect();
+            prop_assert_eq!(&texts, &kept, "{}", formatted);
+            prop_assert_eq!(&formatted, &parsed.format());
+        }
+    }
+
+    #[test]
+    fn test_format() {
+        let src = "fn fib(n) { if n < 2 then { n } else { fib(n - 1) + fib(n - 2) } }; \
+                   xs = map([1, 2], fn(x) { y = x * 2; y }); while 1 { break }; - -x";
+        assert_eq!(
+            "fn fib(n) {\n    if n < 2 then { n } else { fib(n - 1) + fib(n - 2) }\n};\n\
+             xs = map([1, 2], fn(x) {\n    y = x * 2;\n    y\n});\n\
+             while 1 { break };\n\
+             - -x",
+            format_list(&parse(src))
+        );
+        assert_eq!(
+            "-(-3)[0] + !(a && b)",
+            pretty_list(&parse("-(-3)[0] + !(a && b)"))
+        );
+    }
+
+    #[test]
+    fn test_format_tree() {
+        let src = "// Fibonacci numbers.\n\
+                   fn fib(n) { // recursive\n\
+                   if n < 2 then { n } // small\n\
+                   else { fib(n - 1) + fib(n - 2) /* sum */ } };\n\n\n\
+                   x = 1 + /* one */ 2; y = x // trailing\n\
+                   // last";
+        assert_eq!(
+            "// Fibonacci numbers.\n\
+             fn fib(n) {\n    // recursive\n    if n < 2 then {\n        n // small\n    } else {\n        \
+             fib(n - 1) + fib(n - 2) /* sum */\n    }\n};\n\n\
+             x = 1 + /* one */ 2;\n\
+             y = x // trailing\n\
+             // last",
+            SyntaxTree::parse(src).unwrap().format()
+        );
+        // A block comment stays before the token after it, a line comment
+        // inside an expression is moved after its statement.
+        let format = |src: &str| SyntaxTree::parse(src).unwrap().format();
+        assert_eq!("a = 1 + /* x */ 2 // y", format("a=1+ /* x */ 2 // y"));
+        assert_eq!("a = 1 + /* x */ 2 // y", format("a = 1 + /* x */ 2 // y"));
+        assert_eq!(
+            "f(/* a */ 1, (/* b */ x - 1) * 2) // c",
+            format("f(/* a */ 1, (/* b */ x - 1) * 2 // c\n)")
+        );
+    }
+}
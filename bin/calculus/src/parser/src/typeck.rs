@@ -0,0 +1,812 @@
+//! A static type checker, it infers the types of the expressions and the
+//! variables before evaluating anything.
+//!
+//! Only the errors which are certain to be raised when the expression is
+//! evaluated are reported, so a program passing the check may still fail,
+//! like dividing by zero or adding quantities of different dimensions. The
+//! code skipped by a constant condition, like `false && x` or the branches
+//! of `if 0`, is never evaluated and its errors are not reported.
+//! The bodies of the functions are checked even if they are never called.
+
+use std::collections::{HashMap, HashSet};
+use std::fmt::Display;
+use std::marker::PhantomData;
+use std::rc::Rc;
+
+use crate::builtin::BuiltinFunc;
+use crate::calculator_ast::{ControlFlow, Expr, ExprKind, ExprList, FuncDef, Opcode, Value};
+use crate::environment::Environment;
+use crate::error::{ArithError, EvalError};
+use crate::numeric;
+
+/// The type of a value, `Number` is `Int` or `Float` and `Any` is unknown.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Type {
+    // Integers, and fractions in the exact mode.
+    Int,
+    Float,
+    Number,
+    Bool,
+    Str,
+    Null,
+    List,
+    Func,
+    Quantity,
+    Any,
+}
+
+impl Type {
+    pub fn of(v: &Value) -> Type {
+        match v {
+            Value::I64(_) | Value::Rational(_) => Type::Int,
+            Value::F64(_) => Type::Float,
+            Value::Bool(_) => Type::Bool,
+            Value::Str(_) => Type::Str,
+            Value::Null => Type::Null,
+            Value::List(_) => Type::List,
+            Value::Func(_) => Type::Func,
+            Value::Quantity(_) => Type::Quantity,
+        }
+    }
+
+    /// The type of a value which is of any of the types.
+    pub fn join(self, other: Type) -> Type {
+        match (self, other) {
+            (t1, t2) if t1 == t2 => t1,
+            (Type::Int | Type::Float | Type::Number, Type::Int | Type::Float | Type::Number) => {
+                Type::Number
+            }
+            _ => Type::Any,
+        }
+    }
+}
+
+impl Display for Type {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let name = match self {
+            Type::Int => "int",
+            Type::Float => "float",
+            Type::Number => "number",
+            Type::Bool => "bool",
+            Type::Str => "string",
+            Type::Null => "null",
+            Type::List => "list",
+            Type::Func => "function",
+            Type::Quantity => "quantity",
+            Type::Any => "any",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/// The types inferred by `check`, they are valid while the list is not
+/// changed.
+#[derive(Debug)]
+pub struct Types<'a> {
+    exprs: HashMap<*const Expr, Type>,
+    vars: Vec<(String, Type)>,
+    result: Type,
+    _list: PhantomData<&'a ExprList>,
+}
+
+impl Types<'_> {
+    /// The type of an expression of the list, `Any` for the others.
+    pub fn of(&self, expr: &Expr) -> Type {
+        self.exprs
+            .get(&(expr as *const Expr))
+            .copied()
+            .unwrap_or(Type::Any)
+    }
+
+    /// The global variables after the list is evaluated, sorted by name.
+    pub fn vars(&self) -> &[(String, Type)] {
+        &self.vars
+    }
+
+    /// The type of the value of the list.
+    pub fn result(&self) -> Type {
+        self.result
+    }
+}
+
+/// Check the list to be evaluated in `env`, the global variables and the
+/// functions of `env` are known. Returns all the errors found.
+pub fn check<'a>(list: &'a ExprList, env: &Environment) -> Result<Types<'a>, Vec<EvalError>> {
+    let mut checker = Checker {
+        env,
+        exprs: HashMap::new(),
+        errors: Vec::new(),
+        quiet: false,
+        functions: HashMap::new(),
+        globals: HashSet::new(),
+        returns: HashMap::new(),
+    };
+    checker.collect(list, true);
+
+    let mut main = Frame {
+        is_main: true,
+        ..Frame::default()
+    };
+    let result = checker.list(&mut main, list);
+    if !checker.errors.is_empty() {
+        return Err(checker.errors);
+    }
+
+    let mut vars: Vec<_> = main
+        .vars
+        .into_iter()
+        .map(|(name, v)| (name, v.ty))
+        .collect();
+    vars.sort_by(|a, b| a.0.cmp(&b.0));
+    Ok(Types {
+        exprs: checker.exprs,
+        vars,
+        result,
+        _list: PhantomData,
+    })
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct Var {
+    ty: Type,
+    // Assigned on all the paths.
+    definite: bool,
+}
+
+// Variables of the top level or a function call. A variable which is not
+// assigned on any path is missing.
+#[derive(Clone, Debug, Default, PartialEq)]
+struct Frame {
+    vars: HashMap<String, Var>,
+    loops: usize,
+    is_main: bool,
+}
+
+impl Frame {
+    fn assign(&mut self, name: &str, ty: Type) {
+        let var = Var { ty, definite: true };
+        self.vars.insert(name.into(), var);
+    }
+
+    // The variables after either of the paths.
+    fn join(&self, other: &Frame) -> Frame {
+        let mut frame = self.clone();
+        for var in frame.vars.values_mut() {
+            var.definite = false;
+        }
+        for (name, var) in other.vars.iter() {
+            match self.vars.get(name) {
+                Some(v) => frame.vars.insert(
+                    name.clone(),
+                    Var {
+                        ty: v.ty.join(var.ty),
+                        definite: v.definite && var.definite,
+                    },
+                ),
+                None => frame.vars.insert(
+                    name.clone(),
+                    Var {
+                        ty: var.ty,
+                        definite: false,
+                    },
+                ),
+            };
+        }
+        frame
+    }
+}
+
+struct Checker<'e> {
+    env: &'e Environment,
+    exprs: HashMap<*const Expr, Type>,
+    errors: Vec<EvalError>,
+    // The errors are not reported while looking for the types of a loop.
+    quiet: bool,
+    // The functions defined by the list, they are called by name.
+    functions: HashMap<String, Vec<Rc<FuncDef>>>,
+    // Variables assigned by the top level, the functions can read them.
+    globals: HashSet<String>,
+    // The types of the values of the functions, `Any` while checking it.
+    returns: HashMap<*const FuncDef, Type>,
+}
+
+impl Checker<'_> {
+    fn collect(&mut self, list: &ExprList, top: bool) {
+        for expr in list.0.iter().flatten() {
+            self.collect_expr(expr, top);
+        }
+    }
+
+    fn collect_expr(&mut self, expr: &Expr, top: bool) {
+        match expr.kind {
+            ExprKind::Literal(_) | ExprKind::VarRef(_) => {}
+            ExprKind::Assign(ref name, ref rnode) => {
+                if top {
+                    self.globals.insert(name.clone());
+                }
+                self.collect_expr(rnode, top);
+            }
+            ExprKind::FuncDef(ref func) => {
+                self.functions
+                    .entry(func.name.clone())
+                    .or_default()
+                    .push(func.clone());
+                self.collect(&func.body, false);
+            }
+            ExprKind::Lambda(ref func) => self.collect(&func.body, false),
+            ExprKind::OneOp(_, ref node) => self.collect_expr(node, top),
+            ExprKind::TwoOp(_, ref lnode, ref rnode) | ExprKind::Index(ref lnode, ref rnode) => {
+                self.collect_expr(lnode, top);
+                self.collect_expr(rnode, top);
+            }
+            ExprKind::Call(_, ref args)
+            | ExprKind::Builtin(_, ref args)
+            | ExprKind::List(ref args) => {
+                for arg in args {
+                    self.collect_expr(arg, top);
+                }
+            }
+            ExprKind::Flow(ref flow) => match flow {
+                ControlFlow::Condition(cond) => {
+                    self.collect_expr(&cond.cond, top);
+                    self.collect(&cond.if_branch, top);
+                    if let Some(branch) = cond.else_branch.as_ref() {
+                        self.collect(branch, top);
+                    }
+                }
+                ControlFlow::While(while_loop) => {
+                    self.collect_expr(&while_loop.cond, top);
+                    self.collect(&while_loop.body, top);
+                }
+                ControlFlow::For(for_loop) => {
+                    let parts = [&for_loop.init, &for_loop.cond, &for_loop.step];
+                    for part in parts.iter().copied().flatten() {
+                        self.collect_expr(part, top);
+                    }
+                    self.collect(&for_loop.body, top);
+                }
+                ControlFlow::Break | ControlFlow::Continue => {}
+            },
+        }
+    }
+
+    // Check the code which is never run, its errors are not reported.
+    fn quietly<T>(&mut self, check: impl FnOnce(&mut Self) -> T) -> T {
+        let quiet = std::mem::replace(&mut self.quiet, true);
+        let result = check(self);
+        self.quiet = quiet;
+        result
+    }
+
+    fn error(&mut self, err: EvalError) -> Type {
+        if !self.quiet {
+            self.errors.push(err);
+        }
+        Type::Any
+    }
+
+    fn list(&mut self, frame: &mut Frame, list: &ExprList) -> Type {
+        let mut ty = Type::Int;
+        for expr in list.0.iter().flatten() {
+            ty = self.expr(frame, expr);
+        }
+        ty
+    }
+
+    fn expr(&mut self, frame: &mut Frame, expr: &Expr) -> Type {
+        let ty = self.infer(frame, expr);
+        self.exprs.insert(expr as *const Expr, ty);
+        ty
+    }
+
+    fn infer(&mut self, frame: &mut Frame, expr: &Expr) -> Type {
+        let checked = |checker: &mut Self, ty: Result<Type, ArithError>| match ty {
+            Ok(ty) => ty,
+            Err(e) => checker.error(e.at(expr)),
+        };
+
+        match expr.kind {
+            ExprKind::Literal(ref v) => Type::of(v),
+            ExprKind::OneOp(op, ref node) => {
+                let ty = self.expr(frame, node);
+                match op {
+                    Opcode::Not => Type::Bool,
+                    _ => checked(self, neg(ty)),
+                }
+            }
+            ExprKind::TwoOp(op @ (Opcode::And | Opcode::Or), ref lnode, ref rnode) => {
+                self.expr(frame, lnode);
+                // The right side may be skipped, a constant left side which
+                // skips it is never followed by it.
+                let mut right = frame.clone();
+                match constant(lnode) {
+                    Some(left) if left == (op == Opcode::Or) => {
+                        self.quietly(|checker| checker.expr(&mut right, rnode));
+                    }
+                    Some(_) => {
+                        self.expr(frame, rnode);
+                    }
+                    None => {
+                        self.expr(&mut right, rnode);
+                        *frame = frame.join(&right);
+                    }
+                }
+                Type::Bool
+            }
+            ExprKind::TwoOp(op, ref lnode, ref rnode) => {
+                let l = self.expr(frame, lnode);
+                let r = self.expr(frame, rnode);
+                match op {
+                    Opcode::Add | Opcode::Sub | Opcode::Mul | Opcode::Div | Opcode::Rem => {
+                        checked(self, arith(op, l, r))
+                    }
+                    Opcode::Equal | Opcode::NotEqual => Type::Bool,
+                    _ => checked(self, compare(l, r)),
+                }
+            }
+            ExprKind::VarRef(ref name) => self.var(frame, name, expr),
+            ExprKind::Assign(ref name, ref rnode) => {
+                let ty = self.expr(frame, rnode);
+                frame.assign(name, ty);
+                ty
+            }
+            ExprKind::FuncDef(ref func) => {
+                self.function(func);
+                Type::Int
+            }
+            ExprKind::Lambda(ref func) => {
+                self.function(func);
+                Type::Func
+            }
+            ExprKind::Call(ref name, ref args) => self.call(frame, name, args, expr),
+            ExprKind::Builtin(func, ref args) => self.builtin(frame, func, args, expr),
+            ExprKind::List(ref items) => {
+                for item in items {
+                    self.expr(frame, item);
+                }
+                Type::List
+            }
+            ExprKind::Index(ref lnode, ref index) => {
+                let l = self.expr(frame, lnode);
+                let i = self.expr(frame, index);
+                checked(self, self::index(l, i))
+            }
+            ExprKind::Flow(ref flow) => self.flow(frame, flow, expr),
+        }
+    }
+
+    fn var(&mut self, frame: &Frame, name: &str, expr: &Expr) -> Type {
+        let local = frame.vars.get(name).copied();
+        if let Some(Var { ty, definite: true }) = local {
+            return ty;
+        }
+
+        // The top level reads the variables of `env`, the functions read
+        // the global variables as well.
+        let global = match self.env.get(name) {
+            _ if !frame.is_main && self.globals.contains(name) => Some(Type::Any),
+            Some(v) => Some(Type::of(&v)),
+            None => None,
+        };
+        match (local, global) {
+            (Some(local), Some(global)) => local.ty.join(global),
+            (Some(local), None) => local.ty,
+            (None, Some(global)) => global,
+            (None, None) => self.error(EvalError::UndefinedVariable {
+                name: name.into(),
+                span: expr.span,
+            }),
+        }
+    }
+
+    // Check the body of the function once, returns the type of its value.
+    fn function(&mut self, func: &Rc<FuncDef>) -> Type {
+        let key = Rc::as_ptr(func);
+        if let Some(ty) = self.returns.get(&key) {
+            return *ty;
+        }
+        // A recursive call is `Any`.
+        self.returns.insert(key, Type::Any);
+
+        let mut frame = Frame::default();
+        for param in func.params.iter() {
+            frame.assign(param, Type::Any);
+        }
+        // The body is checked once, the errors are always reported.
+        let quiet = std::mem::replace(&mut self.quiet, false);
+        let ty = self.list(&mut frame, &func.body);
+        self.quiet = quiet;
+
+        self.returns.insert(key, ty);
+        ty
+    }
+
+    fn call(&mut self, frame: &mut Frame, name: &str, args: &[Expr], expr: &Expr) -> Type {
+        // The functions of `env` are called until the list defines them.
+        let mut funcs = self.functions.get(name).cloned().unwrap_or_default();
+        let defined = funcs.len();
+        funcs.extend(self.env.function(name));
+        if funcs.is_empty() && self.env.native(name).is_none() {
+            return self.error(EvalError::UndefinedFunction {
+                name: name.into(),
+                span: expr.span,
+            });
+        }
+
+        // The arity is only known if all the definitions agree.
+        if let Some(func) = funcs.first() {
+            let expected = func.params.len();
+            if funcs.iter().all(|func| func.params.len() == expected) && args.len() != expected {
+                return self.error(EvalError::ArityMismatch {
+                    name: name.into(),
+                    expected,
+                    found: args.len(),
+                    span: expr.span,
+                });
+            }
+        }
+        for arg in args {
+            self.expr(frame, arg);
+        }
+        match funcs[..] {
+            [ref func] if defined == 1 => self.function(func),
+            _ => Type::Any,
+        }
+    }
+
+    fn builtin(
+        &mut self,
+        frame: &mut Frame,
+        func: BuiltinFunc,
+        args: &[Expr],
+        expr: &Expr,
+    ) -> Type {
+        let arity = func.arity();
+        if !arity.accepts(args.len()) {
+            return self.error(EvalError::ArityMismatch {
+                name: func.name().into(),
+                expected: arity.min(),
+                found: args.len(),
+                span: expr.span,
+            });
+        }
+        if func.is_numeric() {
+            return self.numeric(frame, func, args, expr);
+        }
+
+        let types: Vec<_> = args.iter().map(|arg| self.expr(frame, arg)).collect();
+        if func.is_higher_order() {
+            return self.higher_order(func, args, &types, expr);
+        }
+        match builtin(func, &types) {
+            Ok(ty) => ty,
+            Err(e) => self.error(e.at(expr)),
+        }
+    }
+
+    // `integrate` and `solve`, the variable is bound while checking the
+    // expression.
+    fn numeric(
+        &mut self,
+        frame: &mut Frame,
+        func: BuiltinFunc,
+        args: &[Expr],
+        expr: &Expr,
+    ) -> Type {
+        let var = match numeric::variable(func, args, expr) {
+            Ok(var) => var,
+            Err(e) => return self.error(e),
+        };
+        for arg in args[2..].iter() {
+            let ty = self.expr(frame, arg);
+            if let Err(e) = number(ty) {
+                return self.error(e.at(expr));
+            }
+        }
+
+        let mut scope = frame.clone();
+        scope.assign(var, Type::Float);
+        let body = &args[0];
+        let ty = self.expr(&mut scope, body);
+        if let Err(e) = number(ty) {
+            self.error(e.at(body));
+        }
+        // The variables defined by the expression are dropped with the
+        // scope, the others may be updated.
+        for (name, v) in frame.vars.iter_mut() {
+            match scope.vars.get(name) {
+                Some(updated) if name != var => v.ty = v.ty.join(updated.ty),
+                _ => {}
+            }
+        }
+        Type::Float
+    }
+
+    fn higher_order(
+        &mut self,
+        func: BuiltinFunc,
+        args: &[Expr],
+        types: &[Type],
+        expr: &Expr,
+    ) -> Type {
+        let list = matches!(types[0], Type::List | Type::Any);
+        let callee = matches!(types[1], Type::Func | Type::Any);
+        if !list || !callee {
+            return self.error(ArithError::TypeMismatch.at(expr));
+        }
+
+        let argc = if func == BuiltinFunc::Reduce { 2 } else { 1 };
+        if let ExprKind::Lambda(ref callee) = args[1].kind {
+            if callee.params.len() != argc {
+                return self.error(EvalError::ArityMismatch {
+                    name: callee.name.clone(),
+                    expected: callee.params.len(),
+                    found: argc,
+                    span: expr.span,
+                });
+            }
+        }
+        match func {
+            BuiltinFunc::Reduce => Type::Any,
+            _ => Type::List,
+        }
+    }
+
+    fn flow(&mut self, frame: &mut Frame, flow: &ControlFlow, expr: &Expr) -> Type {
+        match flow {
+            ControlFlow::Condition(cond) => {
+                self.expr(frame, &cond.cond);
+                let mut if_frame = frame.clone();
+                let mut else_frame = frame.clone();
+                let mut if_ty = |checker: &mut Self| checker.list(&mut if_frame, &cond.if_branch);
+                // A constant condition takes only one of the branches.
+                let taken = constant(&cond.cond);
+                let if_ty = match taken {
+                    Some(false) => self.quietly(if_ty),
+                    _ => if_ty(self),
+                };
+                let else_ty = match (cond.else_branch.as_ref(), taken) {
+                    (Some(branch), Some(true)) => {
+                        self.quietly(|checker| checker.list(&mut else_frame, branch))
+                    }
+                    (Some(branch), _) => self.list(&mut else_frame, branch),
+                    (None, _) => Type::Int,
+                };
+                match taken {
+                    Some(true) => {
+                        *frame = if_frame;
+                        if_ty
+                    }
+                    Some(false) => {
+                        *frame = else_frame;
+                        else_ty
+                    }
+                    None => {
+                        *frame = if_frame.join(&else_frame);
+                        if_ty.join(else_ty)
+                    }
+                }
+            }
+            ControlFlow::While(while_loop) => {
+                self.run_loop(frame, Some(&while_loop.cond), None, &while_loop.body)
+            }
+            ControlFlow::For(for_loop) => {
+                if let Some(init) = for_loop.init.as_ref() {
+                    self.expr(frame, init);
+                }
+                self.run_loop(
+                    frame,
+                    for_loop.cond.as_deref(),
+                    for_loop.step.as_deref(),
+                    &for_loop.body,
+                )
+            }
+            ControlFlow::Break | ControlFlow::Continue if frame.loops == 0 => {
+                let is_break = matches!(flow, ControlFlow::Break);
+                self.error(EvalError::OutsideLoop {
+                    keyword: if is_break { "break" } else { "continue" },
+                    span: expr.span,
+                })
+            }
+            ControlFlow::Break | ControlFlow::Continue => Type::Any,
+        }
+    }
+
+    // The variables assigned by an iteration are read by the next one, so
+    // the iterations are checked until the types don't change.
+    fn run_loop(
+        &mut self,
+        frame: &mut Frame,
+        cond: Option<&Expr>,
+        step: Option<&Expr>,
+        body: &ExprList,
+    ) -> Type {
+        frame.loops += 1;
+        let iteration = |checker: &mut Self, frame: &mut Frame| {
+            if let Some(cond) = cond {
+                checker.expr(frame, cond);
+            }
+            let ty = checker.list(frame, body);
+            if let Some(step) = step {
+                checker.expr(frame, step);
+            }
+            ty
+        };
+
+        let quiet = std::mem::replace(&mut self.quiet, true);
+        loop {
+            let mut next = frame.clone();
+            iteration(self, &mut next);
+            let joined = frame.join(&next);
+            if joined == *frame {
+                break;
+            }
+            *frame = joined;
+        }
+        self.quiet = quiet;
+
+        // The last iteration reports the errors.
+        let ty = iteration(self, &mut frame.clone());
+        frame.loops -= 1;
+        Type::Int.join(ty)
+    }
+}
+
+// The truth of a literal condition.
+fn constant(expr: &Expr) -> Option<bool> {
+    match expr.kind {
+        ExprKind::Literal(ref v) => Some(v.as_bool()),
+        _ => None,
+    }
+}
+
+fn number(ty: Type) -> Result<Type, ArithError> {
+    match ty {
+        Type::Int | Type::Float | Type::Number | Type::Any => Ok(ty),
+        Type::Quantity => Err(ArithError::DimensionMismatch),
+        _ => Err(ArithError::TypeMismatch),
+    }
+}
+
+fn neg(ty: Type) -> Result<Type, ArithError> {
+    match ty {
+        Type::Quantity => Ok(ty),
+        ty => number(ty),
+    }
+}
+
+// The same rules as `Opcode::apply`.
+fn arith(op: Opcode, l: Type, r: Type) -> Result<Type, ArithError> {
+    use Type::*;
+
+    let additive = matches!(op, Opcode::Add | Opcode::Sub | Opcode::Rem);
+    match (l, r) {
+        (Str, Str) | (List, List) if op == Opcode::Add => Ok(l),
+        (Any, Str | List) | (Str | List, Any) if op == Opcode::Add => Ok(Any),
+        (Str | Null | List | Func, _) | (_, Str | Null | List | Func) => {
+            Err(ArithError::TypeMismatch)
+        }
+        (Any, _) | (_, Any) => Ok(Any),
+        (Quantity, Quantity) if additive => Ok(Quantity),
+        // A dimensionless product is `Float`.
+        (Quantity, Quantity) => Ok(Any),
+        (Quantity, _) | (_, Quantity) if additive => Err(ArithError::DimensionMismatch),
+        (Bool, _) | (_, Bool) => Err(ArithError::TypeMismatch),
+        (Quantity, _) | (_, Quantity) => Ok(Quantity),
+        (Int, Int) => Ok(Int),
+        (Float, _) | (_, Float) => Ok(Float),
+        _ => Ok(Number),
+    }
+}
+
+// The same rules as `Value::checked_cmp`.
+fn compare(l: Type, r: Type) -> Result<Type, ArithError> {
+    use Type::*;
+
+    match (l, r) {
+        (Bool, Bool) | (Str, Str) | (Quantity, Quantity) | (Any, _) | (_, Any) => return Ok(Bool),
+        _ => {}
+    }
+    number(l)?;
+    number(r)?;
+    Ok(Bool)
+}
+
+fn index(l: Type, i: Type) -> Result<Type, ArithError> {
+    if !matches!(i, Type::Int | Type::Number | Type::Any) {
+        return Err(ArithError::TypeMismatch);
+    }
+    match l {
+        Type::Str => Ok(Type::Str),
+        Type::List | Type::Any => Ok(Type::Any),
+        _ => Err(ArithError::TypeMismatch),
+    }
+}
+
+// The same rules as `BuiltinFunc::call`.
+fn builtin(func: BuiltinFunc, args: &[Type]) -> Result<Type, ArithError> {
+    match func {
+        BuiltinFunc::Sqrt => match args[0] {
+            Type::Quantity | Type::Any => Ok(Type::Any),
+            ty => number(ty).map(|_| Type::Float),
+        },
+        BuiltinFunc::Exp
+        | BuiltinFunc::Ln
+        | BuiltinFunc::Log
+        | BuiltinFunc::Sin
+        | BuiltinFunc::Cos
+        | BuiltinFunc::Tan
+        | BuiltinFunc::Asin
+        | BuiltinFunc::Acos
+        | BuiltinFunc::Atan => number(args[0]).map(|_| Type::Float),
+        BuiltinFunc::Abs | BuiltinFunc::Floor | BuiltinFunc::Ceil | BuiltinFunc::Round => {
+            neg(args[0])
+        }
+        BuiltinFunc::Pow => match (args[0], args[1]) {
+            (Type::Quantity, Type::Int | Type::Number | Type::Any) | (Type::Any, _) => {
+                Ok(Type::Any)
+            }
+            (Type::Quantity, _) => Err(ArithError::DimensionMismatch),
+            (base, exp) => match (number(base)?, number(exp)?) {
+                (Type::Any, _) | (_, Type::Any) => Ok(Type::Any),
+                (Type::Float, _) | (_, Type::Float) => Ok(Type::Float),
+                // A negative exponent makes `Float`.
+                _ => Ok(Type::Number),
+            },
+        },
+        BuiltinFunc::Min | BuiltinFunc::Max => match args {
+            [Type::List | Type::Any] => Ok(Type::Any),
+            args => extremum(args),
+        },
+        BuiltinFunc::Sum => match args {
+            [Type::List | Type::Any] => Ok(Type::Any),
+            args => {
+                let zero = match args[0] {
+                    Type::Quantity => Type::Quantity,
+                    _ => Type::Int,
+                };
+                args.iter()
+                    .try_fold(zero, |acc, ty| arith(Opcode::Add, acc, *ty))
+            }
+        },
+        BuiltinFunc::Len => match args[0] {
+            Type::List | Type::Str | Type::Any => Ok(Type::Int),
+            _ => Err(ArithError::TypeMismatch),
+        },
+        BuiltinFunc::Print => Ok(*args.last().unwrap()),
+        BuiltinFunc::Convert => {
+            if !matches!(args[1], Type::Quantity | Type::Any) {
+                return Err(ArithError::TypeMismatch);
+            }
+            neg(args[0]).map(|_| Type::Quantity)
+        }
+        BuiltinFunc::Integrate
+        | BuiltinFunc::Solve
+        | BuiltinFunc::Map
+        | BuiltinFunc::Filter
+        | BuiltinFunc::Reduce => unreachable!(),
+    }
+}
+
+// The arguments are compared with the first one, a quantity can't be
+// compared with a number.
+fn extremum(args: &[Type]) -> Result<Type, ArithError> {
+    let mut result = args[0];
+    for ty in args {
+        if *ty == Type::Any {
+            return Ok(Type::Any);
+        }
+        if *ty != Type::Quantity {
+            number(*ty)?;
+        }
+        if (*ty == Type::Quantity) != (args[0] == Type::Quantity) {
+            return Err(ArithError::DimensionMismatch);
+        }
+        result = result.join(*ty);
+    }
+
+    match args.contains(&Type::Float) {
+        true => Ok(Type::Float),
+        false => Ok(result),
+    }
+}
@@ -0,0 +1,350 @@
+//! Units of measure, a `Quantity` is a magnitude in a `Unit`.
+//!
+//! Adding, subtracting and comparing quantities need the same dimension,
+//! the result is in the unit of the left side. Multiplying and dividing
+//! quantities make the SI base units, like `m/s`, and a dimensionless
+//! result is a plain `F64`. `x in km/h` converts a quantity, or reads a
+//! plain number in the unit.
+
+use std::fmt::Display;
+use std::ops::{Div, Mul};
+use std::rc::Rc;
+
+use crate::calculator_ast::{Opcode, Value};
+use crate::error::ArithError;
+
+// The symbols of the SI base units, in the order of `Dimension`.
+const BASE: [&str; 5] = ["kg", "m", "s", "A", "K"];
+
+const MASS: [i32; 5] = [1, 0, 0, 0, 0];
+const LENGTH: [i32; 5] = [0, 1, 0, 0, 0];
+const TIME: [i32; 5] = [0, 0, 1, 0, 0];
+
+// The registry of the units, with the scale to the SI base units.
+const UNITS: &[(&str, f64, [i32; 5])] = &[
+    ("m", 1.0, LENGTH),
+    ("km", 1e3, LENGTH),
+    ("cm", 1e-2, LENGTH),
+    ("mm", 1e-3, LENGTH),
+    ("mi", 1609.344, LENGTH),
+    ("ft", 0.3048, LENGTH),
+    ("kg", 1.0, MASS),
+    ("g", 1e-3, MASS),
+    ("mg", 1e-6, MASS),
+    ("t", 1e3, MASS),
+    ("lb", 0.453_592_37, MASS),
+    ("s", 1.0, TIME),
+    ("ms", 1e-3, TIME),
+    ("us", 1e-6, TIME),
+    ("min", 60.0, TIME),
+    ("h", 3600.0, TIME),
+    ("d", 86400.0, TIME),
+    ("A", 1.0, [0, 0, 0, 1, 0]),
+    ("K", 1.0, [0, 0, 0, 0, 1]),
+    ("Hz", 1.0, [0, 0, -1, 0, 0]),
+    ("N", 1.0, [1, 1, -2, 0, 0]),
+    ("J", 1.0, [1, 2, -2, 0, 0]),
+    ("W", 1.0, [1, 2, -3, 0, 0]),
+    ("Pa", 1.0, [1, -1, -2, 0, 0]),
+    ("L", 1e-3, [0, 3, 0, 0, 0]),
+];
+
+/// The exponents of the base dimensions: mass, length, time, current
+/// and temperature.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Dimension([i32; 5]);
+
+impl Dimension {
+    pub fn is_none(self) -> bool {
+        self == Dimension::default()
+    }
+
+    fn zip(self, other: Dimension, f: fn(i32, i32) -> i32) -> Dimension {
+        let mut exps = self.0;
+        for (e, o) in exps.iter_mut().zip(other.0.iter()) {
+            *e = f(*e, *o);
+        }
+        Dimension(exps)
+    }
+
+    /// `None` if an exponent overflows.
+    pub fn pow(self, n: i32) -> Option<Dimension> {
+        let mut exps = self.0;
+        for e in exps.iter_mut() {
+            *e = e.checked_mul(n)?;
+        }
+        Some(Dimension(exps))
+    }
+
+    /// `None` if an exponent is odd.
+    pub fn sqrt(self) -> Option<Dimension> {
+        let mut exps = self.0;
+        for e in exps.iter_mut() {
+            if *e % 2 != 0 {
+                return None;
+            }
+            *e /= 2;
+        }
+        Some(Dimension(exps))
+    }
+}
+
+impl Mul for Dimension {
+    type Output = Dimension;
+
+    fn mul(self, other: Dimension) -> Dimension {
+        self.zip(other, |a, b| a + b)
+    }
+}
+
+impl Div for Dimension {
+    type Output = Dimension;
+
+    fn div(self, other: Dimension) -> Dimension {
+        self.zip(other, |a, b| a - b)
+    }
+}
+
+/// The SI base units, like `kg*m/s^2`, or `s^-1` if there is no
+/// numerator.
+impl Display for Dimension {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let exps = || BASE.iter().zip(self.0.iter().copied());
+        let part = |symbol: &str, e: i32| match e {
+            1 => symbol.to_string(),
+            e => format!("{}^{}", symbol, e),
+        };
+
+        let numer: Vec<_> = exps()
+            .filter(|(_, e)| *e > 0)
+            .map(|(symbol, e)| part(symbol, e))
+            .collect();
+        if numer.is_empty() {
+            let parts: Vec<_> = exps()
+                .filter(|(_, e)| *e < 0)
+                .map(|(symbol, e)| part(symbol, e))
+                .collect();
+            return write!(f, "{}", parts.join("*"));
+        }
+        write!(f, "{}", numer.join("*"))?;
+        for (symbol, e) in exps().filter(|(_, e)| *e < 0) {
+            write!(f, "/{}", part(symbol, -e))?;
+        }
+        Ok(())
+    }
+}
+
+/// A unit, `scale` converts it to the SI base units.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Unit {
+    symbol: Rc<str>,
+    scale: f64,
+    dim: Dimension,
+}
+
+impl Unit {
+    /// A unit of the registry, like `km` or `ms`.
+    pub fn from_name(name: &str) -> Option<Unit> {
+        UNITS
+            .iter()
+            .find(|(symbol, ..)| *symbol == name)
+            .map(|(symbol, scale, dim)| Unit {
+                symbol: (*symbol).into(),
+                scale: *scale,
+                dim: Dimension(*dim),
+            })
+    }
+
+    /// The unit of a symbol, like `km/h` or `m*s^-2`. The symbols of all the
+    /// units made by the grammar and by `Unit::base` are read back.
+    pub fn parse(symbol: &str) -> Option<Unit> {
+        let power = |part: &str| match part.split_once('^') {
+            None => Unit::from_name(part),
+            Some((name, e)) => {
+                let digits = e.strip_prefix('-').unwrap_or(e);
+                if digits.is_empty() || !digits.bytes().all(|b| b.is_ascii_digit()) {
+                    return None;
+                }
+                Unit::from_name(name)?.pow(e.parse().ok()?)
+            }
+        };
+
+        let end = symbol.find(['*', '/']).unwrap_or(symbol.len());
+        let mut unit = power(&symbol[..end])?;
+        let mut rest = &symbol[end..];
+        while let Some(op) = rest.chars().next() {
+            let end = rest[1..].find(['*', '/']).map_or(rest.len(), |i| i + 1);
+            let p = power(&rest[1..end])?;
+            unit = if op == '*' {
+                unit.mul(&p)
+            } else {
+                unit.div(&p)
+            };
+            rest = &rest[end..];
+        }
+        Some(unit)
+    }
+
+    /// The SI base units of the dimension.
+    pub fn base(dim: Dimension) -> Unit {
+        Unit {
+            symbol: dim.to_string().into(),
+            scale: 1.0,
+            dim,
+        }
+    }
+
+    pub fn symbol(&self) -> &str {
+        &self.symbol
+    }
+
+    pub fn scale(&self) -> f64 {
+        self.scale
+    }
+
+    pub fn dimension(&self) -> Dimension {
+        self.dim
+    }
+
+    /// `a^n`, `None` if an exponent overflows.
+    pub fn pow(&self, n: i32) -> Option<Unit> {
+        Some(Unit {
+            symbol: format!("{}^{}", self.symbol, n).into(),
+            scale: self.scale.powi(n),
+            dim: self.dim.pow(n)?,
+        })
+    }
+
+    /// `a*b`
+    pub fn mul(&self, other: &Unit) -> Unit {
+        Unit {
+            symbol: format!("{}*{}", self.symbol, other.symbol).into(),
+            scale: self.scale * other.scale,
+            dim: self.dim * other.dim,
+        }
+    }
+
+    /// `a/b`
+    pub fn div(&self, other: &Unit) -> Unit {
+        Unit {
+            symbol: format!("{}/{}", self.symbol, other.symbol).into(),
+            scale: self.scale / other.scale,
+            dim: self.dim / other.dim,
+        }
+    }
+}
+
+/// A magnitude in a unit, `3 km` is 3 in `km`.
+#[derive(Clone, Debug)]
+pub struct Quantity {
+    pub value: f64,
+    pub unit: Unit,
+}
+
+impl Quantity {
+    pub fn new(value: f64, unit: Unit) -> Self {
+        Quantity { value, unit }
+    }
+
+    /// The magnitude in the SI base units.
+    pub fn si_value(&self) -> f64 {
+        self.value * self.unit.scale
+    }
+
+    pub fn dimension(&self) -> Dimension {
+        self.unit.dim
+    }
+
+    /// The same unit with another magnitude.
+    pub fn with_value(&self, value: f64) -> Self {
+        Quantity::new(value, self.unit.clone())
+    }
+
+    /// The quantity in another unit of the same dimension.
+    pub fn to(&self, unit: &Unit) -> Result<Quantity, ArithError> {
+        if self.dimension() != unit.dim {
+            return Err(ArithError::DimensionMismatch);
+        }
+        Ok(Quantity::new(self.si_value() / unit.scale, unit.clone()))
+    }
+}
+
+impl PartialEq for Quantity {
+    fn eq(&self, other: &Self) -> bool {
+        self.dimension() == other.dimension() && self.si_value() == other.si_value()
+    }
+}
+
+impl Display for Quantity {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{} {}", self.value, self.unit.symbol)
+    }
+}
+
+impl From<Quantity> for Value {
+    fn from(q: Quantity) -> Self {
+        Value::Quantity(Box::new(q))
+    }
+}
+
+/// A magnitude in the SI base units of the dimension, it's a plain
+/// `F64` if there is no dimension.
+pub fn from_si(value: f64, dim: Dimension) -> Value {
+    if dim.is_none() {
+        Value::F64(value)
+    } else {
+        Value::from(Quantity::new(value, Unit::base(dim)))
+    }
+}
+
+/// `+`, `-`, `*`, `/` and `%` where any of the sides is a quantity, the
+/// sides of `+`, `-` and `%` have the same dimension.
+pub(crate) fn arith(op: Opcode, l: &Value, r: &Value) -> Result<Value, ArithError> {
+    match (op, l, r) {
+        (Opcode::Add | Opcode::Sub | Opcode::Rem, Value::Quantity(q1), Value::Quantity(q2)) => {
+            let v2 = q2.to(&q1.unit)?.value;
+            Ok(Value::from(q1.with_value(match op {
+                Opcode::Add => q1.value + v2,
+                Opcode::Sub => q1.value - v2,
+                _ => q1.value % v2,
+            })))
+        }
+        (Opcode::Add | Opcode::Sub | Opcode::Rem, ..) => Err(ArithError::DimensionMismatch),
+        (Opcode::Mul, Value::Quantity(q1), Value::Quantity(q2)) => Ok(from_si(
+            q1.si_value() * q2.si_value(),
+            q1.dimension() * q2.dimension(),
+        )),
+        (Opcode::Div, Value::Quantity(q1), Value::Quantity(q2)) => Ok(from_si(
+            q1.si_value() / q2.si_value(),
+            q1.dimension() / q2.dimension(),
+        )),
+        (_, Value::Quantity(q), n) => {
+            let n = n.numeric()?;
+            Ok(Value::from(q.with_value(match op {
+                Opcode::Mul => q.value * n,
+                _ => q.value / n,
+            })))
+        }
+        (_, n, Value::Quantity(q)) => {
+            let n = n.numeric()?;
+            Ok(match op {
+                Opcode::Mul => Value::from(q.with_value(n * q.value)),
+                _ => from_si(n / q.si_value(), Dimension::default() / q.dimension()),
+            })
+        }
+        _ => unreachable!(),
+    }
+}
+
+/// `convert(x, unit)`, the unit of the second argument is taken. A plain
+/// number is read in the unit.
+pub(crate) fn convert(v: &Value, unit: &Value) -> Result<Value, ArithError> {
+    let unit = match unit {
+        Value::Quantity(q) => &q.unit,
+        _ => return Err(ArithError::TypeMismatch),
+    };
+    match v {
+        Value::Quantity(q) => Ok(Value::from(q.to(unit)?)),
+        v => Ok(Value::from(Quantity::new(v.numeric()?, unit.clone()))),
+    }
+}
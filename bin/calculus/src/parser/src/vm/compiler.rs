@@ -0,0 +1,541 @@
+use std::collections::HashMap;
+use std::rc::Rc;
+
+use crate::builtin::BuiltinFunc;
+use crate::calculator_ast::{ControlFlow, Expr, ExprKind, ExprList, FuncDef, Opcode, Span, Value};
+use crate::environment::Environment;
+use crate::error::EvalError;
+use crate::numeric;
+use crate::typeck::{Type, Types};
+
+use super::{Function, Op, Program};
+
+/// Compile the list into a program, the functions of `env` called by
+/// the program are compiled too.
+///
+/// Variables are resolved to slots: the top level code only uses global
+/// slots; inside a function, the parameters and the variables assigned by
+/// the function are local slots, the others are global slots. Reading a
+/// local slot which is not assigned yet falls back to the global one,
+/// which is the same as looking up the `Environment`. The variables bound
+/// by `integrate` and `solve` get local slots, in the top level as well.
+///
+/// With the `types` of a checked list, the arithmetic and the comparing of
+/// two floats skip the checks of the other types.
+pub fn compile(list: &ExprList, env: Option<&Environment>, types: Option<&Types>) -> Program {
+    let mut compiler = Compiler {
+        types,
+        ..Compiler::default()
+    };
+    let mut main = Chunk::default();
+    compiler.list(&mut main, list);
+    main.code.push(Op::Return);
+    compiler.program.main_locals = main.slots;
+
+    if let Some(env) = env {
+        for (_, v) in env.globals() {
+            compiler.lambdas_in(&v);
+        }
+    }
+
+    // Compiling a function may call more functions.
+    let mut slot = 0;
+    while let Some(env) = env {
+        let name = match compiler.program.func_names.get(slot) {
+            Some(name) => name.clone(),
+            None => break,
+        };
+        if let Some(func) = env.function(&name) {
+            let index = compiler.function(&func);
+            compiler.program.functions[index as usize].linked = true;
+        }
+        slot += 1;
+    }
+
+    let mut program = compiler.program;
+    program.main = main.code;
+    program
+}
+
+#[derive(Default)]
+struct Compiler<'t> {
+    program: Program,
+    global_slots: HashMap<String, u32>,
+    func_slots: HashMap<String, u32>,
+    types: Option<&'t Types<'t>>,
+}
+
+// Targets of the jumps in a loop, they are patched when the loop is done.
+#[derive(Default)]
+struct LoopLabels {
+    breaks: Vec<usize>,
+    continues: Vec<usize>,
+}
+
+// Code of the main program or a function.
+#[derive(Default)]
+struct Chunk {
+    code: Vec<Op>,
+    loops: Vec<LoopLabels>,
+    // Local slots, the parameters are the first ones.
+    locals: HashMap<String, u32>,
+    params: usize,
+    slots: u32,
+}
+
+impl Chunk {
+    fn emit(&mut self, op: Op) -> usize {
+        self.code.push(op);
+        self.code.len() - 1
+    }
+
+    fn patch(&mut self, pos: usize) {
+        let target = self.code.len() as u32;
+        match &mut self.code[pos] {
+            Op::Jump(to) | Op::JumpIfFalse(to) => *to = target,
+            _ => unreachable!(),
+        }
+    }
+}
+
+impl Compiler<'_> {
+    fn list(&mut self, chunk: &mut Chunk, list: &ExprList) {
+        let mut empty = true;
+        for expr in list.0.iter().flatten() {
+            if !empty {
+                chunk.emit(Op::Pop);
+            }
+            self.expr(chunk, expr);
+            empty = false;
+        }
+        if empty {
+            self.constant(chunk, Value::default());
+        }
+    }
+
+    fn expr(&mut self, chunk: &mut Chunk, expr: &Expr) {
+        match expr.kind {
+            ExprKind::Literal(ref n) => self.constant(chunk, n.clone()),
+            ExprKind::OneOp(op, ref node) => {
+                self.expr(chunk, node);
+                let site = self.site(expr);
+                chunk.emit(Op::Unary(op, site));
+            }
+            ExprKind::TwoOp(op @ (Opcode::And | Opcode::Or), ref lnode, ref rnode) => {
+                // The right side is skipped if the left one decides the result.
+                self.expr(chunk, lnode);
+                let to_false = chunk.emit(Op::JumpIfFalse(0));
+                if op == Opcode::Or {
+                    self.constant(chunk, Value::Bool(true));
+                    let to_end = chunk.emit(Op::Jump(0));
+                    chunk.patch(to_false);
+                    self.expr(chunk, rnode);
+                    chunk.emit(Op::ToBool);
+                    chunk.patch(to_end);
+                } else {
+                    self.expr(chunk, rnode);
+                    chunk.emit(Op::ToBool);
+                    let to_end = chunk.emit(Op::Jump(0));
+                    chunk.patch(to_false);
+                    self.constant(chunk, Value::Bool(false));
+                    chunk.patch(to_end);
+                }
+            }
+            ExprKind::TwoOp(op, ref lnode, ref rnode) => {
+                self.expr(chunk, lnode);
+                self.expr(chunk, rnode);
+                let site = self.site(expr);
+                let floats = self.types.is_some_and(|types| {
+                    types.of(lnode) == Type::Float && types.of(rnode) == Type::Float
+                });
+                match floats {
+                    true => chunk.emit(Op::FloatBinary(op, site)),
+                    false => chunk.emit(Op::Binary(op, site)),
+                };
+            }
+            ExprKind::VarRef(ref name) => self.load(chunk, name, expr.span),
+            ExprKind::Assign(ref name, ref rnode) => {
+                self.expr(chunk, rnode);
+                self.store(chunk, name);
+            }
+            ExprKind::FuncDef(ref func) => {
+                let index = self.function(func);
+                chunk.emit(Op::DefineFn(index));
+                self.constant(chunk, Value::default());
+            }
+            ExprKind::Call(ref name, ref args) => {
+                let func = self.func_slot(name);
+                let argc = args.len() as u32;
+                let span = self.span(expr.span);
+                // The function is resolved before evaluating the arguments.
+                chunk.emit(Op::Resolve { func, argc, span });
+                for arg in args {
+                    self.expr(chunk, arg);
+                }
+                chunk.emit(Op::Call { argc, span });
+            }
+            ExprKind::Builtin(func, ref args) => {
+                let arity = func.arity();
+                if !arity.accepts(args.len()) {
+                    self.fail(
+                        chunk,
+                        EvalError::ArityMismatch {
+                            name: func.name().into(),
+                            expected: arity.min(),
+                            found: args.len(),
+                            span: expr.span,
+                        },
+                    );
+                    return;
+                }
+                if func.is_numeric() {
+                    self.numeric(chunk, expr, func, args);
+                    return;
+                }
+                for arg in args {
+                    self.expr(chunk, arg);
+                }
+                let site = self.site(expr);
+                chunk.emit(Op::Builtin {
+                    func,
+                    argc: args.len() as u32,
+                    site,
+                });
+            }
+            ExprKind::List(ref items) => {
+                for item in items {
+                    self.expr(chunk, item);
+                }
+                chunk.emit(Op::MakeList(items.len() as u32));
+            }
+            ExprKind::Index(ref lnode, ref index) => {
+                self.expr(chunk, lnode);
+                self.expr(chunk, index);
+                let site = self.site(expr);
+                chunk.emit(Op::Index(site));
+            }
+            ExprKind::Lambda(ref func) => {
+                self.lambda(func);
+                self.constant(chunk, Value::Func(func.clone()));
+            }
+            ExprKind::Flow(ref flow) => self.flow(chunk, flow, expr.span),
+        }
+    }
+
+    fn flow(&mut self, chunk: &mut Chunk, flow: &ControlFlow, span: Span) {
+        match flow {
+            ControlFlow::Condition(cond) => {
+                self.expr(chunk, &cond.cond);
+                let to_else = chunk.emit(Op::JumpIfFalse(0));
+                self.list(chunk, &cond.if_branch);
+                let to_end = chunk.emit(Op::Jump(0));
+                chunk.patch(to_else);
+                match cond.else_branch.as_ref() {
+                    Some(branch) => self.list(chunk, branch),
+                    None => self.constant(chunk, Value::default()),
+                }
+                chunk.patch(to_end);
+            }
+            ControlFlow::While(while_loop) => {
+                self.run_loop(chunk, Some(&while_loop.cond), None, &while_loop.body, span)
+            }
+            ControlFlow::For(for_loop) => {
+                if let Some(init) = for_loop.init.as_ref() {
+                    self.expr(chunk, init);
+                    chunk.emit(Op::Pop);
+                }
+                self.run_loop(
+                    chunk,
+                    for_loop.cond.as_deref(),
+                    for_loop.step.as_deref(),
+                    &for_loop.body,
+                    span,
+                )
+            }
+            ControlFlow::Break | ControlFlow::Continue => {
+                let is_break = matches!(flow, ControlFlow::Break);
+                if chunk.loops.is_empty() {
+                    let keyword = if is_break { "break" } else { "continue" };
+                    self.fail(chunk, EvalError::OutsideLoop { keyword, span });
+                    return;
+                }
+
+                let jump = chunk.emit(Op::Jump(0));
+                let labels = chunk.loops.last_mut().unwrap();
+                if is_break {
+                    labels.breaks.push(jump);
+                } else {
+                    labels.continues.push(jump);
+                }
+            }
+        }
+    }
+
+    // The value of the last iteration is kept on the stack, `break` and
+    // `continue` jump with only this value on the stack of the loop.
+    fn run_loop(
+        &mut self,
+        chunk: &mut Chunk,
+        cond: Option<&Expr>,
+        step: Option<&Expr>,
+        body: &ExprList,
+        span: Span,
+    ) {
+        self.constant(chunk, Value::default());
+        let start = chunk.code.len() as u32;
+        let to_end = cond.map(|cond| {
+            self.expr(chunk, cond);
+            chunk.emit(Op::JumpIfFalse(0))
+        });
+        let span = self.span(span);
+        chunk.emit(Op::Tick(span));
+
+        chunk.loops.push(LoopLabels::default());
+        self.list(chunk, body);
+        chunk.emit(Op::Replace);
+        let labels = chunk.loops.pop().unwrap();
+
+        for jump in labels.continues {
+            chunk.patch(jump);
+        }
+        if let Some(step) = step {
+            self.expr(chunk, step);
+            chunk.emit(Op::Pop);
+        }
+        chunk.emit(Op::Jump(start));
+
+        if let Some(to_end) = to_end {
+            chunk.patch(to_end);
+        }
+        for jump in labels.breaks {
+            chunk.patch(jump);
+        }
+    }
+
+    // The arguments after the variable are pushed, then the expression is
+    // compiled inline with the variable bound to a new local slot. The VM
+    // runs the expression to `EndBody` for every point, then jumps over it.
+    fn numeric(&mut self, chunk: &mut Chunk, expr: &Expr, func: BuiltinFunc, args: &[Expr]) {
+        let var = match numeric::variable(func, args, expr) {
+            Ok(var) => var,
+            Err(e) => return self.fail(chunk, e),
+        };
+        for arg in &args[2..] {
+            self.expr(chunk, arg);
+        }
+
+        let local = chunk.slots;
+        chunk.slots += 1;
+        let shadowed = chunk.locals.insert(var.into(), local);
+        let span = self.span(expr.span);
+        let call = self.site(expr);
+        let body = self.site(&args[0]);
+        let pos = chunk.emit(Op::Numeric {
+            func,
+            local,
+            span,
+            call,
+            body,
+            end: 0,
+        });
+        self.expr(chunk, &args[0]);
+        chunk.emit(Op::EndBody);
+
+        let target = chunk.code.len() as u32;
+        if let Op::Numeric { end, .. } = &mut chunk.code[pos] {
+            *end = target;
+        }
+        match shadowed {
+            Some(slot) => chunk.locals.insert(var.into(), slot),
+            None => chunk.locals.remove(var),
+        };
+    }
+
+    fn function(&mut self, func: &Rc<FuncDef>) -> u32 {
+        let mut chunk = Chunk {
+            params: func.params.len(),
+            ..Chunk::default()
+        };
+        let mut assigned = Vec::new();
+        assigned_names(&func.body, &mut assigned);
+        for name in func.params.iter().cloned().chain(assigned) {
+            if !chunk.locals.contains_key(&name) {
+                chunk.locals.insert(name, chunk.slots);
+                chunk.slots += 1;
+            }
+        }
+        self.list(&mut chunk, &func.body);
+        chunk.emit(Op::Return);
+
+        let slot = self.func_slot(&func.name);
+        self.program.functions.push(Function {
+            slot,
+            params: func.params.len() as u32,
+            locals: chunk.slots,
+            code: chunk.code,
+            def: func.clone(),
+            linked: false,
+        });
+        self.program.functions.len() as u32 - 1
+    }
+
+    fn lambda(&mut self, func: &Rc<FuncDef>) {
+        let program = &self.program;
+        let compiled = program
+            .lambdas
+            .iter()
+            .any(|index| Rc::ptr_eq(&program.functions[*index as usize].def, func));
+        if !compiled {
+            let index = self.function(func);
+            self.program.lambdas.push(index);
+        }
+    }
+
+    // The function values of `env` may be called by the program.
+    fn lambdas_in(&mut self, v: &Value) {
+        match v {
+            Value::Func(func) => self.lambda(func),
+            Value::List(list) => list.iter().for_each(|v| self.lambdas_in(v)),
+            _ => {}
+        }
+    }
+
+    fn load(&mut self, chunk: &mut Chunk, name: &str, span: Span) {
+        let span = self.span(span);
+        match chunk.locals.get(name).copied() {
+            // Parameters are always assigned.
+            Some(slot) if (slot as usize) < chunk.params => {
+                chunk.emit(Op::LoadLocal(slot));
+            }
+            Some(local) => {
+                let global = self.global_slot(name);
+                chunk.emit(Op::LoadLocalOrGlobal {
+                    local,
+                    global,
+                    span,
+                });
+            }
+            None => {
+                let slot = self.global_slot(name);
+                chunk.emit(Op::LoadGlobal { slot, span });
+            }
+        }
+    }
+
+    fn store(&mut self, chunk: &mut Chunk, name: &str) {
+        match chunk.locals.get(name).copied() {
+            Some(slot) => chunk.emit(Op::StoreLocal(slot)),
+            None => {
+                let slot = self.global_slot(name);
+                chunk.emit(Op::StoreGlobal(slot))
+            }
+        };
+    }
+
+    fn constant(&mut self, chunk: &mut Chunk, n: Value) {
+        let constants = &mut self.program.constants;
+        let index = match constants.iter().position(|c| same_constant(c, &n)) {
+            Some(index) => index,
+            None => {
+                constants.push(n);
+                constants.len() - 1
+            }
+        };
+        chunk.emit(Op::Const(index as u32));
+    }
+
+    fn fail(&mut self, chunk: &mut Chunk, err: EvalError) {
+        self.program.errors.push(err);
+        chunk.emit(Op::Fail(self.program.errors.len() as u32 - 1));
+    }
+
+    fn site(&mut self, expr: &Expr) -> u32 {
+        self.program.sites.push(expr.clone());
+        self.program.sites.len() as u32 - 1
+    }
+
+    fn span(&mut self, span: Span) -> u32 {
+        self.program.spans.push(span);
+        self.program.spans.len() as u32 - 1
+    }
+
+    fn global_slot(&mut self, name: &str) -> u32 {
+        let globals = &mut self.program.globals;
+        *self.global_slots.entry(name.into()).or_insert_with(|| {
+            globals.push(name.into());
+            globals.len() as u32 - 1
+        })
+    }
+
+    fn func_slot(&mut self, name: &str) -> u32 {
+        let funcs = &mut self.program.func_names;
+        *self.func_slots.entry(name.into()).or_insert_with(|| {
+            funcs.push(name.into());
+            funcs.len() as u32 - 1
+        })
+    }
+}
+
+// `-0.0 == 0.0` and `NaN != NaN`, compare the bits of floats instead.
+fn same_constant(a: &Value, b: &Value) -> bool {
+    match (a, b) {
+        (Value::F64(a), Value::F64(b)) => a.to_bits() == b.to_bits(),
+        (a, b) => a == b,
+    }
+}
+
+// Names assigned in the body, the nested functions are not included.
+fn assigned_names(list: &ExprList, names: &mut Vec<String>) {
+    for expr in list.0.iter().flatten() {
+        assigned_in_expr(expr, names);
+    }
+}
+
+fn assigned_in_expr(expr: &Expr, names: &mut Vec<String>) {
+    match expr.kind {
+        ExprKind::Literal(_) | ExprKind::VarRef(_) | ExprKind::FuncDef(_) | ExprKind::Lambda(_) => {
+        }
+        ExprKind::OneOp(_, ref node) => assigned_in_expr(node, names),
+        ExprKind::TwoOp(_, ref lnode, ref rnode) => {
+            assigned_in_expr(lnode, names);
+            assigned_in_expr(rnode, names);
+        }
+        ExprKind::Assign(ref name, ref rnode) => {
+            names.push(name.clone());
+            assigned_in_expr(rnode, names);
+        }
+        ExprKind::Index(ref lnode, ref index) => {
+            assigned_in_expr(lnode, names);
+            assigned_in_expr(index, names);
+        }
+        ExprKind::Call(_, ref args) | ExprKind::Builtin(_, ref args) | ExprKind::List(ref args) => {
+            for arg in args {
+                assigned_in_expr(arg, names);
+            }
+        }
+        ExprKind::Flow(ref flow) => match flow {
+            ControlFlow::Condition(cond) => {
+                assigned_in_expr(&cond.cond, names);
+                assigned_names(&cond.if_branch, names);
+                if let Some(branch) = cond.else_branch.as_ref() {
+                    assigned_names(branch, names);
+                }
+            }
+            ControlFlow::While(while_loop) => {
+                assigned_in_expr(&while_loop.cond, names);
+                assigned_names(&while_loop.body, names);
+            }
+            ControlFlow::For(for_loop) => {
+                for part in [&for_loop.init, &for_loop.cond, &for_loop.step]
+                    .iter()
+                    .copied()
+                    .flatten()
+                {
+                    assigned_in_expr(part, names);
+                }
+                assigned_names(&for_loop.body, names);
+            }
+            ControlFlow::Break | ControlFlow::Continue => {}
+        },
+    }
+}
@@ -0,0 +1,853 @@
+//! Bytecode backend of the calculator.
+//!
+//! An `ExprList` is compiled to a `Program`, which is run by a stack based
+//! `Vm`. The results, the errors and the steps taken are the same as the
+//! tree-walking `ExprList::eval`, but variables are resolved to slots at
+//! compile time and calls don't use the Rust stack.
+
+mod compiler;
+
+use std::rc::Rc;
+
+use crate::builtin::BuiltinFunc;
+use crate::calculator_ast::{Expr, ExprList, FuncDef, NumericMode, Opcode, Span, Value};
+use crate::environment::{Environment, NativeFunc};
+use crate::error::{EvalError, Result};
+use crate::limits::Limit;
+use crate::typeck::Types;
+use crate::{list, numeric};
+
+/// An instruction of the VM, the operands are indexes into the tables of
+/// the `Program` or into the code.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Op {
+    Const(u32),
+    LoadGlobal {
+        slot: u32,
+        span: u32,
+    },
+    // A parameter, it's always assigned.
+    LoadLocal(u32),
+    // A local variable, it's the global one until it's assigned.
+    LoadLocalOrGlobal {
+        local: u32,
+        global: u32,
+        span: u32,
+    },
+    // The stores keep the value on the stack.
+    StoreGlobal(u32),
+    StoreLocal(u32),
+    Pop,
+    // Pop the value and replace the top of the stack with it.
+    Replace,
+    Unary(Opcode, u32),
+    Binary(Opcode, u32),
+    // `Binary` where both of the sides are checked to be floats.
+    FloatBinary(Opcode, u32),
+    // Replace the top of the stack with its truth, for `&&` and `||`.
+    ToBool,
+    // Pop the elements and push the list of them.
+    MakeList(u32),
+    Index(u32),
+    Jump(u32),
+    JumpIfFalse(u32),
+    DefineFn(u32),
+    // Find the function before the arguments are evaluated.
+    Resolve {
+        func: u32,
+        argc: u32,
+        span: u32,
+    },
+    Call {
+        argc: u32,
+        span: u32,
+    },
+    Builtin {
+        func: BuiltinFunc,
+        argc: u32,
+        site: u32,
+    },
+    // `integrate` or `solve`, the expression follows and ends with
+    // `EndBody`, the code continues at `end`.
+    Numeric {
+        func: BuiltinFunc,
+        local: u32,
+        span: u32,
+        call: u32,
+        body: u32,
+        end: u32,
+    },
+    EndBody,
+    Tick(u32),
+    Fail(u32),
+    Return,
+}
+
+/// A compiled function.
+#[derive(Clone, Debug)]
+pub struct Function {
+    slot: u32,
+    params: u32,
+    locals: u32,
+    code: Vec<Op>,
+    def: Rc<FuncDef>,
+    // Compiled from the `Environment`, it's defined before running.
+    linked: bool,
+}
+
+/// A compiled `ExprList`.
+#[derive(Clone, Debug, Default)]
+pub struct Program {
+    main: Vec<Op>,
+    main_locals: u32,
+    functions: Vec<Function>,
+    constants: Vec<Value>,
+    // The expressions where arithmetic errors are reported.
+    sites: Vec<Expr>,
+    spans: Vec<Span>,
+    errors: Vec<EvalError>,
+    globals: Vec<String>,
+    func_names: Vec<String>,
+    // The functions made by `fn(x) { ... }`, they are called as values.
+    lambdas: Vec<u32>,
+}
+
+impl Program {
+    pub fn compile(list: &ExprList) -> Self {
+        compiler::compile(list, None, None)
+    }
+
+    /// Compile the list with the functions defined in `env`, so the
+    /// program can call the functions of the earlier evaluations. The
+    /// function values in the global variables are compiled as well.
+    pub fn compile_in(list: &ExprList, env: &Environment) -> Self {
+        compiler::compile(list, Some(env), None)
+    }
+
+    /// `compile_in` with the types of the checked list, see `typeck`.
+    pub fn compile_checked(list: &ExprList, env: &Environment, types: &Types) -> Self {
+        compiler::compile(list, Some(env), Some(types))
+    }
+
+    /// The code of the top level.
+    pub fn code(&self) -> &[Op] {
+        &self.main
+    }
+
+    /// Names of the global variables used by the program.
+    pub fn globals(&self) -> &[String] {
+        &self.globals
+    }
+
+    pub fn global_slot(&self, name: &str) -> Option<u32> {
+        self.globals
+            .iter()
+            .position(|global| global == name)
+            .map(|slot| slot as u32)
+    }
+
+    /// Run the program in `env` like `ExprList::eval`.
+    pub fn eval(&self, env: &mut Environment) -> Result<Value> {
+        let mut vm = Vm::new(self);
+        vm.load(env);
+        let v = vm.run();
+        vm.store(env);
+        v
+    }
+}
+
+enum Callee {
+    User(u32),
+    Native(NativeFunc),
+}
+
+struct Frame {
+    // `None` is the top level.
+    func: Option<u32>,
+    pc: usize,
+    // Start of the locals of the frame.
+    base: usize,
+    // Called by a built-in function, `execute` returns at its end.
+    reentry: bool,
+}
+
+/// A stack based virtual machine running a `Program`.
+pub struct Vm<'p> {
+    program: &'p Program,
+    globals: Vec<Option<Value>>,
+    // Index of the function defined in every function slot.
+    functions: Vec<Option<u32>>,
+    natives: Vec<Option<NativeFunc>>,
+    // Functions defined by the program, in the order of definition.
+    defined: Vec<u32>,
+
+    step_limit: Option<u64>,
+    steps: u64,
+    mode: NumericMode,
+
+    stack: Vec<Value>,
+    locals: Vec<Option<Value>>,
+    callees: Vec<Callee>,
+    frames: Vec<Frame>,
+}
+
+impl<'p> Vm<'p> {
+    pub fn new(program: &'p Program) -> Self {
+        let mut functions = vec![None; program.func_names.len()];
+        for (index, func) in program.functions.iter().enumerate() {
+            if func.linked {
+                functions[func.slot as usize] = Some(index as u32);
+            }
+        }
+
+        Vm {
+            program,
+            globals: vec![None; program.globals.len()],
+            functions,
+            natives: vec![None; program.func_names.len()],
+            defined: Vec::new(),
+            step_limit: None,
+            steps: 0,
+            mode: NumericMode::default(),
+            stack: Vec::new(),
+            locals: Vec::new(),
+            callees: Vec::new(),
+            frames: Vec::new(),
+        }
+    }
+
+    /// Load the variables, the native functions, the step limit and the
+    /// numeric mode from `env`, the steps are counted from zero like an
+    /// outermost `ExprList::eval`.
+    pub fn load(&mut self, env: &Environment) {
+        for (slot, name) in self.program.globals.iter().enumerate() {
+            self.globals[slot] = env.get(name);
+        }
+        for (slot, name) in self.program.func_names.iter().enumerate() {
+            self.natives[slot] = env.native(name);
+        }
+        self.step_limit = env.step_limit();
+        self.steps = 0;
+        self.mode = env.mode();
+    }
+
+    /// Store the variables, the functions defined by the program, and
+    /// the steps back into `env`.
+    pub fn store(&self, env: &mut Environment) {
+        for (slot, name) in self.program.globals.iter().enumerate() {
+            if let Some(v) = &self.globals[slot] {
+                env.set(name, v.clone());
+            }
+        }
+        for &index in self.defined.iter() {
+            env.define_function(self.program.functions[index as usize].def.clone());
+        }
+        env.set_steps(self.steps);
+    }
+
+    /// Bind a global variable, it's ignored if the program doesn't use it.
+    pub fn set_global(&mut self, name: &str, value: Value) -> &mut Self {
+        if let Some(slot) = self.program.global_slot(name) {
+            self.globals[slot as usize] = Some(value);
+        }
+        self
+    }
+
+    pub fn global(&self, name: &str) -> Option<Value> {
+        self.program
+            .global_slot(name)
+            .and_then(|slot| self.globals[slot as usize].clone())
+    }
+
+    /// Register a native function, it's ignored if the program doesn't call it.
+    pub fn register_native(&mut self, name: &str, func: NativeFunc) -> &mut Self {
+        if let Some(slot) = self.program.func_names.iter().position(|f| f == name) {
+            self.natives[slot] = Some(func);
+        }
+        self
+    }
+
+    /// Set the step limit and reset the steps taken, `None` means no limit.
+    pub fn set_step_limit(&mut self, limit: Option<u64>) -> &mut Self {
+        self.step_limit = limit;
+        self.steps = 0;
+        self
+    }
+
+    pub fn steps(&self) -> u64 {
+        self.steps
+    }
+
+    pub fn set_mode(&mut self, mode: NumericMode) -> &mut Self {
+        self.mode = mode;
+        self
+    }
+
+    /// Run the program from the start.
+    pub fn run(&mut self) -> Result<Value> {
+        self.stack.clear();
+        self.locals.clear();
+        self.callees.clear();
+        self.frames.clear();
+        self.locals.resize(self.program.main_locals as usize, None);
+        self.frames.push(Frame {
+            func: None,
+            pc: 0,
+            base: 0,
+            reentry: false,
+        });
+        self.execute()
+    }
+
+    fn execute(&mut self) -> Result<Value> {
+        let program = self.program;
+        loop {
+            let frame = self.frames.last_mut().unwrap();
+            let code = match frame.func {
+                Some(index) => &program.functions[index as usize].code,
+                None => &program.main,
+            };
+            let op = code[frame.pc];
+            frame.pc += 1;
+            let base = frame.base;
+
+            match op {
+                Op::Const(index) => self.stack.push(program.constants[index as usize].clone()),
+                Op::LoadGlobal { slot, span } => {
+                    let v = self.load_global(slot, span)?;
+                    self.stack.push(v);
+                }
+                Op::LoadLocal(local) => {
+                    let v = self.locals[base + local as usize].clone().unwrap();
+                    self.stack.push(v);
+                }
+                Op::LoadLocalOrGlobal {
+                    local,
+                    global,
+                    span,
+                } => {
+                    let v = match &self.locals[base + local as usize] {
+                        Some(v) => v.clone(),
+                        None => self.load_global(global, span)?,
+                    };
+                    self.stack.push(v);
+                }
+                Op::StoreGlobal(slot) => {
+                    self.globals[slot as usize] = self.stack.last().cloned();
+                }
+                Op::StoreLocal(local) => {
+                    self.locals[base + local as usize] = self.stack.last().cloned();
+                }
+                Op::Pop => {
+                    self.stack.pop();
+                }
+                Op::Replace => {
+                    let v = self.pop();
+                    *self.stack.last_mut().unwrap() = v;
+                }
+                Op::Unary(op, site) => {
+                    let v = self.pop();
+                    let v = op
+                        .apply_unary(&v, self.mode)
+                        .map_err(|e| e.at(&program.sites[site as usize]))?;
+                    self.stack.push(v);
+                }
+                Op::Binary(op, site) => {
+                    let r = self.pop();
+                    let l = self.pop();
+                    let v = op
+                        .apply(&l, &r, self.mode)
+                        .map_err(|e| e.at(&program.sites[site as usize]))?;
+                    self.stack.push(v);
+                }
+                Op::FloatBinary(op, site) => {
+                    let r = self.pop();
+                    let l = self.pop();
+                    // The host may change a global variable after checking.
+                    let v = match (&l, &r) {
+                        (Value::F64(a), Value::F64(b)) => op.apply_f64(*a, *b),
+                        _ => op
+                            .apply(&l, &r, self.mode)
+                            .map_err(|e| e.at(&program.sites[site as usize]))?,
+                    };
+                    self.stack.push(v);
+                }
+                Op::ToBool => {
+                    let v = self.pop();
+                    self.stack.push(Value::from_bool(v.as_bool()));
+                }
+                Op::MakeList(len) => {
+                    let items = self.stack.split_off(self.stack.len() - len as usize);
+                    self.stack.push(Value::from(items));
+                }
+                Op::Index(site) => {
+                    let index = self.pop();
+                    let l = self.pop();
+                    let v = l
+                        .index(&index)
+                        .map_err(|e| e.at(&program.sites[site as usize]))?;
+                    self.stack.push(v);
+                }
+                Op::Jump(to) => self.jump(to),
+                Op::JumpIfFalse(to) => {
+                    if !self.pop().as_bool() {
+                        self.jump(to);
+                    }
+                }
+                Op::DefineFn(index) => {
+                    let func = &program.functions[index as usize];
+                    self.functions[func.slot as usize] = Some(index);
+                    self.defined.push(index);
+                }
+                Op::Resolve { func, argc, span } => {
+                    let callee = self.resolve(func, argc, span)?;
+                    self.callees.push(callee);
+                }
+                Op::Call { argc, span } => {
+                    let span = program.spans[span as usize];
+                    let args = self.stack.len() - argc as usize;
+                    match self.callees.pop().unwrap() {
+                        Callee::User(index) => {
+                            self.tick(span)?;
+                            let func = &program.functions[index as usize];
+                            let base = self.locals.len();
+                            self.locals.extend(self.stack.drain(args..).map(Some));
+                            self.locals.resize(base + func.locals as usize, None);
+                            self.frames.push(Frame {
+                                func: Some(index),
+                                pc: 0,
+                                base,
+                                reentry: false,
+                            });
+                        }
+                        Callee::Native(func) => {
+                            let values: Vec<_> = self.stack.drain(args..).collect();
+                            self.tick(span)?;
+                            let v = func.call(&values).map_err(|e| e.or_span(span))?;
+                            self.stack.push(v);
+                        }
+                    }
+                }
+                Op::Builtin { func, argc, site } if func.is_higher_order() => {
+                    let call = &program.sites[site as usize];
+                    let args = self.stack.split_off(self.stack.len() - argc as usize);
+                    let v = list::run(func, &args, call, |callee, values| {
+                        self.call_value(callee, values, call.span)
+                    })?;
+                    self.stack.push(v);
+                }
+                Op::Builtin { func, argc, site } => {
+                    let args = self.stack.len() - argc as usize;
+                    let v = func
+                        .call(&self.stack[args..], self.mode)
+                        .map_err(|e| e.at(&program.sites[site as usize]))?;
+                    self.stack.truncate(args);
+                    self.stack.push(v);
+                }
+                Op::Numeric {
+                    func,
+                    local,
+                    span,
+                    call,
+                    body,
+                    end,
+                } => {
+                    let call = &program.sites[call as usize];
+                    let argc = func.arity().min() - 2;
+                    let args = self.stack.len() - argc;
+                    let values = self.stack[args..]
+                        .iter()
+                        .map(|v| v.numeric().map_err(|e| e.at(call)))
+                        .collect::<Result<Vec<_>>>()?;
+                    self.stack.truncate(args);
+
+                    let start = self.frames.last().unwrap().pc;
+                    let slot = base + local as usize;
+                    let body = &program.sites[body as usize];
+                    let span = program.spans[span as usize];
+                    let v = numeric::run(func, &values, call, |x| {
+                        self.tick(span)?;
+                        self.locals[slot] = Some(Value::F64(x));
+                        self.jump(start as u32);
+                        self.execute()?.numeric().map_err(|e| e.at(body))
+                    })?;
+
+                    self.locals[slot] = None;
+                    self.jump(end);
+                    self.stack.push(v);
+                }
+                // Back to the `Numeric` running the expression.
+                Op::EndBody => return Ok(self.pop()),
+                Op::Tick(span) => self.tick(program.spans[span as usize])?,
+                Op::Fail(index) => return Err(program.errors[index as usize].clone()),
+                Op::Return => {
+                    let v = self.pop();
+                    let frame = self.frames.pop().unwrap();
+                    self.locals.truncate(frame.base);
+                    if frame.reentry || self.frames.is_empty() {
+                        return Ok(v);
+                    }
+                    self.stack.push(v);
+                }
+            }
+        }
+    }
+
+    // Run a function value to its end, the arity is checked by the caller.
+    fn call_value(
+        &mut self,
+        callee: &Rc<FuncDef>,
+        values: Vec<Value>,
+        span: Span,
+    ) -> Result<Value> {
+        let program = self.program;
+        let index = program
+            .lambdas
+            .iter()
+            .copied()
+            .find(|index| Rc::ptr_eq(&program.functions[*index as usize].def, callee))
+            // The function is made by another program.
+            .ok_or_else(|| EvalError::UndefinedFunction {
+                name: callee.name.clone(),
+                span,
+            })?;
+
+        self.tick(span)?;
+        let base = self.locals.len();
+        self.locals.extend(values.into_iter().map(Some));
+        self.locals.resize(
+            base + program.functions[index as usize].locals as usize,
+            None,
+        );
+        self.frames.push(Frame {
+            func: Some(index),
+            pc: 0,
+            base,
+            reentry: true,
+        });
+        self.execute()
+    }
+
+    fn pop(&mut self) -> Value {
+        self.stack.pop().unwrap()
+    }
+
+    fn jump(&mut self, to: u32) {
+        self.frames.last_mut().unwrap().pc = to as usize;
+    }
+
+    fn load_global(&self, slot: u32, span: u32) -> Result<Value> {
+        self.globals[slot as usize]
+            .clone()
+            .ok_or_else(|| EvalError::UndefinedVariable {
+                name: self.program.globals[slot as usize].clone(),
+                span: self.program.spans[span as usize],
+            })
+    }
+
+    fn resolve(&self, slot: u32, argc: u32, span: u32) -> Result<Callee> {
+        let name = &self.program.func_names[slot as usize];
+        let span = self.program.spans[span as usize];
+        if let Some(index) = self.functions[slot as usize] {
+            let func = &self.program.functions[index as usize];
+            if func.params != argc {
+                return Err(EvalError::ArityMismatch {
+                    name: name.clone(),
+                    expected: func.params as usize,
+                    found: argc as usize,
+                    span,
+                });
+            }
+            return Ok(Callee::User(index));
+        }
+
+        match self.natives[slot as usize].as_ref() {
+            Some(func) => Ok(Callee::Native(func.clone())),
+            None => Err(EvalError::UndefinedFunction {
+                name: name.clone(),
+                span,
+            }),
+        }
+    }
+
+    fn tick(&mut self, span: Span) -> Result<()> {
+        self.steps += 1;
+        match self.step_limit {
+            Some(limit) if self.steps > limit => Err(EvalError::LimitExceeded {
+                limit: Limit::Steps(limit),
+                span,
+            }),
+            _ => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::calculator::ListParser;
+    use crate::typeck::{self, Type};
+
+    // Run the source with both of the backends, the results and the
+    // globals must be the same.
+    fn differential(src: &str, env: &Environment) -> Option<Value> {
+        let list = ListParser::new().parse(src).unwrap();
+
+        let mut tree_env = env.clone();
+        let expected = list.eval(&mut tree_env);
+
+        let mut vm_env = env.clone();
+        let actual = Program::compile_in(&list, env).eval(&mut vm_env);
+
+        assert_eq!(
+            format!("{:?}", expected),
+            format!("{:?}", actual),
+            "result of {:?}",
+            src
+        );
+        assert_eq!(tree_env.globals(), vm_env.globals(), "globals of {:?}", src);
+        assert_eq!(tree_env.steps(), vm_env.steps(), "steps of {:?}", src);
+
+        // The checked program runs the same, and the value has the type
+        // inferred by the checker. The errors of the checker are raised
+        // by the evaluation too.
+        match typeck::check(&list, env) {
+            Ok(types) => {
+                let mut typed_env = env.clone();
+                let typed = Program::compile_checked(&list, env, &types).eval(&mut typed_env);
+                assert_eq!(
+                    format!("{:?}", expected),
+                    format!("{:?}", typed),
+                    "checked result of {:?}",
+                    src
+                );
+                if let Ok(v) = expected.as_ref() {
+                    let ty = types.result();
+                    assert!(
+                        ty == Type::Any || ty.join(Type::of(v)) == ty,
+                        "type of {:?} is {}",
+                        src,
+                        ty
+                    );
+                }
+            }
+            Err(errors) => assert!(expected.is_err(), "{:?}: {:?}", src, errors),
+        }
+        actual.ok()
+    }
+
+    #[test]
+    fn differential_test() {
+        let corpus = [
+            "1 + 2 * 3",
+            "a = 1; b = a + 2.5; c = b > a",
+            "a = b = 3; a * b",
+            "-(1 - 3) / 2",
+            "1 / 0",
+            "1.0 / 0",
+            "9223372036854775807 + 1",
+            "a = 1; a + b",
+            "x = 3; if x > 2 then { y = 1 } else { y = 2 }; y",
+            "if 0 then { 1 }",
+            "s = 0; i = 0; while i < 10 { i += 1; s += i }",
+            "s = 0; for (i = 0; i < 10; i += 1) { if i == 3 then { continue }; if i == 7 then { break }; s += i }",
+            "for (;;) { break }",
+            "n = 0; while 1 { n += 1; if n >= 5 then { break } }; n",
+            "i = 0; while i < 3 { i += 1; while 1 { break } }",
+            "break",
+            "continue",
+            "fn f() { break }; for (i = 0; i < 3; i += 1) { f() }",
+            "fn add(a, b) { a + b }; add(1, 2) + add(2.5, 1)",
+            "fn fib(n) { if n < 2 then { n } else { fib(n - 1) + fib(n - 2) } }; fib(15)",
+            "fn fact(n) { if n <= 1 then { 1 } else { n * fact(n - 1) } }; fact(20); fact(21)",
+            "g = 10; fn f(x) { g + x }; f(1)",
+            "g = 10; fn f(x) { g = x; g }; f(1); g",
+            "g = 10; fn f() { t = g; g = 2; t + g }; f(); g",
+            "fn f(x) { y = x }; f(1); y",
+            "fn f(x) { x }; f(1, 2)",
+            "f(1)",
+            "fn f(x) { fn g(y) { y * 2 }; g(x) + 1 }; f(3); g(4)",
+            "fn f(x) { x }; a = f(y)",
+            "fn f() { s = 0; for (i = 0; i < 5; i += 1) { s += i }; s }; f()",
+            "sqrt(16) + pow(2, 10) + max(1, 2.5, 2)",
+            "pow(2)",
+            "min()",
+            "sqrt(1 == 1)",
+            "abs(-9223372036854775807 - 1)",
+            "fn sqrt(x) { 0 }; sqrt(4)",
+            "x = 1; fn x() { 2 }; x() + x",
+            "integrate(x * x, x, 0, 3)",
+            "x = 5; k = 2; y = integrate(k * x, x, 0, 1) + x",
+            "fn area(r) { integrate(2 * sqrt(r * r - x * x), x, -r, r) }; area(1.0)",
+            "fn f(x) { integrate(x * t, t, 0, 1) + x }; f(3)",
+            "integrate(integrate(x * y, y, 0, x), x, 0, 1)",
+            "fn g(x) { x * x }; integrate(g(x), x, 0, 1)",
+            "solve(x * x - 2, x, 1)",
+            "solve(x * x + 1, x, 0)",
+            "solve(x * x * x - 8, x, 0)",
+            "integrate(x, 1, 0, 1)",
+            "integrate(x > 1, x, 0, 1)",
+            "integrate(x, x, 0, y)",
+            "integrate(x, x, 0 == 0, 1)",
+            "solve(x)",
+            "s = \"a\" + \"b\"; s == \"ab\" && s < \"b\"",
+            "\"a\" + 1",
+            "x = null; x == null || x",
+            "n = 0; 1 > 2 && (n = 1); true || (n = 2); n",
+            "false || \"\" || null",
+            "!0 && !(1 == 2) && 3",
+            "true && undefined",
+            "fn f(x) { x > 0 && f(x - 1) || x == 0 }; f(10)",
+            "i = 0; while i < 10 && i != 5 { i += 1 }; i",
+            "xs = [1, 2.5, \"a\", [null]]; xs[-1][0] == null && xs[1] > xs[0]",
+            "[1, 2][2]",
+            "[1] + [2, 3] == [1, 2, 3]",
+            "len([1, 2]) + len(\"abc\") + sum([1, 2, 3]) + max([4, 5]) + sum(1, 2)",
+            "min([])",
+            "map([1, 2, 3], fn(x) { x * x })",
+            "k = 3; filter([1, 2, 3, 4], fn(x) { x != k })",
+            "reduce([1, 2, 3], fn(a, x) { a * x }, 1)",
+            "fn total(xs) { reduce(xs, fn(a, x) { a + x }, 0) }; total(map([1, 2], fn(x) { total([x, x]) }))",
+            "map([1], fn(a, b) { a })",
+            "map([1, 0], fn(x) { 1 / x })",
+            "s = 0; map([1, 2, 3], fn(x) { for (;;) { break }; s = s + x }); s",
+            "f = fn(x) { x }; g = [f]; g[0] == f",
+            "v = 3 m / 2 s; d = v * 10 s in km; d > 10 m && d",
+            "1 m + 1 s",
+            "sum(map([1, 2, 3], fn(x) { x * 1 km })) in mi",
+            "sqrt(pow(3 m, 2) + pow(4 m, 2)) in cm",
+            "t = 0 s; for (i = 0; i < 3; i += 1) { t += 90 min }; t in h",
+            "x = 0.5; y = x * 2.0 - x / 4.0; y >= x && y != x",
+            "s = 0.0; for (i = 0; i < 10; i += 1) { s = s + 0.25 }; s",
+            "a = 1 == 1; a + 2",
+            "fn f(x) { x * 0.5 }; f(1.0) + f(2)",
+            "false && (1 + true)",
+            "true || [1][\"a\"]",
+            "if 0 then { 1 + true } else { 2 }",
+            "for (i = 0; i < 0; i += 1) { z = 1 }; z",
+            "while 0 { z = 1 }; z + 1",
+        ];
+        let mut exact = Environment::new();
+        exact.set_mode(NumericMode::Exact);
+        for src in corpus.iter() {
+            differential(src, &Environment::new());
+            differential(src, &exact);
+        }
+    }
+
+    #[test]
+    fn vm_exact_test() {
+        let mut env = Environment::new();
+        env.set_mode(NumericMode::Exact);
+
+        let corpus = [
+            "7 / 2 + 1 / 3",
+            "a = 9223372036854775807; a * a - a * a + 1",
+            "-(-9223372036854775807 - 1)",
+            "fn f(n) { if n <= 1 then { 1 } else { n * f(n - 1) } }; f(30) / f(28)",
+            "pow(2, 100) / pow(2, 98)",
+            "pow(2, -3) + abs(-1 / 2)",
+            "floor(7 / 2) + ceil(-7 / 2) + round(5 / 2)",
+            "min(1 / 3, 1 / 4) < max(1 / 3, 0.3)",
+            "x = 1 / 3; sqrt(x * 3)",
+            "1 / 3 == 2 / 6",
+            "100000000000000000000 / 0",
+        ];
+        for src in corpus.iter() {
+            differential(src, &env);
+        }
+    }
+
+    #[test]
+    fn vm_env_test() {
+        let mut env = Environment::new();
+        env.set("rate", Value::F64(0.5));
+        env.define_native(
+            "half",
+            NativeFunc::new(|args| match args {
+                [v] => Ok(Value::F64(v.as_f64() / 2.0)),
+                _ => Err(EvalError::host("half takes one argument")),
+            }),
+        );
+
+        differential("half(10) * rate", &env);
+        differential("half(1, 2)", &env);
+        differential("fn half(x) { x }; half(10)", &env);
+
+        // Functions of the earlier evaluations are linked into the program.
+        let list = ListParser::new()
+            .parse("fn sq(x) { x * x }; fn quad(x) { sq(sq(x)) }")
+            .unwrap();
+        list.eval(&mut env).unwrap();
+        assert_eq!(Value::I64(81), differential("quad(3)", &env).unwrap());
+
+        // So are the function values in the global variables.
+        let list = ListParser::new()
+            .parse("double = [fn(x) { x * 2 }]")
+            .unwrap();
+        list.eval(&mut env).unwrap();
+        assert_eq!(
+            Value::from(vec![Value::I64(2), Value::I64(4)]),
+            differential("map([1, 2], double[0])", &env).unwrap()
+        );
+
+        env.set_step_limit(Some(100));
+        differential("n = 0; while 1 { n += 1 }", &env);
+        differential("fn f(n) { f(n + 1) }; f(0)", &env);
+        differential("integrate(sqrt(abs(x)), x, -1, 1)", &env);
+        differential("map([1, 2, 3], fn(x) { while 1 { x } })", &env);
+    }
+
+    #[test]
+    fn vm_typed_test() {
+        let mut env = Environment::new();
+        env.set("rate", Value::F64(0.5));
+        let list = ListParser::new().parse("x = rate * 2.0; x > rate").unwrap();
+        let types = typeck::check(&list, &env).unwrap();
+        let program = Program::compile_checked(&list, &env, &types);
+        let floats = program
+            .code()
+            .iter()
+            .filter(|op| matches!(op, Op::FloatBinary(..)))
+            .count();
+        assert_eq!(2, floats);
+        assert_eq!(Value::Bool(true), program.eval(&mut env.clone()).unwrap());
+
+        // The host may change the variables after checking.
+        env.set("rate", Value::I64(3));
+        assert_eq!(Value::Bool(true), program.eval(&mut env.clone()).unwrap());
+        env.set("rate", Value::Bool(true));
+        assert!(matches!(
+            program.eval(&mut env),
+            Err(EvalError::TypeMismatch(_))
+        ));
+    }
+
+    #[test]
+    fn vm_api_test() {
+        let list = ListParser::new()
+            .parse("total = price * discount(rate)")
+            .unwrap();
+        let program = Program::compile(&list);
+        assert_eq!(Some(0), program.global_slot("price"));
+        assert_eq!(None, program.global_slot("discount"));
+
+        let mut vm = Vm::new(&program);
+        vm.set_global("price", Value::I64(100))
+            .set_global("rate", Value::F64(0.2))
+            .register_native(
+                "discount",
+                NativeFunc::new(|args| Ok(Value::F64(1.0 - args[0].as_f64()))),
+            );
+        assert_eq!(Value::F64(80.0), vm.run().unwrap());
+        assert_eq!(Some(Value::F64(80.0)), vm.global("total"));
+        assert_eq!(1, vm.steps());
+
+        // Deep recursion doesn't use the Rust stack.
+        let list = ListParser::new()
+            .parse("fn total(n) { if n == 0 then { 0 } else { n + total(n - 1) } }; total(100000)")
+            .unwrap();
+        let v = Program::compile(&list).eval(&mut Environment::new());
+        assert_eq!(Value::I64(5_000_050_000), v.unwrap());
+    }
+}
@@ -0,0 +1,728 @@
+//! The serialized form of the AST, as JSON or as a compact binary.
+//!
+//! The tree is flattened into a table of entries, an entry refers to the
+//! entries before it by their indexes. So the decoding never recurses, and
+//! the table is validated before the AST is built: every reference points
+//! back to an entry of the right kind, every expression, block and list is
+//! used at most once, the expressions and the blocks exactly once, the names, the operators and the units are the ones the
+//! parser makes, and the tree is at most `MAX_DEPTH` deep.
+//!
+//! The binary form is `MAGIC`, the version as a byte, then the table in
+//! `bincode` with variable-length integers.
+//!
+//! ```
+//! use calculus_parser::interpreter::Interpreter;
+//! use calculus_parser::pretty::pretty_list;
+//! use calculus_parser::wire;
+//!
+//! let list = Interpreter::new().parse("r = 2 km; r * r / 3").unwrap();
+//! let decoded = wire::from_bytes(&wire::to_bytes(&list)).unwrap();
+//! assert_eq!(pretty_list(&list), pretty_list(&decoded));
+//! let decoded = wire::from_json(&wire::to_json(&list)).unwrap();
+//! assert_eq!(pretty_list(&list), pretty_list(&decoded));
+//! ```
+//!
+//! `ExprList`, `Expr`, `ControlFlow` and `Value` are serialized with serde
+//! in the same form, so they can be embedded in the other formats too.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::rc::Rc;
+
+use bincode::Options;
+use num_bigint::BigInt;
+use num_rational::BigRational;
+use num_traits::Zero;
+use serde::de::{self, Deserializer, Visitor};
+use serde::{Deserialize, Serialize, Serializer};
+
+use crate::builtin::BuiltinFunc;
+use crate::calculator_ast::{
+    ControlFlow, Expr, ExprKind, ExprList, ForLoop, FuncDef, IfCondition, Opcode, Span, Value,
+    WhileLoop,
+};
+use crate::cst::is_name;
+use crate::error::DecodeError;
+use crate::units::{Quantity, Unit};
+
+/// The version of the format, the other versions are refused.
+pub const VERSION: u32 = 1;
+
+/// The first bytes of the binary form.
+pub const MAGIC: &[u8; 4] = b"CALC";
+
+/// The deepest tree decoded, the evaluator and the printer recurse on it.
+pub const MAX_DEPTH: usize = 2048;
+
+pub fn to_json(list: &ExprList) -> String {
+    serde_json::to_string(list).expect("the table is always serialized")
+}
+
+pub fn from_json(json: &str) -> Result<ExprList, DecodeError> {
+    // The version is checked first, the entries of another version may
+    // not be read at all.
+    #[derive(Deserialize)]
+    struct Header {
+        version: u32,
+    }
+    let header: Header = serde_json::from_str(json).map_err(malformed)?;
+    check_version(header.version)?;
+    let table: Table = serde_json::from_str(json).map_err(malformed)?;
+    table.decode()?.into_list()
+}
+
+pub fn to_bytes(list: &ExprList) -> Vec<u8> {
+    let table = Table::encode(|encoder| encoder.block(list));
+    let mut bytes = MAGIC.to_vec();
+    bytes.push(VERSION as u8);
+    options()
+        .serialize_into(&mut bytes, &(&table.entries, table.root))
+        .expect("the table is always serialized");
+    bytes
+}
+
+pub fn from_bytes(bytes: &[u8]) -> Result<ExprList, DecodeError> {
+    let rest = bytes
+        .strip_prefix(MAGIC.as_ref())
+        .ok_or(DecodeError::NotAnAst)?;
+    let (version, rest) = rest.split_first().ok_or(DecodeError::NotAnAst)?;
+    check_version(*version as u32)?;
+    let (entries, root) = options().deserialize(rest).map_err(malformed)?;
+    Table {
+        version: VERSION,
+        entries,
+        root,
+    }
+    .decode()?
+    .into_list()
+}
+
+fn options() -> impl Options {
+    bincode::DefaultOptions::new()
+}
+
+fn check_version(version: u32) -> Result<(), DecodeError> {
+    match version {
+        VERSION => Ok(()),
+        found => Err(DecodeError::Version {
+            found,
+            expected: VERSION,
+        }),
+    }
+}
+
+fn malformed(e: impl fmt::Display) -> DecodeError {
+    DecodeError::Malformed(e.to_string())
+}
+
+fn invalid(index: usize, reason: impl Into<String>) -> DecodeError {
+    DecodeError::Invalid {
+        index,
+        reason: reason.into(),
+    }
+}
+
+// The index of an entry in the table.
+type Ref = u32;
+
+#[derive(Serialize, Deserialize)]
+struct Table {
+    version: u32,
+    entries: Vec<Entry>,
+    root: Ref,
+}
+
+#[derive(Serialize, Deserialize)]
+enum Entry {
+    Value(Literal),
+    Func {
+        name: String,
+        params: Vec<String>,
+        body: Ref,
+    },
+    // The statements of a block.
+    Block(Vec<Ref>),
+    Expr(Node, Span),
+}
+
+#[derive(Serialize, Deserialize)]
+enum Literal {
+    Int(i64),
+    Float(#[serde(with = "float")] f64),
+    Bool(bool),
+    Str(String),
+    Null,
+    List(Vec<Ref>),
+    Func(Ref),
+    // The numerator and the denominator in decimal.
+    Rational(String, String),
+    // The unit is its symbol, like `km/h`.
+    Quantity {
+        #[serde(with = "float")]
+        value: f64,
+        unit: String,
+    },
+}
+
+// An expression, the built-in functions are their names.
+#[derive(Serialize, Deserialize)]
+enum Node {
+    Literal(Ref),
+    OneOp(Opcode, Ref),
+    TwoOp(Opcode, Ref, Ref),
+    VarRef(String),
+    Assign(String, Ref),
+    If {
+        cond: Ref,
+        then: Ref,
+        otherwise: Option<Ref>,
+    },
+    While {
+        cond: Ref,
+        body: Ref,
+    },
+    For {
+        init: Option<Ref>,
+        cond: Option<Ref>,
+        step: Option<Ref>,
+        body: Ref,
+    },
+    Break,
+    Continue,
+    FuncDef(Ref),
+    Call(String, Vec<Ref>),
+    Builtin(String, Vec<Ref>),
+    List(Vec<Ref>),
+    Index(Ref, Ref),
+    Lambda(Ref),
+}
+
+// The floats are numbers in JSON, except the ones which are not finite,
+// they are `"inf"`, `"-inf"` and `"NaN"`.
+mod float {
+    use super::*;
+
+    pub fn serialize<S: Serializer>(f: &f64, serializer: S) -> Result<S::Ok, S::Error> {
+        match f.is_finite() || !serializer.is_human_readable() {
+            true => serializer.serialize_f64(*f),
+            false => serializer.serialize_str(&f.to_string()),
+        }
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<f64, D::Error> {
+        struct Float;
+
+        impl Visitor<'_> for Float {
+            type Value = f64;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                write!(f, "a number, \"inf\", \"-inf\" or \"NaN\"")
+            }
+
+            fn visit_f64<E>(self, v: f64) -> Result<f64, E> {
+                Ok(v)
+            }
+
+            fn visit_i64<E>(self, v: i64) -> Result<f64, E> {
+                Ok(v as f64)
+            }
+
+            fn visit_u64<E>(self, v: u64) -> Result<f64, E> {
+                Ok(v as f64)
+            }
+
+            fn visit_str<E: de::Error>(self, v: &str) -> Result<f64, E> {
+                match v {
+                    "inf" | "-inf" | "NaN" => Ok(v.parse().unwrap()),
+                    _ => Err(E::invalid_value(de::Unexpected::Str(v), &self)),
+                }
+            }
+        }
+
+        match deserializer.is_human_readable() {
+            true => deserializer.deserialize_any(Float),
+            false => deserializer.deserialize_f64(Float),
+        }
+    }
+}
+
+impl Table {
+    fn encode(root: impl FnOnce(&mut Encoder) -> Ref) -> Table {
+        let mut encoder = Encoder::default();
+        let root = root(&mut encoder);
+        Table {
+            version: VERSION,
+            entries: encoder.entries,
+            root,
+        }
+    }
+
+    // Build the AST of the root, the entries are checked in order.
+    fn decode(self) -> Result<Decoded, DecodeError> {
+        check_version(self.version)?;
+        let mut decoder = Decoder::default();
+        for (index, entry) in self.entries.into_iter().enumerate() {
+            decoder.index = index;
+            decoder.deepest = 0;
+            let decoded = decoder.entry(entry)?;
+            let depth = decoder.deepest + 1;
+            if depth > MAX_DEPTH {
+                return Err(invalid(
+                    index,
+                    format!("nested deeper than {} levels", MAX_DEPTH),
+                ));
+            }
+            decoder.decoded.push(decoded);
+            decoder.depths.push(depth);
+        }
+
+        decoder.index = decoder.decoded.len();
+        let root = match decoder.at(self.root)? {
+            Decoded::Func(f) => Decoded::Func(f.clone()),
+            _ => decoder.take(self.root)?,
+        };
+        let unused = decoder
+            .decoded
+            .iter()
+            .position(|d| matches!(d, Decoded::Block(_) | Decoded::Expr(_)));
+        match unused {
+            Some(index) => Err(invalid(index, "not used")),
+            None => Ok(root),
+        }
+    }
+}
+
+#[derive(Default)]
+struct Encoder {
+    entries: Vec<Entry>,
+    // The functions shared by `Rc` are encoded once, the lists are not: the
+    // decoder takes each list once so a small table can't expand to a huge one.
+    shared: HashMap<*const (), Ref>,
+}
+
+impl Encoder {
+    fn push(&mut self, entry: Entry) -> Ref {
+        self.entries.push(entry);
+        (self.entries.len() - 1) as Ref
+    }
+
+    fn block(&mut self, list: &ExprList) -> Ref {
+        let stmts = list
+            .0
+            .iter()
+            .flatten()
+            .map(|expr| self.expr(expr))
+            .collect();
+        self.push(Entry::Block(stmts))
+    }
+
+    fn exprs(&mut self, exprs: &[Expr]) -> Vec<Ref> {
+        exprs.iter().map(|expr| self.expr(expr)).collect()
+    }
+
+    fn expr(&mut self, expr: &Expr) -> Ref {
+        let node = match expr.kind {
+            ExprKind::Literal(ref v) => Node::Literal(self.value(v)),
+            ExprKind::OneOp(op, ref node) => Node::OneOp(op, self.expr(node)),
+            ExprKind::TwoOp(op, ref lnode, ref rnode) => {
+                let lnode = self.expr(lnode);
+                Node::TwoOp(op, lnode, self.expr(rnode))
+            }
+            ExprKind::VarRef(ref name) => Node::VarRef(name.clone()),
+            ExprKind::Assign(ref name, ref node) => Node::Assign(name.clone(), self.expr(node)),
+            ExprKind::Flow(ref flow) => self.flow(flow),
+            ExprKind::FuncDef(ref func) => Node::FuncDef(self.func(func)),
+            ExprKind::Call(ref name, ref args) => Node::Call(name.clone(), self.exprs(args)),
+            ExprKind::Builtin(func, ref args) => {
+                Node::Builtin(func.name().into(), self.exprs(args))
+            }
+            ExprKind::List(ref items) => Node::List(self.exprs(items)),
+            ExprKind::Index(ref lnode, ref index) => {
+                let lnode = self.expr(lnode);
+                Node::Index(lnode, self.expr(index))
+            }
+            ExprKind::Lambda(ref func) => Node::Lambda(self.func(func)),
+        };
+        self.push(Entry::Expr(node, expr.span))
+    }
+
+    fn flow(&mut self, flow: &ControlFlow) -> Node {
+        match flow {
+            ControlFlow::Condition(if_cond) => {
+                let cond = self.expr(&if_cond.cond);
+                let then = self.block(&if_cond.if_branch);
+                let otherwise = if_cond.else_branch.as_ref().map(|b| self.block(b));
+                Node::If {
+                    cond,
+                    then,
+                    otherwise,
+                }
+            }
+            ControlFlow::While(while_loop) => {
+                let cond = self.expr(&while_loop.cond);
+                Node::While {
+                    cond,
+                    body: self.block(&while_loop.body),
+                }
+            }
+            ControlFlow::For(for_loop) => {
+                let init = for_loop.init.as_deref().map(|expr| self.expr(expr));
+                let cond = for_loop.cond.as_deref().map(|expr| self.expr(expr));
+                let step = for_loop.step.as_deref().map(|expr| self.expr(expr));
+                Node::For {
+                    init,
+                    cond,
+                    step,
+                    body: self.block(&for_loop.body),
+                }
+            }
+            ControlFlow::Break => Node::Break,
+            ControlFlow::Continue => Node::Continue,
+        }
+    }
+
+    fn func(&mut self, func: &Rc<FuncDef>) -> Ref {
+        let key = Rc::as_ptr(func) as *const ();
+        if let Some(r) = self.shared.get(&key) {
+            return *r;
+        }
+        let body = self.block(&func.body);
+        let r = self.push(Entry::Func {
+            name: func.name.clone(),
+            params: func.params.clone(),
+            body,
+        });
+        self.shared.insert(key, r);
+        r
+    }
+
+    fn value(&mut self, v: &Value) -> Ref {
+        let literal = match v {
+            Value::I64(i) => Literal::Int(*i),
+            Value::F64(f) => Literal::Float(*f),
+            Value::Bool(b) => Literal::Bool(*b),
+            Value::Str(s) => Literal::Str(s.to_string()),
+            Value::Null => Literal::Null,
+            Value::List(list) => Literal::List(list.iter().map(|v| self.value(v)).collect()),
+            Value::Func(func) => Literal::Func(self.func(func)),
+            Value::Rational(r) => Literal::Rational(r.numer().to_string(), r.denom().to_string()),
+            Value::Quantity(q) => Literal::Quantity {
+                value: q.value,
+                unit: q.unit.symbol().into(),
+            },
+        };
+        self.push(Entry::Value(literal))
+    }
+}
+
+// A decoded entry, the expressions and the blocks are moved to the one
+// using them.
+enum Decoded {
+    Value(Value),
+    Func(Rc<FuncDef>),
+    Block(ExprList),
+    Expr(Expr),
+    Used,
+}
+
+impl Decoded {
+    fn into_list(self) -> Result<ExprList, DecodeError> {
+        match self {
+            Decoded::Block(list) => Ok(list),
+            _ => Err(DecodeError::Malformed("the root is not a block".into())),
+        }
+    }
+
+    fn into_expr(self) -> Result<Expr, DecodeError> {
+        match self {
+            Decoded::Expr(expr) => Ok(expr),
+            _ => Err(DecodeError::Malformed(
+                "the root is not an expression".into(),
+            )),
+        }
+    }
+
+    fn into_value(self) -> Result<Value, DecodeError> {
+        match self {
+            Decoded::Value(v) => Ok(v),
+            _ => Err(DecodeError::Malformed("the root is not a value".into())),
+        }
+    }
+}
+
+#[derive(Default)]
+struct Decoder {
+    decoded: Vec<Decoded>,
+    depths: Vec<usize>,
+    // The entry being decoded and the deepest one it refers to.
+    index: usize,
+    deepest: usize,
+}
+
+impl Decoder {
+    fn at(&mut self, r: Ref) -> Result<&mut Decoded, DecodeError> {
+        let i = r as usize;
+        if i >= self.decoded.len() {
+            return Err(invalid(
+                self.index,
+                format!("refers to the entry {} which is not before it", r),
+            ));
+        }
+        self.deepest = self.deepest.max(self.depths[i]);
+        Ok(&mut self.decoded[i])
+    }
+
+    // Move the expression or the block out, it's used only once.
+    fn take(&mut self, r: Ref) -> Result<Decoded, DecodeError> {
+        let index = self.index;
+        match std::mem::replace(self.at(r)?, Decoded::Used) {
+            Decoded::Used => Err(invalid(index, format!("uses the entry {} twice", r))),
+            decoded => Ok(decoded),
+        }
+    }
+
+    fn expr(&mut self, r: Ref) -> Result<Expr, DecodeError> {
+        match self.take(r)? {
+            Decoded::Expr(expr) => Ok(expr),
+            _ => Err(invalid(
+                self.index,
+                format!("the entry {} is not an expression", r),
+            )),
+        }
+    }
+
+    fn boxed(&mut self, r: Ref) -> Result<Box<Expr>, DecodeError> {
+        self.expr(r).map(Box::new)
+    }
+
+    fn exprs(&mut self, refs: Vec<Ref>) -> Result<Vec<Expr>, DecodeError> {
+        refs.into_iter().map(|r| self.expr(r)).collect()
+    }
+
+    fn block(&mut self, r: Ref) -> Result<ExprList, DecodeError> {
+        match self.take(r)? {
+            Decoded::Block(list) => Ok(list),
+            _ => Err(invalid(
+                self.index,
+                format!("the entry {} is not a block", r),
+            )),
+        }
+    }
+
+    // The scalars are copied, a list is moved out like an expression.
+    fn value(&mut self, r: Ref) -> Result<Value, DecodeError> {
+        let index = self.index;
+        match self.at(r)? {
+            Decoded::Value(Value::List(_)) => match self.take(r)? {
+                Decoded::Value(v) => Ok(v),
+                _ => unreachable!("the entry is a list"),
+            },
+            Decoded::Value(v) => Ok(v.clone()),
+            Decoded::Used => Err(invalid(index, format!("uses the entry {} twice", r))),
+            _ => Err(invalid(index, format!("the entry {} is not a value", r))),
+        }
+    }
+
+    fn func(&mut self, r: Ref) -> Result<Rc<FuncDef>, DecodeError> {
+        let index = self.index;
+        match self.at(r)? {
+            Decoded::Func(func) => Ok(func.clone()),
+            _ => Err(invalid(index, format!("the entry {} is not a function", r))),
+        }
+    }
+
+    fn name(&self, name: String) -> Result<String, DecodeError> {
+        match is_name(&name) {
+            true => Ok(name),
+            false => Err(invalid(self.index, format!("`{}` is not a name", name))),
+        }
+    }
+
+    fn entry(&mut self, entry: Entry) -> Result<Decoded, DecodeError> {
+        Ok(match entry {
+            Entry::Value(literal) => Decoded::Value(self.literal(literal)?),
+            Entry::Func { name, params, body } => Decoded::Func(Rc::new(FuncDef {
+                name: self.name(name)?,
+                params: params
+                    .into_iter()
+                    .map(|param| self.name(param))
+                    .collect::<Result<_, _>>()?,
+                body: self.block(body)?,
+            })),
+            Entry::Block(stmts) => {
+                let stmts = self.exprs(stmts)?;
+                Decoded::Block(match stmts.is_empty() {
+                    true => ExprList(None),
+                    false => ExprList(Some(stmts.into_iter().map(Rc::new).collect())),
+                })
+            }
+            Entry::Expr(node, span) => {
+                if span.start > span.end {
+                    return Err(invalid(self.index, "the span ends before it starts"));
+                }
+                Decoded::Expr(Expr::new(self.node(node)?, span))
+            }
+        })
+    }
+
+    fn literal(&mut self, literal: Literal) -> Result<Value, DecodeError> {
+        Ok(match literal {
+            Literal::Int(i) => Value::I64(i),
+            Literal::Float(f) => Value::F64(f),
+            Literal::Bool(b) => Value::Bool(b),
+            Literal::Str(s) => Value::from(s),
+            Literal::Null => Value::Null,
+            Literal::List(items) => Value::from(
+                items
+                    .into_iter()
+                    .map(|r| self.value(r))
+                    .collect::<Result<Vec<_>, _>>()?,
+            ),
+            Literal::Func(r) => Value::Func(self.func(r)?),
+            Literal::Rational(numer, denom) => {
+                let int = |digits: &str| digits.parse::<BigInt>().ok();
+                match (int(&numer), int(&denom)) {
+                    (Some(numer), Some(denom)) if !denom.is_zero() => {
+                        Value::from_rational(BigRational::new(numer, denom))
+                    }
+                    _ => {
+                        let reason = format!("`{}/{}` is not a fraction", numer, denom);
+                        return Err(invalid(self.index, reason));
+                    }
+                }
+            }
+            Literal::Quantity { value, unit } => match Unit::parse(&unit) {
+                Some(unit) => Value::from(Quantity::new(value, unit)),
+                None => return Err(invalid(self.index, format!("unknown unit `{}`", unit))),
+            },
+        })
+    }
+
+    fn node(&mut self, node: Node) -> Result<ExprKind, DecodeError> {
+        let not_op = |index, op, what| invalid(index, format!("`{:?}` is not a {}", op, what));
+        Ok(match node {
+            Node::Literal(r) => ExprKind::Literal(self.value(r)?),
+            Node::OneOp(op, node) => match op {
+                Opcode::Sub | Opcode::Add | Opcode::Not => ExprKind::OneOp(op, self.boxed(node)?),
+                _ => return Err(not_op(self.index, op, "unary operator")),
+            },
+            Node::TwoOp(op, lnode, rnode) => match op {
+                Opcode::Assign | Opcode::Ref | Opcode::Not => {
+                    return Err(not_op(self.index, op, "binary operator"));
+                }
+                _ => ExprKind::TwoOp(op, self.boxed(lnode)?, self.boxed(rnode)?),
+            },
+            Node::VarRef(name) => ExprKind::VarRef(self.name(name)?),
+            Node::Assign(name, node) => ExprKind::Assign(self.name(name)?, self.boxed(node)?),
+            Node::If {
+                cond,
+                then,
+                otherwise,
+            } => ExprKind::Flow(ControlFlow::Condition(IfCondition {
+                cond: self.boxed(cond)?,
+                if_branch: self.block(then)?,
+                else_branch: otherwise.map(|r| self.block(r)).transpose()?,
+            })),
+            Node::While { cond, body } => ExprKind::Flow(ControlFlow::While(WhileLoop {
+                cond: self.boxed(cond)?,
+                body: self.block(body)?,
+            })),
+            Node::For {
+                init,
+                cond,
+                step,
+                body,
+            } => ExprKind::Flow(ControlFlow::For(ForLoop {
+                init: init.map(|r| self.boxed(r)).transpose()?,
+                cond: cond.map(|r| self.boxed(r)).transpose()?,
+                step: step.map(|r| self.boxed(r)).transpose()?,
+                body: self.block(body)?,
+            })),
+            Node::Break => ExprKind::Flow(ControlFlow::Break),
+            Node::Continue => ExprKind::Flow(ControlFlow::Continue),
+            Node::FuncDef(r) => ExprKind::FuncDef(self.func(r)?),
+            Node::Call(name, args) => {
+                // The parser resolves the built-in functions.
+                if BuiltinFunc::from_name(&name).is_some() {
+                    let reason = format!("`{}` is a built-in function", name);
+                    return Err(invalid(self.index, reason));
+                }
+                ExprKind::Call(self.name(name)?, self.exprs(args)?)
+            }
+            Node::Builtin(name, args) => match BuiltinFunc::from_name(&name) {
+                Some(func) => ExprKind::Builtin(func, self.exprs(args)?),
+                None => {
+                    let reason = format!("unknown built-in function `{}`", name);
+                    return Err(invalid(self.index, reason));
+                }
+            },
+            Node::List(items) => ExprKind::List(self.exprs(items)?),
+            Node::Index(lnode, index) => ExprKind::Index(self.boxed(lnode)?, self.boxed(index)?),
+            Node::Lambda(r) => ExprKind::Lambda(self.func(r)?),
+        })
+    }
+}
+
+impl Serialize for ExprList {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        Table::encode(|encoder| encoder.block(self)).serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for ExprList {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let table = Table::deserialize(deserializer)?;
+        table
+            .decode()
+            .and_then(Decoded::into_list)
+            .map_err(de::Error::custom)
+    }
+}
+
+impl Serialize for Expr {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        Table::encode(|encoder| encoder.expr(self)).serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for Expr {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let table = Table::deserialize(deserializer)?;
+        table
+            .decode()
+            .and_then(Decoded::into_expr)
+            .map_err(de::Error::custom)
+    }
+}
+
+/// The control flow is serialized as an expression without a span.
+impl Serialize for ControlFlow {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        Expr::new(ExprKind::Flow(self.clone()), Span::default()).serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for ControlFlow {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        match Expr::deserialize(deserializer)?.kind {
+            ExprKind::Flow(flow) => Ok(flow),
+            _ => Err(de::Error::custom("the root is not a control flow")),
+        }
+    }
+}
+
+impl Serialize for Value {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        Table::encode(|encoder| encoder.value(self)).serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for Value {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let table = Table::deserialize(deserializer)?;
+        table
+            .decode()
+            .and_then(Decoded::into_value)
+            .map_err(de::Error::custom)
+    }
+}
@@ -0,0 +1,289 @@
+use calculus_parser::calculator::{ExprParser, ListParser};
+use calculus_parser::calculator_ast::{ExprKind, NumericMode};
+use calculus_parser::derivative::derive;
+use calculus_parser::diagnostic::Diagnostic;
+use calculus_parser::environment::Environment;
+use calculus_parser::optimizer::optimize_list;
+use calculus_parser::pretty::{pretty, pretty_list};
+use calculus_parser::typeck;
+use lalrpop_util::ParseError;
+
+// Every input can take at most this many loop iterations and calls.
+const STEP_LIMIT: u64 = 10_000_000;
+
+const HELP: &str = "\
+:vars          list the global variables
+:reset         drop all the variables and functions
+:mode [m]      print or set the numeric mode, `checked` or `exact`
+:ast <source>  print the syntax tree of the source
+:opt <source>  print the simplified source
+:type <source> print the type of the source without evaluating it
+:d/dx <expr>   print the derivative of the expression with respect to x
+:history       list the history
+:!<n>          run the n-th line in the history again
+:help          print this help
+:quit          exit";
+
+/// Line-oriented session, the variables and functions are kept
+/// between lines.
+pub struct Repl {
+    parser: ListParser,
+    env: Environment,
+    // Lines of an unfinished input, like a multi-line `if` block.
+    pending: Vec<String>,
+    history: Vec<String>,
+    finished: bool,
+    step_limit: u64,
+}
+
+impl Default for Repl {
+    fn default() -> Self {
+        Repl {
+            parser: ListParser::new(),
+            env: Environment::new(),
+            pending: Vec::new(),
+            history: Vec::new(),
+            finished: false,
+            step_limit: STEP_LIMIT,
+        }
+    }
+}
+
+impl Repl {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn prompt(&self) -> &'static str {
+        if self.pending.is_empty() {
+            "> "
+        } else {
+            ".. "
+        }
+    }
+
+    /// `:quit` is received.
+    pub fn finished(&self) -> bool {
+        self.finished
+    }
+
+    /// Feed a line to the session, returns the text to print.
+    pub fn feed(&mut self, line: &str) -> Option<String> {
+        let trimmed = line.trim();
+        if self.pending.is_empty() {
+            if trimmed.is_empty() {
+                return None;
+            }
+            if let Some(command) = trimmed.strip_prefix(':') {
+                return self.command(command.trim());
+            }
+        } else if trimmed.is_empty() {
+            // An empty line gives up the unfinished input.
+            let source = self.take_pending();
+            return Some(format!("error: incomplete input `{}`", source));
+        }
+
+        self.pending.push(line.to_string());
+        let source = self.pending.join("\n");
+        let parsed = match self.parser.parse(&source) {
+            // Wait for more lines if the input is not finished.
+            Err(ParseError::UnrecognizedEOF { .. }) => return None,
+            Err(e) => Err(Diagnostic::from_parse_error(&e).render(&source)),
+            Ok(list) => Ok(list),
+        };
+        self.pending.clear();
+        self.env.set_step_limit(Some(self.step_limit));
+
+        let output = match parsed {
+            Ok(list) => match list.eval(&mut self.env) {
+                // A definition is echoed by its signature, its value is `0`.
+                Ok(v) => match list.0.iter().flatten().last().map(|expr| &expr.kind) {
+                    Some(ExprKind::FuncDef(func)) => {
+                        format!("fn {}({})", func.name, func.params.join(", "))
+                    }
+                    _ => v.to_string(),
+                },
+                Err(e) => Diagnostic::from_eval_error(&e, &source).render(&source),
+            },
+            Err(e) => e,
+        };
+        self.history.push(source);
+        Some(output)
+    }
+
+    fn take_pending(&mut self) -> String {
+        let source = self.pending.join("\n");
+        self.pending.clear();
+        source
+    }
+
+    fn command(&mut self, command: &str) -> Option<String> {
+        let (name, arg) = match command.find(char::is_whitespace) {
+            Some(pos) => (&command[..pos], command[pos..].trim()),
+            None => (command, ""),
+        };
+
+        match name {
+            "vars" => {
+                let vars: Vec<_> = self
+                    .env
+                    .globals()
+                    .into_iter()
+                    .map(|(name, v)| format!("{} = {}", name, v))
+                    .collect();
+                Some(vars.join("\n"))
+            }
+            "reset" => {
+                self.env.reset();
+                None
+            }
+            "mode" => match arg {
+                "" => Some(format!("{:?}", self.env.mode()).to_lowercase()),
+                "checked" => {
+                    self.env.set_mode(NumericMode::Checked);
+                    None
+                }
+                "exact" => {
+                    self.env.set_mode(NumericMode::Exact);
+                    None
+                }
+                _ => Some(format!(
+                    "error: unknown mode `{}`, expected `checked` or `exact`",
+                    arg
+                )),
+            },
+            "ast" => Some(match self.parser.parse(arg) {
+                Ok(list) => format!("{:?}", list),
+                Err(e) => Diagnostic::from_parse_error(&e).render(arg),
+            }),
+            "opt" => Some(match self.parser.parse(arg) {
+                Ok(list) => pretty_list(&optimize_list(&list)),
+                Err(e) => Diagnostic::from_parse_error(&e).render(arg),
+            }),
+            "type" => Some(match self.parser.parse(arg) {
+                Ok(list) => match typeck::check(&list, &self.env) {
+                    Ok(types) => types.result().to_string(),
+                    Err(errors) => {
+                        let errors: Vec<_> = errors
+                            .iter()
+                            .map(|e| Diagnostic::from_eval_error(e, arg).render(arg))
+                            .collect();
+                        errors.join("\n")
+                    }
+                },
+                Err(e) => Diagnostic::from_parse_error(&e).render(arg),
+            }),
+            "history" => {
+                let lines: Vec<_> = self
+                    .history
+                    .iter()
+                    .enumerate()
+                    .map(|(i, line)| format!("{:>4}  {}", i + 1, line))
+                    .collect();
+                Some(lines.join("\n"))
+            }
+            "help" => Some(HELP.to_string()),
+            "quit" | "q" => {
+                self.finished = true;
+                None
+            }
+            _ if name.starts_with("d/d") && name.len() > 3 => {
+                Some(self.derivative(&name[3..], arg))
+            }
+            _ => match name.strip_prefix('!').and_then(|n| n.parse::<usize>().ok()) {
+                Some(n) if n >= 1 && n <= self.history.len() => {
+                    let source = self.history[n - 1].clone();
+                    self.feed(&source)
+                }
+                Some(n) => Some(format!("error: no line {} in the history", n)),
+                None => Some(format!("error: unknown command `:{}`, try :help", name)),
+            },
+        }
+    }
+
+    fn derivative(&self, var: &str, source: &str) -> String {
+        let expr = match ExprParser::new().parse(source) {
+            Ok(expr) => expr,
+            Err(e) => return Diagnostic::from_parse_error(&e).render(source),
+        };
+        match derive(&expr, var) {
+            Ok(d) => pretty(&d),
+            Err(e) => Diagnostic::from(&e).render(source),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_session() {
+        let mut repl = Repl::new();
+        assert_eq!(Some("2".to_string()), repl.feed("a = 2"));
+        assert_eq!(Some("6".to_string()), repl.feed("a * 3"));
+        assert_eq!(None, repl.feed("fn sq(x) {"));
+        assert_eq!(".. ", repl.prompt());
+        assert_eq!(None, repl.feed("  x * x"));
+        assert_eq!(Some("fn sq(x)".to_string()), repl.feed("}"));
+        assert_eq!("> ", repl.prompt());
+        assert_eq!(None, repl.feed("if (sq(a) == 4) then {"));
+        assert_eq!(Some("1.5".to_string()), repl.feed("1.5 }"));
+        assert_eq!(
+            Some("fn add(a, b)".to_string()),
+            repl.feed("fn add(a, b) { a + b }")
+        );
+        assert_eq!(
+            Some("3".to_string()),
+            repl.feed("fn id(x) { x }; add(1, 2)")
+        );
+
+        assert_eq!(Some("a = 2".to_string()), repl.feed(":vars"));
+        assert_eq!(None, repl.feed(":reset"));
+        assert_eq!(Some(String::new()), repl.feed(":vars"));
+        assert_eq!(
+            Some("error: undefined variable `a`\n --> 1:5\n  |\n1 | b = a\n  |     ^".to_string()),
+            repl.feed("b = a")
+        );
+
+        assert!(repl.feed(":ast a + 1").unwrap().contains("Add"));
+        assert_eq!(Some("a + 2".to_string()), repl.feed(":opt a * 1 + (1 + 1)"));
+        assert_eq!(Some("x + x".to_string()), repl.feed(":d/dx x * x + 3"));
+        assert!(repl
+            .feed(":d/dx x < 1")
+            .unwrap()
+            .starts_with("error: cannot differentiate a comparison"));
+        assert!(repl.feed(":history").unwrap().contains("   2  a * 3"));
+        assert_eq!(Some("2".to_string()), repl.feed(":!1"));
+        assert_eq!(Some("5".to_string()), repl.feed("a + 3"));
+        assert_eq!(Some("float".to_string()), repl.feed(":type a * 1.5"));
+        assert!(repl
+            .feed(":type a + true")
+            .unwrap()
+            .starts_with("error: type mismatch"));
+        assert_eq!(Some("a = 2".to_string()), repl.feed("\"a = \" + \"2\""));
+
+        assert_eq!(Some("checked".to_string()), repl.feed(":mode"));
+        assert_eq!(Some("3".to_string()), repl.feed("7 / 2"));
+        assert_eq!(None, repl.feed(":mode exact"));
+        assert_eq!(Some("7/2".to_string()), repl.feed("7 / 2"));
+        assert_eq!(Some("1 / 3".to_string()), repl.feed(":opt 1 / 3"));
+        assert!(repl
+            .feed(":mode fast")
+            .unwrap()
+            .starts_with("error: unknown mode"));
+        assert_eq!(None, repl.feed(":mode checked"));
+
+        repl.step_limit = 1000;
+        assert!(repl
+            .feed("while (1 == 1) { a += 1 }")
+            .unwrap()
+            .starts_with("error: evaluation exceeds the limit"));
+
+        assert!(repl.feed("1 +").is_none());
+        assert!(repl.feed("").unwrap().starts_with("error"));
+
+        repl.feed(":quit");
+        assert!(repl.finished());
+    }
+}